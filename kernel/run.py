@@ -95,6 +95,8 @@ parser.add_argument("--qemu-memory", type=int,
                     help="How much total memory in MiB (will get evenly divided among nodes).", default=1024)
 parser.add_argument("--qemu-pmem", type=int,
                     help="How much total peristent memory in MiB (will get evenly divided among nodes).", required=False, default=0)
+parser.add_argument("--qemu-pmem-path", type=str,
+                    help="Back the NVDIMMs with files in this directory (their contents survive the VM).", required=False, default="")
 parser.add_argument("--qemu-affinity", action="store_true", default=False,
                     help="Pin QEMU instance to dedicated host cores.")
 parser.add_argument("--qemu-prealloc", action="store_true", default=False,
@@ -422,7 +424,12 @@ def run_qemu(args):
             if args.qemu_cores > 0 and args.qemu_pmem:
                 pmem_per_node = args.qemu_pmem / args.qemu_nodes
                 default = "/mnt/node{}".format(node)
-                if os.path.isdir(default):
+                if args.qemu_pmem_path:
+                    pmem_file = os.path.join(
+                        args.qemu_pmem_path, "pmem{}".format(node))
+                    qemu_default_args += ['-object', 'memory-backend-file,id=pmem{},mem-path={},size={}M,pmem=off,share=on'.format(
+                        node, pmem_file, int(pmem_per_node))]
+                elif os.path.isdir(default):
                     qemu_default_args += ['-object', 'memory-backend-file,id=pmem{},mem-path={},size={}M,pmem=on,share=on'.format(
                         node, default, int(pmem_per_node))]
                 else:
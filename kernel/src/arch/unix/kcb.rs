@@ -7,15 +7,15 @@ use alloc::sync::Arc;
 use core::any::Any;
 use core::cell::{RefCell, RefMut};
 
-use arrayvec::ArrayVec;
 use cnr::{Replica as MlnrReplica, ReplicaToken as MlnrReplicaToken};
 use node_replication::{Replica, ReplicaToken};
 
 use crate::cnrfs::MlnrKernelNode;
 use crate::error::KError;
 use crate::nr::KernelNode;
-use crate::nrproc::NrProcess;
-use crate::process::{Pid, MAX_PROCESSES};
+use crate::nrproc::ProcessTable;
+use crate::process::Pid;
+use crate::scheduler::RunQueue;
 use crate::{
     kcb::{ArchSpecificKcb, BootloaderArguments, Kcb},
     memory::mcache::TCacheSp,
@@ -23,7 +23,7 @@ use crate::{
 
 use super::process::{UnixProcess, UnixThread};
 use super::vspace::VSpace;
-use super::{KernelArgs, MAX_NUMA_NODES};
+use super::KernelArgs;
 
 static KERNEL_ARGS: KernelArgs = KernelArgs::new();
 
@@ -61,6 +61,7 @@ pub struct ArchKcb {
     pub replica: Option<(Arc<Replica<'static, KernelNode>>, ReplicaToken)>,
     pub cnr_replica: Option<(Arc<MlnrReplica<'static, MlnrKernelNode>>, MlnrReplicaToken)>,
     pub current_executor: Option<Box<UnixThread>>,
+    pub run_queue: RunQueue<UnixThread>,
 }
 
 impl ArchKcb {
@@ -71,6 +72,7 @@ impl ArchKcb {
             replica: None,
             cnr_replica: None,
             current_executor: None,
+            run_queue: RunQueue::new(),
         }
     }
 
@@ -132,13 +134,7 @@ impl ArchSpecificKcb for ArchKcb {
         Err(KError::ProcessNotSet)
     }
 
-    #[allow(clippy::type_complexity)] // fix this once `associated_type_defaults` works
-    fn process_table(
-        &self,
-    ) -> &'static ArrayVec<
-        ArrayVec<Arc<Replica<'static, NrProcess<Self::Process>>>, MAX_PROCESSES>,
-        MAX_NUMA_NODES,
-    > {
+    fn process_table(&self) -> &'static ProcessTable<Self::Process> {
         &*super::process::PROCESS_TABLE
     }
 }
@@ -37,6 +37,10 @@ pub fn advance_fs_replica() {
     unimplemented!("eager_advance_fs_replica not implemented for unix");
 }
 
+pub fn request_reclaim(_gtid: atopology::GlobalThreadId) {
+    // No IPIs on unix, the request is picked up lazily
+}
+
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 #[ctor]
@@ -90,7 +94,10 @@ fn init_setup() {
 
     let log: Arc<Log<Op>> = Arc::try_new(Log::<Op>::new(LARGE_PAGE_SIZE))
         .expect("Not enough memory to initialize system");
-    let bsp_replica = Replica::<KernelNode>::new(&log);
+    let bsp_replica = Replica::<KernelNode>::with_data(
+        &log,
+        KernelNode::new(kcb::get_kcb().cmdline.max_processes),
+    );
     let local_ridx = bsp_replica
         .register()
         .expect("Failed to register with Replica.");
@@ -3,7 +3,6 @@
 
 //! A dummy process implementation for the unix platform.
 use alloc::boxed::Box;
-use alloc::sync::Arc;
 use alloc::vec::Vec;
 use bootloader_shared::Module;
 use core::ops::{Deref, DerefMut};
@@ -13,61 +12,21 @@ use arrayvec::ArrayVec;
 use kpi::process::FrameId;
 use lazy_static::lazy_static;
 
-use node_replication::{Dispatch, Log, Replica};
-
 use crate::error::KError;
 use crate::fs::Fd;
-use crate::kcb::{self, ArchSpecificKcb};
 use crate::memory::detmem::DA;
 use crate::memory::vspace::AddressSpace;
 use crate::memory::vspace::MapAction;
-use crate::memory::{Frame, VAddr, LARGE_PAGE_SIZE};
-use crate::nrproc::NrProcess;
-use crate::process::{
-    Eid, Executor, Pid, Process, ResumeHandle, MAX_FRAMES_PER_PROCESS, MAX_PROCESSES,
-};
+use crate::memory::{Frame, VAddr};
+use crate::nrproc::ProcessTable;
+use crate::process::{Eid, Executor, Pid, Process, ResumeHandle, MAX_FRAMES_PER_PROCESS};
 
 use super::debug;
 use super::vspace::VSpace;
-use super::MAX_NUMA_NODES;
 
 lazy_static! {
-    pub static ref PROCESS_TABLE: ArrayVec<ArrayVec<Arc<Replica<'static, NrProcess<UnixProcess>>>, MAX_PROCESSES>, MAX_NUMA_NODES> = {
-        // Want at least one replica...
-        let numa_nodes = core::cmp::max(1, atopology::MACHINE_TOPOLOGY.num_nodes());
-
-        let mut numa_cache = ArrayVec::new();
-        for _n in 0..numa_nodes {
-            let process_replicas = ArrayVec::new();
-            debug_assert!(!numa_cache.is_full(), "Ensured by loop range");
-            numa_cache.push(process_replicas)
-        }
-
-        for pid in 0..MAX_PROCESSES {
-                let log = Arc::try_new(Log::<<NrProcess<UnixProcess> as Dispatch>::WriteOperation>::new(
-                    LARGE_PAGE_SIZE,
-                )).expect("Can't initialize processes, out of memory.");
-
-            let da = DA::new().expect("Can't initialize process deterministic memory allocator");
-            for node in 0..numa_nodes {
-                let kcb = kcb::get_kcb();
-                assert!(kcb.set_mem_affinity(node as atopology::NodeId).is_ok());
-
-                debug_assert!(!numa_cache[node].is_full(), "Ensured by loop range");
-
-
-                let p = Box::try_new(UnixProcess::new(pid, da.clone()).expect("Can't create process during init")).expect("Not enough memory to initialize processes");
-                let nrp = NrProcess::new(p, da.clone());
-
-                numa_cache[node].push(Replica::<NrProcess<UnixProcess>>::with_data(&log, nrp));
-
-                debug_assert_eq!(kcb.arch.node(), 0, "Expect initialization to happen on node 0.");
-                assert!(kcb.set_mem_affinity(0).is_ok());
-            }
-        }
-
-        numa_cache
-    };
+    /// The replicas of all processes (created on-demand).
+    pub static ref PROCESS_TABLE: ProcessTable<UnixProcess> = ProcessTable::new(UnixProcess::new);
 }
 
 /// TODO: This code is same as x86_64 process. Can we remove it?
@@ -265,6 +224,10 @@ impl Process for UnixProcess {
         Ok(Box::new(UnixThread::default()))
     }
 
+    fn release_executor(&mut self, _eid: Eid) -> Result<(), KError> {
+        Ok(())
+    }
+
     fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)> {
         Some((1, &mut self.fd))
     }
@@ -292,6 +255,14 @@ impl Process for UnixProcess {
     fn deallocate_frame(&mut self, _fid: FrameId) -> Result<Frame, KError> {
         Err(KError::InvalidFrameId)
     }
+
+    fn destroy(&mut self) -> Result<Vec<Frame>, KError> {
+        *self = UnixProcess {
+            vspace: VSpace::new(),
+            ..Default::default()
+        };
+        Ok(Vec::new())
+    }
 }
 
 pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
@@ -6,5 +6,9 @@
 /// Default when to raise the next timer irq (in rdtsc ticks)
 pub const DEFAULT_TIMER_DEADLINE: u64 = 2_000_000_000;
 
+/// How long an executor runs before it is preempted (in rdtsc ticks) if
+/// other executors wait for the core
+pub const DEFAULT_TIME_SLICE: u64 = 20_000_000;
+
 /// Register a periodic timer to advance replica.
 pub fn set(_deadline: u64) {}
@@ -48,6 +48,7 @@ use apic::ApicDriver;
 use klogger::{sprint, sprintln};
 use log::{info, trace, warn};
 
+use crate::error::KError;
 use crate::kcb::ArchSpecificKcb;
 use crate::memory::vspace::MapAction;
 use crate::memory::Frame;
@@ -312,10 +313,11 @@ unsafe fn unhandled_irq(a: &ExceptionArguments) {
     debug::shutdown(ExitReason::UnhandledInterrupt);
 }
 
-/// Handler for unexpected page-faults.
+/// Handler for page-faults.
 ///
-/// TODO: Right now we terminate kernel.
-/// Should abort process and resume.
+/// Page-faults in reserved regions of a process are resolved by mapping a
+/// fresh page, other user-space faults go to the process (see
+/// [`user_fault`]). Page-faults in the kernel are fatal.
 unsafe fn pf_handler(a: &ExceptionArguments) {
     use crate::arch::kcb;
 
@@ -332,6 +334,21 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
             .expect("A pid must be set in this if branch (US bit set in page-fault error)");
 
         match nrproc::NrProcess::<Ring3Process>::resolve(pid, faulting_address_va) {
+            Ok(_) if err.contains(PageFaultError::P | PageFaultError::WR) => {
+                // A write to a read-only page, it might be a copy-on-write
+                // page that needs its private copy first (otherwise it's a
+                // spurious page-fault like below):
+                match nrproc::NrProcess::<Ring3Process>::copy_on_write(pid, faulting_address_va) {
+                    Ok(Some(handle)) => super::tlb::shootdown(pid, handle),
+                    Ok(None) | Err(KError::NotMapped) => {}
+                    Err(e) => {
+                        warn!("Can't copy page {}: {}", faulting_address_va, e);
+                        user_fault(a, faulting_address as u64);
+                    }
+                }
+                let r = kcb_iret_handle(kcb);
+                r.resume()
+            }
             Ok((paddr, rights)) => {
                 // TODO(harden): We probably want to warn/abort if we get many
                 // "spurious" pfaults for the same addr in quick succession: one
@@ -348,9 +365,18 @@ unsafe fn pf_handler(a: &ExceptionArguments) {
                 r.resume()
             }
             Err(_) => {
-                // unresolved page-fault, proceed with abort below
+                // The first access to a page of a reserved region, back it
+                // with memory and retry the access:
+                if nrproc::NrProcess::<Ring3Process>::map_lazy_page(pid, faulting_address_va)
+                    .is_ok()
+                {
+                    let r = kcb_iret_handle(kcb);
+                    r.resume()
+                }
             }
         }
+
+        user_fault(a, faulting_address as u64);
     }
 
     sprintln!("[IRQ] Page Fault on {}", kcb.arch.id());
@@ -483,8 +509,9 @@ unsafe fn bkp_handler(a: &ExceptionArguments) {
 
 /// Handler for the timer exception.
 ///
-/// We currently use it to periodically make sure that a replica
-/// makes forward progress to avoid liveness issues.
+/// We use it to periodically make sure that a replica makes forward progress
+/// to avoid liveness issues, and to switch between executors that share a
+/// core once a time-slice is up.
 unsafe fn timer_handler(a: &ExceptionArguments) {
     #[cfg(feature = "test-timer")]
     {
@@ -497,29 +524,36 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
     // Periodically advance replica state, then resume immediately
     nr::KernelNode::synchronize();
     let kcb = get_kcb();
-    for pid in 0..crate::process::MAX_PROCESSES {
+    for pid in super::process::PROCESS_TABLE.pids() {
         nrproc::NrProcess::<Ring3Process>::synchronize(pid);
     }
+    // Give memory back in case another core ran out
+    crate::memory::KernelAllocator::reclaim_if_requested();
 
     if kcb.arch.has_executor() {
+        // Pick up processes that were assigned to this core in the meantime
+        if let Err(e) = crate::scheduler::update_run_queue() {
+            warn!("Unable to update the run-queue: {:?}", e);
+        }
+
+        if !kcb.arch.run_queue.is_empty() {
+            // Someone else is waiting, time-slice is up
+            match kcb.arch.preempt_current_executor() {
+                Ok(()) => crate::scheduler::schedule(),
+                Err(e) => warn!("Unable to preempt executor: {:?}", e),
+            }
+        }
+
         // TODO(process-mgmt): Ensures that we still periodically
         // check and advance replicas even on cores that have a core.
         // Only a single idle core per replica should probably do that,
         // so if cores go properly back to idling when finished execution,
         // this is no longer necessary...
-        let is_replica_main_thread = {
-            let thread = atopology::MACHINE_TOPOLOGY.current_thread();
-            thread.node().is_none()
-                || thread
-                    .node()
-                    .unwrap()
-                    .threads()
-                    .next()
-                    .map(|t| t.id == thread.id)
-                    .unwrap_or(false)
-        };
-        if is_replica_main_thread {
-            timer::set(timer::DEFAULT_TIMER_DEADLINE);
+        timer::set(timer::DEFAULT_TIMER_DEADLINE);
+
+        // Deliver events that were sent to the process
+        if let Some(r) = event_upcall(kcb, a) {
+            r.resume()
         }
 
         // Return immediately
@@ -532,11 +566,108 @@ unsafe fn timer_handler(a: &ExceptionArguments) {
     }
 }
 
+/// Returns an upcall that delivers the next pending event to the process
+/// that runs on the current core.
+///
+/// Events stay pending while the process has upcalls disabled, a later timer
+/// interrupt picks them up.
+fn event_upcall(kcb: &crate::kcb::Kcb<Arch86Kcb>, a: &ExceptionArguments) -> Option<Ring3Resumer> {
+    let mut plock = kcb.arch.current_executor();
+    let p = plock.as_mut().ok()?;
+    if p.vcpu().upcalls_disabled(VAddr::from(a.rip)) {
+        return None;
+    }
+
+    let event = match nrproc::NrProcess::<Ring3Process>::take_event(p.pid) {
+        Ok(event) => event?,
+        Err(e) => {
+            warn!("Unable to check events of process {}: {:?}", p.pid, e);
+            return None;
+        }
+    };
+    trace!("Deliver event {} to process {}", event, p.pid);
+
+    // Copy CURRENT_SAVE_AREA to process enabled save area
+    // then resume in the upcall handler
+    p.vcpu().disable_upcalls();
+    kcb.arch.save_area.as_ref().map(|sa| {
+        p.vcpu().enabled_state = **sa;
+    });
+
+    Some(p.upcall(kpi::upcall::EVENT, event))
+}
+
+/// Handles a page-fault or general protection fault of the process running
+/// on the current core that the kernel can't resolve.
+///
+/// Processes that subscribed to `EVENT_SEGV` get an upcall with the
+/// exception vector and error code (and the faulting address in
+/// `VirtualCpu::fault_address`), all others are terminated with exit code
+/// 128 + `EVENT_SEGV`.
+unsafe fn user_fault(a: &ExceptionArguments, fault_address: u64) -> ! {
+    let kcb = get_kcb();
+    if let Some(r) = fault_upcall(kcb, a, fault_address) {
+        r.resume()
+    }
+
+    let pid = kcb
+        .current_pid()
+        .expect("A pid must be set for faults in user-space");
+    warn!(
+        "Process {} faulted (vector {:#x} error {:#x}) at rip {:#x} accessing {:#x}, terminating it",
+        pid, a.vector, a.exception, a.rip, fault_address
+    );
+
+    let code = 128 + kpi::process::EVENT_SEGV;
+    match super::syscall::process_exit(code) {
+        Ok(_) => unreachable!("process_exit doesn't return"),
+        Err(e) => panic!("Unable to terminate process {}: {:?}", pid, e),
+    }
+}
+
+/// Prepares the upcall for a fault of the process (if it can handle it).
+fn fault_upcall(
+    kcb: &crate::kcb::Kcb<Arch86Kcb>,
+    a: &ExceptionArguments,
+    fault_address: u64,
+) -> Option<Ring3Resumer> {
+    let mut plock = kcb.arch.current_executor();
+    let p = plock.as_mut().ok()?;
+    if p.vcpu().upcalls_disabled(VAddr::from(a.rip)) {
+        // The fault happened in the upcall handler, we can't deliver it
+        return None;
+    }
+
+    match nrproc::NrProcess::<Ring3Process>::subscribed_events(p.pid) {
+        Ok(events) if events & (1 << kpi::process::EVENT_SEGV) != 0 => {}
+        Ok(_) => return None,
+        Err(e) => {
+            warn!("Unable to check events of process {}: {:?}", p.pid, e);
+            return None;
+        }
+    }
+    trace!("Deliver fault {:#x} to process {}", a.vector, p.pid);
+
+    // Copy CURRENT_SAVE_AREA to process enabled save area
+    // then resume in the upcall handler
+    p.vcpu().disable_upcalls();
+    p.vcpu().fault_address = VAddr::from(fault_address);
+    kcb.arch.save_area.as_ref().map(|sa| {
+        p.vcpu().enabled_state = **sa;
+    });
+
+    Some(p.upcall(a.vector, a.exception))
+}
+
 /// Handler for a general protection exception.
 ///
-/// TODO: Right now we terminate kernel.
-/// Should abort process and resume.
+/// Faults in user-space go to the process (see [`user_fault`]), faults in
+/// the kernel are fatal.
 unsafe fn gp_handler(a: &ExceptionArguments) {
+    if a.cs & 0b11 == Ring::Ring3 as u64 {
+        user_fault(a, 0);
+    }
+
     let desc = &EXCEPTIONS[a.vector as usize];
     sprint!("\n[IRQ] GENERAL PROTECTION FAULT: ");
     sprintln!("From {}", desc.source);
@@ -671,6 +802,25 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
             trace!("handle_generic_exception {:?}", a);
 
             let mut plock = kcb.arch.current_executor();
+            // Device interrupts only go to the process that routed them, the
+            // core may run another process (or none) by now
+            let deliver = match plock.as_ref() {
+                Ok(p) if is_device_vector(a.vector) => {
+                    crate::vectors::owner(kcb.arch.id(), a.vector)
+                        .map_or(false, |owner| owner == Some(p.pid))
+                }
+                Ok(_p) => true,
+                Err(_e) => false,
+            };
+            if !deliver {
+                debug!("Dropping interrupt {} on core {}", a.vector, kcb.arch.id());
+                drop(plock);
+                if kcb.arch.has_executor() {
+                    kcb_iret_handle(kcb).resume()
+                } else {
+                    crate::scheduler::schedule()
+                }
+            }
             let p = plock.as_mut().unwrap();
 
             let resumer = {
@@ -754,6 +904,12 @@ pub extern "C" fn handle_generic_exception(a: ExceptionArguments) -> ! {
     unreachable!("Should not come here")
 }
 
+/// Is `vector` one of the vectors processes allocate for their devices (see
+/// [`crate::vectors`])?
+fn is_device_vector(vector: u64) -> bool {
+    (kpi::io::FIRST_DEVICE_VECTOR..=kpi::io::LAST_DEVICE_VECTOR).contains(&vector)
+}
+
 /// Registers a handler IRQ handler function.
 pub unsafe fn register_handler(
     vector: usize,
@@ -801,8 +957,8 @@ pub fn ioapic_initialize() {
 ///
 /// # TODO
 /// Currently this just enables everything and routes it to
-/// core 0. This is because, we should probably just support MSI(X)
-/// and don't invest a lot in legacy interrupts...
+/// core 0. Devices that support MSI / MSI-X should use those instead
+/// (see [`super::pci::bind_vector`]).
 pub fn ioapic_establish_route(_gsi: u64, _core: u64) {
     use crate::memory::vspace::MapAction;
     use crate::memory::{paddr_to_kernel_vaddr, PAddr};
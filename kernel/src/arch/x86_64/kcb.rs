@@ -11,10 +11,8 @@ use core::pin::Pin;
 use core::ptr;
 
 use apic::x2apic::X2APICDriver;
-use arrayvec::ArrayVec;
 use cnr::{Replica as MlnrReplica, ReplicaToken as MlnrReplicaToken};
 use log::trace;
-use node_replication::Replica;
 use x86::current::segmentation::{self};
 use x86::current::task::TaskStateSegment;
 use x86::msr::{wrmsr, IA32_KERNEL_GSBASE};
@@ -23,9 +21,9 @@ use crate::cnrfs::MlnrKernelNode;
 use crate::error::KError;
 use crate::fs::{FileSystem, MlnrFS};
 use crate::kcb::{ArchSpecificKcb, Kcb};
-use crate::nrproc::NrProcess;
+use crate::nrproc::ProcessTable;
 use crate::process::Pid;
-use crate::process::MAX_PROCESSES;
+use crate::scheduler::RunQueue;
 use crate::stack::{OwnedStack, Stack};
 
 use super::gdb::KernelDebugger;
@@ -33,8 +31,8 @@ use super::gdt::GdtTable;
 use super::irq::IdtTable;
 use super::process::{Ring3Executor, Ring3Process};
 use super::vspace::page_table::PageTable;
+use super::vspace::pcid::PcidCache;
 use super::KernelArgs;
-use super::MAX_NUMA_NODES;
 
 /// Try to retrieve the KCB by reading the gs register.
 ///
@@ -123,12 +121,18 @@ pub struct Arch86Kcb {
     /// A handle to the currently active (scheduled) process.
     current_executor: Option<Box<Ring3Executor>>,
 
+    /// Executors that wait for their turn to run on this core.
+    pub run_queue: RunQueue<Ring3Executor>,
+
     /// A handle to the initial kernel address space (created for us by the
     /// bootloader) It contains a 1:1 mapping of
     ///  * all physical memory (above `KERNEL_BASE`)
     ///  * IO APIC and local APIC memory (after initialization has completed)
     init_vspace: RefCell<PageTable>,
 
+    /// The PCIDs this core handed out to processes.
+    pub(crate) pcids: PcidCache,
+
     /// A handle to the node-local CNR based kernel replica.
     pub cnr_replica: Option<(Arc<MlnrReplica<'static, MlnrKernelNode>>, MlnrReplicaToken)>,
 
@@ -201,8 +205,10 @@ impl Arch86Kcb {
             tss: TaskStateSegment::new(),
             idt: Default::default(),
             current_executor: None, // We don't have an executor to schedule initially
+            run_queue: RunQueue::new(),
             save_area: None,
             init_vspace: RefCell::new(init_vspace),
+            pcids: Default::default(),
             interrupt_stack: None,
             syscall_stack: None,
             unrecoverable_fault_stack: None,
@@ -262,6 +268,25 @@ impl Arch86Kcb {
         self.current_executor.replace(new_executor)
     }
 
+    /// Stops the current executor and puts it at the back of the run-queue.
+    ///
+    /// The state the executor was interrupted in (from the core's save-area)
+    /// is stored in the executor so it can be resumed later. Its `VirtualCpu`
+    /// area lives in the process' memory and moves along with it.
+    pub fn preempt_current_executor(&mut self) -> Result<(), KError> {
+        self.run_queue.try_reserve(1)?;
+        let mut executor = self.current_executor.take().ok_or(KError::ProcessNotSet)?;
+        if let Some(save_area) = self.save_area.as_ref() {
+            executor.save_area = **save_area;
+        }
+        self.run_queue.push(executor, true)
+    }
+
+    /// Removes the executor that is currently running on this core (if any).
+    pub fn take_current_executor(&mut self) -> Option<Box<Ring3Executor>> {
+        self.current_executor.take()
+    }
+
     pub fn has_executor(&self) -> bool {
         self.current_executor.is_some()
     }
@@ -390,12 +415,7 @@ impl crate::kcb::ArchSpecificKcb for Arch86Kcb {
         Ok(self.current_executor()?.pid)
     }
 
-    fn process_table(
-        &self,
-    ) -> &'static ArrayVec<
-        ArrayVec<Arc<Replica<'static, NrProcess<Self::Process>>>, MAX_PROCESSES>,
-        MAX_NUMA_NODES,
-    > {
+    fn process_table(&self) -> &'static ProcessTable<Self::Process> {
         &*super::process::PROCESS_TABLE
     }
 }
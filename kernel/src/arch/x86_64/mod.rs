@@ -27,8 +27,9 @@ use core::mem::transmute;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::cnrfs::{MlnrKernelNode, Modify};
+use crate::fs::pmem::PMEMFS_REGION_SIZE;
 use crate::kcb::{BootloaderArguments, Kcb};
-use crate::memory::{mcache, Frame, GlobalMemory, BASE_PAGE_SIZE, KERNEL_BASE};
+use crate::memory::{mcache, Frame, GlobalMemory, BASE_PAGE_SIZE, KERNEL_BASE, LARGE_PAGE_SIZE};
 use crate::nr::{KernelNode, Op};
 use crate::stack::OwnedStack;
 use crate::{xmain, ExitReason};
@@ -40,7 +41,7 @@ use cnr::{Log as MlnrLog, Replica as MlnrReplica};
 use driverkit::DriverControl;
 use fallible_collections::{FallibleVecGlobal, TryClone};
 use klogger::sprint;
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use node_replication::{Log, Replica};
 use x86::bits64::paging::{PAddr, VAddr, PML4};
 use x86::{controlregs, cpuid};
@@ -61,6 +62,7 @@ pub mod gdt;
 pub mod irq;
 pub mod kcb;
 pub mod memory;
+pub mod pci;
 pub mod process;
 pub mod syscall;
 pub mod timer;
@@ -220,6 +222,7 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
     enable_sse();
     enable_fsgsbase();
     assert_required_cpu_features();
+    vspace::pcid::enable_pcid();
     syscall::enable_fast_syscalls();
     irq::disable();
 
@@ -273,7 +276,6 @@ fn start_app_core(args: Arc<AppCoreArgs>, initialized: &AtomicBool) {
 
         let fs_replica = args.fs_replica.register().unwrap();
         kcb.arch.setup_cnr(args.fs_replica.clone(), fs_replica);
-        kcb.register_with_process_replicas();
 
         // Don't modify this line without adjusting `coreboot` integration test:
         info!(
@@ -340,7 +342,10 @@ fn boot_app_cores(
             .expect("Can't set affinity");
 
         debug_assert!(replicas.capacity() > node, "No re-allocation.");
-        replicas.push(Replica::<'static, KernelNode>::new(&log));
+        replicas.push(Replica::<'static, KernelNode>::with_data(
+            &log,
+            KernelNode::new(cmdline.max_processes),
+        ));
 
         debug_assert!(fs_replicas.capacity() > node, "No re-allocation.");
         fs_replicas.push(MlnrReplica::new(
@@ -569,7 +574,6 @@ fn map_physical_persistent_memory() {
 #[no_mangle]
 #[start]
 fn _start(argc: isize, _argv: *const *const u8) -> isize {
-    use crate::memory::LARGE_PAGE_SIZE;
     use core::slice;
 
     sprint!("\r\n");
@@ -612,6 +616,7 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // Figure out what this machine supports,
     // fail if it doesn't have what we need.
     assert_required_cpu_features();
+    vspace::pcid::enable_pcid();
     syscall::enable_fast_syscalls();
 
     // Initializes the serial console.
@@ -803,6 +808,13 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     drop(memory_regions);
     annotated_regions.sort_by(|&a, &b| a.affinity.partial_cmp(&b.affinity).unwrap());
 
+    // With `pmemfs` we carve the file-system log out of the first region,
+    // every node keeps (most of) its persistent memory
+    let pmemfs_region = match cmdline.pmemfs && !annotated_regions.is_empty() {
+        true => carve_pmemfs_region(&mut annotated_regions),
+        false => None,
+    };
+
     // This call is safe here because we assume that our `annotated_regions` is correct.
     let global_memory = match annotated_regions.len() > 0 {
         true => unsafe { GlobalMemory::new(annotated_regions).unwrap() },
@@ -828,7 +840,8 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     // and store it in the BSP kcb
     let log: Arc<Log<Op>> = Arc::try_new(Log::<Op>::new(LARGE_PAGE_SIZE))
         .expect("Not enough memory to initialize system");
-    let bsp_replica = Replica::<KernelNode>::new(&log);
+    let bsp_replica =
+        Replica::<KernelNode>::with_data(&log, KernelNode::new(cmdline.max_processes));
     let local_ridx = bsp_replica.register().unwrap();
     {
         let kcb = kcb::get_kcb();
@@ -909,11 +922,9 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         }
     }
 
-    {
-        lazy_static::initialize(&process::PROCESS_TABLE);
-        let kcb = kcb::get_kcb();
-        kcb.register_with_process_replicas();
-    }
+    lazy_static::initialize(&process::PROCESS_TABLE);
+    lazy_static::initialize(&crate::memory::RECLAIM_REQUESTS);
+    lazy_static::initialize(&crate::memory::RECLAIM_CORES);
 
     #[cfg(feature = "gdb")]
     {
@@ -938,6 +949,11 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
         fs_replica,
     );
 
+    // Recover the files (needs all file-system replicas)
+    if let Some(region) = pmemfs_region {
+        mount_pmemfs(region);
+    }
+
     // Done with initialization, now we go in
     // the arch-independent part:
     let _r = xmain();
@@ -946,7 +962,56 @@ fn _start(argc: isize, _argv: *const *const u8) -> isize {
     debug::shutdown(ExitReason::ReturnFromMain);
 }
 
+/// Takes a large-page aligned chunk of `PMEMFS_REGION_SIZE` bytes for the
+/// file-system log out of the first region in `regions`.
+///
+/// What's left of the region goes back to `regions`.
+fn carve_pmemfs_region(regions: &mut ArrayVec<Frame, MAX_PHYSICAL_REGIONS>) -> Option<Frame> {
+    let region = regions[0];
+    let aligned_base = round_up!(region.base.as_usize(), LARGE_PAGE_SIZE);
+    let padding = aligned_base - region.base.as_usize();
+    if padding + PMEMFS_REGION_SIZE > region.size() {
+        warn!(
+            "Persistent memory region {:?} too small for the file-system",
+            region
+        );
+        return None;
+    }
+
+    let (low, high) = region.split_at(padding);
+    let (log, rest) = high.split_at(PMEMFS_REGION_SIZE);
+    regions[0] = rest;
+    if low.size() > 0 && regions.try_push(low).is_err() {
+        warn!("Too many memory regions, losing {:?}", low);
+    }
+    regions.retain(|frame| frame.size() > 0);
+    regions.sort_by(|&a, &b| a.affinity.partial_cmp(&b.affinity).unwrap());
+
+    Some(log)
+}
+
+/// Makes the file-system persistent, with its log in `region`.
+fn mount_pmemfs(region: Frame) {
+    let base = paddr_to_kernel_vaddr(region.base);
+    // Safe: The region was taken out of the persistent memory allocator.
+    match unsafe { MlnrKernelNode::mount_pmem(base, region.size) } {
+        Ok(records) => info!(
+            "Mounted persistent file-system in {:#x} -- {:#x} ({} records redone)",
+            region.base,
+            region.base + region.size as u64,
+            records
+        ),
+        Err(e) => error!("Can't mount persistent file-system: {}", e),
+    }
+}
+
 /// For cores that advances the replica eagerly. This avoids additional IPI costs.
 pub fn advance_fs_replica() {
     tlb::eager_advance_fs_replica();
 }
+
+/// Sends an IPI to core `gtid` to make it give the free frames in its TCaches
+/// back to the NCaches.
+pub fn request_reclaim(gtid: atopology::GlobalThreadId) {
+    tlb::reclaim(gtid);
+}
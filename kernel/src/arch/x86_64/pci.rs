@@ -0,0 +1,364 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! MSI / MSI-X interrupt routing for PCI devices.
+//!
+//! Device drivers run in user-space and program their devices themselves,
+//! the kernel only writes the MSI / MSI-X messages: the message address
+//! decides which core receives the interrupt, so processes can only route
+//! interrupts to vectors they allocated (see [`crate::vectors`]).
+
+use core::ptr;
+
+use kpi::io::PciAddress;
+use log::{trace, warn};
+use spin::Mutex;
+use x86::apic::ApicId;
+use x86::io;
+
+use crate::error::KError;
+use crate::memory::vspace::{AddressSpace, MapAction};
+use crate::process::Pid;
+use crate::vectors::Binding;
+
+use super::kcb::get_kcb;
+use super::memory::{paddr_to_kernel_vaddr, PAddr, BASE_PAGE_SIZE};
+
+const PCI_CONF_ADDR: u16 = 0xcf8;
+const PCI_CONF_DATA: u16 = 0xcfc;
+
+/// Serializes the accesses to the configuration space (the address and data
+/// ports are shared by all cores).
+static CONFSPACE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Vendor ID (low 16 bits) and device ID (high 16 bits).
+const VENDOR_DEVICE_REG: u8 = 0x00;
+/// Vendor ID read for functions that don't exist.
+const INVALID_VENDOR: u32 = 0xffff;
+
+/// Command (low 16 bits) and status register (high 16 bits).
+const COMMAND_STATUS_REG: u8 = 0x04;
+/// The device has a capability list.
+const STATUS_CAP_LIST: u32 = 1 << 20;
+/// Offset of the first capability.
+const CAP_POINTER_REG: u8 = 0x34;
+/// The first base address register.
+const BAR0_REG: u8 = 0x10;
+
+const CAP_ID_MSI: u32 = 0x05;
+const CAP_ID_MSIX: u32 = 0x11;
+
+/// Bits of the MSI message control register.
+const MSI_ENABLE: u32 = 1 << 0;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u32 = 0b111 << 4;
+const MSI_64BIT: u32 = 1 << 7;
+
+/// Bits of the MSI-X message control register.
+const MSIX_TABLE_SIZE: u32 = 0x7ff;
+const MSIX_FUNCTION_MASK: u32 = 1 << 14;
+const MSIX_ENABLE: u32 = 1 << 15;
+
+/// Size of an entry in the MSI-X table.
+const MSIX_ENTRY_SIZE: u64 = 16;
+/// Bit in the vector control word of an MSI-X entry.
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Messages written to this address become interrupts of a local APIC.
+const MSI_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+fn config_address(device: PciAddress, reg: u8) -> u32 {
+    (1 << 31)
+        | (device.bus as u32) << 16
+        | (device.dev as u32) << 11
+        | (device.fun as u32) << 8
+        | (reg as u32 & 0xfc)
+}
+
+fn confread(device: PciAddress, reg: u8) -> u32 {
+    let _l = CONFSPACE_LOCK.lock();
+    unsafe {
+        io::outl(PCI_CONF_ADDR, config_address(device, reg));
+        io::inl(PCI_CONF_DATA)
+    }
+}
+
+fn confwrite(device: PciAddress, reg: u8, value: u32) {
+    trace!("confwrite {:?} reg({:#x}) = {:#x}", device, reg, value);
+    let _l = CONFSPACE_LOCK.lock();
+    unsafe {
+        io::outl(PCI_CONF_ADDR, config_address(device, reg));
+        io::outl(PCI_CONF_DATA, value);
+    }
+}
+
+/// Is there a device (function) at `device`?
+pub fn exists(device: PciAddress) -> bool {
+    confread(device, VENDOR_DEVICE_REG) & 0xffff != INVALID_VENDOR
+}
+
+/// Returns the offset of capability `id` in the configuration space.
+fn find_capability(device: PciAddress, id: u32) -> Option<u8> {
+    if confread(device, COMMAND_STATUS_REG) & STATUS_CAP_LIST == 0 {
+        return None;
+    }
+
+    let mut offset = (confread(device, CAP_POINTER_REG) & 0xfc) as u8;
+    // Bound the walk in case the list is corrupt (there is room for at most
+    // 48 capabilities after the header)
+    for _ in 0..48 {
+        if offset == 0 {
+            return None;
+        }
+        let header = confread(device, offset);
+        if header & 0xff == id {
+            return Some(offset);
+        }
+        offset = ((header >> 8) & 0xfc) as u8;
+    }
+
+    None
+}
+
+/// Returns the physical address a memory BAR of the device points to.
+fn bar_address(device: PciAddress, bar: u8) -> Result<PAddr, KError> {
+    if bar > 5 {
+        return Err(KError::NoMsiSupport);
+    }
+
+    let reg = BAR0_REG + bar * 4;
+    let low = confread(device, reg);
+    if low & 0x1 != 0 {
+        // I/O space BARs can't hold the MSI-X table
+        return Err(KError::NoMsiSupport);
+    }
+
+    let mut address = (low & !0xf) as u64;
+    if (low >> 1) & 0b11 == 0b10 {
+        address |= (confread(device, reg + 4) as u64) << 32;
+    }
+    Ok(PAddr::from(address))
+}
+
+/// The MSI message (address and data) that raises `vector` on core `gtid`.
+fn msi_message(gtid: atopology::GlobalThreadId, vector: u64) -> Result<(u32, u32), KError> {
+    let thread = atopology::MACHINE_TOPOLOGY
+        .threads
+        .get(gtid)
+        .ok_or(KError::InvalidGlobalThreadId)?;
+    let apic_id = match thread.apic_id() {
+        ApicId::XApic(id) => id as u32,
+        ApicId::X2Apic(id) => id,
+    };
+    // The destination field only has 8 bits (we don't do interrupt
+    // remapping)
+    if apic_id > 0xff {
+        return Err(KError::NotSupported);
+    }
+
+    // Physical destination mode, fixed delivery and edge triggered
+    Ok((MSI_ADDRESS_BASE | apic_id << 12, vector as u32))
+}
+
+/// Routes interrupt `entry` of `device` to `vector` on core `gtid`.
+///
+/// `entry` is the entry in the MSI-X table, devices that only support MSI
+/// have a single entry (0).
+pub fn bind_vector(
+    device: PciAddress,
+    entry: u64,
+    gtid: atopology::GlobalThreadId,
+    vector: u64,
+) -> Result<(), KError> {
+    let (address, data) = msi_message(gtid, vector)?;
+
+    if let Some(cap) = find_capability(device, CAP_ID_MSIX) {
+        bind_msix(device, cap, entry, address, data)
+    } else if let Some(cap) = find_capability(device, CAP_ID_MSI) {
+        if entry != 0 {
+            return Err(KError::InvalidMsiEntry { entry });
+        }
+        bind_msi(device, cap, address, data);
+        Ok(())
+    } else {
+        Err(KError::NoMsiSupport)
+    }
+}
+
+fn bind_msi(device: PciAddress, cap: u8, address: u32, data: u32) {
+    trace!("MSI of {:?} -> {:#x} {:#x}", device, address, data);
+    let control = confread(device, cap) >> 16;
+
+    confwrite(device, cap + 4, address);
+    if control & MSI_64BIT != 0 {
+        confwrite(device, cap + 8, 0);
+        confwrite(device, cap + 12, data);
+    } else {
+        confwrite(device, cap + 8, data);
+    }
+
+    // We only hand out a single message per device
+    let control = (control & !MSI_MULTIPLE_MESSAGE_ENABLE) | MSI_ENABLE;
+    confwrite(
+        device,
+        cap,
+        (confread(device, cap) & 0xffff) | control << 16,
+    );
+}
+
+fn bind_msix(
+    device: PciAddress,
+    cap: u8,
+    entry: u64,
+    address: u32,
+    data: u32,
+) -> Result<(), KError> {
+    let control = confread(device, cap) >> 16;
+    let entries = (control & MSIX_TABLE_SIZE) as u64 + 1;
+    if entry >= entries {
+        return Err(KError::InvalidMsiEntry { entry });
+    }
+    trace!(
+        "MSI-X {} of {:?} -> {:#x} {:#x}",
+        entry,
+        device,
+        address,
+        data
+    );
+
+    let words = msix_entry(device, cap, entry)?;
+    unsafe {
+        // Mask the entry while it's inconsistent
+        let vector_control = ptr::read_volatile(words.add(3));
+        ptr::write_volatile(words.add(3), vector_control | MSIX_ENTRY_MASKED);
+        ptr::write_volatile(words, address);
+        ptr::write_volatile(words.add(1), 0);
+        ptr::write_volatile(words.add(2), data);
+        ptr::write_volatile(words.add(3), vector_control & !MSIX_ENTRY_MASKED);
+    }
+
+    // This also turns off the legacy interrupts of the device
+    let control = (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK;
+    confwrite(
+        device,
+        cap,
+        (confread(device, cap) & 0xffff) | control << 16,
+    );
+
+    Ok(())
+}
+
+/// Stops interrupt `entry` of `device` from raising the vector it was bound
+/// to with [`bind_vector`].
+///
+/// MSI-X entries are masked, for MSI the whole capability is disabled (it
+/// only has the one entry).
+pub fn unbind_vector(device: PciAddress, entry: u64) -> Result<(), KError> {
+    if let Some(cap) = find_capability(device, CAP_ID_MSIX) {
+        let control = confread(device, cap) >> 16;
+        if entry > (control & MSIX_TABLE_SIZE) as u64 {
+            return Err(KError::InvalidMsiEntry { entry });
+        }
+        trace!("Mask MSI-X {} of {:?}", entry, device);
+
+        let words = msix_entry(device, cap, entry)?;
+        unsafe {
+            let vector_control = ptr::read_volatile(words.add(3));
+            ptr::write_volatile(words.add(3), vector_control | MSIX_ENTRY_MASKED);
+        }
+        Ok(())
+    } else if let Some(cap) = find_capability(device, CAP_ID_MSI) {
+        if entry != 0 {
+            return Err(KError::InvalidMsiEntry { entry });
+        }
+        trace!("Disable MSI of {:?}", device);
+
+        let control = (confread(device, cap) >> 16) & !MSI_ENABLE;
+        confwrite(
+            device,
+            cap,
+            (confread(device, cap) & 0xffff) | control << 16,
+        );
+        Ok(())
+    } else {
+        Err(KError::NoMsiSupport)
+    }
+}
+
+/// Unbinds the device entries that are routed to vectors of `pid` and match
+/// `filter`.
+///
+/// This has to happen before the vectors are freed, otherwise the devices
+/// keep raising vectors nobody owns. Entries that can't be unbound are only
+/// logged.
+pub fn unbind_vectors<F>(pid: Pid, filter: F) -> Result<(), KError>
+where
+    F: Fn(&Binding) -> bool,
+{
+    for binding in crate::vectors::bindings(pid)?.iter().filter(|b| filter(b)) {
+        if let Err(e) = unbind_vector(binding.device, binding.entry) {
+            warn!("Unable to unbind {:?}: {}", binding, e);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the (mapped) words of `entry` in the MSI-X table of `device`.
+fn msix_entry(device: PciAddress, cap: u8, entry: u64) -> Result<*mut u32, KError> {
+    let table = confread(device, cap + 4);
+    let bar = (table & 0x7) as u8;
+    let table_paddr = bar_address(device, bar)? + (table & !0x7) as u64;
+    let entry_paddr = table_paddr + entry * MSIX_ENTRY_SIZE;
+    map_device_page(entry_paddr)?;
+
+    Ok(paddr_to_kernel_vaddr(entry_paddr).as_mut_ptr())
+}
+
+/// Makes sure the page with the device register at `paddr` is mapped in the
+/// kernel address space.
+///
+/// Fails with `KError::AlreadyMapped` if something else is mapped there.
+fn map_device_page(paddr: PAddr) -> Result<(), KError> {
+    let kcb = get_kcb();
+    let page = PAddr::from(paddr.as_u64() & !(BASE_PAGE_SIZE as u64 - 1));
+    let vaddr = paddr_to_kernel_vaddr(page);
+    let mut vspace = kcb.arch.init_vspace();
+
+    // The page may be mapped already (by us or as part of a bigger mapping),
+    // `map_generic` would panic if we tried to map it again
+    match vspace.resolve(vaddr) {
+        Ok((mapped, _rights)) if mapped == page => return Ok(()),
+        Ok(_) => return Err(KError::AlreadyMapped { base: vaddr }),
+        Err(KError::NotMapped) => {}
+        Err(e) => return Err(e),
+    }
+
+    // Dry-run first, this returns an error instead of panicking on overlaps
+    vspace.map_generic(
+        vaddr,
+        (page, BASE_PAGE_SIZE),
+        MapAction::ReadWriteKernel,
+        false,
+    )?;
+    vspace.map_generic(
+        vaddr,
+        (page, BASE_PAGE_SIZE),
+        MapAction::ReadWriteKernel,
+        true,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pci_address() {
+        let device = PciAddress {
+            bus: 0x1b,
+            dev: 0x1f,
+            fun: 0x5,
+        };
+        assert_eq!(PciAddress::from(u64::from(device)), device);
+        assert_eq!(config_address(device, 0x13), 0x801b_fd10);
+    }
+}
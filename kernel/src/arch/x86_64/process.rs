@@ -3,7 +3,6 @@
 
 use alloc::boxed::Box;
 use alloc::collections::TryReserveError;
-use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cmp::PartialEq;
 use core::ops::{Deref, DerefMut};
@@ -12,12 +11,11 @@ use core::{fmt, ptr};
 use crate::error::KError;
 use arrayvec::ArrayVec;
 use fallible_collections::try_vec;
-use fallible_collections::FallibleVec;
+use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use kpi::arch::SaveArea;
 use kpi::process::{FrameId, ELF_OFFSET, EXECUTOR_OFFSET};
 use lazy_static::lazy_static;
 use log::{debug, info, trace, warn};
-use node_replication::{Dispatch, Log, Replica};
 use x86::bits64::paging::*;
 use x86::bits64::rflags;
 use x86::{controlregs, Ring};
@@ -28,15 +26,16 @@ use crate::kcb::{self, Kcb};
 use crate::memory::detmem::DA;
 use crate::memory::vspace::{AddressSpace, MapAction};
 use crate::memory::{paddr_to_kernel_vaddr, Frame, KernelAllocator, MemType, PAddr, VAddr};
-use crate::nrproc::NrProcess;
+use crate::nrproc::{NrProcess, ProcessTable};
 use crate::process::{
-    Eid, Executor, Pid, Process, ResumeHandle, MAX_FRAMES_PER_PROCESS, MAX_PROCESSES,
+    Eid, Executor, Pid, Process, ResumeHandle, MAX_FRAMES_PER_PROCESS,
     MAX_WRITEABLE_SECTIONS_PER_PROCESS,
 };
 use crate::round_up;
 
 use super::gdt::GdtTable;
 use super::kcb::Arch86Kcb;
+use super::vspace::pcid;
 use super::vspace::*;
 use super::Module;
 use super::MAX_NUMA_NODES;
@@ -44,40 +43,8 @@ use super::MAX_NUMA_NODES;
 const INVALID_EXECUTOR_START: VAddr = VAddr(0xdeadffff);
 
 lazy_static! {
-    pub static ref PROCESS_TABLE: ArrayVec<ArrayVec<Arc<Replica<'static, NrProcess<Ring3Process>>>, MAX_PROCESSES>, MAX_NUMA_NODES> = {
-        // Want at least one replica...
-        let numa_nodes = core::cmp::max(1, atopology::MACHINE_TOPOLOGY.num_nodes());
-
-        let mut numa_cache = ArrayVec::new();
-        for _n in 0..numa_nodes {
-            let process_replicas = ArrayVec::new();
-            debug_assert!(!numa_cache.is_full());
-            numa_cache.push(process_replicas)
-        }
-
-        for pid in 0..MAX_PROCESSES {
-                let log = Arc::try_new(Log::<<NrProcess<Ring3Process> as Dispatch>::WriteOperation>::new(
-                    LARGE_PAGE_SIZE,
-                )).expect("Can't initialize processes, out of memory.");
-
-            let da = DA::new().expect("Can't initialize process deterministic memory allocator");
-            for node in 0..numa_nodes {
-                let kcb = kcb::get_kcb();
-                kcb.set_mem_affinity(node as atopology::NodeId).expect("Can't change affinity");
-                debug_assert!(!numa_cache[node].is_full());
-
-                let p = Box::try_new(Ring3Process::new(pid, da.clone()).expect("Can't create process during init")).expect("Not enough memory to initialize processes");
-                let nrp = NrProcess::new(p, da.clone());
-
-                numa_cache[node].push(Replica::<NrProcess<Ring3Process>>::with_data(&log, nrp));
-
-                debug_assert_eq!(kcb.arch.node(), 0, "Expect initialization to happen on node 0.");
-                kcb.set_mem_affinity(0 as atopology::NodeId).expect("Can't change affinity");
-            }
-        }
-
-        numa_cache
-    };
+    /// The replicas of all processes (created on-demand).
+    pub static ref PROCESS_TABLE: ProcessTable<Ring3Process> = ProcessTable::new(Ring3Process::new);
 }
 
 pub struct UserPtr<T> {
@@ -837,11 +804,14 @@ impl Executor for Ring3Executor {
         }
     }
 
+    /// Continue where the executor was preempted.
     fn resume(&self) -> Self::Resumer {
         assert_eq!(kcb::get_kcb().node, self.affinity, "Run on remote replica?");
 
         self.maybe_switch_vspace();
-        Ring3Resumer::new_restore(&self.save_area as *const kpi::arch::SaveArea)
+        // The executor was interrupted (not in a syscall), so we have to
+        // restore all registers
+        Ring3Resumer::new_iret(&self.save_area as *const kpi::arch::SaveArea)
     }
 
     fn upcall(&self, vector: u64, exception: u64) -> Self::Resumer {
@@ -862,10 +832,10 @@ impl Executor for Ring3Executor {
 
     fn maybe_switch_vspace(&self) {
         unsafe {
-            let current_pml4 = PAddr::from(controlregs::cr3());
-            if current_pml4 != self.pml4 {
+            if pcid::current_pml4() != self.pml4 {
                 trace!("Switching to 0x{:x}", self.pml4);
-                controlregs::cr3_write(self.pml4.into());
+                let kcb = kcb::get_kcb();
+                pcid::switch_to(&mut kcb.arch.pcids, self.pid, self.pml4);
             }
         }
     }
@@ -887,6 +857,9 @@ pub struct Ring3Process {
     pub entry_point: VAddr,
     /// Executor cache (holds a per-region cache of executors)
     pub executor_cache: ArrayVec<Option<Vec<Box<Ring3Executor>>>, MAX_NUMA_NODES>,
+    /// Executors that are currently handed out to a core (they go back into
+    /// the `executor_cache` once the core is released).
+    pub assigned_executors: Vec<Ring3Executor>,
     /// Offset where executor memory is located in user-space.
     pub executor_offset: VAddr,
     /// File descriptors for the opened file.
@@ -895,6 +868,10 @@ pub struct Ring3Process {
     pub frames: ArrayVec<Option<Frame>, MAX_FRAMES_PER_PROCESS>,
     /// Frames of the writeable ELF data section (shared across all replicated Process structs)
     pub writeable_sections: ArrayVec<Frame, MAX_WRITEABLE_SECTIONS_PER_PROCESS>,
+    /// Frames of the read-only ELF sections (allocated for every replica).
+    pub read_only_sections: Vec<Frame>,
+    /// Frames that hold the executors (shared across all replicated Process structs)
+    pub executor_frames: Vec<Frame>,
     /// Section in ELF where last read-only header is
     ///
     /// (TODO(robustness): assumes that all read-only segments come before
@@ -922,11 +899,14 @@ impl Ring3Process {
             vspace: VSpace::new(da)?,
             entry_point: VAddr::from(0usize),
             executor_cache,
+            assigned_executors: Vec::new(),
             executor_offset: VAddr::from(EXECUTOR_OFFSET),
             fds,
             pinfo: Default::default(),
             frames,
             writeable_sections: ArrayVec::new(),
+            read_only_sections: Vec::new(),
+            executor_frames: Vec::new(),
             read_only_offset: VAddr::zero(),
         })
     }
@@ -1023,10 +1003,14 @@ impl elfloader::ElfLoader for Ring3Process {
                         map_action == MapAction::ReadUser
                             || map_action == MapAction::ReadExecuteUser
                     );
-                    let mut pmanager = kcb.mem_manager();
-                    pmanager
+                    let frame = kcb
+                        .mem_manager()
                         .allocate_large_page()
-                        .expect("We refilled so allocation should work.")
+                        .expect("We refilled so allocation should work.");
+                    self.read_only_sections
+                        .try_push(frame)
+                        .map_err(|_e| elfloader::ElfLoaderErr::OutOfMemory)?;
+                    frame
                 };
 
                 trace!(
@@ -1252,16 +1236,35 @@ impl Process for Ring3Process {
         &mut self,
         for_region: atopology::NodeId,
     ) -> Result<Box<Ring3Executor>, KError> {
+        FallibleVec::try_reserve(&mut self.assigned_executors, 1)?;
         match &mut self.executor_cache[for_region as usize] {
             Some(ref mut executor_list) => {
                 let ret = executor_list.pop().ok_or(KError::ExecutorCacheExhausted)?;
                 //info!("get executor {} with affinity {}", ret.eid, for_region);
+                self.assigned_executors.push(*ret);
                 Ok(ret)
             }
             None => Err(KError::NoExecutorAllocated),
         }
     }
 
+    fn release_executor(&mut self, eid: Eid) -> Result<(), KError> {
+        let idx = self
+            .assigned_executors
+            .iter()
+            .position(|e| e.eid == eid)
+            .ok_or(KError::ExecutorNoLongerValid)?;
+
+        let executor = Box::try_new(self.assigned_executors[idx])?;
+        match &mut self.executor_cache[executor.affinity as usize] {
+            Some(ref mut vector) => vector.try_push(executor)?,
+            None => self.executor_cache[executor.affinity as usize] = Some(try_vec![executor]?),
+        }
+        self.assigned_executors.swap_remove(idx);
+
+        Ok(())
+    }
+
     /// Create a series of dispatcher objects for the process
     fn allocate_executors(&mut self, memory: Frame) -> Result<usize, KError> {
         let executor_space_requirement = Ring3Executor::EXECUTOR_SPACE_REQUIREMENT;
@@ -1272,6 +1275,7 @@ impl Process for Ring3Process {
             self.vspace
                 .map_frame(self.executor_offset, memory, MapAction::ReadWriteUser)
                 .expect("Can't map user-space executor memory.");
+            self.executor_frames.try_push(memory)?;
 
             info!(
                 "executor space base expanded {:#x} size: {} end {:#x}",
@@ -1386,6 +1390,33 @@ impl Process for Ring3Process {
             _ => Err(KError::InvalidFileDescriptor),
         }
     }
+
+    fn destroy(&mut self) -> Result<Vec<Frame>, KError> {
+        let da = self
+            .vspace
+            .page_table
+            .da
+            .clone()
+            .expect("Process vspace is always created with a DA.");
+
+        let mut shared_frames = Vec::try_with_capacity(
+            self.writeable_sections.len() + self.executor_frames.len() + self.frames.len(),
+        )?;
+        shared_frames.extend(self.writeable_sections.drain(..));
+        shared_frames.extend(self.executor_frames.drain(..));
+        shared_frames.extend(self.frames.iter_mut().filter_map(|frame| frame.take()));
+
+        // The read-only sections were allocated by this replica
+        for frame in self.read_only_sections.drain(..) {
+            KernelAllocator::release_frame(frame, MemType::Mem)?;
+        }
+
+        // Drops the old vspace (frees the page-tables but not the mapped
+        // frames), the executors that are still cached and the fds.
+        *self = Ring3Process::new(self.pid, da)?;
+
+        Ok(shared_frames)
+    }
 }
 
 /// Spawns a new process
@@ -1417,3 +1448,117 @@ pub fn spawn(binary: &'static str) -> Result<Pid, KError> {
 
     Ok(pid)
 }
+
+/// Spawns a new process on behalf of process `parent`.
+///
+/// `binary` is a (null-terminated) string in the address space of `parent`,
+/// that names a module passed to the kernel at boot or a file in the FS.
+/// The new process starts running on core `gtid`.
+#[cfg(target_os = "none")]
+pub fn spawn_from(
+    parent: Pid,
+    binary: u64,
+    gtid: atopology::GlobalThreadId,
+) -> Result<Pid, KError> {
+    use crate::nr;
+    use crate::process::{
+        allocate_dispatchers, find_module, load_module_from_fs, make_process_from_module,
+        userptr_to_str, Binary,
+    };
+
+    let affinity = atopology::MACHINE_TOPOLOGY
+        .threads()
+        .find(|thread| thread.id == gtid)
+        .map(|thread| thread.node_id.unwrap_or(0))
+        .ok_or(KError::InvalidGlobalThreadId)?;
+
+    let binary = match find_module(&userptr_to_str(binary)?) {
+        Some(module) => Binary::Boot(module),
+        None => load_module_from_fs(parent, binary)?,
+    };
+
+    let pid = make_process_from_module::<Ring3Process>(binary, Some(parent))?;
+    let started = allocate_dispatchers::<Ring3Process>(pid).and_then(|()| {
+        nr::KernelNode::allocate_core_to_process(
+            pid,
+            INVALID_EXECUTOR_START, // This VAddr is irrelevant as it is overriden later
+            Some(affinity),
+            Some(gtid),
+        )
+    });
+
+    if let Err(e) = started {
+        // The parent never learns about the pid, so we tear the process down
+        // and reap it right away
+        let _alive = exit(pid, 0)?;
+        let _exit_code = nr::KernelNode::reap_pid(parent, pid)?;
+        return Err(e);
+    }
+
+    Ok(pid)
+}
+
+/// Takes core `gtid` away from process `pid` and gives it back to the
+/// scheduler.
+///
+/// Whatever the process was running on `gtid` is stopped right away.
+#[cfg(target_os = "none")]
+pub fn release_core(pid: Pid, gtid: atopology::GlobalThreadId) -> Result<(), KError> {
+    use crate::nr;
+
+    // The devices must stop raising the vectors before we free them
+    super::pci::unbind_vectors(pid, |binding| binding.gtid == gtid)?;
+    nr::KernelNode::release_core(pid, gtid)?;
+
+    let kcb = kcb::get_kcb();
+    let is_local = gtid == kcb.arch.id();
+    if !is_local {
+        // Only hand the executor out again once it no longer runs anywhere
+        super::tlb::terminate(pid, &[gtid]);
+    }
+
+    // This can fail, so do it before we tear down any local state
+    NrProcess::<Ring3Process>::release_executor(pid, gtid)?;
+
+    if is_local {
+        drop(kcb.arch.take_current_executor());
+        unsafe { controlregs::cr3_write(kcb.arch.init_vspace().pml4_address().into()) };
+        kcb.arch.pcids.release(pid);
+    }
+
+    Ok(())
+}
+
+/// Tears down process `pid` after it called exit (or was killed).
+///
+/// - First we unbind the interrupts of its devices, make sure no core will
+///   schedule the process anymore and stop all cores that currently run one
+///   of its executors (this might include us)
+/// - Then we destroy the process state in NR and give all memory that is
+///   shared between replicas back to the allocator
+/// - Finally we write back the writable file mappings, remove the
+///   file-descriptors of the process and release its pid (the `exit_code` is
+///   kept until someone waits for the process)
+///
+/// Returns the number of processes that are still alive afterwards.
+#[cfg(target_os = "none")]
+pub fn exit(pid: Pid, exit_code: u64) -> Result<usize, KError> {
+    use crate::{cnrfs, nr};
+
+    super::pci::unbind_vectors(pid, |_binding| true)?;
+    nr::KernelNode::release_cores(pid)?;
+    let cores = NrProcess::<Ring3Process>::active_cores(pid)?;
+    super::tlb::terminate(pid, &cores);
+    // Frames unmapped before may still wait for their shootdown
+    super::tlb::complete_deferred_releases(pid)?;
+
+    for (frame, mem_type) in NrProcess::<Ring3Process>::destroy(pid)? {
+        KernelAllocator::release_frame(frame, mem_type)?;
+    }
+
+    if let Err(e) = cnrfs::MlnrKernelNode::write_back(pid, None) {
+        warn!("Unable to write back the file mappings of {}: {}", pid, e);
+    }
+    cnrfs::MlnrKernelNode::remove_process(pid)?;
+    nr::KernelNode::free_pid(pid, exit_code)
+}
@@ -15,10 +15,11 @@ use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 use x86::bits64::rflags;
 use x86::msr::{rdmsr, wrmsr, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
 
+use kpi::io::MapFlags;
 use kpi::process::FrameId;
 use kpi::{
-    FileOperation, MemType, ProcessOperation, SystemCall, SystemCallError, SystemOperation,
-    VSpaceOperation,
+    AllocationPolicy, FileOperation, IpcOperation, MemType, ProcessOperation, SystemCall,
+    SystemCallError, SystemOperation, VSpaceOperation,
 };
 
 use crate::error::KError;
@@ -27,17 +28,18 @@ use crate::kcb::ArchSpecificKcb;
 use crate::memory::vspace::MapAction;
 use crate::memory::{Frame, PhysicalPageProvider, KERNEL_BASE};
 use crate::process::{Pid, ResumeHandle};
-use crate::{cnrfs, nr, nrproc};
+use crate::{cnrfs, ipc, nr, nrproc, round_up};
 
 use super::gdt::GdtTable;
 use super::process::{Ring3Process, UserValue};
+use super::tlb::ShootdownBatch;
 
 extern "C" {
     #[no_mangle]
     fn syscall_enter();
 }
 
-fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+fn handle_system(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
     let op = SystemOperation::from(arg1);
 
     match op {
@@ -70,13 +72,63 @@ fn handle_system(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
         }
         SystemOperation::Stats => {
             let kcb = super::kcb::get_kcb();
+            let (reaped_base_pages, reaped_large_pages) = kcb.reaped_pages();
             info!("IRQ handler time: {} cycles", kcb.tlb_time);
-            Ok((0, 0))
+            info!(
+                "Reaped pages: {} base-pages, {} large-pages",
+                reaped_base_pages, reaped_large_pages
+            );
+            Ok((reaped_base_pages as u64, reaped_large_pages as u64))
         }
         SystemOperation::GetCoreID => {
             let kcb = super::kcb::get_kcb();
             Ok((kcb.arch.id() as u64, 0))
         }
+        SystemOperation::VerifyPageTables => {
+            let vaddr_buf = arg3;
+            let vaddr_buf_len = arg4;
+
+            let kcb = super::kcb::get_kcb();
+            let current = kcb.current_pid()?;
+            let pid = if arg2 == kpi::process::CURRENT_PROCESS {
+                current
+            } else {
+                arg2.try_into().map_err(|_e| KError::NoProcessFoundForPid)?
+            };
+            // Processes can only inspect themselves, their parent or children
+            if pid != current && !nr::KernelNode::is_related(current, pid)? {
+                return Err(KError::ProcessNotRelated);
+            }
+
+            let (divergences, total) = nrproc::NrProcess::<Ring3Process>::verify_replicas(
+                pid,
+                kpi::system::MAX_REPORTED_DIVERGENCES,
+            )?;
+
+            let serialized = serde_cbor::to_vec(&divergences).unwrap();
+            if serialized.len() <= vaddr_buf_len as usize {
+                let mut user_slice = super::process::UserSlice::new(vaddr_buf, serialized.len());
+                user_slice.copy_from_slice(serialized.as_slice());
+            }
+
+            Ok((serialized.len() as u64, total as u64))
+        }
+        SystemOperation::GetMemoryNode => {
+            let paddr = arg2;
+            if atopology::MACHINE_TOPOLOGY.num_nodes() == 0 {
+                return Ok((0, 0));
+            }
+
+            for node in atopology::MACHINE_TOPOLOGY.nodes() {
+                for region in node.memory() {
+                    let (_, mid, _) = region.contains(paddr, paddr + 1);
+                    if !region.is_hotplug_region() && mid.0 > 0 {
+                        return Ok((node.id as u64, 0));
+                    }
+                }
+            }
+            Err(KError::BadAddress)
+        }
         SystemOperation::Unknown => Err(KError::InvalidSystemOperation { a: arg1 }),
     }
 }
@@ -121,19 +173,34 @@ fn process_print(buf: UserValue<&str>) -> Result<(u64, u64), KError> {
 }
 
 /// System call handler for process exit
-fn process_exit(code: u64) -> Result<(u64, u64), KError> {
-    debug!("Process got exit, we are done for now...");
-    // TODO: For now just a dummy version that exits Qemu
-    if code != 0 {
-        // When testing we want to indicate to our integration
-        // test that our user-space test failed with a non-zero exit
-        super::debug::shutdown(crate::ExitReason::UserSpaceError);
-    } else {
-        super::debug::shutdown(crate::ExitReason::Ok);
+pub(crate) fn process_exit(code: u64) -> Result<(u64, u64), KError> {
+    let kcb = super::kcb::get_kcb();
+    let pid = kcb.current_pid()?;
+    debug!("Process {} exited with {}", pid, code);
+
+    let alive = super::process::exit(pid, code)?;
+    if alive == 0 {
+        // The last process is gone, we are done:
+        if code != 0 {
+            // When testing we want to indicate to our integration
+            // test that our user-space test failed with a non-zero exit
+            super::debug::shutdown(crate::ExitReason::UserSpaceError);
+        } else {
+            super::debug::shutdown(crate::ExitReason::Ok);
+        }
     }
+
+    // Our executor is gone, find something else to run on this core
+    crate::scheduler::schedule()
 }
 
-fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+fn handle_process(
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> Result<(u64, u64), KError> {
     let op = ProcessOperation::from(arg1);
 
     match op {
@@ -185,27 +252,77 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
             Ok((serialized.len() as u64, 0))
         }
         ProcessOperation::RequestCore => {
-            let gtid: usize = arg2.try_into().unwrap();
             let entry_point = arg3;
             let kcb = super::kcb::get_kcb();
 
-            let mut affinity = None;
-            for thread in atopology::MACHINE_TOPOLOGY.threads() {
-                if thread.id == gtid {
-                    affinity = Some(thread.node_id.unwrap_or(0));
+            let (gtid, affinity) = if arg2 == kpi::process::ANY_CORE {
+                // Let the kernel pick a core (optionally on a given node)
+                let affinity = if arg4 == kpi::process::ANY_NODE {
+                    None
+                } else {
+                    let node: atopology::NodeId =
+                        arg4.try_into().map_err(|_e| KError::InvalidAffinityId)?;
+                    if node >= core::cmp::max(1, atopology::MACHINE_TOPOLOGY.num_nodes()) {
+                        return Err(KError::InvalidAffinityId);
+                    }
+                    Some(node)
+                };
+                (None, affinity)
+            } else {
+                let gtid: usize = arg2.try_into().unwrap();
+                let mut affinity = None;
+                for thread in atopology::MACHINE_TOPOLOGY.threads() {
+                    if thread.id == gtid {
+                        affinity = Some(thread.node_id.unwrap_or(0));
+                    }
                 }
-            }
-            let affinity = affinity.ok_or(KError::InvalidGlobalThreadId)?;
+                let affinity = affinity.ok_or(KError::InvalidGlobalThreadId)?;
+                (Some(gtid), Some(affinity))
+            };
             let pid = kcb.current_pid()?;
 
             let gtid = nr::KernelNode::allocate_core_to_process(
                 pid,
                 VAddr::from(entry_point),
-                Some(affinity),
-                Some(gtid),
+                affinity,
+                gtid,
             )?;
 
-            Ok((arg2, 0))
+            Ok((gtid as u64, 0))
+        }
+        ProcessOperation::ReleaseCore => {
+            let gtid: usize = arg2
+                .try_into()
+                .map_err(|_e| KError::InvalidGlobalThreadId)?;
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            super::process::release_core(pid, gtid)?;
+            if gtid == kcb.arch.id() {
+                // We no longer have an executor, run something else
+                crate::scheduler::schedule()
+            }
+
+            Ok((0, 0))
+        }
+        ProcessOperation::Spawn => {
+            let binary = arg2;
+            let gtid: usize = arg3
+                .try_into()
+                .map_err(|_e| KError::InvalidGlobalThreadId)?;
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            let child = super::process::spawn_from(pid, binary, gtid)?;
+            Ok((child as u64, 0))
+        }
+        ProcessOperation::Wait => {
+            let pid: usize = arg2.try_into().map_err(|_e| KError::NoProcessFoundForPid)?;
+            let kcb = super::kcb::get_kcb();
+            let parent = kcb.current_pid()?;
+
+            let exit_code = nr::KernelNode::reap_pid(parent, pid)?;
+            Ok((exit_code, 0))
         }
         ProcessOperation::AllocatePhysical => {
             let page_size: usize = arg2.try_into().unwrap_or(0);
@@ -243,13 +360,164 @@ fn handle_process(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
 
             Ok((fid as u64, frame.base.as_u64()))
         }
-        ProcessOperation::SubscribeEvent => Err(KError::InvalidProcessOperation { a: arg1 }),
+        ProcessOperation::SubscribeEvent => {
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            let previous = nrproc::NrProcess::<Ring3Process>::subscribe_events(pid, arg2)?;
+            Ok((previous, 0))
+        }
+        ProcessOperation::SendEvent => {
+            let event = arg3;
+            if event == 0 || event > kpi::process::MAX_EVENT {
+                return Err(KError::InvalidEvent { event });
+            }
+
+            let kcb = super::kcb::get_kcb();
+            let current = kcb.current_pid()?;
+            let pid = if arg2 == kpi::process::CURRENT_PROCESS {
+                current
+            } else {
+                arg2.try_into().map_err(|_e| KError::NoProcessFoundForPid)?
+            };
+            // Processes can only signal themselves, their parent or children
+            if pid != current && !nr::KernelNode::is_related(current, pid)? {
+                return Err(KError::EventNotPermitted);
+            }
+
+            let queued = nrproc::NrProcess::<Ring3Process>::post_event(pid, event)?;
+            let terminates = matches!(
+                event,
+                kpi::process::EVENT_INT
+                    | kpi::process::EVENT_KILL
+                    | kpi::process::EVENT_SEGV
+                    | kpi::process::EVENT_TERM
+            );
+            if !queued && terminates {
+                // Like a shell, report termination by an event as 128 + event
+                let code = 128 + event;
+                if pid == current {
+                    return process_exit(code);
+                }
+
+                debug!("Process {} killed by {} with event {}", pid, current, event);
+                let _alive = super::process::exit(pid, code)?;
+            }
+
+            Ok((0, 0))
+        }
+        ProcessOperation::AllocateMsiVector => {
+            let gtid: usize = arg2
+                .try_into()
+                .map_err(|_e| KError::InvalidGlobalThreadId)?;
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            let vector = crate::vectors::allocate(pid, gtid)?;
+            Ok((vector, 0))
+        }
+        ProcessOperation::BindMsiVector => {
+            let device = kpi::io::PciAddress::from(arg2);
+            let entry = arg3;
+            let gtid: usize = arg4
+                .try_into()
+                .map_err(|_e| KError::InvalidGlobalThreadId)?;
+            let vector = arg5;
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            if !super::pci::exists(device) {
+                return Err(KError::NoMsiSupport);
+            }
+            let binding = crate::vectors::Binding {
+                device,
+                entry,
+                gtid,
+                vector,
+            };
+            crate::vectors::bind(pid, binding)?;
+            super::pci::bind_vector(device, entry, gtid, vector)?;
+            Ok((0, 0))
+        }
+        ProcessOperation::FreeMsiVector => {
+            let gtid: usize = arg2
+                .try_into()
+                .map_err(|_e| KError::InvalidGlobalThreadId)?;
+            let vector = arg3;
+            let kcb = super::kcb::get_kcb();
+            let pid = kcb.current_pid()?;
+
+            if crate::vectors::owner(gtid, vector)? != Some(pid) {
+                return Err(KError::VectorNotAllocated);
+            }
+            super::pci::unbind_vectors(pid, |b| b.gtid == gtid && b.vector == vector)?;
+            crate::vectors::free(pid, gtid, vector)?;
+            Ok((0, 0))
+        }
         ProcessOperation::Unknown => Err(KError::InvalidProcessOperation { a: arg1 }),
     }
 }
 
+/// Unmaps frames of a process and flushes them from the TLBs in a single
+/// shootdown at the end.
+///
+/// Memory the process owned goes back to the allocator once the TLB
+/// shootdown is done.
+struct Unmapper {
+    pid: Pid,
+    batch: ShootdownBatch,
+    owned: Vec<(Frame, MemType)>,
+}
+
+impl Unmapper {
+    fn new(pid: Pid) -> Self {
+        Unmapper {
+            pid,
+            batch: ShootdownBatch::new(pid),
+            owned: Vec::new(),
+        }
+    }
+
+    /// Unmaps the frame at `vaddr` and returns its size.
+    fn unmap(&mut self, vaddr: VAddr) -> Result<usize, KError> {
+        let (handle, owned) = nrproc::NrProcess::<Ring3Process>::unmap(self.pid, vaddr)?;
+        self.batch.add(&handle)?;
+        if let Some(mem_type) = owned {
+            self.owned.try_push((handle.frame, mem_type))?;
+        }
+        Ok(handle.frame.size)
+    }
+
+    /// Flushes the unmapped frames from all TLBs and releases the memory.
+    fn finish(self) -> Result<(), KError> {
+        if !self.batch.is_empty() {
+            super::tlb::shootdown_batch(self.batch);
+        }
+        for (frame, mem_type) in self.owned {
+            crate::memory::KernelAllocator::release_frame(frame, mem_type)?;
+        }
+        Ok(())
+    }
+
+    /// Like `finish` but doesn't wait for the other cores to flush their TLB,
+    /// the memory is released once they did.
+    fn finish_async(self) -> Result<(), KError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let shootdown = super::tlb::shootdown_async(self.batch);
+        super::tlb::release_after(self.pid, shootdown, self.owned)
+    }
+}
+
 /// System call handler for vspace operations
-fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError> {
+fn handle_vspace(
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> Result<(u64, u64), KError> {
     let op = VSpaceOperation::from(arg1);
     let base = VAddr::from(arg2);
     let region_size = arg3;
@@ -258,57 +526,51 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
     let kcb = super::kcb::get_kcb();
     let mut p = kcb.arch.current_executor()?;
 
+    // Regions the process unmapped before must be gone from all TLBs before
+    // we change its address space again
+    super::tlb::complete_deferred_releases(p.pid)?;
+
     match op {
         VSpaceOperation::MapMem | VSpaceOperation::MapPMem => unsafe {
             let (bp, lp) = crate::memory::size_to_pages(region_size as usize);
-            let mut frames = Vec::try_with_capacity(bp + lp)?;
             let mem_type = match op {
                 VSpaceOperation::MapMem => MemType::Mem,
                 VSpaceOperation::MapPMem => MemType::PMem,
                 _ => unreachable!(), // We already checked before coming here.
             };
-            crate::memory::KernelAllocator::try_refill_tcache(20 + bp, lp, mem_type)?;
+            // A policy in the flags replaces the one of the process
+            let policy = match (mem_type, arg4) {
+                (MemType::Mem, 0) => {
+                    nrproc::NrProcess::<Ring3Process>::allocation_policy(p.pid)?
+                }
+                (MemType::Mem, flags) => {
+                    let policy =
+                        AllocationPolicy::from_flags(flags).ok_or(KError::InvalidFlags)?;
+                    crate::memory::policy::validate(policy)?;
+                    nrproc::NrProcess::<Ring3Process>::set_allocation_policy(p.pid, policy)?;
+                    policy
+                }
+                _ => AllocationPolicy::Local,
+            };
+            let mut frames =
+                crate::memory::policy::allocate_frames(policy, bp, lp, mem_type)?;
+            // TODO(correctness): Make sure we have 20 pages for page-tables
+            if let Err(e) = crate::memory::KernelAllocator::try_refill_tcache(20, 0, MemType::Mem)
+            {
+                crate::memory::policy::release_frames(frames, mem_type);
+                return Err(e);
+            }
 
             // TODO(apihell): This `paddr` is bogus, it will return the PAddr of the
             // first frame mapped but if you map multiple Frames, no chance getting that
             // Better would be a function to request physically consecutive DMA memory
             // or use IO-MMU translation (see also rumpuser_pci_dmalloc)
             // also better to just return what NR replies with...
-            let mut paddr = None;
+            let paddr = frames.first().map(|frame| frame.base);
             let mut total_len = 0;
-            {
-                let mut pmanager = match mem_type {
-                    MemType::Mem => kcb.mem_manager(),
-                    MemType::PMem => kcb.pmem_manager(),
-                    _ => unreachable!(),
-                };
-
-                for _i in 0..lp {
-                    let mut frame = pmanager
-                        .allocate_large_page()
-                        .expect("We refilled so allocation should work.");
-                    total_len += frame.size;
-                    unsafe { frame.zero() };
-                    frames
-                        .try_push(frame)
-                        .expect("Can't fail see `try_with_capacity`");
-                    if paddr.is_none() {
-                        paddr = Some(frame.base);
-                    }
-                }
-                for _i in 0..bp {
-                    let mut frame = pmanager
-                        .allocate_base_page()
-                        .expect("We refilled so allocation should work.");
-                    total_len += frame.size;
-                    unsafe { frame.zero() };
-                    frames
-                        .try_push(frame)
-                        .expect("Can't fail see `try_with_capacity`");
-                    if paddr.is_none() {
-                        paddr = Some(frame.base);
-                    }
-                }
+            for frame in frames.iter_mut() {
+                total_len += frame.size;
+                unsafe { frame.zero() };
             }
 
             nrproc::NrProcess::<Ring3Process>::map_frames(
@@ -316,6 +578,7 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
                 base,
                 frames,
                 MapAction::ReadWriteUser,
+                mem_type,
             )
             .expect("Can't map memory");
 
@@ -345,13 +608,147 @@ fn handle_vspace(arg1: u64, arg2: u64, arg3: u64) -> Result<(u64, u64), KError>
             )?;
             Ok((paddr.as_u64(), size as u64))
         },
+        VSpaceOperation::MapMemLazy => {
+            if !base.is_base_page_aligned() || region_size == 0 {
+                return Err(KError::InvalidBase);
+            }
+            let size = round_up!(region_size as usize, BASE_PAGE_SIZE);
+            nrproc::NrProcess::<Ring3Process>::reserve(p.pid, base, size, MapAction::ReadWriteUser)
+        }
         VSpaceOperation::UnmapMem | VSpaceOperation::UnmapPMem => {
-            let handle = nrproc::NrProcess::<Ring3Process>::unmap(p.pid, base)?;
-            let va: u64 = handle.vaddr.as_u64();
-            let sz: u64 = handle.frame.size as u64;
-            super::tlb::shootdown(handle);
+            // The process can't access the region anymore once the TLB
+            // entries are gone (from all cores), so we don't have to wait
+            // for the other cores here.
+            let mut unmapper = Unmapper::new(p.pid);
+
+            // A reserved region only has the pages mapped that were touched.
+            if let Ok((base, size)) = nrproc::NrProcess::<Ring3Process>::unreserve(p.pid, base) {
+                let mut unmapped = Ok(());
+                for offset in (0..size).step_by(BASE_PAGE_SIZE) {
+                    if nrproc::NrProcess::<Ring3Process>::resolve(p.pid, base + offset).is_ok() {
+                        unmapped = unmapper.unmap(base + offset).map(|_size| ());
+                        if unmapped.is_err() {
+                            break;
+                        }
+                    }
+                }
+                unmapper.finish_async()?;
+                unmapped?;
+                return Ok((base.as_u64(), size as u64));
+            }
+
+            // Unmap every frame of the region (at least one)
+            let mut unmapped = 0;
+            let result = loop {
+                match unmapper.unmap(base + unmapped) {
+                    Ok(size) => unmapped += size,
+                    Err(e) => break Err(e),
+                }
+                if unmapped as u64 >= region_size {
+                    break Ok(());
+                }
+            };
+            unmapper.finish_async()?;
+            result?;
+
+            Ok((base.as_u64(), unmapped as u64))
+        }
+        VSpaceOperation::MapFile => {
+            let fd = arg4 & 0xffff_ffff;
+            let flags = MapFlags::from(arg4 >> 32);
+            let offset = arg5;
+            if !base.is_base_page_aligned() || region_size == 0 {
+                return Err(KError::InvalidBase);
+            }
+            if offset % BASE_PAGE_SIZE as u64 != 0
+                || offset
+                    .checked_add(region_size)
+                    .map_or(true, |end| end > i64::MAX as u64)
+            {
+                return Err(KError::InvalidOffset);
+            }
+            if flags.is_shared() == flags.is_private() {
+                return Err(KError::InvalidFlags);
+            }
+            let action = match (flags.is_write(), flags.is_exec()) {
+                (false, false) => MapAction::ReadUser,
+                (false, true) => MapAction::ReadExecuteUser,
+                (true, false) => MapAction::ReadWriteUser,
+                (true, true) => MapAction::ReadWriteExecuteUser,
+            };
+
+            // Both kinds of mappings start out with the pages of the file, a
+            // private mapping gets a copy of a page on the first write to it.
+            let writable_shared = flags.is_shared() && flags.is_write();
+            let paddrs = cnrfs::MlnrKernelNode::file_map(
+                p.pid,
+                fd,
+                base,
+                region_size,
+                offset as i64,
+                writable_shared,
+            )?;
+            let mut frames = Vec::try_with_capacity(paddrs.len())?;
+            for paddr in paddrs {
+                frames.try_push(Frame::new(paddr, BASE_PAGE_SIZE, kcb.node))?;
+            }
+
+            let mut mapped_pages = 0;
+            let mapped = if flags.is_private() && flags.is_write() {
+                nrproc::NrProcess::<Ring3Process>::map_copy_on_write_frames(
+                    p.pid,
+                    base,
+                    frames,
+                    action,
+                    &mut mapped_pages,
+                )
+            } else {
+                nrproc::NrProcess::<Ring3Process>::map_shared_frames(
+                    p.pid,
+                    base,
+                    frames,
+                    action,
+                    &mut mapped_pages,
+                )
+            };
+            if let Err(e) = mapped {
+                // Undo what got mapped before the error, the pages after it
+                // may belong to an older mapping.
+                let mut unmapper = Unmapper::new(p.pid);
+                for i in 0..mapped_pages {
+                    let _r = unmapper.unmap(base + i * BASE_PAGE_SIZE);
+                }
+                unmapper.finish()?;
+                cnrfs::MlnrKernelNode::file_unmap(p.pid, base)?;
+                return Err(e);
+            }
+
+            Ok((base.as_u64(), region_size))
+        }
+        VSpaceOperation::UnmapFile => {
+            if !base.is_base_page_aligned() || region_size == 0 {
+                return Err(KError::InvalidBase);
+            }
 
-            Ok((va, sz))
+            let pages = round_up!(region_size as usize, BASE_PAGE_SIZE) / BASE_PAGE_SIZE;
+            // Private copies are freed, the pages of the file have to be gone
+            // from all TLBs before the file releases them.
+            let mut unmapper = Unmapper::new(p.pid);
+            let mut unmapped = Ok(0);
+            for i in 0..pages {
+                unmapped = unmapper.unmap(base + i * BASE_PAGE_SIZE);
+                if unmapped.is_err() {
+                    break;
+                }
+            }
+            unmapper.finish()?;
+            unmapped?;
+            // The other replicas only see the stores to a writable shared
+            // mapping once we wrote them back.
+            cnrfs::MlnrKernelNode::write_back(p.pid, Some(base))?;
+            cnrfs::MlnrKernelNode::file_unmap(p.pid, base)?;
+
+            Ok((base.as_u64(), region_size))
         }
         VSpaceOperation::Identify => unsafe {
             trace!("Identify base {:#x}.", base);
@@ -453,6 +850,15 @@ fn handle_fileio(
 
             cnrfs::MlnrKernelNode::mkdir(pid, pathname, modes)
         }
+        FileOperation::ReadDir => {
+            let fd = arg2;
+            let buffer = arg3;
+            let len = arg4;
+            let cookie = arg5;
+            let _r = user_virt_addr_valid(pid, buffer, len)?;
+
+            cnrfs::MlnrKernelNode::read_dir(pid, fd, buffer, len, cookie as usize)
+        }
         FileOperation::Unknown => {
             unreachable!("FileOperation not allowed");
             Err(KError::NotSupported)
@@ -464,6 +870,23 @@ fn handle_fileio(
 /// sizes. Or maintain a list of (low, high) memory limits per process and check
 /// if (base, size) are within the process memory limits.
 fn user_virt_addr_valid(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KError> {
+    // The kernel can't take a page-fault on behalf of the process, so
+    // reserved pages are backed here before they're accessed (and
+    // copy-on-write pages are copied, the kernel might write to them).
+    let resolve = |vaddr: VAddr| {
+        let resolved = nrproc::NrProcess::<Ring3Process>::resolve(pid, vaddr).or_else(|_e| {
+            nrproc::NrProcess::<Ring3Process>::map_lazy_page(pid, vaddr)?;
+            nrproc::NrProcess::<Ring3Process>::resolve(pid, vaddr)
+        })?;
+        match nrproc::NrProcess::<Ring3Process>::copy_on_write(pid, vaddr) {
+            Ok(Some(handle)) => {
+                super::tlb::shootdown(pid, handle);
+                nrproc::NrProcess::<Ring3Process>::resolve(pid, vaddr)
+            }
+            Ok(None) | Err(KError::NotMapped) => Ok(resolved),
+            Err(e) => Err(e),
+        }
+    };
     let mut base = base;
     let upper_addr = base + size;
 
@@ -471,14 +894,11 @@ fn user_virt_addr_valid(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KE
         while base <= upper_addr {
             // Validate addresses for the buffer end.
             if upper_addr - base <= BASE_PAGE_SIZE as u64 {
-                let _r = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
-                return nrproc::NrProcess::<Ring3Process>::resolve(
-                    pid,
-                    VAddr::from(upper_addr - 1),
-                );
+                let _r = resolve(VAddr::from(base))?;
+                return resolve(VAddr::from(upper_addr - 1));
             }
 
-            let _r = nrproc::NrProcess::<Ring3Process>::resolve(pid, VAddr::from(base))?;
+            let _r = resolve(VAddr::from(base))?;
             base += BASE_PAGE_SIZE as u64;
         }
         return Ok((base, size));
@@ -486,6 +906,56 @@ fn user_virt_addr_valid(pid: Pid, base: u64, size: u64) -> Result<(u64, u64), KE
     Err(KError::BadAddress)
 }
 
+fn handle_ipc(arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> Result<(u64, u64), KError> {
+    let op = IpcOperation::from(arg1);
+
+    let kcb = super::kcb::get_kcb();
+    let pid = kcb.arch.current_pid()?;
+
+    match op {
+        IpcOperation::Create => {
+            let name = arg2;
+            let capacity = arg3.try_into().map_err(|_e| KError::InvalidLength)?;
+            let id = ipc::create(pid, name, capacity)?;
+            Ok((id, 0))
+        }
+        IpcOperation::Attach => {
+            let name = arg2;
+            let id = ipc::attach(pid, name)?;
+            Ok((id, 0))
+        }
+        IpcOperation::Detach => {
+            ipc::detach(pid, arg2)?;
+            Ok((0, 0))
+        }
+        IpcOperation::Grant => {
+            let id = arg2;
+            let other = arg3.try_into().map_err(|_e| KError::NoProcessFoundForPid)?;
+            ipc::grant(pid, id, other)?;
+            Ok((0, 0))
+        }
+        IpcOperation::Send => {
+            let id = arg2;
+            let buffer = arg3;
+            let len = arg4;
+
+            let _r = user_virt_addr_valid(pid, buffer, len)?;
+            ipc::send(pid, id, buffer, len as usize)?;
+            Ok((0, 0))
+        }
+        IpcOperation::Receive => {
+            let id = arg2;
+            let buffer = arg3;
+            let len = arg4;
+
+            let _r = user_virt_addr_valid(pid, buffer, len)?;
+            let received = ipc::receive(pid, id, buffer, len as usize)?;
+            Ok((received as u64, 0))
+        }
+        IpcOperation::Unknown => Err(KError::InvalidIpcOperation { a: arg1 }),
+    }
+}
+
 #[allow(unused)]
 fn debug_print_syscall(function: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) {
     sprint!("syscall: {:?}", SystemCall::new(function));
@@ -531,6 +1001,16 @@ fn debug_print_syscall(function: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64
                 arg5
             );
         }
+        SystemCall::Ipc => {
+            sprintln!(
+                " {:?} {} {} {} {}",
+                IpcOperation::from(arg1),
+                arg2,
+                arg3,
+                arg4,
+                arg5
+            );
+        }
         SystemCall::Unknown => unreachable!(),
     }
 }
@@ -546,10 +1026,11 @@ pub extern "C" fn syscall_handle(
     arg5: u64,
 ) -> ! {
     let status: Result<(u64, u64), KError> = match SystemCall::new(function) {
-        SystemCall::System => handle_system(arg1, arg2, arg3),
-        SystemCall::Process => handle_process(arg1, arg2, arg3),
-        SystemCall::VSpace => handle_vspace(arg1, arg2, arg3),
+        SystemCall::System => handle_system(arg1, arg2, arg3, arg4),
+        SystemCall::Process => handle_process(arg1, arg2, arg3, arg4, arg5),
+        SystemCall::VSpace => handle_vspace(arg1, arg2, arg3, arg4, arg5),
         SystemCall::FileIO => handle_fileio(arg1, arg2, arg3, arg4, arg5),
+        SystemCall::Ipc => handle_ipc(arg1, arg2, arg3, arg4),
         _ => Err(KError::InvalidSyscallArgument1 { a: function }),
     };
 
@@ -565,9 +1046,15 @@ pub extern "C" fn syscall_handle(
                 });
             }
             Err(status) => {
-                error!("System call returned with error: {:?}", status);
+                let code: SystemCallError = status.clone().into();
+                if code == SystemCallError::WouldBlock {
+                    // Callers poll for these, not worth a message
+                    trace!("System call would block: {:?}", status);
+                } else {
+                    error!("System call returned with error: {:?}", status);
+                }
                 kcb.arch.save_area.as_mut().map(|sa| {
-                    sa.set_syscall_error_code(status.into());
+                    sa.set_syscall_error_code(code);
                 });
             }
         };
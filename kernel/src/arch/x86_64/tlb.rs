@@ -4,12 +4,12 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::ops::Range;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 use apic::ApicDriver;
 use bit_field::BitField;
 use crossbeam_queue::ArrayQueue;
-use fallible_collections::FallibleVecGlobal;
+use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use lazy_static::lazy_static;
 use log::trace;
 use x86::apic::{
@@ -17,9 +17,16 @@ use x86::apic::{
     TriggerMode,
 };
 
+use kpi::MemType;
+
 use super::memory::BASE_PAGE_SIZE;
+use super::vspace::pcid;
+use super::MAX_CORES;
+use crate::error::KError;
 use crate::kcb;
-use crate::memory::vspace::TlbFlushHandle;
+use crate::memory::vspace::{CoreBitMap, TlbFlushHandle};
+use crate::memory::{Frame, KernelAllocator, VAddr};
+use crate::process::Pid;
 use crate::{cnrfs, is_page_aligned, nr};
 
 // In the xAPIC mode, the Destination Format Register (DFR) through the MMIO
@@ -37,17 +44,80 @@ use crate::{cnrfs, is_page_aligned, nr};
 // derived from the 32-bit local x2APIC ID: Logical x2APIC ID = [(x2APIC
 // ID[19:4] « 16) | (1 « x2APIC ID[3:0])]
 
+/// How many shootdowns can wait for a core before senders have to wait for
+/// the core to handle some of them.
 const IPI_WORKQUEUE_CAPACITY: usize = 4;
 
+/// Cores flush their entire TLB instead of single pages if a shootdown
+/// covers more pages than this.
+const FULL_FLUSH_THRESHOLD: usize = 20;
+
+/// The work other cores sent to a core.
+///
+/// Every kind of work has its own queue because it can be done in different
+/// places: shootdowns while we wait for others, replica advances by idle
+/// cores that poll and everything else only in the IPI handler.
+struct WorkQueues {
+    shootdowns: ArrayQueue<Arc<Shootdown>>,
+    /// Every other core has at most one termination in flight (`terminate`
+    /// waits for them), so this is sized to never fill up.
+    terminations: ArrayQueue<Arc<Termination>>,
+    /// The logs the core was asked to advance (one bit per log id).
+    advances: [AtomicU64; (MAX_CORES + 1 + 63) / 64],
+    /// The core was asked to give its cached frames back (see
+    /// `KernelAllocator::reclaim_if_requested`).
+    reclaim: AtomicBool,
+}
+
+impl WorkQueues {
+    fn new(num_threads: usize) -> Self {
+        // ArrayQueue does memory allocation on `new`, maybe have try_new,
+        // but this is fine since it's during initialization
+        WorkQueues {
+            shootdowns: ArrayQueue::new(IPI_WORKQUEUE_CAPACITY),
+            terminations: ArrayQueue::new(num_threads),
+            advances: Default::default(),
+            reclaim: AtomicBool::new(false),
+        }
+    }
+
+    /// Records that the core should advance log `log_id`.
+    fn request_advance(&self, log_id: usize) {
+        assert!(log_id < self.advances.len() * 64, "Invalid log id");
+        self.advances[log_id / 64].fetch_or(1 << (log_id % 64), Ordering::AcqRel);
+    }
+
+    /// Advances the logs we were asked to, returns false if there were none.
+    fn advance_requested(&self) -> bool {
+        let mut advanced = false;
+        for (word, requested) in self.advances.iter().enumerate() {
+            let mut bits = requested.swap(0, Ordering::AcqRel);
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                bits &= !(1 << bit);
+                advance_log(word * 64 + bit);
+                advanced = true;
+            }
+        }
+        advanced
+    }
+
+    /// Handles all shootdowns that are waiting for the core.
+    fn process_shootdowns(&self) {
+        while let Some(shootdown) = self.shootdowns.pop() {
+            trace!("TLB channel got msg {:?}", shootdown);
+            shootdown.process();
+        }
+    }
+}
+
 lazy_static! {
-    static ref IPI_WORKQUEUE: Vec<ArrayQueue<WorkItem>> = {
+    static ref IPI_WORKQUEUE: Vec<WorkQueues> = {
         let num_threads = atopology::MACHINE_TOPOLOGY.num_threads();
         let mut channels =
             Vec::try_with_capacity(num_threads).expect("Not enough memory to initialize system");
         for _i in 0..num_threads {
-            // ArrayQueue does memory allocation on `new`, maybe have try_new,
-            // but this is fine since it's during initialization
-            channels.push(ArrayQueue::new(IPI_WORKQUEUE_CAPACITY));
+            channels.push(WorkQueues::new(num_threads));
         }
 
         channels
@@ -58,71 +128,278 @@ lazy_static! {
 pub enum WorkItem {
     Shootdown(Arc<Shootdown>),
     AdvanceReplica(usize),
+    Terminate(Arc<Termination>),
+    Reclaim,
 }
 
+/// Request for a core to stop running the executor of process `pid`.
 #[derive(Debug)]
-pub struct Shootdown {
-    vregion: Range<u64>,
+pub struct Termination {
+    pid: crate::process::Pid,
     ack: AtomicBool,
 }
 
+impl Termination {
+    /// Create a new termination request.
+    pub fn new(pid: crate::process::Pid) -> Self {
+        Termination {
+            pid,
+            ack: AtomicBool::new(false),
+        }
+    }
+
+    /// Check if receiver has stopped running the process.
+    pub fn is_acknowledged(&self) -> bool {
+        self.ack.load(Ordering::Relaxed)
+    }
+
+    /// Drops the executor of `pid` (if it runs or waits on this core) and
+    /// switches back to the kernel address space.
+    ///
+    /// The interrupt handler will end up in the scheduler once it notices
+    /// the core no longer has an executor.
+    fn process(&self) {
+        let kcb = kcb::get_kcb();
+        kcb.arch.run_queue.remove(self.pid);
+        if kcb
+            .arch
+            .current_executor()
+            .map_or(false, |e| e.pid == self.pid)
+        {
+            let _executor = kcb.arch.take_current_executor();
+            unsafe { x86::controlregs::cr3_write(kcb.arch.init_vspace().pml4_address().into()) };
+        }
+        // The TLB may still have entries of the process (under its PCID)
+        kcb.arch.pcids.release(self.pid);
+
+        // Only acknowledge once we're no longer using the process' page-tables
+        self.ack.store(true, Ordering::Release);
+    }
+}
+
+/// Virtual address ranges that have to be flushed from the TLBs of a set of
+/// cores.
+///
+/// Collects the `TlbFlushHandle`s of many unmap operations, so they can be
+/// flushed with a single IPI round (see [`shootdown_batch`]).
+#[derive(Debug)]
+pub struct ShootdownBatch {
+    /// The process the regions belong to.
+    pid: Pid,
+    ranges: Vec<Range<u64>>,
+    cores: CoreBitMap,
+}
+
+impl ShootdownBatch {
+    pub fn new(pid: Pid) -> Self {
+        ShootdownBatch {
+            pid,
+            ranges: Vec::new(),
+            cores: Default::default(),
+        }
+    }
+
+    /// Adds the region of `handle` to the batch (adjacent regions are merged).
+    pub fn add(&mut self, handle: &TlbFlushHandle) -> Result<(), KError> {
+        for gtid in handle.cores() {
+            self.cores.set_bit(gtid, true);
+        }
+
+        let range = handle.vaddr.as_u64()..(handle.vaddr + handle.frame.size).as_u64();
+        match self.ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            Some(last) if range.end == last.start => last.start = range.start,
+            _ => self.ranges.try_push(range)?,
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Shootdown {
+    /// The process whose address space changed (`None` for the address
+    /// space that is currently loaded on the core).
+    pid: Option<Pid>,
+    vregions: Vec<Range<u64>>,
+    /// How many cores still have to flush their TLB.
+    pending: AtomicUsize,
+}
+
 impl Shootdown {
-    /// Create a new shootdown request.
+    /// Create a new shootdown request (for a single core).
     pub fn new(vregion: Range<u64>) -> Self {
-        debug_assert!(is_page_aligned!(vregion.start));
-        debug_assert!(is_page_aligned!(vregion.end));
+        let mut vregions = Vec::new();
+        vregions
+            .try_push(vregion)
+            .expect("TODO(error-handling): ideally: no possible failure during shootdown");
+        Shootdown::with_regions(None, vregions, 1)
+    }
+
+    /// Create a shootdown request for `cores` cores that flushes all
+    /// `vregions` of process `pid`.
+    fn with_regions(pid: Option<Pid>, vregions: Vec<Range<u64>>, cores: usize) -> Self {
+        debug_assert!(vregions
+            .iter()
+            .all(|r| is_page_aligned!(r.start) && is_page_aligned!(r.end)));
         Shootdown {
-            vregion,
-            ack: AtomicBool::new(false),
+            pid,
+            vregions,
+            pending: AtomicUsize::new(cores),
         }
     }
 
     /// Acknowledge shootdown to sender/requestor core.
     fn acknowledge(&self) {
-        self.ack.store(true, Ordering::Relaxed);
+        self.pending.fetch_sub(1, Ordering::Release);
     }
 
-    /// Check if receiver has acknowledged the shootdown.
+    /// Check if all receivers have acknowledged the shootdown.
     pub fn is_acknowledged(&self) -> bool {
-        self.ack.load(Ordering::Relaxed)
+        self.pending.load(Ordering::Acquire) == 0
+    }
+
+    /// Waits until all receivers have acknowledged the shootdown.
+    ///
+    /// Meanwhile, we handle shootdowns that other cores sent to us (they
+    /// might be waiting on us as well).
+    pub fn wait(&self) {
+        let my_gtid = kcb::get_kcb().arch.id();
+        while !self.is_acknowledged() {
+            process_pending_shootdowns(my_gtid);
+            core::hint::spin_loop();
+        }
     }
 
     /// Flush the TLB entries.
     fn process(&self) {
-        // Safe to acknowledge first as we won't return/interrupt
-        // before this function completes:
+        let pages: usize = self
+            .vregions
+            .iter()
+            .map(|r| (r.end - r.start) as usize / BASE_PAGE_SIZE)
+            .sum();
+
+        let full = pages > FULL_FLUSH_THRESHOLD;
+
+        match self.pid {
+            Some(pid) if pcid::pcid_enabled() => self.flush_pcid(pid, full),
+            _ => self.flush_current(full),
+        }
+
+        // Safe to acknowledge here since the flush is done, any access after
+        // this goes through the page-tables again:
         self.acknowledge();
+    }
+
+    fn pages(&self) -> impl Iterator<Item = u64> + '_ {
+        self.vregions
+            .iter()
+            .flat_map(|r| r.clone().step_by(BASE_PAGE_SIZE))
+    }
 
-        let it = self.vregion.clone().step_by(BASE_PAGE_SIZE);
-        if it.count() > 20 {
+    /// Flush the regions from the address space that is currently loaded.
+    fn flush_current(&self, full: bool) {
+        if full {
             trace!("flush the entire TLB");
             unsafe { x86::tlb::flush_all() };
         } else {
-            let it = self.vregion.clone().step_by(BASE_PAGE_SIZE);
-            for va in it {
+            for va in self.pages() {
                 trace!("flushing TLB page {:#x}", va);
                 unsafe { x86::tlb::flush(va as usize) };
             }
         }
     }
+
+    /// Flush the regions of `pid`, the TLB has entries of the process even
+    /// if it doesn't run on the core right now.
+    fn flush_pcid(&self, pid: Pid, full: bool) {
+        let kcb = kcb::get_kcb();
+        match kcb.arch.pcids.lookup(pid) {
+            // The core has no TLB entries of the process
+            None => {}
+            Some(pcid) if pcid == pcid::current_pcid() => self.flush_current(full),
+            Some(pcid) if pcid::has_invpcid() => {
+                if full {
+                    trace!("flush the entire TLB of PCID {}", pcid);
+                    unsafe { pcid::flush_all(pcid) };
+                } else {
+                    for va in self.pages() {
+                        trace!("flushing TLB page {:#x} of PCID {}", va, pcid);
+                        unsafe { pcid::flush(pcid, VAddr::from(va)) };
+                    }
+                }
+            }
+            // Without invpcid, the entries are flushed once the PCID is used
+            // again
+            Some(_pcid) => {
+                kcb.arch.pcids.release(pid);
+            }
+        }
+    }
 }
 
-pub fn enqueue(gtid: atopology::GlobalThreadId, s: WorkItem) {
-    trace!("TLB enqueue shootdown msg {:?}", s);
-    let _ignore = IPI_WORKQUEUE[gtid as usize].push(s);
+/// Sends `item` to core `gtid`, the caller still has to notify the core with
+/// an IPI.
+///
+/// Work is never dropped: if the queue of `gtid` is full, we notify the core
+/// and handle the shootdowns sent to us until there is room again (`gtid`
+/// might be waiting for us to acknowledge a shootdown).
+pub fn enqueue(gtid: atopology::GlobalThreadId, item: WorkItem) {
+    trace!("TLB enqueue shootdown msg {:?}", item);
+    let queues = &IPI_WORKQUEUE[gtid as usize];
+    match item {
+        WorkItem::Shootdown(shootdown) => {
+            let mut shootdown = shootdown;
+            while let Err(back) = queues.shootdowns.push(shootdown) {
+                shootdown = back;
+                wait_for_room(gtid);
+            }
+        }
+        WorkItem::Terminate(termination) => {
+            let mut termination = termination;
+            while let Err(back) = queues.terminations.push(termination) {
+                termination = back;
+                wait_for_room(gtid);
+            }
+        }
+        WorkItem::AdvanceReplica(log_id) => queues.request_advance(log_id),
+        WorkItem::Reclaim => queues.reclaim.store(true, Ordering::Release),
+    }
+}
+
+/// Makes sure the full queue of `gtid` gets drained, while we still do our
+/// part for the shootdowns sent to us.
+fn wait_for_room(gtid: atopology::GlobalThreadId) {
+    let my_gtid = kcb::get_kcb().arch.id();
+    if gtid != my_gtid {
+        notify(gtid);
+    }
+    process_pending_shootdowns(my_gtid);
+    core::hint::spin_loop();
 }
 
+/// Handles the shootdowns in the work queue of `gtid` (other work is left
+/// for the IPI handler).
+fn process_pending_shootdowns(gtid: atopology::GlobalThreadId) {
+    IPI_WORKQUEUE[gtid as usize].process_shootdowns();
+}
+
+/// Handles all work in the queue of `gtid` (called from the IPI handlers).
 pub fn dequeue(gtid: atopology::GlobalThreadId) {
-    match IPI_WORKQUEUE[gtid as usize].pop() {
-        Some(msg) => match msg {
-            WorkItem::Shootdown(s) => {
-                trace!("TLB channel got msg {:?}", s);
-                s.process();
-            }
-            WorkItem::AdvanceReplica(log_id) => advance_log(log_id),
-        },
-        None => { /*IPI request was handled by eager_advance_fs_replica()*/ }
+    let queues = &IPI_WORKQUEUE[gtid as usize];
+    queues.process_shootdowns();
+    while let Some(termination) = queues.terminations.pop() {
+        trace!("TLB channel got msg {:?}", termination);
+        termination.process();
     }
+    if queues.reclaim.swap(false, Ordering::AcqRel) {
+        KernelAllocator::reclaim_if_requested();
+    }
+    // Requests may also be handled by eager_advance_fs_replica()
+    let _advanced = queues.advance_requested();
 }
 
 fn advance_log(log_id: usize) {
@@ -144,29 +421,21 @@ pub fn eager_advance_fs_replica() {
     let kcb = kcb::get_kcb();
     let core_id = kcb.arch.id();
 
-    match IPI_WORKQUEUE[core_id].pop() {
-        Some(msg) => {
-            match &msg {
-                WorkItem::Shootdown(_s) => {
-                    // If its for TLB shootdown, insert it back into the queue.
-                    enqueue(core_id, msg)
-                }
-                WorkItem::AdvanceReplica(log_id) => advance_log(*log_id),
+    // Terminations and reclaim requests are left for the IPI handler
+    let queues = &IPI_WORKQUEUE[core_id];
+    queues.process_shootdowns();
+    if !queues.advance_requested() {
+        let kcb = super::kcb::get_kcb();
+        match kcb.arch.cnr_replica.as_ref() {
+            Some(replica) => {
+                let log_id = replica.1.id();
+                // Synchronize NR-replica
+                let _ignore = nr::KernelNode::synchronize();
+                // Synchronize Mlnr-replica.
+                advance_log(log_id);
             }
-        }
-        None => {
-            let kcb = super::kcb::get_kcb();
-            match kcb.arch.cnr_replica.as_ref() {
-                Some(replica) => {
-                    let log_id = replica.1.id();
-                    // Synchronize NR-replica
-                    let _ignore = nr::KernelNode::synchronize();
-                    // Synchronize Mlnr-replica.
-                    advance_log(log_id);
-                }
-                None => unreachable!("eager_advance_fs_replica: KCB does not have cnr_replica!"),
-            };
-        }
+            None => unreachable!("eager_advance_fs_replica: KCB does not have cnr_replica!"),
+        };
     }
 }
 
@@ -207,19 +476,14 @@ fn send_ipi_multicast(ldr: u32) {
     unsafe { apic.send_ipi(icr) }
 }
 
-/// Runs the TLB shootdown protocol.
-///
-/// Takes the `TlbFlushHandle` and figures out what cores it needs to send an IPI to.
-/// It divides IPIs into clusters to avoid overhead of sending IPIs individually.
-/// Finally, waits until all cores have acknowledged the IPI before it returns.
-pub fn shootdown(handle: TlbFlushHandle) {
-    let my_gtid = super::kcb::get_kcb().arch.id();
-
+/// Returns the logical destinations for all 16 IPI clusters with no core
+/// selected yet.
+fn empty_cluster_destinations() -> [u32; 16] {
     // We support up to 16 IPI clusters, this will address `16*16 = 256` cores
     // Cluster ID (LDR[31:16]) is the address of the destination cluster
     // We pre-configure the upper half (cluster ID) of LDR here
     // by initializing the elements
-    let mut cluster_destination: [u32; 16] = [
+    [
         0 << 16,
         1 << 16,
         2 << 16,
@@ -236,56 +500,211 @@ pub fn shootdown(handle: TlbFlushHandle) {
         13 << 16,
         14 << 16,
         15 << 16,
-    ];
+    ]
+}
+
+/// Adds `gtid` to the cluster it belongs to in `cluster_destination`.
+fn add_cluster_destination(cluster_destination: &mut [u32; 16], gtid: atopology::GlobalThreadId) {
+    let apic_id = atopology::MACHINE_TOPOLOGY.threads[gtid].apic_id();
+    let cluster_addr = apic_id.x2apic_logical_cluster_address();
+    let cluster = apic_id.x2apic_logical_cluster_id();
+
+    trace!(
+        "Send IPI to gtid:{} in cluster:{} cluster_addr:{}",
+        gtid,
+        cluster,
+        cluster_addr
+    );
+    cluster_destination[cluster as usize].set_bit(cluster_addr as usize, true);
+}
+
+/// Notify the cores in all clusters of new work in the queue.
+fn notify_clusters(cluster_destination: [u32; 16]) {
+    for cluster_ldr in cluster_destination {
+        // Do we need to send to anyone inside this cluster?
+        if cluster_ldr.get_bits(0..=3) != 0 {
+            trace!("send ipi multicast to {}", cluster_ldr);
+            send_ipi_multicast(cluster_ldr);
+        }
+    }
+}
 
-    let num_cores = atopology::MACHINE_TOPOLOGY.num_threads();
-    let mut shootdowns: Vec<Arc<Shootdown>> = Vec::try_with_capacity(num_cores)
+/// Notify core `gtid` of new work in its queue.
+pub fn notify(gtid: atopology::GlobalThreadId) {
+    let mut cluster_destination = empty_cluster_destinations();
+    add_cluster_destination(&mut cluster_destination, gtid);
+    notify_clusters(cluster_destination);
+}
+
+/// Runs the TLB shootdown protocol.
+///
+/// Takes the `TlbFlushHandle` (of an unmap in process `pid`) and figures out what cores it needs to send an IPI to.
+/// It divides IPIs into clusters to avoid overhead of sending IPIs individually.
+/// Finally, waits until all cores have acknowledged the IPI before it returns.
+pub fn shootdown(pid: Pid, handle: TlbFlushHandle) {
+    let mut batch = ShootdownBatch::new(pid);
+    batch
+        .add(&handle)
         .expect("TODO(error-handling): ideally: no possible failure during shootdown");
-    let range = handle.vaddr.as_u64()..(handle.vaddr + handle.frame.size).as_u64();
+    shootdown_batch(batch);
+}
 
-    for gtid in handle.cores() {
-        if gtid != my_gtid {
-            let apic_id = atopology::MACHINE_TOPOLOGY.threads[gtid].apic_id();
-            let cluster_addr = apic_id.x2apic_logical_cluster_address();
-            let cluster = apic_id.x2apic_logical_cluster_id();
-
-            trace!(
-                "Send shootdown to gtid:{} in cluster:{} cluster_addr:{}",
-                gtid,
-                cluster,
-                cluster_addr
-            );
-            cluster_destination[cluster as usize].set_bit(cluster_addr as usize, true);
+/// Flushes all regions of `batch` from the TLBs with a single IPI round and
+/// waits until all cores have acknowledged it.
+pub fn shootdown_batch(batch: ShootdownBatch) {
+    shootdown_async(batch).wait();
+    trace!("done with all shootdowns");
+}
 
-            let shootdown = Arc::try_new(Shootdown::new(range.clone()))
-                .expect("TODO(error-handling): ideally: no possible failure during shootdown");
-            enqueue(gtid, WorkItem::Shootdown(shootdown.clone()));
+/// Starts to flush all regions of `batch` from the TLBs.
+///
+/// Our own TLB is flushed before this returns, the other cores are done once
+/// the returned request `is_acknowledged`.
+pub fn shootdown_async(batch: ShootdownBatch) -> Arc<Shootdown> {
+    let my_gtid = super::kcb::get_kcb().arch.id();
+    let mut cluster_destination = empty_cluster_destinations();
 
-            debug_assert!(shootdowns.len() < shootdowns.capacity(), "Avoid realloc");
-            shootdowns.push(shootdown);
+    let mut cores = 0;
+    for gtid in batch.cores.iter() {
+        if gtid != my_gtid {
+            add_cluster_destination(&mut cluster_destination, gtid);
+            cores += 1;
         }
     }
 
-    // Notify the cores in all clusters of new work in the queue
-    for cluster_ldr in cluster_destination {
-        // Do we need to send to anyone inside this cluster?
-        if cluster_ldr.get_bits(0..=3) != 0 {
-            trace!("send ipi multicast to {}", cluster_ldr);
-            send_ipi_multicast(cluster_ldr);
+    // The request counts us as receiver too
+    let shootdown = Arc::try_new(Shootdown::with_regions(
+        Some(batch.pid),
+        batch.ranges,
+        cores + 1,
+    ))
+    .expect("TODO(error-handling): ideally: no possible failure during shootdown");
+    for gtid in batch.cores.iter() {
+        if gtid != my_gtid {
+            enqueue(gtid, WorkItem::Shootdown(shootdown.clone()));
         }
     }
 
+    notify_clusters(cluster_destination);
+
     // Finally, we also need to shootdown our own TLB
-    let shootdown = Shootdown::new(range);
     shootdown.process();
 
-    // Wait synchronously on cores to complete
-    while !shootdowns.is_empty() {
-        shootdowns.drain_filter(|s| s.is_acknowledged());
+    shootdown
+}
+
+/// Frames that go back to the allocator once a shootdown is acknowledged.
+struct DeferredRelease {
+    pid: Pid,
+    shootdown: Arc<Shootdown>,
+    frames: Vec<(Frame, MemType)>,
+}
+
+/// Frames of unmapped regions that other cores might still access through
+/// their TLB.
+static DEFERRED_RELEASES: spin::Mutex<Vec<DeferredRelease>> = spin::Mutex::new(Vec::new());
+
+/// Gives `frames` back to the allocator once `shootdown` is acknowledged.
+///
+/// Until then, every vspace operation of `pid` waits for the shootdown (see
+/// [`complete_deferred_releases`]), so the process can't observe stale TLB
+/// entries after it mapped something else in the region.
+pub fn release_after(
+    pid: Pid,
+    shootdown: Arc<Shootdown>,
+    frames: Vec<(Frame, MemType)>,
+) -> Result<(), KError> {
+    if shootdown.is_acknowledged() {
+        for (frame, mem_type) in frames {
+            KernelAllocator::release_frame(frame, mem_type)?;
+        }
+        return Ok(());
+    }
+
+    let mut deferred = DEFERRED_RELEASES.lock();
+    deferred.try_push(DeferredRelease {
+        pid,
+        shootdown,
+        frames,
+    })?;
+    Ok(())
+}
+
+/// Releases the frames of all acknowledged shootdowns, waits for the
+/// outstanding shootdowns of `pid` first.
+pub fn complete_deferred_releases(pid: Pid) -> Result<(), KError> {
+    let mut completed: Vec<DeferredRelease> = Vec::new();
+    {
+        let mut deferred = DEFERRED_RELEASES.lock();
+        if deferred.is_empty() {
+            return Ok(());
+        }
+        completed.try_reserve(deferred.len())?;
+        completed.extend(deferred.drain_filter(|d| d.pid == pid || d.shootdown.is_acknowledged()));
+    }
+
+    for d in completed {
+        d.shootdown.wait();
+        for (frame, mem_type) in d.frames {
+            KernelAllocator::release_frame(frame, mem_type)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stops process `pid` on all `cores` and on the current core.
+///
+/// Waits until every core has dropped the executor and switched away from
+/// the page-tables of the process before it returns.
+pub fn terminate(pid: crate::process::Pid, cores: &[atopology::GlobalThreadId]) {
+    // The current core might not be in `cores` (e.g., if it just sends an
+    // event to another process that runs here too)
+    Termination::new(pid).process();
+
+    let my_gtid = super::kcb::get_kcb().arch.id();
+    let mut cluster_destination = empty_cluster_destinations();
+
+    let mut terminations: Vec<Arc<Termination>> = Vec::try_with_capacity(cores.len())
+        .expect("TODO(error-handling): ideally: no possible failure during terminate");
+    for &gtid in cores {
+        if gtid != my_gtid {
+            add_cluster_destination(&mut cluster_destination, gtid);
+
+            let termination = Arc::try_new(Termination::new(pid))
+                .expect("TODO(error-handling): ideally: no possible failure during terminate");
+            enqueue(gtid, WorkItem::Terminate(termination.clone()));
+
+            debug_assert!(
+                terminations.len() < terminations.capacity(),
+                "Avoid realloc"
+            );
+            terminations.push(termination);
+        }
+    }
+
+    notify_clusters(cluster_destination);
+
+    // Wait synchronously on cores to complete (they might wait for us to
+    // make room for a shootdown in the meantime)
+    while !terminations.is_empty() {
+        terminations.drain_filter(|t| t.is_acknowledged());
+        process_pending_shootdowns(my_gtid);
         core::hint::spin_loop();
     }
 
-    trace!("done with all shootdowns");
+    trace!("done with all terminations");
+}
+
+/// Asks core `gtid` to give its cached frames back to the NCaches.
+///
+/// Requests that arrive before the core handled the previous one are merged
+/// (the core gives everything back anyways).
+pub fn reclaim(gtid: atopology::GlobalThreadId) {
+    trace!("Send Reclaim IPI to {}", gtid);
+    let apic_id = atopology::MACHINE_TOPOLOGY.threads[gtid as usize].apic_id();
+
+    enqueue(gtid, WorkItem::Reclaim);
+    send_ipi_to_apic(apic_id);
 }
 
 pub fn advance_replica(gtid: atopology::GlobalThreadId, log_id: usize) {
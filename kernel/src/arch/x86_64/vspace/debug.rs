@@ -9,7 +9,6 @@ use core::pin::Pin;
 
 use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use log::info;
-use x86::controlregs;
 use x86::current::paging::*;
 
 use super::page_table::PageTable;
@@ -112,8 +111,7 @@ impl PageTable {
 
 #[allow(unused)]
 pub unsafe fn dump_current_table(log_level: usize) {
-    let cr_three: u64 = controlregs::cr3();
-    let pml4: PAddr = PAddr::from(cr_three);
+    let pml4: PAddr = super::pcid::current_pml4();
     let pml4_table = transmute::<VAddr, &PML4>(paddr_to_kernel_vaddr(pml4));
 
     dump_table(pml4_table, log_level);
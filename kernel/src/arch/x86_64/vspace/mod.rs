@@ -1,12 +1,14 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use alloc::vec::Vec;
 use core::ops::Bound::*;
 
 use fallible_collections::btree::BTreeMap;
 
 mod debug;
 pub mod page_table; /* TODO(encapsulation): This should be a private module but we break encapsulation in a few places */
+pub mod pcid;
 #[cfg(test)]
 mod test;
 
@@ -78,6 +80,18 @@ impl AddressSpace for VSpace {
         self.page_table.resolve(addr)
     }
 
+    fn overlaps(&self, base: VAddr, size: usize) -> bool {
+        // Mappings don't overlap each other, so only the last one that starts
+        // before the end of the region can reach into it
+        let range = base.as_usize()..base.as_usize() + size;
+        self.mappings
+            .range((Unbounded, Excluded(VAddr::from(range.end))))
+            .next_back()
+            .map_or(false, |(&existing_base, existing_mapping)| {
+                existing_mapping.vrange(existing_base).end > range.start
+            })
+    }
+
     fn unmap(&mut self, base: VAddr) -> Result<TlbFlushHandle, KError> {
         for (&existing_base, existing_mapping) in
             self.mappings.range((Unbounded, Included(base))).rev()
@@ -102,6 +116,10 @@ impl AddressSpace for VSpace {
         mapping.rights = new_rights;
         Ok(r)
     }
+
+    fn mappings(&self) -> Result<Vec<MappedPage>, KError> {
+        self.page_table.mappings()
+    }
 }
 
 impl Drop for VSpace {
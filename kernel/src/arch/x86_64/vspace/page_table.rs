@@ -2,11 +2,13 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::alloc::Layout;
 use core::mem::transmute;
 use core::pin::Pin;
 use core::ptr::NonNull;
 
+use fallible_collections::FallibleVec;
 use kpi::KERNEL_BASE;
 use log::{debug, trace};
 use x86::bits64::paging::*;
@@ -167,6 +169,67 @@ impl AddressSpace for PageTable {
         // TODO(correctness+memory): we lose topology information here...
         Ok(TlbFlushHandle::new(vaddr, Frame::new(paddr, size, 0)))
     }
+
+    fn mappings(&self) -> Result<Vec<MappedPage>, KError> {
+        // Everything above `KERNEL_BASE` is the (shared) kernel part
+        let user_entries = pml4_index(VAddr::from(KERNEL_BASE));
+        let pml4_slot_size = HUGE_PAGE_SIZE * PAGE_SIZE_ENTRIES;
+
+        let mut pages = Vec::new();
+        for (pml4_idx, pml4_entry) in self.pml4.iter().enumerate().take(user_entries) {
+            if !pml4_entry.is_present() {
+                continue;
+            }
+
+            let pdpt = self.get_pdpt(*pml4_entry);
+            for (pdpt_idx, pdpt_entry) in pdpt.iter().enumerate() {
+                if !pdpt_entry.is_present() {
+                    continue;
+                }
+                let vaddr = pml4_idx * pml4_slot_size + pdpt_idx * HUGE_PAGE_SIZE;
+                if pdpt_entry.is_page() {
+                    pages.try_push(MappedPage {
+                        vaddr: VAddr::from(vaddr),
+                        paddr: pdpt_entry.address(),
+                        size: HUGE_PAGE_SIZE,
+                        rights: pdpt_entry.flags().into(),
+                    })?;
+                    continue;
+                }
+
+                let pd = self.get_pd(*pdpt_entry);
+                for (pd_idx, pd_entry) in pd.iter().enumerate() {
+                    if !pd_entry.is_present() {
+                        continue;
+                    }
+                    let vaddr = vaddr + pd_idx * LARGE_PAGE_SIZE;
+                    if pd_entry.is_page() {
+                        pages.try_push(MappedPage {
+                            vaddr: VAddr::from(vaddr),
+                            paddr: pd_entry.address(),
+                            size: LARGE_PAGE_SIZE,
+                            rights: pd_entry.flags().into(),
+                        })?;
+                        continue;
+                    }
+
+                    let pt = self.get_pt(*pd_entry);
+                    for (pt_idx, pt_entry) in pt.iter().enumerate() {
+                        if pt_entry.is_present() {
+                            pages.try_push(MappedPage {
+                                vaddr: VAddr::from(vaddr + pt_idx * BASE_PAGE_SIZE),
+                                paddr: pt_entry.address(),
+                                size: BASE_PAGE_SIZE,
+                                rights: pt_entry.flags().into(),
+                            })?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(pages)
+    }
 }
 
 impl PageTable {
@@ -900,7 +963,7 @@ impl<'a> ReadOnlyPageTable<'a> {
     /// We should have better type-safety for this to help with unsafety.
     /// Ideally using some token that is consumed on every page-table switch.
     pub unsafe fn current() -> Self {
-        let cr3 = PAddr::from(x86::controlregs::cr3());
+        let cr3 = super::pcid::current_pml4();
         assert_ne!(cr3, PAddr::zero());
         let pml4 = transmute::<VAddr, &PML4>(paddr_to_kernel_vaddr(cr3));
         ReadOnlyPageTable { pml4 }
@@ -0,0 +1,263 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Process-context identifiers (PCIDs).
+//!
+//! With PCIDs enabled the CPU tags TLB entries with the PCID that is in cr3,
+//! so we can switch between address spaces without flushing the TLB. The
+//! PCIDs are handed out per core (see [`PcidCache`]): a process gets one the
+//! first time it runs on a core and keeps it until the core needs it for
+//! another process (or the process goes away).
+//!
+//! The kernel address space always uses [`KERNEL_PCID`]. If the CPU doesn't
+//! support PCIDs, every switch flushes the TLB (as it did before).
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::info;
+use x86::bits64::paging::{PAddr, VAddr};
+use x86::{controlregs, cpuid};
+
+use crate::process::Pid;
+
+/// The PCID of the kernel address space (and of everything when PCIDs are
+/// disabled).
+pub const KERNEL_PCID: u16 = 0;
+
+/// How many processes can have a PCID on a core at the same time.
+///
+/// The hardware supports 4096 PCIDs, but the TLB only holds a few address
+/// spaces worth of entries anyways.
+const PCID_SLOTS: usize = 32;
+
+/// The PCID bits of cr3.
+const CR3_PCID_MASK: u64 = 0xfff;
+
+/// Keep the TLB entries of the PCID when writing cr3.
+const CR3_NOFLUSH: u64 = 1 << 63;
+
+/// Set if the cores run with CR4.PCIDE.
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set if the cores support the `invpcid` instruction.
+static HAS_INVPCID: AtomicBool = AtomicBool::new(false);
+
+/// Enables PCIDs on the current core (if the CPU supports them).
+///
+/// Has to be called on every core before it runs a process.
+pub fn enable_pcid() {
+    let cpuid = cpuid::CpuId::new();
+    let has_pcid = cpuid.get_feature_info().map_or(false, |f| f.has_pcid());
+    let has_invpcid = cpuid
+        .get_extended_feature_info()
+        .map_or(false, |f| f.has_invpcid());
+
+    // Setting CR4.PCIDE faults unless we currently run with PCID 0
+    let cr3 = unsafe { controlregs::cr3() };
+    if !has_pcid || cr3 & CR3_PCID_MASK != 0 {
+        info!("PCIDs not supported, vspace switches flush the TLB");
+        return;
+    }
+
+    unsafe {
+        let mut cr4 = controlregs::cr4();
+        cr4 |= controlregs::Cr4::CR4_ENABLE_PCID;
+        controlregs::cr4_write(cr4);
+    }
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+    HAS_INVPCID.store(has_invpcid, Ordering::Relaxed);
+}
+
+/// Are TLB entries tagged with PCIDs?
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Can we invalidate the entries of a PCID that isn't loaded in cr3?
+pub fn has_invpcid() -> bool {
+    HAS_INVPCID.load(Ordering::Relaxed)
+}
+
+/// The PCID the core currently runs with.
+pub fn current_pcid() -> u16 {
+    (unsafe { controlregs::cr3() } & CR3_PCID_MASK) as u16
+}
+
+/// The page-table the core currently runs with.
+pub fn current_pml4() -> PAddr {
+    PAddr::from(unsafe { controlregs::cr3() } & !(CR3_PCID_MASK | CR3_NOFLUSH))
+}
+
+/// The PCIDs a core handed out to processes.
+///
+/// Slot `i` holds the process that owns PCID `i + 1`.
+pub struct PcidCache {
+    /// The owner of every PCID and when it last got used.
+    slots: [Option<(Pid, u64)>; PCID_SLOTS],
+    /// Increases on every `assign` (to find the least recently used PCID).
+    clock: u64,
+}
+
+impl Default for PcidCache {
+    fn default() -> Self {
+        PcidCache {
+            slots: [None; PCID_SLOTS],
+            clock: 0,
+        }
+    }
+}
+
+impl PcidCache {
+    fn slot_to_pcid(slot: usize) -> u16 {
+        (slot + 1) as u16
+    }
+
+    /// Returns the PCID of `pid` on this core (if it has one).
+    pub fn lookup(&self, pid: Pid) -> Option<u16> {
+        self.slots
+            .iter()
+            .position(|slot| matches!(slot, Some((p, _)) if *p == pid))
+            .map(PcidCache::slot_to_pcid)
+    }
+
+    /// Returns the PCID for `pid`, takes the least recently used one if the
+    /// process doesn't have one yet.
+    ///
+    /// The second value is true if the PCID got newly assigned: the TLB may
+    /// still contain entries from its previous owner.
+    pub fn assign(&mut self, pid: Pid) -> (u16, bool) {
+        self.clock += 1;
+        let clock = self.clock;
+
+        if let Some(slot) = self
+            .slots
+            .iter()
+            .position(|slot| matches!(slot, Some((p, _)) if *p == pid))
+        {
+            self.slots[slot] = Some((pid, clock));
+            return (PcidCache::slot_to_pcid(slot), false);
+        }
+
+        let slot = self
+            .slots
+            .iter()
+            .position(|slot| slot.is_none())
+            .unwrap_or_else(|| {
+                let mut lru = 0;
+                for (i, slot) in self.slots.iter().enumerate() {
+                    if slot.map(|(_, used)| used) < self.slots[lru].map(|(_, used)| used) {
+                        lru = i;
+                    }
+                }
+                lru
+            });
+        self.slots[slot] = Some((pid, clock));
+        (PcidCache::slot_to_pcid(slot), true)
+    }
+
+    /// Takes the PCID away from `pid` (its TLB entries are flushed once the
+    /// PCID is assigned again).
+    pub fn release(&mut self, pid: Pid) -> Option<u16> {
+        let slot = self
+            .slots
+            .iter()
+            .position(|slot| matches!(slot, Some((p, _)) if *p == pid))?;
+        self.slots[slot] = None;
+        Some(PcidCache::slot_to_pcid(slot))
+    }
+}
+
+/// Loads the page-table `pml4` of process `pid`.
+///
+/// # Safety
+/// The page-table has to map the kernel.
+pub unsafe fn switch_to(pcids: &mut PcidCache, pid: Pid, pml4: PAddr) {
+    if !pcid_enabled() {
+        controlregs::cr3_write(pml4.into());
+        return;
+    }
+
+    let (pcid, fresh) = pcids.assign(pid);
+    let mut cr3 = pml4.as_u64() | pcid as u64;
+    if !fresh {
+        cr3 |= CR3_NOFLUSH;
+    }
+    controlregs::cr3_write(cr3);
+}
+
+/// The `invpcid` invalidation types.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy)]
+enum InvpcidType {
+    IndividualAddress = 0,
+    SingleContext = 1,
+}
+
+/// Invalidates TLB entries tagged with `pcid`.
+///
+/// # Safety
+/// Needs `has_invpcid`.
+unsafe fn invpcid(kind: InvpcidType, pcid: u16, vaddr: VAddr) {
+    let descriptor: [u64; 2] = [pcid as u64, vaddr.as_u64()];
+    asm!(
+        "invpcid ({0}), {1}",
+        in(reg) &descriptor,
+        in(reg) kind as u64,
+        options(att_syntax, nostack, preserves_flags)
+    );
+}
+
+/// Flushes the page at `vaddr` of `pcid` from the TLB.
+///
+/// # Safety
+/// Needs `has_invpcid`.
+pub unsafe fn flush(pcid: u16, vaddr: VAddr) {
+    invpcid(InvpcidType::IndividualAddress, pcid, vaddr);
+}
+
+/// Flushes all (non-global) entries of `pcid` from the TLB.
+///
+/// # Safety
+/// Needs `has_invpcid`.
+pub unsafe fn flush_all(pcid: u16) {
+    invpcid(InvpcidType::SingleContext, pcid, VAddr::zero());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn assign_keeps_pcid() {
+        let mut pcids = PcidCache::default();
+        let (a, fresh) = pcids.assign(1);
+        assert!(fresh);
+        assert_ne!(a, KERNEL_PCID);
+        assert_eq!(pcids.assign(1), (a, false));
+        assert_eq!(pcids.lookup(1), Some(a));
+
+        let (b, fresh) = pcids.assign(2);
+        assert!(fresh);
+        assert_ne!(a, b);
+
+        assert_eq!(pcids.release(1), Some(a));
+        assert_eq!(pcids.lookup(1), None);
+        assert_eq!(pcids.release(1), None);
+        assert!(pcids.assign(1).1);
+    }
+
+    #[test]
+    fn assign_evicts_lru() {
+        let mut pcids = PcidCache::default();
+        for pid in 0..PCID_SLOTS {
+            pcids.assign(pid);
+        }
+        // Process 0 runs again, so 1 is the least recently used one
+        let (pcid0, _) = pcids.assign(0);
+        let pcid1 = pcids.lookup(1).unwrap();
+
+        assert_eq!(pcids.assign(PCID_SLOTS), (pcid1, true));
+        assert_eq!(pcids.lookup(1), None);
+        assert_eq!(pcids.lookup(0), Some(pcid0));
+    }
+}
@@ -3,20 +3,23 @@
 
 use crate::arch::process::{UserPtr, UserSlice};
 use crate::error::KError;
-use crate::fs::fd::FileDesc;
+use crate::fs::fd::{FileDesc, FileMapping};
+use crate::fs::pmem::{self, PmemLog, Record, Records};
 use crate::fs::{
     Buffer, FileDescriptor, FileSystem, Filename, Flags, Len, MlnrFS, Mnode, Modes, NrLock, Offset,
     FD, MNODE_OFFSET,
 };
-use crate::memory::VAddr;
+use crate::memory::{PAddr, VAddr};
 use crate::prelude::*;
 use crate::process::{userptr_to_str, KernSlice, Pid};
 
 use alloc::sync::Arc;
 use cnr::{Dispatch, LogMapper};
+use fallible_collections::FallibleVec;
 use hashbrown::HashMap;
 use kpi::io::*;
 use kpi::FileOperation;
+use log::warn;
 
 pub struct MlnrKernelNode {
     /// TODO: RwLock should be okay for read-write operations as those ops
@@ -46,6 +49,14 @@ pub enum Modify {
     FileDelete(Pid, String),
     FileRename(Pid, String, String),
     MkDir(Pid, String, Modes),
+    /// Map (part of) a file, the last argument is set for writable shared
+    /// mappings.
+    FileMap(Pid, FD, u64, Len, Offset, bool),
+    FileUnmap(Pid, u64),
+    /// Write the contents of a writable mapping back to the file.
+    FileWriteBack(Pid, Mnode, Arc<[u8]>, Offset),
+    /// Redo the records (of the given epoch) recovered from persistent memory.
+    Recover(Arc<[u8]>, u64),
 }
 
 // TODO: Stateless op to log mapping. Maintain some state for correct redirection.
@@ -64,6 +75,12 @@ impl LogMapper for Modify {
             Modify::FileDelete(_pid, _filename) => push_to_all(nlogs, logs),
             Modify::FileRename(_pid, _oldname, _newname) => push_to_all(nlogs, logs),
             Modify::MkDir(_pid, _name, _modes) => push_to_all(nlogs, logs),
+            Modify::FileMap(_pid, _fd, _base, _len, _offset, _writable) => push_to_all(nlogs, logs),
+            Modify::FileUnmap(_pid, _base) => push_to_all(nlogs, logs),
+            Modify::FileWriteBack(_pid, mnode, _data, _offset) => {
+                logs.push((*mnode as usize - MNODE_OFFSET) % nlogs)
+            }
+            Modify::Recover(_records, _epoch) => push_to_all(nlogs, logs),
         }
 
         fn push_to_all(nlogs: usize, logs: &mut Vec<usize>) {
@@ -79,7 +96,11 @@ pub enum Access {
     FileRead(Pid, FD, Mnode, Buffer, Len, Offset),
     FileInfo(Pid, Filename, Mnode, u64),
     FdToMnode(Pid, FD),
-    FileNameToMnode(Pid, Filename),
+    FileNameToMnode(Pid, String),
+    FileReadDir(Pid, FD, Buffer, Len, usize),
+    /// The contents of the writable mappings of a process (the one at the
+    /// given base or all of them).
+    MappedData(Pid, Option<u64>),
     Synchronize(usize),
 }
 
@@ -98,6 +119,8 @@ impl LogMapper for Access {
             // TODO: Assume that all metadata modifying operations go through log 0.
             Access::FdToMnode(_pid, _fd) => logs.push(0),
             Access::FileNameToMnode(_pid, _filename) => logs.push(0),
+            Access::FileReadDir(_pid, _fd, _buffer, _len, _cookie) => logs.push(0),
+            Access::MappedData(_pid, _base) => logs.push(0),
             // Log number start with 1 in CNR, however, replica uses mod
             // operation which starts with 0; hence `log_id - 1`.
             Access::Synchronize(log_id) => logs.push((*log_id - 1) % nlogs),
@@ -111,13 +134,21 @@ pub enum MlnrNodeResult {
     ProcessRemoved(Pid),
     FileOpened(FD),
     FileAccessed(Len),
+    /// Number of bytes written and the offset they were written at.
+    FileWritten(Len, u64),
     FileClosed(u64),
     FileDeleted,
     FileInfo(FileInfo),
     FileRenamed,
     DirCreated,
+    DirRead(u64),
     MappedFileToMnode(u64),
+    FileMapped(Vec<PAddr>),
+    FileUnmapped,
+    /// Contents of writable mappings (mnode, offset in the file, data).
+    MappedData(Vec<(Mnode, Offset, Arc<[u8]>)>),
     Synchronized,
+    Recovered(usize),
 }
 
 /// TODO: Most of the functions looks same as in nr.rs. Merge the
@@ -138,6 +169,21 @@ impl MlnrKernelNode {
             })
     }
 
+    pub fn remove_process(pid: usize) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::ProcessRemove(pid), *token);
+                match response {
+                    Ok(MlnrNodeResult::ProcessRemoved(pid)) => Ok((pid as u64, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     pub fn map_fd(pid: Pid, pathname: u64, flags: u64, modes: u64) -> Result<(FD, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -145,11 +191,47 @@ impl MlnrKernelNode {
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let filename = userptr_to_str(pathname)?;
-                let response =
-                    replica.execute_mut_scan(Modify::FileOpen(pid, filename, flags, modes), *token);
+                // Opening a file only modifies the file-system if it creates
+                // or truncates the file.
+                let file_flags = FileFlags::from(flags);
+                let mut log = if file_flags.is_create() || file_flags.is_truncate() {
+                    pmem::lock()
+                } else {
+                    None
+                };
+                let mut existed = false;
+                if let Some(log) = log.as_mut() {
+                    existed = MlnrKernelNode::filename_to_mnode(pid, filename.clone()).is_ok();
+                    log.reserve(&Record::Create {
+                        mnode: 0,
+                        modes,
+                        pathname: &filename,
+                    })?;
+                }
+
+                let response = replica.execute_mut_scan(
+                    Modify::FileOpen(pid, filename.clone(), flags, modes),
+                    *token,
+                );
 
                 match response {
-                    Ok(MlnrNodeResult::FileOpened(fd)) => Ok((fd, 0)),
+                    Ok(MlnrNodeResult::FileOpened(fd)) => {
+                        if let Some(log) = log.as_mut() {
+                            if !existed {
+                                let (mnode, _) = MlnrKernelNode::fd_to_mnode(pid, fd)?;
+                                log.append(&Record::Create {
+                                    mnode,
+                                    modes,
+                                    pathname: &filename,
+                                })?;
+                            } else if file_flags.is_truncate() {
+                                log.append(&Record::Truncate {
+                                    pathname: &filename,
+                                })?;
+                            }
+                        }
+                        Ok((fd, 0))
+                    }
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -174,14 +256,31 @@ impl MlnrKernelNode {
             |(replica, token)| match op {
                 FileOperation::Write | FileOperation::WriteAt => {
                     let kernslice = KernSlice::new(buffer, len as usize);
+                    let mut log = pmem::lock();
+                    if let Some(log) = log.as_mut() {
+                        log.reserve(&Record::Write {
+                            mnode,
+                            offset: 0,
+                            data: &kernslice.buffer,
+                        })?;
+                    }
 
                     let response = replica.execute_mut(
-                        Modify::FileWrite(pid, fd, mnode, kernslice.buffer, len, offset),
+                        Modify::FileWrite(pid, fd, mnode, kernslice.buffer.clone(), len, offset),
                         *token,
                     );
 
                     match response {
-                        Ok(MlnrNodeResult::FileAccessed(len)) => Ok((len, 0)),
+                        Ok(MlnrNodeResult::FileWritten(len, offset)) => {
+                            if let Some(log) = log.as_mut() {
+                                log.append(&Record::Write {
+                                    mnode,
+                                    offset,
+                                    data: &kernslice.buffer[..len as usize],
+                                })?;
+                            }
+                            Ok((len, 0))
+                        }
                         Err(e) => Err(e),
                         Ok(_) => unreachable!("Got unexpected response"),
                     }
@@ -227,10 +326,24 @@ impl MlnrKernelNode {
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let filename = userptr_to_str(name)?;
-                let response = replica.execute_mut_scan(Modify::FileDelete(pid, filename), *token);
+                let mut log = pmem::lock();
+                let record = Record::Delete {
+                    pathname: &filename,
+                };
+                if let Some(log) = log.as_mut() {
+                    log.reserve(&record)?;
+                }
+
+                let response =
+                    replica.execute_mut_scan(Modify::FileDelete(pid, filename.clone()), *token);
 
                 match response {
-                    Ok(MlnrNodeResult::FileDeleted) => Ok((0, 0)),
+                    Ok(MlnrNodeResult::FileDeleted) => {
+                        if let Some(log) = log.as_mut() {
+                            log.append(&record)?;
+                        }
+                        Ok((0, 0))
+                    }
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -238,7 +351,8 @@ impl MlnrKernelNode {
     }
 
     pub fn file_info(pid: Pid, name: u64, info_ptr: u64) -> Result<(u64, u64), KError> {
-        let (mnode, _) = MlnrKernelNode::filename_to_mnode(pid, name)?;
+        let filename = userptr_to_str(name)?;
+        let (mnode, _) = MlnrKernelNode::filename_to_mnode(pid, filename)?;
 
         let kcb = super::kcb::get_kcb();
         kcb.arch
@@ -271,11 +385,26 @@ impl MlnrKernelNode {
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let oldfilename = userptr_to_str(oldname)?;
                 let newfilename = userptr_to_str(newname)?;
+                let mut log = pmem::lock();
+                let record = Record::Rename {
+                    oldname: &oldfilename,
+                    newname: &newfilename,
+                };
+                if let Some(log) = log.as_mut() {
+                    log.reserve(&record)?;
+                }
 
-                let response = replica
-                    .execute_mut_scan(Modify::FileRename(pid, oldfilename, newfilename), *token);
+                let response = replica.execute_mut_scan(
+                    Modify::FileRename(pid, oldfilename.clone(), newfilename.clone()),
+                    *token,
+                );
                 match response {
-                    Ok(MlnrNodeResult::FileRenamed) => Ok((0, 0)),
+                    Ok(MlnrNodeResult::FileRenamed) => {
+                        if let Some(log) = log.as_mut() {
+                            log.append(&record)?;
+                        }
+                        Ok((0, 0))
+                    }
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -289,11 +418,162 @@ impl MlnrKernelNode {
             .as_ref()
             .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let filename = userptr_to_str(pathname)?;
+                let mut log = pmem::lock();
+                if let Some(log) = log.as_mut() {
+                    log.reserve(&Record::MkDir {
+                        mnode: 0,
+                        modes,
+                        pathname: &filename,
+                    })?;
+                }
+
+                let response =
+                    replica.execute_mut_scan(Modify::MkDir(pid, filename.clone(), modes), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::DirCreated) => {
+                        if let Some(log) = log.as_mut() {
+                            let (mnode, _) =
+                                MlnrKernelNode::filename_to_mnode(pid, filename.clone())?;
+                            log.append(&Record::MkDir {
+                                mnode,
+                                modes,
+                                pathname: &filename,
+                            })?;
+                        }
+                        Ok((0, 0))
+                    }
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Map the file opened as `fd` at `base` of the process.
+    ///
+    /// Returns the physical addresses of the file pages in the local
+    /// replica. They are updated in place by every `FileWrite` the replica
+    /// applies and stay pinned until `file_unmap` is called for `base`.
+    ///
+    /// Stores to a `writable` mapping only change the pages of the local
+    /// replica, `write_back` brings them to the other replicas (and the
+    /// persistent log).
+    pub fn file_map(
+        pid: Pid,
+        fd: FD,
+        base: VAddr,
+        len: Len,
+        offset: Offset,
+        writable: bool,
+    ) -> Result<Vec<PAddr>, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut_scan(
+                    Modify::FileMap(pid, fd, base.as_u64(), len, offset, writable),
+                    *token,
+                );
+
+                match response {
+                    Ok(MlnrNodeResult::FileMapped(pages)) => Ok(pages),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Writes the contents of the writable mappings of process `pid` (the
+    /// one at `base` or all of them) back to their files.
+    ///
+    /// This makes the stores done through the mappings visible to the other
+    /// replicas.
+    pub fn write_back(pid: Pid, base: Option<VAddr>) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let base = base.map(|base| base.as_u64());
+                let mapped = match replica.execute(Access::MappedData(pid, base), *token) {
+                    Ok(MlnrNodeResult::MappedData(mapped)) => mapped,
+                    Err(e) => return Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                };
+
+                for (mnode, offset, data) in mapped {
+                    let mut log = pmem::lock();
+                    if let Some(log) = log.as_mut() {
+                        log.reserve(&Record::Write {
+                            mnode,
+                            offset: 0,
+                            data: &data,
+                        })?;
+                    }
+
+                    let response = replica.execute_mut(
+                        Modify::FileWriteBack(pid, mnode, data.clone(), offset),
+                        *token,
+                    );
+                    match response {
+                        Ok(MlnrNodeResult::FileWritten(len, offset)) => {
+                            if let Some(log) = log.as_mut() {
+                                log.append(&Record::Write {
+                                    mnode,
+                                    offset,
+                                    data: &data[..len as usize],
+                                })?;
+                            }
+                        }
+                        Err(e) => return Err(e),
+                        Ok(_) => unreachable!("Got unexpected response"),
+                    }
+                }
+                Ok(())
+            })
+    }
+
+    /// Undo a `file_map` at `base`, this is a no-op if no file is mapped
+    /// at `base`.
+    pub fn file_unmap(pid: Pid, base: VAddr) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
                 let response =
-                    replica.execute_mut_scan(Modify::MkDir(pid, filename, modes), *token);
+                    replica.execute_mut_scan(Modify::FileUnmap(pid, base.as_u64()), *token);
 
                 match response {
-                    Ok(MlnrNodeResult::DirCreated) => Ok((0, 0)),
+                    Ok(MlnrNodeResult::FileUnmapped) => Ok((0, 0)),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Read the entries of the directory opened as `fd` into `buffer`,
+    /// starting at entry `cookie`.
+    ///
+    /// Returns the number of entries read and the cookie to continue from.
+    pub fn read_dir(
+        pid: Pid,
+        fd: FD,
+        buffer: Buffer,
+        len: Len,
+        cookie: usize,
+    ) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.arch
+            .cnr_replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response =
+                    replica.execute(Access::FileReadDir(pid, fd, buffer, len, cookie), *token);
+
+                match response {
+                    Ok(MlnrNodeResult::DirRead(entries)) => Ok((entries, cookie as u64 + entries)),
                     Err(e) => Err(e),
                     Ok(_) => unreachable!("Got unexpected response"),
                 }
@@ -318,7 +598,7 @@ impl MlnrKernelNode {
     }
 
     #[inline(always)]
-    pub fn filename_to_mnode(pid: Pid, filename: Filename) -> Result<(u64, u64), KError> {
+    pub fn filename_to_mnode(pid: Pid, filename: String) -> Result<(u64, u64), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.arch
             .cnr_replica
@@ -348,6 +628,36 @@ impl MlnrKernelNode {
                 }
             })
     }
+
+    /// Makes the file-system persistent with the log in the PMem region at
+    /// `base`: the files in the log are recovered (on all replicas) and every
+    /// later modification is appended to it.
+    ///
+    /// Returns the number of records that were redone.
+    ///
+    /// # Safety
+    /// `base` has to point to `size` bytes of persistent memory that is used
+    /// by nothing but the file-system.
+    pub unsafe fn mount_pmem(base: VAddr, size: usize) -> Result<usize, KError> {
+        let log = PmemLog::mount(base.as_mut_ptr(), size)?;
+        let (records, epoch) = log.snapshot();
+
+        let kcb = super::kcb::get_kcb();
+        let recovered = kcb.arch.cnr_replica.as_ref().map_or(
+            Err(KError::ReplicaNotSet),
+            |(replica, token)| {
+                let response = replica.execute_mut_scan(Modify::Recover(records, epoch), *token);
+                match response {
+                    Ok(MlnrNodeResult::Recovered(count)) => Ok(count),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            },
+        )?;
+
+        pmem::install(log);
+        Ok(recovered)
+    }
 }
 
 impl Dispatch for MlnrKernelNode {
@@ -408,6 +718,25 @@ impl Dispatch for MlnrKernelNode {
                 Ok(MlnrNodeResult::FileInfo(f_info))
             }
 
+            Access::MappedData(pid, base) => {
+                let process_map_locked = self.process_map.read();
+                let p = process_map_locked
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+
+                let mut mapped = Vec::new();
+                for mapping in p
+                    .mappings()
+                    .filter(|m| m.writable && base.map_or(true, |base| base == m.base))
+                {
+                    let data = self
+                        .fs
+                        .read_contents(mapping.mnode, mapping.offset, mapping.len)?;
+                    mapped.try_push((mapping.mnode, mapping.offset as Offset, data))?;
+                }
+                Ok(MlnrNodeResult::MappedData(mapped))
+            }
+
             Access::FdToMnode(pid, fd) => {
                 let process_map_locked = self.process_map.read();
                 let p = process_map_locked
@@ -419,15 +748,13 @@ impl Dispatch for MlnrKernelNode {
                 Ok(MlnrNodeResult::MappedFileToMnode(mnode_num))
             }
 
-            Access::FileNameToMnode(pid, name) => {
+            Access::FileNameToMnode(pid, filename) => {
                 let _p = self
                     .process_map
                     .read()
                     .get(&pid)
                     .ok_or(KError::NoProcessFoundForPid)?;
 
-                let filename = userptr_to_str(name)?;
-
                 match self.fs.lookup(&filename) {
                     // match on (file_exists, mnode_number)
                     Some(mnode) => Ok(MlnrNodeResult::MappedFileToMnode(*mnode)),
@@ -435,6 +762,22 @@ impl Dispatch for MlnrKernelNode {
                 }
             }
 
+            Access::FileReadDir(pid, fd, buffer, len, cookie) => {
+                let mut userslice = UserSlice::new(buffer, len as usize);
+                let process_lookup = self.process_map.read();
+                let p = process_lookup
+                    .get(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+
+                let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
+                if !fd.get_flags().is_read() {
+                    return Err(KError::PermissionError);
+                }
+
+                let entries = self.fs.readdir(fd.get_mnode(), &mut userslice, cookie)?;
+                Ok(MlnrNodeResult::DirRead(entries as u64))
+            }
+
             Access::Synchronize(_log_id) => {
                 // A NOP that just makes sure we've advanced the replica
                 Ok(MlnrNodeResult::Synchronized)
@@ -454,7 +797,11 @@ impl Dispatch for MlnrKernelNode {
 
             Modify::ProcessRemove(pid) => {
                 let mut pmap = self.process_map.write();
-                let _file_desc = pmap.remove(&pid).ok_or(KError::NoFileDescForPid)?;
+                let file_desc = pmap.remove(&pid).ok_or(KError::NoFileDescForPid)?;
+                for mnode in file_desc.mapped_mnodes() {
+                    // The file can't be removed while mapped, so unmap won't fail.
+                    let _r = self.fs.unmap(mnode);
+                }
                 Ok(MlnrNodeResult::ProcessRemoved(pid))
             }
 
@@ -530,7 +877,7 @@ impl Dispatch for MlnrKernelNode {
                             // Update offset when FileWrite doesn't give an explicit offset value.
                             fd.update_offset(curr_offset + len);
                         }
-                        Ok(MlnrNodeResult::FileAccessed(len as u64))
+                        Ok(MlnrNodeResult::FileWritten(len as u64, curr_offset as u64))
                     }
                     Err(e) => Err(e),
                 }
@@ -574,6 +921,62 @@ impl Dispatch for MlnrKernelNode {
                 let _is_created = self.fs.mkdir(&filename, modes)?;
                 Ok(MlnrNodeResult::DirCreated)
             }
+
+            Modify::FileMap(pid, fd, base, len, offset, writable) => {
+                let mut process_lookup = self.process_map.write();
+                let p = process_lookup
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+
+                let fd = p.get_fd(fd as usize).ok_or(KError::PermissionError)?;
+                if !fd.get_flags().is_read() || (writable && !fd.get_flags().is_write()) {
+                    return Err(KError::PermissionError);
+                }
+                let mnode_num = fd.get_mnode();
+
+                let pages = self.fs.map(mnode_num, offset as usize, len as usize)?;
+                let mapping = FileMapping {
+                    base,
+                    mnode: mnode_num,
+                    offset: offset as usize,
+                    len: len as usize,
+                    writable,
+                };
+                if let Err(e) = p.add_mapping(mapping) {
+                    let _r = self.fs.unmap(mnode_num);
+                    return Err(e);
+                }
+                Ok(MlnrNodeResult::FileMapped(pages))
+            }
+
+            Modify::FileUnmap(pid, base) => {
+                let mut process_lookup = self.process_map.write();
+                let p = process_lookup
+                    .get_mut(&pid)
+                    .ok_or(KError::NoProcessFoundForPid)?;
+
+                if let Some(mnode_num) = p.remove_mapping(base) {
+                    self.fs.unmap(mnode_num)?;
+                }
+                Ok(MlnrNodeResult::FileUnmapped)
+            }
+
+            Modify::FileWriteBack(_pid, mnode, data, offset) => {
+                let len = self.fs.write(mnode, &data, offset as usize)?;
+                Ok(MlnrNodeResult::FileWritten(len as u64, offset as u64))
+            }
+
+            Modify::Recover(records, epoch) => {
+                let mut count = 0;
+                for record in Records::new(&records, epoch) {
+                    // The log only has modifications that succeeded before
+                    if let Err(e) = self.fs.redo(&record) {
+                        warn!("Failed to redo {:?}: {}", record, e);
+                    }
+                    count += 1;
+                }
+                Ok(MlnrNodeResult::Recovered(count))
+            }
         }
     }
 }
@@ -15,8 +15,11 @@ pub enum KError {
     BadAddress,
     GlobalMemoryNotSet,
     CoreAlreadyAllocated,
+    CoreNotAllocated,
+    NoFreeCore,
     OutOfMemory,
     ReplicaNotSet,
+    ReplicaRegistrationFailed,
     ProcessNotSet,
     NotSupported,
     OutOfPids,
@@ -27,6 +30,7 @@ pub enum KError {
     InvalidVSpaceOperation { a: u64 },
     InvalidProcessOperation { a: u64 },
     InvalidSystemOperation { a: u64 },
+    InvalidIpcOperation { a: u64 },
 
     // Physical memory errors
     InvalidLayout,
@@ -55,6 +59,27 @@ pub enum KError {
     TooManyRegisteredFrames,
     InvalidFileDescriptor,
     BinaryNotFound { binary: &'static str },
+    ProcessNotExited,
+    ProcessNotChild,
+    ProcessNotRelated,
+    InvalidEvent { event: u64 },
+    EventNotPermitted,
+
+    // IPC errors
+    ChannelNotFound,
+    ChannelExists,
+    ChannelEmpty,
+    ChannelFull,
+    ChannelAccessDenied,
+    TooManyChannels,
+    MessageTooLarge,
+
+    // Interrupt errors
+    OutOfVectors,
+    VectorNotAllocated,
+    NoMsiSupport,
+    InvalidMsiEntry { entry: u64 },
+    DeviceNotOwned,
 
     // Address space errors
     InvalidFrame,
@@ -72,6 +97,8 @@ pub enum KError {
     AlreadyPresent,
     DirectoryError,
     OpenFileLimit,
+    FileMapped,
+    PersistentLogFull,
     FileDescForPidAlreadyAdded,
     NoFileDescForPid,
 
@@ -139,7 +166,29 @@ impl From<KError> for SystemCallError {
             KError::InvalidSyscallArgument1 { .. } => SystemCallError::NotSupported,
             KError::InvalidVSpaceOperation { .. } => SystemCallError::NotSupported,
             KError::InvalidProcessOperation { .. } => SystemCallError::NotSupported,
+            KError::InvalidIpcOperation { .. } => SystemCallError::NotSupported,
             KError::BadAddress { .. } => SystemCallError::BadAddress,
+            KError::ProcessNotExited => SystemCallError::WouldBlock,
+            KError::ProcessNotChild => SystemCallError::PermissionError,
+            KError::ProcessNotRelated => SystemCallError::PermissionError,
+            KError::InvalidEvent { .. } => SystemCallError::NotSupported,
+            KError::EventNotPermitted => SystemCallError::PermissionError,
+            KError::ChannelNotFound => SystemCallError::BadFileDescriptor,
+            KError::ChannelExists => SystemCallError::PermissionError,
+            KError::ChannelAccessDenied => SystemCallError::PermissionError,
+            KError::TooManyChannels => SystemCallError::OutOfMemory,
+            KError::ChannelEmpty | KError::ChannelFull => SystemCallError::WouldBlock,
+            KError::MessageTooLarge => SystemCallError::NotSupported,
+            KError::OutOfVectors => SystemCallError::OutOfMemory,
+            KError::VectorNotAllocated => SystemCallError::PermissionError,
+            KError::NoMsiSupport | KError::InvalidMsiEntry { .. } => SystemCallError::NotSupported,
+            KError::DeviceNotOwned => SystemCallError::PermissionError,
+            KError::CoreNotAllocated => SystemCallError::PermissionError,
+            KError::FileMapped => SystemCallError::PermissionError,
+            KError::PersistentLogFull => SystemCallError::OutOfMemory,
+            KError::OutOfMemory => SystemCallError::OutOfMemory,
+            KError::InvalidFlags => SystemCallError::BadFlags,
+            KError::InvalidAffinityId => SystemCallError::NotSupported,
             _ => SystemCallError::InternalError,
         }
     }
@@ -156,6 +205,9 @@ impl fmt::Display for KError {
         match self {
             KError::ProcessNotSet => write!(f, "The core has no current process set."),
             KError::ReplicaNotSet => write!(f, "Replica is not set-up in the KCB."),
+            KError::ReplicaRegistrationFailed => {
+                write!(f, "Unable to register the core with a replica.")
+            }
             KError::NoExecutorForCore => {
                 write!(
                     f,
@@ -174,6 +226,12 @@ impl fmt::Display for KError {
                     "The requested core is already allocated by another process."
                 )
             }
+            KError::CoreNotAllocated => {
+                write!(f, "The core is not allocated to the process.")
+            }
+            KError::NoFreeCore => {
+                write!(f, "No unallocated core matches the requested affinity.")
+            }
             KError::InvalidSyscallArgument1 { a } => {
                 write!(f, "Invalid 1st syscall argument supplied: {}", a)
             }
@@ -198,6 +256,13 @@ impl fmt::Display for KError {
                     a
                 )
             }
+            KError::InvalidIpcOperation { a } => {
+                write!(
+                    f,
+                    "Invalid IPC Operation (2nd syscall argument) supplied: {}",
+                    a
+                )
+            }
             KError::InvalidAffinityId => {
                 write!(f, "Specified an invalid NUMA node ID for affinity.")
             }
@@ -225,6 +290,25 @@ impl fmt::Display for KError {
             KError::TooManyProcesses => write!(f, "Not enough space in process table (out of PIDs)."),
             KError::TooManyRegisteredFrames => write!(f, "Can't register more frames with the process (out of FIDs)."),
             KError::BinaryNotFound { binary } => write!(f, "Can't spawn binary {}: Not found", binary),
+            KError::ProcessNotExited => write!(f, "The process is still running."),
+            KError::ProcessNotChild => write!(f, "The process is not a child of the caller."),
+            KError::ProcessNotRelated => write!(f, "The process is not the caller, its parent or a child."),
+            KError::InvalidEvent { event } => write!(f, "Event {} can't be sent to a process.", event),
+            KError::EventNotPermitted => write!(f, "Events can only be sent to the parent or a child."),
+
+            KError::ChannelNotFound => write!(f, "No channel with the given name or id (or not attached to it)."),
+            KError::ChannelExists => write!(f, "A channel with the given name exists already."),
+            KError::ChannelEmpty => write!(f, "No message in the channel."),
+            KError::ChannelFull => write!(f, "The channel can't queue more messages."),
+            KError::ChannelAccessDenied => write!(f, "Process is not allowed to attach to the channel."),
+            KError::TooManyChannels => write!(f, "Process is attached to too many channels."),
+            KError::MessageTooLarge => write!(f, "The message doesn't fit in the channel or the receive buffer."),
+
+            KError::OutOfVectors => write!(f, "No free interrupt vector left on the core."),
+            KError::VectorNotAllocated => write!(f, "The interrupt vector isn't allocated to the process."),
+            KError::NoMsiSupport => write!(f, "The device supports neither MSI nor MSI-X."),
+            KError::InvalidMsiEntry { entry } => write!(f, "The device has no MSI / MSI-X entry {}.", entry),
+            KError::DeviceNotOwned => write!(f, "The device belongs to another process."),
 
             KError::InvalidFrame => write!(f, "Supplied frame was invalid"),
             KError::AlreadyMapped{base} => write!(f, "Address space operation covers existing mapping {:?}", base),
@@ -248,6 +332,8 @@ impl fmt::Display for KError {
             KError::AlreadyPresent => write!(f, "Fd/File already exists"),
             KError::DirectoryError => write!(f, "Can't read or write to a directory"),
             KError::OpenFileLimit => write!(f, "Maximum files are opened for a process"),
+            KError::FileMapped => write!(f, "File is mapped into an address space"),
+            KError::PersistentLogFull => write!(f, "No space left in the persistent file-system log"),
 
             KError::DebuggerAlreadyAttached => write!(f, "Debugger is already attached"),
             KError::DebuggerStmFailure => write!(f, "Failure while running the GDB state machine"),
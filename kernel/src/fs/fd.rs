@@ -1,11 +1,30 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use super::{Fd, MAX_FILES_PER_PROCESS};
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+
+use super::{Fd, Mnode, MAX_FILES_PER_PROCESS};
 use crate::error::KError;
 
+/// (Part of) a file that is mapped into a process.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FileMapping {
+    /// Where the file is mapped in the address space.
+    pub base: u64,
+    pub mnode: Mnode,
+    /// Offset of the first mapped byte in the file.
+    pub offset: usize,
+    pub len: usize,
+    /// Stores to the mapping are written back to the file.
+    pub writable: bool,
+}
+
 pub struct FileDesc {
     fds: arrayvec::ArrayVec<Option<Fd>, MAX_FILES_PER_PROCESS>,
+    /// Files that are mapped into the process.
+    mappings: Vec<FileMapping>,
 }
 
 impl Default for FileDesc {
@@ -13,6 +32,7 @@ impl Default for FileDesc {
         const NONE_FD: Option<Fd> = None;
         FileDesc {
             fds: arrayvec::ArrayVec::from([NONE_FD; MAX_FILES_PER_PROCESS]),
+            mappings: Vec::new(),
         }
     }
 }
@@ -43,4 +63,26 @@ impl FileDesc {
     pub fn get_fd(&self, index: usize) -> Option<&Fd> {
         self.fds[index].as_ref()
     }
+
+    /// Remember that a file is mapped into the process.
+    pub fn add_mapping(&mut self, mapping: FileMapping) -> Result<(), KError> {
+        self.mappings.try_push(mapping)?;
+        Ok(())
+    }
+
+    /// Forget the mapping at `base`, returns the mnode that was mapped.
+    pub fn remove_mapping(&mut self, base: u64) -> Option<Mnode> {
+        let idx = self.mappings.iter().position(|m| m.base == base)?;
+        Some(self.mappings.swap_remove(idx).mnode)
+    }
+
+    /// All files mapped by the process.
+    pub fn mappings(&self) -> impl Iterator<Item = &FileMapping> + '_ {
+        self.mappings.iter()
+    }
+
+    /// The mnodes of all files mapped by the process.
+    pub fn mapped_mnodes(&self) -> impl Iterator<Item = Mnode> + '_ {
+        self.mappings.iter().map(|m| m.mnode)
+    }
 }
@@ -1,30 +1,80 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use alloc::collections::TryReserveError;
+use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
 
 use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use kpi::io::*;
 
 use crate::error::KError;
-use crate::memory::BASE_PAGE_SIZE;
+use crate::memory::{kernel_vaddr_to_paddr, PAddr, VAddr, BASE_PAGE_SIZE};
 
 use super::Modes;
 
+/// A page of file contents.
+///
+/// Pages are page aligned, so they can be mapped into a process on their own.
+#[derive(Debug, Eq, PartialEq)]
+#[repr(C, align(4096))]
+struct Page([u8; BASE_PAGE_SIZE]);
+
 #[derive(Debug, Eq, PartialEq)]
 /// The buffer is used by the file. Each buffer is BASE_PAGE_SIZE
 /// long and a file consists of many such buffers.
 struct Buffer {
-    data: Vec<u8>,
+    page: Box<Page>,
+    /// How many bytes of the page are used by the file.
+    len: usize,
 }
 
 impl Buffer {
-    /// This function tries to allocate a vector of BASE_PAGE_SIZE long
-    /// and returns a buffer in case of the success; error otherwise.
-    pub fn try_alloc_buffer() -> Result<Buffer, TryReserveError> {
-        Vec::try_with_capacity(BASE_PAGE_SIZE).map(|data| Buffer { data })
+    /// This function tries to allocate a (zeroed) page and returns a buffer
+    /// in case of the success; error otherwise.
+    ///
+    /// The whole page is zeroed, buffers can be mapped into a process so the
+    /// bytes past the end of the file must not leak kernel memory.
+    pub fn try_alloc_buffer() -> Result<Buffer, KError> {
+        let page = Box::try_new(Page([0; BASE_PAGE_SIZE]))?;
+        Ok(Buffer { page, len: 0 })
+    }
+
+    /// Number of bytes the buffer can hold.
+    fn capacity(&self) -> usize {
+        BASE_PAGE_SIZE
+    }
+
+    /// Changes the number of used bytes to `new_len`, the bytes that become
+    /// part of the buffer are zeroed.
+    fn resize(&mut self, new_len: usize) {
+        assert!(new_len <= self.capacity(), "Buffer is a single page");
+        if new_len > self.len {
+            self.page.0[self.len..new_len].fill(0);
+        }
+        self.len = new_len;
+    }
+
+    /// The physical address of the page backing the buffer.
+    fn paddr(&self) -> PAddr {
+        let paddr = kernel_vaddr_to_paddr(VAddr::from(self.page.0.as_ptr() as u64));
+        debug_assert!(paddr.is_base_page_aligned(), "Pages are page aligned");
+        paddr
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.page.0[..self.len]
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.page.0[..self.len]
     }
 }
 
@@ -51,15 +101,15 @@ impl File {
         let buffer_num = self.mcache.len();
         match buffer_num {
             0 => 0,
-            1 => self.mcache[buffer_num - 1].data.len(),
+            1 => self.mcache[buffer_num - 1].len(),
             _ => {
-                match self.mcache[buffer_num - 1].data.len() {
+                match self.mcache[buffer_num - 1].len() {
                     // If resize_file()/write() added some empty buffers to be filled
                     // later, then scan all the buffers to get the file-size.
                     0 => {
                         let mut len = 0;
                         for buf in &self.mcache {
-                            match buf.data.len() {
+                            match buf.len() {
                                 0 => break,
                                 curr_buff_len => len += curr_buff_len,
                             }
@@ -90,28 +140,20 @@ impl File {
         }
 
         let free_in_last_buffer = match self.mcache.last() {
-            Some(buffer) => BASE_PAGE_SIZE - buffer.data.len(),
+            Some(buffer) => BASE_PAGE_SIZE - buffer.len(),
             None => 0,
         };
 
         let add_new = new_len - curr_file_len;
         if add_new <= free_in_last_buffer {
             // Don't need to add new buffer
-            let offset = self.mcache.last().unwrap().data.len();
-            self.mcache
-                .last_mut()
-                .unwrap()
-                .data
-                .try_resize(offset + add_new, 0)
-                .map_err(|e| e.into())
+            let offset = self.mcache.last().unwrap().len();
+            self.mcache.last_mut().unwrap().resize(offset + add_new);
+            Ok(())
         } else {
             // Add new buffer
             if !self.mcache.is_empty() {
-                self.mcache
-                    .last_mut()
-                    .unwrap()
-                    .data
-                    .try_resize(BASE_PAGE_SIZE, 0)?;
+                self.mcache.last_mut().unwrap().resize(BASE_PAGE_SIZE);
             }
 
             let remaining = add_new - free_in_last_buffer;
@@ -119,10 +161,10 @@ impl File {
             let mut vec = Vec::try_with_capacity(new_buffers)?;
 
             for _i in 0..new_buffers {
-                let mut buffer = Buffer::try_alloc_buffer()?;
                 // TODO(error-handling): On failure, might want to
                 // shrink previous buffers again?
-                buffer.data.try_resize(BASE_PAGE_SIZE, 0)?;
+                let mut buffer = Buffer::try_alloc_buffer()?;
+                buffer.resize(BASE_PAGE_SIZE);
 
                 debug_assert!(vec.len() < vec.capacity(), "ensured by try_with_capacity");
                 vec.push(buffer);
@@ -133,11 +175,7 @@ impl File {
                 let sure_bytes_to_write = (new_buffers - 1) * BASE_PAGE_SIZE;
                 let bytes_in_last_buffer = new_len - (self.get_size() + sure_bytes_to_write);
 
-                // TODO(error-handling): shrink others again on error?
-                vec.last_mut()
-                    .unwrap()
-                    .data
-                    .try_resize(bytes_in_last_buffer, 0)?;
+                vec.last_mut().unwrap().resize(bytes_in_last_buffer);
             }
 
             self.mcache.try_append(&mut vec).map_err(|e| e.into())
@@ -161,7 +199,7 @@ impl File {
 
         let len = end_offset - start_offset;
         while copied < len {
-            let useful_data_curr_buffer = self.mcache[buffer_num].data.len() - offset_in_buffer;
+            let useful_data_curr_buffer = self.mcache[buffer_num].len() - offset_in_buffer;
             let remaining = len - copied;
 
             let src_start = offset_in_buffer;
@@ -176,7 +214,7 @@ impl File {
                 copied += remaining;
             }
             user_slice[dst_start..dst_end]
-                .copy_from_slice(&self.mcache[buffer_num].data[src_start..src_end]);
+                .copy_from_slice(&self.mcache[buffer_num][src_start..src_end]);
             buffer_num += 1;
             dst_start = dst_end;
             offset_in_buffer = 0;
@@ -228,7 +266,7 @@ impl File {
                 copied += remaining;
             }
 
-            self.mcache[buffer_num].data[src_start..src_end]
+            self.mcache[buffer_num][src_start..src_end]
                 .copy_from_slice(&user_slice[dst_start..dst_end]);
             buffer_num += 1;
             dst_start = dst_end;
@@ -238,6 +276,19 @@ impl File {
         Ok(len)
     }
 
+    /// Returns the physical address of the page that holds the file
+    /// contents at `offset` (which has to be page aligned).
+    ///
+    /// Fails with `InvalidOffset` if the file doesn't extend to `offset`.
+    pub fn page_paddr(&self, offset: usize) -> Result<PAddr, KError> {
+        debug_assert_eq!(offset % BASE_PAGE_SIZE, 0, "offset is not page aligned");
+        let buffer_num = offset_to_buffernum(offset, BASE_PAGE_SIZE);
+        match self.mcache.get(buffer_num) {
+            Some(buffer) if offset < self.get_size() => Ok(buffer.paddr()),
+            _ => Err(KError::InvalidOffset),
+        }
+    }
+
     /// Truncate the file in reasponse of O_TRUNC flag.
     pub fn file_truncate(&mut self) {
         self.mcache.clear();
@@ -294,8 +345,9 @@ pub mod test {
     /// This method test the size of the allocated buffer.
     fn test_buffer_alloc() {
         let buffer = Buffer::try_alloc_buffer().unwrap();
-        assert_eq!(buffer.data.len(), 0);
-        assert_eq!(buffer.data.capacity(), BASE_PAGE_SIZE);
+        assert_eq!(buffer.len(), 0);
+        assert_eq!(buffer.capacity(), BASE_PAGE_SIZE);
+        assert_eq!(buffer.page.0.as_ptr() as usize % BASE_PAGE_SIZE, 0);
     }
 
     #[test]
@@ -342,7 +394,7 @@ pub mod test {
 
         // verify the content for first buffer
         for i in 0..4096 {
-            assert_eq!(file.mcache[0].data[i], 0xb);
+            assert_eq!(file.mcache[0][i], 0xb);
         }
     }
 
@@ -402,11 +454,11 @@ pub mod test {
 
         // verify the content for first buffer
         for i in 0..4095 {
-            assert_eq!(file.mcache[0].data[i], 0xa);
+            assert_eq!(file.mcache[0][i], 0xa);
         }
         // verify the content for second buffer
         for i in 0..4096 {
-            assert_eq!(file.mcache[1].data[i], 0xb);
+            assert_eq!(file.mcache[1][i], 0xb);
         }
     }
 }
@@ -2,13 +2,19 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::convert::TryFrom;
 
-use kpi::io::FileType;
+use fallible_collections::btree::BTreeMap;
+use fallible_collections::{FallibleVec, FallibleVecGlobal};
+use kpi::io::{FileModes, FileType};
 
 use crate::arch::process::UserSlice;
 use crate::error::KError;
 use crate::fallible_string::TryString;
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
+use crate::round_up;
 
 use super::file::*;
 use super::{Mnode, Modes};
@@ -20,6 +26,10 @@ pub struct MemNode {
     name: String,
     node_type: FileType,
     file: Option<File>,
+    /// Entries of a directory, keyed by name; `None` for files.
+    children: Option<BTreeMap<String, Arc<Mnode>>>,
+    /// Number of (shared) mappings of the file into process address spaces.
+    mappings: usize,
 }
 
 /// Required for the testing
@@ -29,6 +39,7 @@ impl PartialEq for MemNode {
             && (self.name == other.name)
             && (self.node_type == other.node_type)
             && (self.file == other.file)
+            && (self.children == other.children)
     }
 }
 
@@ -40,6 +51,8 @@ impl Default for MemNode {
             name: String::new(),
             node_type: FileType::File,
             file: None,
+            children: None,
+            mappings: 0,
         }
     }
 }
@@ -52,10 +65,10 @@ impl MemNode {
         modes: Modes,
         node_type: FileType,
     ) -> Result<MemNode, KError> {
-        let file = match node_type {
-            FileType::Directory => None,
+        let (file, children) = match node_type {
+            FileType::Directory => (None, Some(BTreeMap::new())),
             FileType::File => match File::new(modes) {
-                Ok(file) => Some(file),
+                Ok(file) => (Some(file), None),
                 Err(e) => return Err(e),
             },
         };
@@ -65,9 +78,69 @@ impl MemNode {
             name: TryString::try_from(pathname)?.into(),
             node_type,
             file,
+            children,
+            mappings: 0,
         })
     }
 
+    /// Get the name of the mnode (the last component of its path).
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Change the name of the mnode, in response to a rename.
+    pub fn set_name(&mut self, name: &str) -> Result<(), KError> {
+        self.name = TryString::try_from(name)?.into();
+        Ok(())
+    }
+
+    /// Find the mnode of the directory entry `name`.
+    ///
+    /// Returns `DirectoryError` in case this mnode is not a directory.
+    pub fn lookup_child(&self, name: &str) -> Result<Option<&Arc<Mnode>>, KError> {
+        match &self.children {
+            Some(children) => Ok(children.get(name)),
+            None => Err(KError::DirectoryError),
+        }
+    }
+
+    /// Add an entry `name` to a directory.
+    pub fn insert_child(&mut self, name: &str, mnode: Arc<Mnode>) -> Result<(), KError> {
+        let name: String = TryString::try_from(name)?.into();
+        match &mut self.children {
+            Some(children) => match children.try_insert(name, mnode)? {
+                None => Ok(()),
+                Some(_old) => Err(KError::AlreadyPresent),
+            },
+            None => Err(KError::DirectoryError),
+        }
+    }
+
+    /// Remove the entry `name` from a directory.
+    pub fn remove_child(&mut self, name: &str) -> Result<Arc<Mnode>, KError> {
+        match &mut self.children {
+            Some(children) => children.remove(name).ok_or(KError::InvalidFile),
+            None => Err(KError::DirectoryError),
+        }
+    }
+
+    /// Iterate over the entries of a directory, ordered by name.
+    ///
+    /// Returns `DirectoryError` in case this mnode is not a directory.
+    pub fn children(&self) -> Result<impl Iterator<Item = (&String, &Arc<Mnode>)>, KError> {
+        match &self.children {
+            Some(children) => Ok(children.iter()),
+            None => Err(KError::DirectoryError),
+        }
+    }
+
+    /// Returns true if the mnode is a directory without any entries.
+    pub fn is_empty_dir(&self) -> bool {
+        self.children
+            .as_ref()
+            .map_or(false, |children| children.is_empty())
+    }
+
     /// Write to an in-memory file.
     pub fn write(&mut self, buffer: &[u8], offset: usize) -> Result<usize, KError> {
         // Return if the user doesn't have write permissions for the file.
@@ -118,17 +191,75 @@ impl MemNode {
         self.file.as_ref().unwrap().get_size()
     }
 
+    /// Get the modes of the file (directories are always `S_IRWXU`).
+    pub fn get_modes(&self) -> Modes {
+        match self.file.as_ref() {
+            Some(file) => file.get_mode().into(),
+            None => FileModes::S_IRWXU.into(),
+        }
+    }
+
+    /// Copies the file contents at `offset` into `buffer`, regardless of the
+    /// file modes. Returns the number of bytes copied.
+    pub fn read_raw(&self, buffer: &mut [u8], offset: usize) -> Result<usize, KError> {
+        let file = self.file.as_ref().ok_or(KError::DirectoryError)?;
+        let end = core::cmp::min(file.get_size(), offset + buffer.len());
+        if offset >= end {
+            return Ok(0);
+        }
+        file.read_file(&mut buffer[..end - offset], offset, end)
+    }
+
     /// Get the type of mnode; Directory or file.
     pub fn get_mnode_type(&self) -> FileType {
         self.node_type
     }
 
+    /// Pins the pages that hold the file contents in `[offset, offset + len)`
+    /// and returns their physical addresses.
+    ///
+    /// The pages stay in place (the file can't be truncated or removed) until
+    /// `unmap` is called.
+    pub fn map(&mut self, offset: usize, len: usize) -> Result<Vec<PAddr>, KError> {
+        if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_readable()
+        {
+            return Err(KError::PermissionError);
+        }
+        if offset % BASE_PAGE_SIZE != 0 || len == 0 {
+            return Err(KError::InvalidOffset);
+        }
+
+        let file = self.file.as_ref().unwrap();
+        let mut pages = Vec::try_with_capacity(round_up!(len, BASE_PAGE_SIZE) / BASE_PAGE_SIZE)?;
+        for page_offset in (offset..offset + len).step_by(BASE_PAGE_SIZE) {
+            pages.try_push(file.page_paddr(page_offset)?)?;
+        }
+
+        self.mappings += 1;
+        Ok(pages)
+    }
+
+    /// Releases the pages pinned by an earlier `map`.
+    pub fn unmap(&mut self) {
+        debug_assert!(self.mappings > 0, "unmap without a mapping");
+        self.mappings = self.mappings.saturating_sub(1);
+    }
+
+    /// Returns true if the file is mapped by any process.
+    pub fn is_mapped(&self) -> bool {
+        self.mappings > 0
+    }
+
     /// Truncate the file in reasponse of O_TRUNC flag.
     pub fn file_truncate(&mut self) -> Result<(), KError> {
         if self.node_type != FileType::File || !self.file.as_ref().unwrap().get_mode().is_writable()
         {
             return Err(KError::PermissionError);
         }
+        // The pages of a mapped file can't go away.
+        if self.is_mapped() {
+            return Err(KError::FileMapped);
+        }
 
         // The method doesn't fail after this point, so returning Ok().
         self.file.as_mut().unwrap().file_truncate();
@@ -152,6 +283,33 @@ pub mod test {
         assert_eq!(memnode.mnode_num, 1);
         assert_eq!(memnode.name, filename.to_string());
         assert_eq!(memnode.node_type, FileType::Directory);
+        assert!(memnode.is_empty_dir());
+    }
+
+    #[test]
+    /// Add and remove entries of a directory mnode.
+    fn test_mnode_directory_entries() {
+        let mut dir =
+            MemNode::new(1, "dir", FileModes::S_IRWXU.into(), FileType::Directory).unwrap();
+        assert_eq!(dir.lookup_child("a"), Ok(None));
+        assert_eq!(dir.insert_child("a", Arc::new(2)), Ok(()));
+        assert_eq!(
+            dir.insert_child("a", Arc::new(3)),
+            Err(KError::AlreadyPresent)
+        );
+        assert_eq!(dir.lookup_child("a"), Ok(Some(&Arc::new(2))));
+        assert!(!dir.is_empty_dir());
+        assert_eq!(dir.remove_child("a"), Ok(Arc::new(2)));
+        assert_eq!(dir.remove_child("a"), Err(KError::InvalidFile));
+        assert!(dir.is_empty_dir());
+
+        let mut file = MemNode::new(2, "a", FileModes::S_IRWXU.into(), FileType::File).unwrap();
+        assert_eq!(file.lookup_child("a"), Err(KError::DirectoryError));
+        assert_eq!(
+            file.insert_child("a", Arc::new(3)),
+            Err(KError::DirectoryError)
+        );
+        assert!(!file.is_empty_dir());
     }
 
     #[test]
@@ -413,6 +571,41 @@ pub mod test {
         assert_eq!(memnode.file_truncate(), Err(KError::PermissionError));
     }
 
+    #[test]
+    /// Test that a mapped file can't be truncated until it is unmapped.
+    fn test_file_truncate_for_mapped_file() {
+        let mut memnode =
+            MemNode::new(1, "file.txt", FileModes::S_IRWXU.into(), FileType::File).unwrap();
+        let buffer: &mut [u8; 10] = &mut [0xb; 10];
+        assert_eq!(memnode.write(buffer, 0).unwrap(), 10);
+
+        memnode.mappings += 1;
+        assert!(memnode.is_mapped());
+        assert_eq!(memnode.file_truncate(), Err(KError::FileMapped));
+        memnode.unmap();
+        assert!(!memnode.is_mapped());
+        assert_eq!(memnode.file_truncate(), Ok(()));
+    }
+
+    #[test]
+    /// Test map arguments that don't describe pages of the file.
+    fn test_mnode_map_invalid() {
+        let mut dir =
+            MemNode::new(1, "dir", FileModes::S_IRWXU.into(), FileType::Directory).unwrap();
+        assert_eq!(dir.map(0, BASE_PAGE_SIZE), Err(KError::PermissionError));
+
+        let mut file = MemNode::new(2, "a", FileModes::S_IRWXU.into(), FileType::File).unwrap();
+        assert_eq!(file.map(0, BASE_PAGE_SIZE), Err(KError::InvalidOffset));
+        let buffer: &mut [u8; 10] = &mut [0xb; 10];
+        assert_eq!(file.write(buffer, 0).unwrap(), 10);
+        assert_eq!(file.map(1, BASE_PAGE_SIZE), Err(KError::InvalidOffset));
+        assert_eq!(
+            file.map(BASE_PAGE_SIZE, BASE_PAGE_SIZE),
+            Err(KError::InvalidOffset)
+        );
+        assert!(!file.is_mapped());
+    }
+
     #[test]
     /// Test file_truncate for readable file; should fail.
     fn test_file_truncate_for_nonwritable_file() {
@@ -3,20 +3,23 @@
 
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::convert::TryFrom;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+use fallible_collections::{FallibleVec, FallibleVecGlobal};
 use hashbrown::HashMap;
 use kpi::io::*;
-use spin::RwLock;
 
 use crate::arch::process::UserSlice;
 use crate::error::KError;
-use crate::fallible_string::TryString;
+use crate::fallible_string::{FallibleString, TryString};
+use crate::memory::{PAddr, BASE_PAGE_SIZE};
 
 pub use rwlock::RwLock as NrLock;
 
 pub mod fd;
+pub mod pmem;
 
 mod file;
 mod mnode;
@@ -25,6 +28,7 @@ mod rwlock;
 mod test;
 
 use mnode::MemNode;
+use pmem::Record;
 
 /// The maximum number of open files for a process.
 pub const MAX_FILES_PER_PROCESS: usize = 4096;
@@ -62,6 +66,14 @@ pub trait FileSystem {
     fn truncate(&self, pathname: &str) -> Result<(), KError>;
     fn rename(&self, oldname: &str, newname: &str) -> Result<(), KError>;
     fn mkdir(&self, pathname: &str, modes: Modes) -> Result<(), KError>;
+    fn readdir(
+        &self,
+        mnode_num: Mnode,
+        buffer: &mut UserSlice,
+        cookie: usize,
+    ) -> Result<usize, KError>;
+    fn map(&self, mnode_num: Mnode, offset: usize, len: usize) -> Result<Vec<PAddr>, KError>;
+    fn unmap(&self, mnode_num: Mnode) -> Result<(), KError>;
 }
 
 /// Abstract definition of a file descriptor.
@@ -117,14 +129,33 @@ impl FileDescriptor for Fd {
 /// The mnode number assigned to the first file.
 pub const MNODE_OFFSET: usize = 2;
 
+/// Returns the components of a path. Empty components are ignored, so paths
+/// are always resolved starting from the root directory.
+fn path_components(pathname: &str) -> impl Iterator<Item = &str> {
+    pathname.split('/').filter(|name| !name.is_empty())
+}
+
+/// Splits a path into the path of the parent directory and the name of the
+/// last component. The name is `None` if the path refers to the root.
+fn split_path(pathname: &str) -> (&str, Option<&str>) {
+    let pathname = pathname.trim_end_matches('/');
+    match pathname.rfind('/') {
+        Some(idx) => (&pathname[..idx], Some(&pathname[idx + 1..])),
+        None if pathname.is_empty() => ("", None),
+        None => ("", Some(pathname)),
+    }
+}
+
 /// The in-memory file-system representation.
+///
+/// Directories are mnodes that hold their entries (name -> mnode number),
+/// paths are resolved component by component starting from the root.
 #[derive(Debug)]
 pub struct MlnrFS {
-    /// Only create file will lock the hashmap in write mode,
-    /// every other operation is locked in read mode.
+    /// Only operations that add or remove mnodes lock the hashmap in write
+    /// mode, every other operation is locked in read mode.
     mnodes: NrLock<HashMap<Mnode, NrLock<MemNode>>>,
-    files: RwLock<HashMap<String, Arc<Mnode>>>,
-    root: (String, Mnode),
+    root: (String, Arc<Mnode>),
     nextmemnode: AtomicUsize,
 }
 
@@ -149,23 +180,15 @@ impl Default for MlnrFS {
                 .unwrap(),
             ),
         );
-        let files = RwLock::new(HashMap::new());
-        files.write().insert(
-            TryString::try_from(rootdir)
-                .expect("Not enough memory to initialize system")
-                .into(),
-            Arc::try_new(1).expect("Not enough memory to initialize system"),
-        );
         let root = (
             TryString::try_from(rootdir)
                 .expect("Not enough memory to initialize system")
                 .into(),
-            1,
+            Arc::try_new(rootmnode).expect("Not enough memory to initialize system"),
         );
 
         MlnrFS {
             mnodes,
-            files,
             root,
             nextmemnode: AtomicUsize::new(MNODE_OFFSET),
         }
@@ -177,33 +200,188 @@ impl MlnrFS {
     fn get_next_mno(&self) -> usize {
         self.nextmemnode.fetch_add(1, Ordering::Relaxed)
     }
-}
 
-impl FileSystem for MlnrFS {
-    fn create(&self, pathname: &str, modes: Modes) -> Result<u64, KError> {
-        // Check if the file with the same name already exists.
-        if self.files.read().get(pathname).is_some() {
-            return Err(KError::AlreadyPresent);
+    /// Resolves a path (given as its components) to an mnode.
+    ///
+    /// Returns `InvalidFile` if a component doesn't exist and `DirectoryError`
+    /// if a component (other than the last one) is not a directory.
+    fn walk<'a>(
+        &self,
+        mnodes: &HashMap<Mnode, NrLock<MemNode>>,
+        components: impl Iterator<Item = &'a str>,
+    ) -> Result<Arc<Mnode>, KError> {
+        let mut current = self.root.1.clone();
+        for name in components {
+            let next = mnodes
+                .get(&*current)
+                .ok_or(KError::InvalidFile)?
+                .read()
+                .lookup_child(name)?
+                .cloned()
+                .ok_or(KError::InvalidFile)?;
+            current = next;
         }
-        let pathname_string = TryString::try_from(pathname)?.into();
+        Ok(current)
+    }
+
+    /// Creates a new file or directory, the parent directory has to exist.
+    ///
+    /// The mnode gets the number `mnode` if given (when recreating a file),
+    /// otherwise the next available one.
+    fn create_mnode(
+        &self,
+        pathname: &str,
+        modes: Modes,
+        node_type: FileType,
+        mnode: Option<Mnode>,
+    ) -> Result<Mnode, KError> {
+        let (parent, name) = split_path(pathname);
+        // The root directory always exists.
+        let name = name.ok_or(KError::AlreadyPresent)?;
 
-        let mnode_num = self.get_next_mno() as u64;
-        // TODO(error-handling): can we ignore or should we decrease mnode_num
-        // on error?
-        let arc_mnode_num = Arc::try_new(mnode_num)?;
         let mut mnodes = self.mnodes.write();
+        let parent = self.walk(&mnodes, path_components(parent))?;
         mnodes.try_reserve(1)?;
 
-        // TODO: For now all newly created mnode are for file. How to differentiate
-        // between a file and a directory. Take input from the user?
-        let memnode = MemNode::new(mnode_num, pathname, modes, FileType::File)?;
+        let (mnode_num, memnode) = {
+            let mut parent_node = mnodes.get(&*parent).ok_or(KError::InvalidFile)?.write();
+            // Check if the file with the same name already exists.
+            if parent_node.lookup_child(name)?.is_some() {
+                return Err(KError::AlreadyPresent);
+            }
 
-        self.files.write().insert(pathname_string, arc_mnode_num);
+            let mnode_num = match mnode {
+                Some(mnode_num) => {
+                    // Later mnodes must not reuse the number.
+                    self.nextmemnode
+                        .fetch_max(mnode_num as usize + 1, Ordering::Relaxed);
+                    mnode_num
+                }
+                None => self.get_next_mno() as u64,
+            };
+            // TODO(error-handling): can we ignore or should we decrease mnode_num
+            // on error?
+            let memnode = MemNode::new(mnode_num, name, modes, node_type)?;
+            parent_node.insert_child(name, Arc::try_new(mnode_num)?)?;
+            (mnode_num, memnode)
+        };
         mnodes.insert(mnode_num, NrLock::new(memnode));
 
         Ok(mnode_num)
     }
 
+    /// Applies a modification read from the persistent log.
+    pub fn redo(&self, record: &Record) -> Result<(), KError> {
+        match *record {
+            Record::Create {
+                mnode,
+                modes,
+                pathname,
+            } => self
+                .create_mnode(pathname, modes, FileType::File, Some(mnode))
+                .map(|_mnode| ()),
+            Record::MkDir {
+                mnode,
+                modes,
+                pathname,
+            } => self
+                .create_mnode(pathname, modes, FileType::Directory, Some(mnode))
+                .map(|_mnode| ()),
+            Record::Write {
+                mnode,
+                offset,
+                data,
+            } => self.write(mnode, data, offset as usize).map(|_len| ()),
+            Record::Truncate { pathname } => self.truncate(pathname),
+            Record::Delete { pathname } => self.delete(pathname),
+            Record::Rename { oldname, newname } => self.rename(oldname, newname),
+        }
+    }
+
+    /// Returns a copy of (up to) `len` bytes of the file contents at `offset`.
+    pub fn read_contents(
+        &self,
+        mnode_num: Mnode,
+        offset: usize,
+        len: usize,
+    ) -> Result<Arc<[u8]>, KError> {
+        let mnodes = self.mnodes.read();
+        let node = mnodes.get(&mnode_num).ok_or(KError::InvalidFile)?.read();
+        let len = core::cmp::min(len, node.get_file_size().saturating_sub(offset));
+
+        let mut contents: Vec<u8> = Vec::try_with_capacity(len)?;
+        contents.try_resize(len, 0)?;
+        let read = node.read_raw(&mut contents, offset)?;
+        contents.truncate(read);
+        Ok(Arc::from(contents))
+    }
+
+    /// Passes the records that recreate the file-system (when redone on an
+    /// empty one) to `f`.
+    ///
+    /// Parent directories come before their entries and files are followed
+    /// by the writes of their contents.
+    pub fn checkpoint(
+        &self,
+        f: &mut dyn FnMut(Record) -> Result<(), KError>,
+    ) -> Result<(), KError> {
+        let mnodes = self.mnodes.read();
+        let mut chunk: Vec<u8> = Vec::try_with_capacity(BASE_PAGE_SIZE)?;
+        chunk.try_resize(BASE_PAGE_SIZE, 0)?;
+
+        let mut dirs: Vec<(Mnode, String)> = Vec::new();
+        dirs.try_push((*self.root.1, String::new()))?;
+        while let Some((dir, path)) = dirs.pop() {
+            let dir = mnodes.get(&dir).ok_or(KError::InvalidFile)?.read();
+            for (name, mnode) in dir.children()? {
+                let mut pathname = String::try_with_capacity(path.len() + 1 + name.len())?;
+                pathname.try_push_str(&path)?;
+                pathname.try_push('/')?;
+                pathname.try_push_str(name)?;
+
+                let node = mnodes.get(&**mnode).ok_or(KError::InvalidFile)?.read();
+                match node.get_mnode_type() {
+                    FileType::Directory => {
+                        f(Record::MkDir {
+                            mnode: **mnode,
+                            modes: node.get_modes(),
+                            pathname: &pathname,
+                        })?;
+                        dirs.try_push((**mnode, pathname))?;
+                    }
+                    FileType::File => {
+                        f(Record::Create {
+                            mnode: **mnode,
+                            modes: node.get_modes(),
+                            pathname: &pathname,
+                        })?;
+
+                        let mut offset = 0;
+                        while offset < node.get_file_size() {
+                            let len = node.read_raw(&mut chunk, offset)?;
+                            f(Record::Write {
+                                mnode: **mnode,
+                                offset: offset as u64,
+                                data: &chunk[..len],
+                            })?;
+                            offset += len;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FileSystem for MlnrFS {
+    fn create(&self, pathname: &str, modes: Modes) -> Result<u64, KError> {
+        // TODO: For now all newly created mnode are for file. How to differentiate
+        // between a file and a directory. Take input from the user?
+        self.create_mnode(pathname, modes, FileType::File, None)
+    }
+
     fn write(&self, mnode_num: Mnode, buffer: &[u8], offset: usize) -> Result<usize, KError> {
         match self.mnodes.read().get(&mnode_num) {
             Some(mnode) => mnode.write().write(buffer, offset),
@@ -224,7 +402,8 @@ impl FileSystem for MlnrFS {
     }
 
     fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
-        self.files.read().get(pathname).cloned()
+        self.walk(&self.mnodes.read(), path_components(pathname))
+            .ok()
     }
 
     fn file_info(&self, mnode: Mnode) -> FileInfo {
@@ -243,77 +422,189 @@ impl FileSystem for MlnrFS {
         }
     }
 
+    /// Delete a file or an empty directory.
     fn delete(&self, pathname: &str) -> Result<(), KError> {
-        let mut files = self.files.write();
-        if let Some(mnode) = files.get(pathname) {
-            if Arc::strong_count(mnode) == 1 {
-                self.mnodes.write().remove(mnode);
-            } else {
-                return Err(KError::PermissionError);
+        let (parent, name) = split_path(pathname);
+        // The root directory can't be removed.
+        let name = name.ok_or(KError::PermissionError)?;
+
+        let mut mnodes = self.mnodes.write();
+        let parent = self.walk(&mnodes, path_components(parent))?;
+        let mnode_num = {
+            let mut parent_node = mnodes.get(&*parent).ok_or(KError::InvalidFile)?.write();
+            let mnode_num = match parent_node.lookup_child(name)? {
+                Some(mnode) if Arc::strong_count(mnode) == 1 => **mnode,
+                Some(_mnode) => return Err(KError::PermissionError),
+                None => return Err(KError::InvalidFile),
+            };
+
+            let memnode = mnodes.get(&mnode_num).ok_or(KError::InvalidFile)?.read();
+            if memnode.get_mnode_type() == FileType::Directory && !memnode.is_empty_dir() {
+                return Err(KError::DirectoryError);
             }
-        } else {
-            return Err(KError::InvalidFile);
-        }
+            if memnode.is_mapped() {
+                return Err(KError::FileMapped);
+            }
+            drop(memnode);
 
-        let r = files.remove(pathname);
+            parent_node.remove_child(name)?;
+            mnode_num
+        };
+
+        let r = mnodes.remove(&mnode_num);
         assert!(r.is_some(), "Didn't remove the mnode?");
         Ok(())
     }
 
     fn truncate(&self, pathname: &str) -> Result<(), KError> {
-        match self.files.read().get(pathname) {
-            Some(mnode) => match self.mnodes.read().get(mnode) {
-                Some(memnode) => memnode.write().file_truncate(),
-                None => Err(KError::InvalidFile),
-            },
+        let mnodes = self.mnodes.read();
+        let mnode = self.walk(&mnodes, path_components(pathname))?;
+        match mnodes.get(&*mnode) {
+            Some(memnode) => memnode.write().file_truncate(),
             None => Err(KError::InvalidFile),
         }
     }
 
+    /// Move a file or a directory (along with everything below it) to a new
+    /// path. An existing file, or empty directory, at `newname` is replaced.
     fn rename(&self, oldname: &str, newname: &str) -> Result<(), KError> {
-        if self.files.read().get(oldname).is_none() {
-            return Err(KError::InvalidFile);
-        }
-        let newname_key = TryString::try_from(newname)?.into();
+        let (old_parent, old_name) = split_path(oldname);
+        let (new_parent, new_name) = split_path(newname);
+        // The root directory can't be moved or replaced.
+        let (old_name, new_name) = match (old_name, new_name) {
+            (Some(old_name), Some(new_name)) => (old_name, new_name),
+            _ => return Err(KError::PermissionError),
+        };
 
-        // If the newfile exists then overwrite it with the oldfile.
-        if self.files.read().get(newname).is_some() {
-            self.delete(newname).unwrap();
+        let mut mnodes = self.mnodes.write();
+        let old_parent = self.walk(&mnodes, path_components(old_parent))?;
+        let src = mnodes
+            .get(&*old_parent)
+            .ok_or(KError::InvalidFile)?
+            .read()
+            .lookup_child(old_name)?
+            .cloned()
+            .ok_or(KError::InvalidFile)?;
+        let new_parent = self.walk(&mnodes, path_components(new_parent))?;
+
+        // Renaming a path to itself is a no-op, but a directory can't be moved
+        // into its own subtree.
+        let mut old_path = path_components(oldname);
+        let mut new_path = path_components(newname);
+        loop {
+            match (old_path.next(), new_path.next()) {
+                (None, None) => return Ok(()),
+                (None, Some(_)) => return Err(KError::DirectoryError),
+                (Some(old), Some(new)) if old == new => continue,
+                _ => break,
+            }
         }
 
-        // TODO: Can we optimize it somehow?
-        let mut lock_at_root = self.files.write();
-        match lock_at_root.remove_entry(oldname) {
-            Some((_key, oldnmode)) => match lock_at_root.insert(newname_key, oldnmode) {
-                None => Ok(()),
-                Some(_) => Err(KError::PermissionError),
-            },
-            None => Err(KError::InvalidFile),
+        let dst = match mnodes
+            .get(&*new_parent)
+            .ok_or(KError::InvalidFile)?
+            .read()
+            .lookup_child(new_name)?
+        {
+            Some(dst) if Arc::strong_count(dst) == 1 => Some(**dst),
+            Some(_dst) => return Err(KError::PermissionError),
+            None => None,
+        };
+
+        // If the new path exists then overwrite it with the old one.
+        if let Some(dst) = dst {
+            {
+                let src_node = mnodes.get(&*src).ok_or(KError::InvalidFile)?.read();
+                let dst_node = mnodes.get(&dst).ok_or(KError::InvalidFile)?.read();
+                match (src_node.get_mnode_type(), dst_node.get_mnode_type()) {
+                    (FileType::File, FileType::File) => {}
+                    (FileType::Directory, FileType::Directory) if dst_node.is_empty_dir() => {}
+                    _ => return Err(KError::DirectoryError),
+                }
+                if dst_node.is_mapped() {
+                    return Err(KError::FileMapped);
+                }
+            }
+
+            mnodes
+                .get(&*new_parent)
+                .ok_or(KError::InvalidFile)?
+                .write()
+                .remove_child(new_name)?;
+            mnodes.remove(&dst);
         }
+
+        let mnode_num = *src;
+        mnodes
+            .get(&*new_parent)
+            .ok_or(KError::InvalidFile)?
+            .write()
+            .insert_child(new_name, src)?;
+        mnodes
+            .get(&*old_parent)
+            .ok_or(KError::InvalidFile)?
+            .write()
+            .remove_child(old_name)?;
+        mnodes
+            .get(&mnode_num)
+            .ok_or(KError::InvalidFile)?
+            .write()
+            .set_name(new_name)
     }
 
-    /// Create a directory. The implementation is quite simplistic for now, and only used
-    /// by leveldb benchmark.
+    /// Create a directory, the parent directory has to exist.
     fn mkdir(&self, pathname: &str, modes: Modes) -> Result<(), KError> {
-        // Check if the file with the same name already exists.
-        if self.files.read().get(pathname).is_some() {
-            return Err(KError::AlreadyPresent);
+        self.create_mnode(pathname, modes, FileType::Directory, None)
+            .map(|_mnode| ())
+    }
+
+    /// Fill `buffer` with `DirEntry`s of the directory, starting at the
+    /// `cookie`-th entry. Returns the number of entries that were written.
+    fn readdir(
+        &self,
+        mnode_num: Mnode,
+        buffer: &mut UserSlice,
+        cookie: usize,
+    ) -> Result<usize, KError> {
+        let mnodes = self.mnodes.read();
+        let dir = mnodes.get(&mnode_num).ok_or(KError::InvalidFile)?.read();
+
+        let entry_size = core::mem::size_of::<DirEntry>();
+        let mut entries = 0;
+        for ((name, mnode), chunk) in dir
+            .children()?
+            .skip(cookie)
+            .zip(buffer.chunks_exact_mut(entry_size))
+        {
+            let ftype = mnodes
+                .get(&**mnode)
+                .ok_or(KError::InvalidFile)?
+                .read()
+                .get_mnode_type();
+            chunk.copy_from_slice(DirEntry::new(**mnode, ftype, name).as_bytes());
+            entries += 1;
         }
 
-        let pathname_key = TryString::try_from(pathname)?.into();
-        let mnode_num = self.get_next_mno() as u64;
-        // TODO(error-handling): Should we decrease mnode-num or ignore?
-        let arc_mnode_num = Arc::try_new(mnode_num)?;
-        let mut mnodes = self.mnodes.write();
-        mnodes.try_reserve(1)?;
+        Ok(entries)
+    }
 
-        let memnode = match MemNode::new(mnode_num, pathname, modes, FileType::Directory) {
-            Ok(memnode) => memnode,
-            Err(e) => return Err(e),
-        };
-        self.files.write().insert(pathname_key, arc_mnode_num);
-        mnodes.insert(mnode_num, NrLock::new(memnode));
+    /// Pin the pages of a file so they can be mapped into an address space,
+    /// returns the physical address of every page in `[offset, offset + len)`.
+    fn map(&self, mnode_num: Mnode, offset: usize, len: usize) -> Result<Vec<PAddr>, KError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => mnode.write().map(offset, len),
+            None => Err(KError::InvalidFile),
+        }
+    }
 
-        Ok(())
+    /// Undo an earlier `map` of the file.
+    fn unmap(&self, mnode_num: Mnode) -> Result<(), KError> {
+        match self.mnodes.read().get(&mnode_num) {
+            Some(mnode) => {
+                mnode.write().unmap();
+                Ok(())
+            }
+            None => Err(KError::InvalidFile),
+        }
     }
 }
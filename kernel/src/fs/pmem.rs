@@ -0,0 +1,718 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Persistence of the file-system in persistent memory (PMem).
+//!
+//! The replicated [`MlnrFS`] instances stay in DRAM; every modification is
+//! also appended as a [`Record`] to a redo log in a PMem region before the
+//! system call returns. At mount time the records are redone on the empty
+//! file-system, which brings back all files (with the same mnode numbers).
+//!
+//! The region starts with a superblock, followed by two equally sized log
+//! areas. Only one of them is active (`epoch % 2`). Once the active area is
+//! full, the file-system is written as a compact sequence of records to the
+//! other area (with the next epoch) and the superblock is switched to it in
+//! a single 8-byte store. A crash at any point leaves either the old or the
+//! new area active.
+//!
+//! Records carry the epoch of their area and a checksum, the log ends at the
+//! first record that doesn't check out (i.e., one torn by a crash or stale
+//! contents from an earlier epoch).
+
+use alloc::sync::Arc;
+use core::arch::x86_64::{_mm_clflush, _mm_sfence};
+use core::convert::TryInto;
+use core::ptr;
+
+use log::info;
+use spin::{Mutex, MutexGuard, Once};
+
+use crate::error::KError;
+use crate::memory::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+use crate::round_up;
+
+use super::{MlnrFS, Mnode, Modes};
+
+/// Identifies a region formatted by us.
+const SUPERBLOCK_MAGIC: u64 = 0x7366_6d70_5f6b_726e; // "nrk_pmfs"
+/// Version of the on-media layout.
+const LAYOUT_VERSION: u64 = 1;
+/// Space reserved for the superblock at the start of the region.
+const SUPERBLOCK_SIZE: usize = BASE_PAGE_SIZE;
+
+/// How much persistent memory we set aside for the file-system log (the
+/// rest of the region stays with the persistent memory allocator).
+pub const PMEMFS_REGION_SIZE: usize = 64 * LARGE_PAGE_SIZE;
+
+/// Identifies the start of a record.
+const RECORD_MAGIC: u32 = 0x6c6b_726e; // "nrkl"
+/// Size of the header in front of every record.
+const HEADER_SIZE: usize = 32;
+/// Records start at 8-byte aligned offsets.
+const RECORD_ALIGN: usize = 8;
+
+const CACHE_LINE_SIZE: usize = 64;
+
+const KIND_CREATE: u32 = 1;
+const KIND_MKDIR: u32 = 2;
+const KIND_WRITE: u32 = 3;
+const KIND_TRUNCATE: u32 = 4;
+const KIND_DELETE: u32 = 5;
+const KIND_RENAME: u32 = 6;
+
+/// A modification of the file-system, as it is stored in the log.
+///
+/// Files are referred to by path, except for writes which use the mnode
+/// number (so they are independent of later renames).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Record<'a> {
+    Create {
+        mnode: Mnode,
+        modes: Modes,
+        pathname: &'a str,
+    },
+    MkDir {
+        mnode: Mnode,
+        modes: Modes,
+        pathname: &'a str,
+    },
+    Write {
+        mnode: Mnode,
+        offset: u64,
+        data: &'a [u8],
+    },
+    Truncate {
+        pathname: &'a str,
+    },
+    Delete {
+        pathname: &'a str,
+    },
+    Rename {
+        oldname: &'a str,
+        newname: &'a str,
+    },
+}
+
+impl<'a> Record<'a> {
+    /// Space the record takes up in the log.
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + round_up!(self.payload_len(), RECORD_ALIGN)
+    }
+
+    fn kind(&self) -> u32 {
+        match self {
+            Record::Create { .. } => KIND_CREATE,
+            Record::MkDir { .. } => KIND_MKDIR,
+            Record::Write { .. } => KIND_WRITE,
+            Record::Truncate { .. } => KIND_TRUNCATE,
+            Record::Delete { .. } => KIND_DELETE,
+            Record::Rename { .. } => KIND_RENAME,
+        }
+    }
+
+    fn payload_len(&self) -> usize {
+        match self {
+            Record::Create { pathname, .. } | Record::MkDir { pathname, .. } => 16 + pathname.len(),
+            Record::Write { data, .. } => 16 + data.len(),
+            Record::Truncate { pathname } | Record::Delete { pathname } => pathname.len(),
+            Record::Rename { oldname, newname } => 8 + oldname.len() + newname.len(),
+        }
+    }
+
+    fn encode_payload(&self, payload: &mut [u8]) {
+        let (first, second, rest): (u64, u64, &[u8]) = match *self {
+            Record::Create {
+                mnode,
+                modes,
+                pathname,
+            }
+            | Record::MkDir {
+                mnode,
+                modes,
+                pathname,
+            } => (mnode, modes, pathname.as_bytes()),
+            Record::Write {
+                mnode,
+                offset,
+                data,
+            } => (mnode, offset, data),
+            Record::Truncate { pathname } | Record::Delete { pathname } => {
+                payload.copy_from_slice(pathname.as_bytes());
+                return;
+            }
+            Record::Rename { oldname, newname } => {
+                payload[..8].copy_from_slice(&(oldname.len() as u64).to_le_bytes());
+                payload[8..8 + oldname.len()].copy_from_slice(oldname.as_bytes());
+                payload[8 + oldname.len()..].copy_from_slice(newname.as_bytes());
+                return;
+            }
+        };
+
+        payload[..8].copy_from_slice(&first.to_le_bytes());
+        payload[8..16].copy_from_slice(&second.to_le_bytes());
+        payload[16..].copy_from_slice(rest);
+    }
+
+    fn decode(kind: u32, payload: &'a [u8]) -> Option<Record<'a>> {
+        let word = |offset: usize| -> Option<u64> {
+            Some(u64::from_le_bytes(
+                payload.get(offset..offset + 8)?.try_into().ok()?,
+            ))
+        };
+        let string = |bytes: &'a [u8]| core::str::from_utf8(bytes).ok();
+
+        match kind {
+            KIND_CREATE => Some(Record::Create {
+                mnode: word(0)?,
+                modes: word(8)?,
+                pathname: string(&payload[16..])?,
+            }),
+            KIND_MKDIR => Some(Record::MkDir {
+                mnode: word(0)?,
+                modes: word(8)?,
+                pathname: string(&payload[16..])?,
+            }),
+            KIND_WRITE => Some(Record::Write {
+                mnode: word(0)?,
+                offset: word(8)?,
+                data: &payload[16..],
+            }),
+            KIND_TRUNCATE => Some(Record::Truncate {
+                pathname: string(payload)?,
+            }),
+            KIND_DELETE => Some(Record::Delete {
+                pathname: string(payload)?,
+            }),
+            KIND_RENAME => {
+                let split = 8usize.checked_add(word(0)? as usize)?;
+                Some(Record::Rename {
+                    oldname: string(payload.get(8..split)?)?,
+                    newname: string(payload.get(split..)?)?,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// FNV-1a hash over everything that makes up a record.
+fn checksum(kind: u32, epoch: u64, payload: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x100_0000_01b3;
+
+    kind.to_le_bytes()
+        .iter()
+        .chain(epoch.to_le_bytes().iter())
+        .chain((payload.len() as u64).to_le_bytes().iter())
+        .chain(payload.iter())
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/// Writes the cache-lines covering `bytes` back to memory.
+fn persist(bytes: &[u8]) {
+    let start = bytes.as_ptr() as usize & !(CACHE_LINE_SIZE - 1);
+    let end = bytes.as_ptr() as usize + bytes.len();
+    for line in (start..end).step_by(CACHE_LINE_SIZE) {
+        unsafe { _mm_clflush(line as *const u8) };
+    }
+    unsafe { _mm_sfence() };
+}
+
+/// Writes `record` at `offset` of `area` and returns the offset after it.
+fn write_record(area: &mut [u8], offset: usize, epoch: u64, record: &Record) -> usize {
+    let payload_len = record.payload_len();
+    let bytes = &mut area[offset..offset + record.size()];
+    record.encode_payload(&mut bytes[HEADER_SIZE..HEADER_SIZE + payload_len]);
+    let sum = checksum(
+        record.kind(),
+        epoch,
+        &bytes[HEADER_SIZE..HEADER_SIZE + payload_len],
+    );
+
+    bytes[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    bytes[4..8].copy_from_slice(&record.kind().to_le_bytes());
+    bytes[8..16].copy_from_slice(&(payload_len as u64).to_le_bytes());
+    bytes[16..24].copy_from_slice(&epoch.to_le_bytes());
+    bytes[24..32].copy_from_slice(&sum.to_le_bytes());
+    persist(bytes);
+
+    offset + bytes.len()
+}
+
+/// Makes sure no record is found at `offset` of `area`.
+fn invalidate(area: &mut [u8], offset: usize) {
+    if let Some(header) = area.get_mut(offset..offset + HEADER_SIZE) {
+        header.fill(0);
+        persist(header);
+    }
+}
+
+/// Iterates over the valid records (of `epoch`) at the start of an area.
+pub struct Records<'a> {
+    area: &'a [u8],
+    epoch: u64,
+    offset: usize,
+}
+
+impl<'a> Records<'a> {
+    pub fn new(area: &'a [u8], epoch: u64) -> Records<'a> {
+        Records {
+            area,
+            epoch,
+            offset: 0,
+        }
+    }
+
+    /// Offset of the next record in the area.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Record<'a>> {
+        let header = self.area.get(self.offset..self.offset + HEADER_SIZE)?;
+        let word =
+            |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let kind = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if magic != RECORD_MAGIC || word(16) != self.epoch {
+            return None;
+        }
+
+        let start = self.offset + HEADER_SIZE;
+        let end = start.checked_add(word(8) as usize)?;
+        let payload = self.area.get(start..end)?;
+        if checksum(kind, self.epoch, payload) != word(24) {
+            return None;
+        }
+
+        let record = Record::decode(kind, payload)?;
+        self.offset = core::cmp::min(round_up!(end, RECORD_ALIGN), self.area.len());
+        Some(record)
+    }
+}
+
+/// The superblock at the start of the region.
+#[repr(C)]
+struct Superblock {
+    magic: u64,
+    version: u64,
+    area_size: u64,
+    /// Epoch of the active area, written last when switching areas.
+    epoch: u64,
+}
+
+/// The redo log of the file-system in a PMem region.
+pub struct PmemLog {
+    /// Start of the region (in the kernel address space).
+    base: *mut u8,
+    /// Size of each of the two log areas.
+    area_size: usize,
+    /// Epoch of the active area.
+    epoch: u64,
+    /// End of the records in the active area.
+    tail: usize,
+}
+
+// Safe: The log is the only user of the region.
+unsafe impl Send for PmemLog {}
+
+impl PmemLog {
+    /// Opens the log in the `size` bytes at `base`, the region is formatted
+    /// if it doesn't hold a log yet.
+    ///
+    /// # Safety
+    /// `base` has to point to `size` bytes of (persistent) memory that is
+    /// used by nothing but the log.
+    pub unsafe fn mount(base: *mut u8, size: usize) -> Result<PmemLog, KError> {
+        if size < SUPERBLOCK_SIZE + 2 * BASE_PAGE_SIZE {
+            return Err(KError::NotSupported);
+        }
+        let area_size = ((size - SUPERBLOCK_SIZE) / 2) & !(CACHE_LINE_SIZE - 1);
+
+        let mut log = PmemLog {
+            base,
+            area_size,
+            epoch: 0,
+            tail: 0,
+        };
+        let superblock = base as *mut Superblock;
+        if ptr::read_volatile(&(*superblock).magic) != SUPERBLOCK_MAGIC
+            || ptr::read_volatile(&(*superblock).version) != LAYOUT_VERSION
+            || ptr::read_volatile(&(*superblock).area_size) != area_size as u64
+        {
+            info!("Formatting PMem region {:p} ({} bytes)", base, size);
+            log.format();
+        }
+
+        log.epoch = ptr::read_volatile(&(*superblock).epoch);
+        let mut records = Records::new(log.area(log.epoch), log.epoch);
+        while records.next().is_some() {}
+        log.tail = records.offset();
+        // Remove what's left of a record torn by a crash, so it can't get
+        // mistaken for a valid one after later appends.
+        let (epoch, tail) = (log.epoch, log.tail);
+        invalidate(log.area_mut(epoch), tail);
+
+        Ok(log)
+    }
+
+    fn format(&mut self) {
+        invalidate(self.area_mut(0), 0);
+        invalidate(self.area_mut(1), 0);
+
+        let superblock = self.base as *mut Superblock;
+        unsafe {
+            ptr::write_volatile(&mut (*superblock).version, LAYOUT_VERSION);
+            ptr::write_volatile(&mut (*superblock).area_size, self.area_size as u64);
+            ptr::write_volatile(&mut (*superblock).epoch, 0);
+            persist(core::slice::from_raw_parts(self.base, SUPERBLOCK_SIZE));
+            // The region is only valid once the rest of the superblock is
+            ptr::write_volatile(&mut (*superblock).magic, SUPERBLOCK_MAGIC);
+            persist(core::slice::from_raw_parts(self.base, SUPERBLOCK_SIZE));
+        }
+    }
+
+    fn area(&self, epoch: u64) -> &[u8] {
+        let offset = SUPERBLOCK_SIZE + (epoch % 2) as usize * self.area_size;
+        unsafe { core::slice::from_raw_parts(self.base.add(offset), self.area_size) }
+    }
+
+    fn area_mut(&mut self, epoch: u64) -> &mut [u8] {
+        let offset = SUPERBLOCK_SIZE + (epoch % 2) as usize * self.area_size;
+        unsafe { core::slice::from_raw_parts_mut(self.base.add(offset), self.area_size) }
+    }
+
+    /// The records in the log.
+    pub fn records(&self) -> Records<'_> {
+        Records::new(&self.area(self.epoch)[..self.tail], self.epoch)
+    }
+
+    /// A copy of the records in the log, along with their epoch (to read
+    /// them with [`Records`]).
+    pub fn snapshot(&self) -> (Arc<[u8]>, u64) {
+        let records = &self.area(self.epoch)[..self.tail];
+        (Arc::<[u8]>::from(records), self.epoch)
+    }
+
+    /// Makes sure `record` (or a smaller one) can be appended.
+    ///
+    /// Compacts the log if the active area is full, fails with
+    /// `PersistentLogFull` if that doesn't free up enough space.
+    pub fn reserve(&mut self, record: &Record) -> Result<(), KError> {
+        if self.tail + record.size() <= self.area_size {
+            return Ok(());
+        }
+
+        self.compact()?;
+        if self.tail + record.size() <= self.area_size {
+            Ok(())
+        } else {
+            Err(KError::PersistentLogFull)
+        }
+    }
+
+    /// Appends `record` to the log, it is persistent once this returns.
+    pub fn append(&mut self, record: &Record) -> Result<(), KError> {
+        if self.tail + record.size() > self.area_size {
+            return Err(KError::PersistentLogFull);
+        }
+
+        let (epoch, tail) = (self.epoch, self.tail);
+        self.tail = write_record(self.area_mut(epoch), tail, epoch, record);
+        Ok(())
+    }
+
+    /// Replaces the records in the log with the ones that recreate the
+    /// file-system as it is now.
+    fn compact(&mut self) -> Result<(), KError> {
+        let fs = MlnrFS::default();
+        for record in self.records() {
+            fs.redo(&record)?;
+        }
+
+        let epoch = self.epoch + 1;
+        let area = self.area_mut(epoch);
+        let mut tail = 0;
+        fs.checkpoint(&mut |record| {
+            if tail + record.size() > area.len() {
+                return Err(KError::PersistentLogFull);
+            }
+            tail = write_record(area, tail, epoch, &record);
+            Ok(())
+        })?;
+        invalidate(area, tail);
+
+        // Switch to the new area
+        let superblock = self.base as *mut Superblock;
+        unsafe {
+            ptr::write_volatile(&mut (*superblock).epoch, epoch);
+            persist(core::slice::from_raw_parts(self.base, SUPERBLOCK_SIZE));
+        }
+        info!(
+            "Compacted file-system log from {} to {} bytes",
+            self.tail, tail
+        );
+        self.epoch = epoch;
+        self.tail = tail;
+
+        Ok(())
+    }
+}
+
+/// The log of the file-system, if it is persistent.
+static PMEM_LOG: Once<Mutex<PmemLog>> = Once::new();
+
+/// Makes all later file-system modifications go to `log`.
+pub fn install(log: PmemLog) {
+    PMEM_LOG.call_once(|| Mutex::new(log));
+}
+
+/// Locks the log of the file-system, returns `None` if the file-system is
+/// not persistent.
+///
+/// Modifications are done while holding the lock, so they end up in the log
+/// in the same order the replicas apply them.
+pub fn lock() -> Option<MutexGuard<'static, PmemLog>> {
+    let log = PMEM_LOG.get()?;
+    loop {
+        if let Some(guard) = log.try_lock() {
+            return Some(guard);
+        }
+        // The core holding the lock might wait for our replica to make
+        // progress.
+        crate::arch::advance_fs_replica();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use kpi::io::*;
+
+    use crate::fs::FileSystem;
+
+    const REGION_SIZE: usize = SUPERBLOCK_SIZE + 2 * 4 * BASE_PAGE_SIZE;
+
+    fn mount(region: &mut Vec<u8>) -> PmemLog {
+        unsafe { PmemLog::mount(region.as_mut_ptr(), region.len()).unwrap() }
+    }
+
+    fn recover(log: &PmemLog) -> MlnrFS {
+        let fs = MlnrFS::default();
+        for record in log.records() {
+            fs.redo(&record).unwrap();
+        }
+        fs
+    }
+
+    fn contents(fs: &MlnrFS, pathname: &str) -> Vec<u8> {
+        let mnode = *fs.lookup(pathname).unwrap();
+        let size = fs.file_info(mnode).fsize as usize;
+        let mut buffer = vec![0; size];
+        let mnodes = fs.mnodes.read();
+        let node = mnodes.get(&mnode).unwrap().read();
+        assert_eq!(node.read_raw(&mut buffer, 0), Ok(size));
+        buffer
+    }
+
+    #[test]
+    fn record_encoding() {
+        let records = [
+            Record::Create {
+                mnode: 2,
+                modes: 0o7,
+                pathname: "/file",
+            },
+            Record::MkDir {
+                mnode: 3,
+                modes: 0o7,
+                pathname: "/dir",
+            },
+            Record::Write {
+                mnode: 2,
+                offset: 0x1234,
+                data: &[0xa, 0xb, 0xc],
+            },
+            Record::Truncate { pathname: "/file" },
+            Record::Delete { pathname: "/dir" },
+            Record::Rename {
+                oldname: "/file",
+                newname: "/dir/file",
+            },
+        ];
+
+        let mut area = vec![0; BASE_PAGE_SIZE];
+        let mut offset = 0;
+        for record in records.iter() {
+            offset = write_record(&mut area, offset, 7, record);
+        }
+        assert_eq!(offset, records.iter().map(|r| r.size()).sum::<usize>());
+
+        let mut parsed = Records::new(&area, 7);
+        assert!(parsed.by_ref().eq(records.iter().copied()));
+        assert_eq!(parsed.offset(), offset);
+        // Records of another epoch are ignored
+        assert_eq!(Records::new(&area, 6).count(), 0);
+    }
+
+    #[test]
+    fn torn_record() {
+        let mut area = vec![0; BASE_PAGE_SIZE];
+        let first = Record::Delete { pathname: "/a" };
+        let second = Record::Delete { pathname: "/b" };
+        let offset = write_record(&mut area, 0, 0, &first);
+        write_record(&mut area, offset, 0, &second);
+
+        area[offset + HEADER_SIZE] = b'c';
+        let mut records = Records::new(&area, 0);
+        assert_eq!(records.next(), Some(first));
+        assert_eq!(records.next(), None);
+        assert_eq!(records.offset(), offset);
+    }
+
+    #[test]
+    fn remount() {
+        let mut region = vec![0xff; REGION_SIZE];
+        let mut log = mount(&mut region);
+        assert_eq!(log.records().count(), 0);
+
+        let records = [
+            Record::MkDir {
+                mnode: 2,
+                modes: FileModes::S_IRWXU.into(),
+                pathname: "/dir",
+            },
+            Record::Create {
+                mnode: 3,
+                modes: FileModes::S_IRWXU.into(),
+                pathname: "/dir/file",
+            },
+            Record::Write {
+                mnode: 3,
+                offset: 0,
+                data: b"hello",
+            },
+        ];
+        for record in records.iter() {
+            log.reserve(record).unwrap();
+            log.append(record).unwrap();
+        }
+        drop(log);
+
+        let log = mount(&mut region);
+        assert!(log.records().eq(records.iter().copied()));
+        let fs = recover(&log);
+        assert_eq!(contents(&fs, "/dir/file"), b"hello");
+        assert_eq!(*fs.lookup("/dir/file").unwrap(), 3);
+        // New mnodes don't collide with the recovered ones
+        assert_eq!(fs.create("/other", FileModes::S_IRWXU.into()), Ok(4));
+    }
+
+    #[test]
+    fn compaction() {
+        let mut region = vec![0; REGION_SIZE];
+        let mut log = mount(&mut region);
+        let modes = FileModes::S_IRWXU.into();
+
+        let create = Record::Create {
+            mnode: 2,
+            modes,
+            pathname: "/file",
+        };
+        log.reserve(&create).unwrap();
+        log.append(&create).unwrap();
+
+        // Overwriting the file over and over fills up the log
+        let data = vec![0x5a; BASE_PAGE_SIZE];
+        for i in 0..64 {
+            let write = Record::Write {
+                mnode: 2,
+                offset: (i % 2) * BASE_PAGE_SIZE as u64,
+                data: &data,
+            };
+            log.reserve(&write).unwrap();
+            log.append(&write).unwrap();
+        }
+        assert!(log.epoch > 0);
+        drop(log);
+
+        let log = mount(&mut region);
+        let fs = recover(&log);
+        assert_eq!(contents(&fs, "/file"), vec![0x5a; 2 * BASE_PAGE_SIZE]);
+
+        // A record that doesn't fit even after compaction
+        let data = vec![0; 8 * BASE_PAGE_SIZE];
+        let mut log = log;
+        assert_eq!(
+            log.reserve(&Record::Write {
+                mnode: 2,
+                offset: 0,
+                data: &data
+            }),
+            Err(KError::PersistentLogFull)
+        );
+    }
+
+    #[test]
+    fn crash_during_compaction() {
+        let mut region = vec![0; REGION_SIZE];
+        let mut log = mount(&mut region);
+        let modes = FileModes::S_IRWXU.into();
+        for (mnode, pathname) in [(2, "/a"), (3, "/b")].iter() {
+            let record = Record::Create {
+                mnode: *mnode,
+                modes,
+                pathname: *pathname,
+            };
+            log.append(&record).unwrap();
+        }
+
+        // The checkpoint is written to the inactive area but the superblock
+        // still points to the old one
+        let fs = recover(&log);
+        fs.delete("/a").unwrap();
+        let mut tail = 0;
+        let area = log.area_mut(1);
+        fs.checkpoint(&mut |record| {
+            tail = write_record(area, tail, 1, &record);
+            Ok(())
+        })
+        .unwrap();
+        drop(log);
+
+        let log = mount(&mut region);
+        let fs = recover(&log);
+        assert!(fs.lookup("/a").is_some());
+        assert!(fs.lookup("/b").is_some());
+    }
+
+    #[test]
+    fn checkpoint_recreates_fs() {
+        let fs = MlnrFS::default();
+        let modes = FileModes::S_IRWXU.into();
+        fs.mkdir("/dir", modes).unwrap();
+        fs.mkdir("/dir/sub", modes).unwrap();
+        let file = fs.create("/dir/sub/file", modes).unwrap();
+        fs.write(file, &[1; 3 * BASE_PAGE_SIZE + 5], 7).unwrap();
+        let empty = fs.create("/empty", FileModes::S_IRUSR.into()).unwrap();
+        fs.rename("/dir/sub", "/sub").unwrap();
+
+        let copy = MlnrFS::default();
+        fs.checkpoint(&mut |record| copy.redo(&record)).unwrap();
+
+        assert_eq!(*copy.lookup("/sub/file").unwrap(), file);
+        assert_eq!(*copy.lookup("/empty").unwrap(), empty);
+        assert!(copy.lookup("/dir").is_some());
+        assert!(copy.lookup("/dir/sub").is_none());
+        assert_eq!(contents(&copy, "/sub/file"), contents(&fs, "/sub/file"));
+    }
+}
@@ -3,6 +3,8 @@
 
 //! Test the file-sytem implementation using unit-tests and proptest.
 
+use alloc::format;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::cell::RefCell;
 use core::cmp::{Eq, PartialEq};
@@ -24,8 +26,8 @@ use crate::*;
 enum ModelOperation {
     /// Stores a write to an mnode, at given offset, pattern, length.
     Write(Mnode, usize, char, usize),
-    /// Stores info about created files.
-    Created(String, Modes, Mnode),
+    /// Stores info about created files and directories.
+    Created(String, Modes, Mnode, FileType),
 }
 
 /// The FS model that we strive to implement.
@@ -39,9 +41,12 @@ struct ModelFS {
 impl Default for ModelFS {
     fn default() -> Self {
         let oplog = RefCell::new(Vec::with_capacity(64));
-        oplog
-            .borrow_mut()
-            .push(ModelOperation::Created("/".to_string(), 0, 1));
+        oplog.borrow_mut().push(ModelOperation::Created(
+            "/".to_string(),
+            0,
+            1,
+            FileType::Directory,
+        ));
         ModelFS {
             oplog,
             mnode_counter: RefCell::new(1),
@@ -50,11 +55,100 @@ impl Default for ModelFS {
 }
 
 impl ModelFS {
+    /// Turns a path into its canonical form (starts with `/`, no empty
+    /// components).
+    fn normalize(path: &str) -> String {
+        let mut normalized = String::new();
+        for name in path_components(path) {
+            normalized.push('/');
+            normalized.push_str(name);
+        }
+        if normalized.is_empty() {
+            normalized.push('/');
+        }
+        normalized
+    }
+
+    /// Appends `name` to the (normalized) directory path `dir`.
+    fn join(dir: &str, name: &str) -> String {
+        ModelFS::normalize(&format!("{}/{}", dir, name))
+    }
+
+    /// Find the type of a path.
+    fn path_type(&self, path: &String) -> Option<FileType> {
+        for x in self.oplog.borrow().iter().rev() {
+            match x {
+                ModelOperation::Created(name, _mode, _mnode, ftype) => {
+                    if &name == &path {
+                        return Some(*ftype);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Check if a directory has any entries.
+    fn has_children(&self, path: &String) -> bool {
+        let prefix = format!("{}/", path);
+        self.oplog.borrow().iter().any(|x| match x {
+            ModelOperation::Created(name, _mode, _mnode, _ftype) => name.starts_with(&prefix),
+            _ => false,
+        })
+    }
+
+    /// Resolve the (parent directory) path `path` component by component,
+    /// returns the normalized path.
+    fn walk(&self, path: &str) -> Result<String, KError> {
+        let mut current = String::from("/");
+        for name in path_components(path) {
+            if self.path_type(&current) != Some(FileType::Directory) {
+                return Err(KError::DirectoryError);
+            }
+            current = ModelFS::join(&current, name);
+            if !self.file_exists(&current) {
+                return Err(KError::InvalidFile);
+            }
+        }
+        Ok(current)
+    }
+
+    /// Resolves the parent directory of `path` and returns the normalized path.
+    fn walk_parent(&self, parent: &str, name: &str) -> Result<String, KError> {
+        let parent = self.walk(parent)?;
+        if self.path_type(&parent) != Some(FileType::Directory) {
+            return Err(KError::DirectoryError);
+        }
+        Ok(ModelFS::join(&parent, name))
+    }
+
+    /// Create puts the file or directory in the oplog and increases the mnode counter.
+    fn create_entry(&self, pathname: &str, mode: Modes, ftype: FileType) -> Result<u64, KError> {
+        let (parent, name) = split_path(pathname);
+        let name = name.ok_or(KError::AlreadyPresent)?;
+        let path = self.walk_parent(parent, name)?;
+
+        if self.file_exists(&path) {
+            Err(KError::AlreadyPresent)
+        } else {
+            *self.mnode_counter.borrow_mut() += 1;
+            self.oplog.borrow_mut().push(ModelOperation::Created(
+                path,
+                mode,
+                *self.mnode_counter.borrow(),
+                ftype,
+            ));
+            Ok(*self.mnode_counter.borrow())
+        }
+    }
+
     /// Find mnode of a path.
     fn path_to_mnode(&self, path: &String) -> Option<Mnode> {
         for x in self.oplog.borrow().iter().rev() {
             match x {
-                ModelOperation::Created(name, _mode, mnode) => {
+                ModelOperation::Created(name, _mode, mnode, _ftype) => {
                     if &name == &path {
                         return Some(*mnode);
                     }
@@ -66,11 +160,27 @@ impl ModelFS {
         None
     }
 
+    /// Find the path (and type) of a mnode.
+    fn mnode_to_path(&self, look_for: Mnode) -> Option<(String, FileType)> {
+        for x in self.oplog.borrow().iter().rev() {
+            match x {
+                ModelOperation::Created(name, _mode, mnode, ftype) => {
+                    if look_for == *mnode {
+                        return Some((name.clone(), *ftype));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+
     /// Find index of a path in the oplog.
     fn path_to_idx(&self, path: &String) -> Option<usize> {
         for (idx, x) in self.oplog.borrow().iter().enumerate().rev() {
             match x {
-                ModelOperation::Created(name, _mode, _mnode) => {
+                ModelOperation::Created(name, _mode, _mnode, _ftype) => {
                     if &name == &path {
                         return Some(idx);
                     }
@@ -91,7 +201,7 @@ impl ModelFS {
     fn mnode_exists(&self, look_for: Mnode) -> bool {
         for x in self.oplog.borrow().iter().rev() {
             match x {
-                ModelOperation::Created(_name, _mode, mnode) => {
+                ModelOperation::Created(_name, _mode, mnode, _ftype) => {
                     if look_for == *mnode {
                         return true;
                     }
@@ -133,18 +243,7 @@ impl ModelFS {
 impl FileSystem for ModelFS {
     // Create just puts the file in the oplop and increases mnode counter.
     fn create(&self, pathname: &str, mode: Modes) -> Result<u64, KError> {
-        let path = String::from(pathname);
-        if self.file_exists(&path) {
-            Err(KError::AlreadyPresent)
-        } else {
-            *self.mnode_counter.borrow_mut() += 1;
-            self.oplog.borrow_mut().push(ModelOperation::Created(
-                path,
-                mode,
-                *self.mnode_counter.borrow(),
-            ));
-            Ok(*self.mnode_counter.borrow())
-        }
+        self.create_entry(pathname, mode, FileType::File)
     }
 
     /// Write just logs the write to the oplog.
@@ -156,8 +255,11 @@ impl FileSystem for ModelFS {
                 trace!("seen {:?}", x);
                 match x {
                     // Check if the file is writable or not
-                    ModelOperation::Created(_path, mode, mnode) => {
-                        if mnode_num == *mnode && !FileModes::from(*mode).is_writable() {
+                    ModelOperation::Created(_path, mode, mnode, ftype) => {
+                        if mnode_num == *mnode
+                            && (*ftype == FileType::Directory
+                                || !FileModes::from(*mode).is_writable())
+                        {
                             return Err(KError::PermissionError);
                         }
                     }
@@ -236,8 +338,11 @@ impl FileSystem for ModelFS {
                         // else: The write is not relevant
                     }
 
-                    ModelOperation::Created(_path, mode, mnode) => {
-                        if mnode_num == *mnode && !FileModes::from(*mode).is_readable() {
+                    ModelOperation::Created(_path, mode, mnode, ftype) => {
+                        if mnode_num == *mnode
+                            && (*ftype == FileType::Directory
+                                || !FileModes::from(*mode).is_readable())
+                        {
                             return Err(KError::PermissionError);
                         }
                     }
@@ -273,12 +378,20 @@ impl FileSystem for ModelFS {
 
     /// Lookup just returns the mnode.
     fn lookup(&self, pathname: &str) -> Option<Arc<Mnode>> {
-        self.path_to_mnode(&String::from(pathname)).map(Arc::from)
+        self.path_to_mnode(&ModelFS::normalize(pathname))
+            .map(Arc::from)
     }
 
     /// Delete finds and removes a path from the oplog again.
     fn delete(&self, pathname: &str) -> Result<(), KError> {
-        if let Some(idx) = self.path_to_idx(&String::from(pathname)) {
+        let (parent, name) = split_path(pathname);
+        let name = name.ok_or(KError::PermissionError)?;
+        let path = self.walk_parent(parent, name)?;
+
+        if let Some(idx) = self.path_to_idx(&path) {
+            if self.path_type(&path) == Some(FileType::Directory) && self.has_children(&path) {
+                return Err(KError::DirectoryError);
+            }
             self.oplog.borrow_mut().remove(idx);
             // We leave corresponding ModelOperation::Write entries
             // in the log for now...
@@ -298,13 +411,110 @@ impl FileSystem for ModelFS {
         Ok(())
     }
 
-    /// Return a `dummy` response for rename operation
-    fn rename(&self, _oldname: &str, _newname: &str) -> Result<(), KError> {
+    /// Rename replaces the path prefix of the moved entry (and everything
+    /// below it) in the oplog.
+    fn rename(&self, oldname: &str, newname: &str) -> Result<(), KError> {
+        let (old_parent, old_name) = split_path(oldname);
+        let (new_parent, new_name) = split_path(newname);
+        let (old_name, new_name) = match (old_name, new_name) {
+            (Some(old_name), Some(new_name)) => (old_name, new_name),
+            _ => return Err(KError::PermissionError),
+        };
+
+        let old = self.walk_parent(old_parent, old_name)?;
+        let old_type = self.path_type(&old).ok_or(KError::InvalidFile)?;
+        let new_parent = self.walk(new_parent)?;
+        let new = ModelFS::join(&new_parent, new_name);
+        if old == new {
+            return Ok(());
+        }
+        if new.starts_with(&format!("{}/", old)) {
+            return Err(KError::DirectoryError);
+        }
+        if self.path_type(&new_parent) != Some(FileType::Directory) {
+            return Err(KError::DirectoryError);
+        }
+
+        if let Some(new_type) = self.path_type(&new) {
+            match (old_type, new_type) {
+                (FileType::File, FileType::File) => {}
+                (FileType::Directory, FileType::Directory) if !self.has_children(&new) => {}
+                _ => return Err(KError::DirectoryError),
+            }
+            let idx = self.path_to_idx(&new).unwrap();
+            self.oplog.borrow_mut().remove(idx);
+        }
+
+        let prefix = format!("{}/", old);
+        for x in self.oplog.borrow_mut().iter_mut() {
+            match x {
+                ModelOperation::Created(name, _mode, _mnode, _ftype) => {
+                    if *name == old {
+                        *name = new.clone();
+                    } else if name.starts_with(&prefix) {
+                        *name = format!("{}/{}", new, &name[prefix.len()..]);
+                    }
+                }
+                _ => {}
+            }
+        }
         Ok(())
     }
 
-    fn mkdir(&self, _pathname: &str, _mode: Modes) -> Result<(), KError> {
-        Ok(())
+    fn mkdir(&self, pathname: &str, mode: Modes) -> Result<(), KError> {
+        self.create_entry(pathname, mode, FileType::Directory)
+            .map(|_mnode| ())
+    }
+
+    /// Readdir collects the direct children of the directory from the oplog,
+    /// sorted by name.
+    fn readdir(
+        &self,
+        mnode_num: Mnode,
+        buffer: &mut UserSlice,
+        cookie: usize,
+    ) -> Result<usize, KError> {
+        let (path, ftype) = self.mnode_to_path(mnode_num).ok_or(KError::InvalidFile)?;
+        if ftype != FileType::Directory {
+            return Err(KError::DirectoryError);
+        }
+
+        let mut children: Vec<(String, Mnode, FileType)> = Vec::new();
+        for x in self.oplog.borrow().iter() {
+            match x {
+                ModelOperation::Created(name, _mode, mnode, ftype) => {
+                    if let (parent, Some(child)) = split_path(name) {
+                        if ModelFS::normalize(parent) == path {
+                            children.push((child.to_string(), *mnode, *ftype));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let entry_size = core::mem::size_of::<DirEntry>();
+        let mut entries = 0;
+        for ((name, mnode, ftype), chunk) in children
+            .iter()
+            .skip(cookie)
+            .zip(buffer.chunks_exact_mut(entry_size))
+        {
+            chunk.copy_from_slice(DirEntry::new(*mnode, *ftype, name).as_bytes());
+            entries += 1;
+        }
+
+        Ok(entries)
+    }
+
+    /// The model has no pages to hand out, mappings aren't modeled.
+    fn map(&self, _mnode_num: Mnode, _offset: usize, _len: usize) -> Result<Vec<PAddr>, KError> {
+        Err(KError::NotSupported)
+    }
+
+    fn unmap(&self, _mnode_num: Mnode) -> Result<(), KError> {
+        Err(KError::NotSupported)
     }
 }
 
@@ -374,6 +584,9 @@ enum TestAction {
     Create(Vec<String>, Modes),
     Delete(Vec<String>),
     Lookup(Vec<String>),
+    MkDir(Vec<String>, Modes),
+    Rename(Vec<String>, Vec<String>),
+    ReadDir(Mnode, usize, usize),
 }
 
 /// Generates one `TestAction` entry randomly.
@@ -391,6 +604,10 @@ fn action() -> impl Strategy<Value = TestAction> {
         (path(), mode_gen(0xfff)).prop_map(|(a, b)| TestAction::Create(a, b)),
         path().prop_map(TestAction::Delete),
         path().prop_map(TestAction::Lookup),
+        (path(), mode_gen(0xfff)).prop_map(|(a, b)| TestAction::MkDir(a, b)),
+        (path(), path()).prop_map(|(a, b)| TestAction::Rename(a, b)),
+        (mnode_gen(16), offset_gen(8), size_gen(8))
+            .prop_map(|(a, b, c)| TestAction::ReadDir(a, b, c)),
     ]
 }
 
@@ -448,10 +665,9 @@ fn path_names() -> impl Strategy<Value = String> {
     ]
 }
 
-/// Creates a path of depth a given depth (4), represented as a
-/// vector of Strings.
+/// Creates a path of depth 1 to 3, represented as a vector of Strings.
 fn path() -> impl Strategy<Value = Vec<String>> {
-    proptest::collection::vec(path_names(), 4)
+    proptest::collection::vec(path_names(), 1..4)
 }
 
 proptest! {
@@ -505,6 +721,30 @@ proptest! {
                     let rtotest = totest.lookup(path_str.as_str());
                     assert_eq!(rmodel, rtotest);
                 }
+                MkDir(path, mode) => {
+                    let path_str = path.join("/");
+
+                    let rmodel = model.mkdir(path_str.as_str(), mode);
+                    let rtotest = totest.mkdir(path_str.as_str(), mode);
+                    assert_eq!(rmodel, rtotest);
+                }
+                Rename(oldpath, newpath) => {
+                    let oldpath_str = oldpath.join("/");
+                    let newpath_str = newpath.join("/");
+
+                    let rmodel = model.rename(oldpath_str.as_str(), newpath_str.as_str());
+                    let rtotest = totest.rename(oldpath_str.as_str(), newpath_str.as_str());
+                    assert_eq!(rmodel, rtotest);
+                }
+                ReadDir(mnode, cookie, entries) => {
+                    let mut buffer1: Vec<u8> = vec![0; entries * core::mem::size_of::<DirEntry>()];
+                    let mut buffer2: Vec<u8> = vec![0; entries * core::mem::size_of::<DirEntry>()];
+
+                    let rmodel = model.readdir(mnode, &mut UserSlice::from_slice(buffer1.as_mut_slice()), cookie);
+                    let rtotest = totest.readdir(mnode, &mut UserSlice::from_slice(buffer2.as_mut_slice()), cookie);
+                    assert_eq!(rmodel, rtotest);
+                    assert_eq!(buffer1, buffer2);
+                }
             }
         }
     }
@@ -528,9 +768,9 @@ fn test_file_descriptor() {
 fn test_memfs_init() {
     let memfs: MlnrFS = Default::default();
     let root = String::from("/");
-    assert_eq!(memfs.root, (root.to_owned(), 1));
+    assert_eq!(memfs.root, (root.to_owned(), Arc::new(1)));
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 2);
-    assert_eq!(memfs.files.read().get(&root), Some(&Arc::new(1)));
+    assert_eq!(memfs.lookup(&root), Some(Arc::new(1)));
     assert_eq!(
         *memfs.mnodes.read().get(&1).unwrap().read(),
         MemNode::new(1, "/", FileModes::S_IRWXU.into(), FileType::Directory).unwrap()
@@ -545,10 +785,7 @@ fn test_file_create() {
     let mnode = memfs.create(filename, FileModes::S_IRUSR.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
 }
 
 /// Create a file with non-read permission and try to read it.
@@ -561,10 +798,7 @@ fn test_file_read_permission_error() {
     let mnode = memfs.create(filename, FileModes::S_IWUSR.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
     // On error read returns 0.
     assert_eq!(
         memfs
@@ -583,10 +817,7 @@ fn test_file_write_permission_error() {
     let mnode = memfs.create(filename, FileModes::S_IRUSR.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
     // On error read returns 0.
     assert_eq!(
         memfs.write(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0),
@@ -603,10 +834,7 @@ fn test_file_write() {
     let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
     assert_eq!(
         memfs
             .write(2, &mut UserSlice::new(buffer.as_ptr() as u64, 10), 0)
@@ -627,10 +855,7 @@ fn test_file_read() {
     let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
     assert_eq!(
         memfs
             .write(2, &mut UserSlice::new(wbuffer.as_ptr() as u64, len), 0)
@@ -655,10 +880,7 @@ fn test_file_lookup() {
     let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
     let mnode = memfs.lookup(filename);
     assert_eq!(mnode, Some(Arc::new(2)));
 }
@@ -671,10 +893,7 @@ fn test_file_fake_lookup() {
     let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
     let mnode = memfs.lookup("filename");
     assert_eq!(mnode, None);
 }
@@ -687,10 +906,7 @@ fn test_file_duplicate_create() {
     let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
     assert_eq!(
         memfs.create(filename, FileModes::S_IRWXU.into()),
         Err(KError::AlreadyPresent)
@@ -705,10 +921,7 @@ fn test_file_info() {
     let mnode = memfs.create(filename, FileModes::S_IRWXU.into()).unwrap();
     assert_eq!(mnode, 2);
     assert_eq!(memfs.nextmemnode.load(Ordering::Relaxed), 3);
-    assert_eq!(
-        memfs.files.read().get(&String::from("file.txt")),
-        Some(&Arc::new(2))
-    );
+    assert_eq!(memfs.lookup("file.txt"), Some(Arc::new(2)));
     assert_eq!(memfs.file_info(2), FileInfo { ftype: 2, fsize: 0 });
 }
 
@@ -813,3 +1026,126 @@ fn test_file_rename_to_existent_file() {
     // New file points to old mnode.
     assert_eq!(*memfs.lookup(newname).unwrap(), oldmnode);
 }
+
+/// Files can only be created in existing directories.
+#[test]
+fn test_create_requires_parent() {
+    let memfs: MlnrFS = Default::default();
+    assert_eq!(
+        memfs.create("/a/file.txt", FileModes::S_IRWXU.into()),
+        Err(KError::InvalidFile)
+    );
+    assert_eq!(
+        memfs.mkdir("/a/b", FileModes::S_IRWXU.into()),
+        Err(KError::InvalidFile)
+    );
+    assert_eq!(memfs.mkdir("/a", FileModes::S_IRWXU.into()), Ok(()));
+    assert_eq!(
+        memfs.mkdir("/a", FileModes::S_IRWXU.into()),
+        Err(KError::AlreadyPresent)
+    );
+    let mnode = memfs
+        .create("/a/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+    assert_eq!(memfs.lookup("/a/file.txt"), Some(Arc::new(mnode)));
+    assert_eq!(memfs.lookup("a//file.txt/"), Some(Arc::new(mnode)));
+
+    // A file is not a directory.
+    assert_eq!(
+        memfs.create("/a/file.txt/b", FileModes::S_IRWXU.into()),
+        Err(KError::DirectoryError)
+    );
+    assert_eq!(memfs.lookup("/a/file.txt/b"), None);
+}
+
+/// Only empty directories can be deleted.
+#[test]
+fn test_delete_directory() {
+    let memfs: MlnrFS = Default::default();
+    assert_eq!(memfs.mkdir("/a", FileModes::S_IRWXU.into()), Ok(()));
+    assert!(memfs
+        .create("/a/file.txt", FileModes::S_IRWXU.into())
+        .is_ok());
+    assert_eq!(memfs.delete("/a"), Err(KError::DirectoryError));
+    assert_eq!(memfs.delete("/a/file.txt"), Ok(()));
+    assert_eq!(memfs.delete("/a"), Ok(()));
+    assert_eq!(memfs.lookup("/a"), None);
+    assert_eq!(memfs.delete("/"), Err(KError::PermissionError));
+}
+
+/// Renaming a directory moves everything below it.
+#[test]
+fn test_directory_rename() {
+    let memfs: MlnrFS = Default::default();
+    assert_eq!(memfs.mkdir("/a", FileModes::S_IRWXU.into()), Ok(()));
+    assert_eq!(memfs.mkdir("/a/b", FileModes::S_IRWXU.into()), Ok(()));
+    assert_eq!(memfs.mkdir("/c", FileModes::S_IRWXU.into()), Ok(()));
+    let mnode = memfs
+        .create("/a/b/file.txt", FileModes::S_IRWXU.into())
+        .unwrap();
+
+    // Can't move a directory into itself.
+    assert_eq!(memfs.rename("/a", "/a/b/d"), Err(KError::DirectoryError));
+    // Can't replace a non-empty directory.
+    assert_eq!(memfs.rename("/c", "/a"), Err(KError::DirectoryError));
+    // Can't replace a directory with a file.
+    assert_eq!(
+        memfs.rename("/a/b/file.txt", "/c"),
+        Err(KError::DirectoryError)
+    );
+
+    assert_eq!(memfs.rename("/a", "/c/d"), Ok(()));
+    assert_eq!(memfs.lookup("/a"), None);
+    assert_eq!(memfs.lookup("/a/b/file.txt"), None);
+    assert_eq!(memfs.lookup("/c/d/b/file.txt"), Some(Arc::new(mnode)));
+}
+
+/// Read the entries of a directory in multiple steps.
+#[test]
+fn test_readdir() {
+    let memfs: MlnrFS = Default::default();
+    let mode = FileModes::S_IRWXU.into();
+    assert_eq!(memfs.mkdir("/dir", mode), Ok(()));
+    let file = memfs.create("/dir/file", mode).unwrap();
+    assert_eq!(memfs.mkdir("/dir/a", mode), Ok(()));
+    let dir = *memfs.lookup("/dir").unwrap();
+    let subdir = *memfs.lookup("/dir/a").unwrap();
+
+    let entry_size = core::mem::size_of::<DirEntry>();
+    let mut buffer: Vec<u8> = vec![0; entry_size];
+    assert_eq!(
+        memfs.readdir(dir, &mut UserSlice::from_slice(buffer.as_mut_slice()), 0),
+        Ok(1)
+    );
+    assert_eq!(
+        buffer,
+        DirEntry::new(subdir, FileType::Directory, "a").as_bytes()
+    );
+    assert_eq!(
+        memfs.readdir(dir, &mut UserSlice::from_slice(buffer.as_mut_slice()), 1),
+        Ok(1)
+    );
+    assert_eq!(
+        buffer,
+        DirEntry::new(file, FileType::File, "file").as_bytes()
+    );
+    assert_eq!(
+        memfs.readdir(dir, &mut UserSlice::from_slice(buffer.as_mut_slice()), 2),
+        Ok(0)
+    );
+
+    // A buffer that is too small for a single entry doesn't get any.
+    let mut small: Vec<u8> = vec![0; entry_size - 1];
+    assert_eq!(
+        memfs.readdir(dir, &mut UserSlice::from_slice(small.as_mut_slice()), 0),
+        Ok(0)
+    );
+    assert_eq!(
+        memfs.readdir(file, &mut UserSlice::from_slice(buffer.as_mut_slice()), 0),
+        Err(KError::DirectoryError)
+    );
+    assert_eq!(
+        memfs.readdir(0xdead, &mut UserSlice::from_slice(buffer.as_mut_slice()), 0),
+        Err(KError::InvalidFile)
+    );
+}
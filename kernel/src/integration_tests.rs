@@ -12,7 +12,7 @@ use crate::ExitReason;
 type MainFn = fn();
 
 #[cfg(feature = "integration-test")]
-const INTEGRATION_TESTS: [(&'static str, MainFn); 26] = [
+const INTEGRATION_TESTS: [(&'static str, MainFn); 28] = [
     ("exit", just_exit_ok),
     ("wrgsbase", wrgsbase),
     ("pfault-early", just_exit_fail),
@@ -34,7 +34,9 @@ const INTEGRATION_TESTS: [(&'static str, MainFn); 26] = [
     ("userspace-smp", userspace),
     ("vspace-debug", vspace_debug),
     ("shootdown-simple", shootdown_simple),
+    ("shootdown-queue-full", shootdown_queue_full),
     ("replica-advance", replica_advance),
+    ("reclaim-memory", reclaim_memory),
     ("vmxnet-smoltcp", vmxnet_smoltcp),
     ("gdb", gdb),
     ("cxl-read", cxl_read),
@@ -776,6 +778,115 @@ pub fn cxl_read() {
     shutdown(ExitReason::Ok);
 }
 
+/// Sends more shootdowns to every other core than their work queues can
+/// hold (without notifying them first), none of them may get lost.
+#[cfg(all(feature = "integration-test", target_arch = "x86_64"))]
+fn shootdown_queue_full() {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use fallible_collections::vec::FallibleVec;
+    use fallible_collections::FallibleVecGlobal;
+    use log::info;
+
+    use crate::arch;
+
+    const ROUNDS: usize = 64;
+    let my_gtid = kcb::get_kcb().arch.id();
+    let threads = atopology::MACHINE_TOPOLOGY.num_threads();
+
+    let mut shootdowns = Vec::try_with_capacity(threads * ROUNDS).expect("succeeds");
+    for _round in 0..ROUNDS {
+        for t in atopology::MACHINE_TOPOLOGY
+            .threads()
+            .filter(|t| t.id != my_gtid)
+        {
+            let shootdown =
+                Arc::try_new(arch::tlb::Shootdown::new(0x1000..0x2000)).expect("succeeds");
+            arch::tlb::enqueue(t.id, arch::tlb::WorkItem::Shootdown(shootdown.clone()));
+            shootdowns.try_push(shootdown).expect("succeeds");
+        }
+    }
+    info!("Enqueued {} shootdowns", shootdowns.len());
+
+    // Wakes up the cores for the shootdowns that are still in the queues
+    for t in atopology::MACHINE_TOPOLOGY
+        .threads()
+        .filter(|t| t.id != my_gtid)
+    {
+        arch::tlb::notify(t.id);
+    }
+    for shootdown in shootdowns {
+        shootdown.wait();
+    }
+
+    info!("All shootdowns acknowledged");
+    shutdown(ExitReason::Ok);
+}
+
+/// Exhausts the memory of our node, so refilling the TCache has to reclaim
+/// the frames that other cores cached before it fails with `OutOfMemory`.
+#[cfg(all(feature = "integration-test", target_arch = "x86_64"))]
+fn reclaim_memory() {
+    use core::sync::atomic::Ordering;
+
+    use kpi::MemType;
+    use log::info;
+
+    use crate::error::KError;
+    use crate::memory::{
+        AllocatorStatistics, Frame, KernelAllocator, PAddr, PhysicalPageProvider, BASE_PAGE_SIZE,
+        RECLAIM_REQUESTS,
+    };
+
+    const END_OF_LIST: u64 = u64::MAX;
+
+    let kcb = kcb::get_kcb();
+    let my_gtid = kcb.arch.id();
+    let node = kcb.physical_memory.affinity;
+    let gmanager = kcb.physical_memory.gmanager.expect("Have a gmanager");
+    let cached = gmanager.node_caches[node as usize].lock().free_base_pages()
+        + kcb.mem_manager().free_base_pages();
+
+    // The frames are linked through their first word, we can't allocate
+    // heap memory while the node is out of memory
+    let mut head = END_OF_LIST;
+    let mut allocated = 0;
+    let error = loop {
+        let frame = kcb.mem_manager().allocate_base_page();
+        match frame {
+            Ok(frame) => {
+                unsafe { *frame.kernel_vaddr().as_mut_ptr::<u64>() = head };
+                head = frame.base.as_u64();
+                allocated += 1;
+            }
+            Err(_) => match KernelAllocator::try_refill_tcache(64, 0, MemType::Mem) {
+                Ok(()) => continue,
+                // A partial refill still gives us some frames
+                Err(_) if kcb.mem_manager().free_base_pages() > 0 => continue,
+                Err(e) => break e,
+            },
+        }
+    };
+    let reclaimed = atopology::MACHINE_TOPOLOGY
+        .threads()
+        .filter(|t| t.id != my_gtid)
+        .all(|t| !RECLAIM_REQUESTS[t.id].load(Ordering::Acquire));
+
+    while head != END_OF_LIST {
+        let frame = Frame::new(PAddr::from(head), BASE_PAGE_SIZE, node);
+        head = unsafe { *frame.kernel_vaddr().as_ptr::<u64>() };
+        KernelAllocator::release_frame(frame, MemType::Mem).expect("Can't release frame");
+    }
+
+    assert_eq!(error, KError::OutOfMemory);
+    assert!(reclaimed, "All other cores gave their frames back");
+    assert!(allocated >= cached, "Got all frames of the node");
+    info!("Reclaimed {} frames from other cores", allocated - cached);
+    info!("Out of memory after reclaiming");
+    shutdown(ExitReason::Ok);
+}
+
 /// Test shootdown facilities in the kernel.
 #[cfg(all(feature = "integration-test", target_arch = "x86_64"))]
 fn shootdown_simple() {
@@ -0,0 +1,417 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Message channels between processes.
+//!
+//! A channel is a bounded queue of messages with a (numeric) name that
+//! processes attach to. The channels are part of the replicated state in
+//! [`KernelNode`](crate::nr::KernelNode), so processes can use them from any
+//! core on any replica.
+//!
+//! Only the creator of a channel, its parent and its children can attach to
+//! a channel by name; other processes have to be granted access by a process
+//! that is attached already.
+//!
+//! A process can be attached to at most `MAX_CHANNELS_PER_PROCESS` channels
+//! and a channel goes away once no process is attached to it, so processes
+//! can't use up the kernel heap with channels.
+
+// Only the system calls use the wrappers below
+#![cfg_attr(not(target_os = "none"), allow(dead_code))]
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use hashbrown::HashMap;
+use kpi::ipc::{ChannelId, MAX_CHANNELS_PER_PROCESS, MAX_CHANNEL_CAPACITY, MAX_MESSAGE_SIZE};
+use log::trace;
+
+use crate::arch::process::UserSlice;
+use crate::error::KError;
+use crate::nr::{NodeResult, Op};
+use crate::process::{KernSlice, Pid};
+
+struct Channel {
+    /// Name that processes use to attach to the channel.
+    name: u64,
+    /// The process that created the channel (until it exits).
+    creator: Option<Pid>,
+    /// Processes that were granted access to the channel.
+    granted: Vec<Pid>,
+    /// How many messages can be queued.
+    capacity: usize,
+    /// Processes attached to the channel.
+    attached: Vec<Pid>,
+    /// Queued messages, oldest first.
+    messages: VecDeque<Arc<[u8]>>,
+}
+
+/// All channels in the system.
+#[derive(Default)]
+pub struct Channels {
+    channels: HashMap<ChannelId, Channel>,
+    next_id: ChannelId,
+}
+
+impl Channels {
+    /// Looks up channel `id`, fails unless `pid` is attached to it.
+    fn get_mut(&mut self, pid: Pid, id: ChannelId) -> Result<&mut Channel, KError> {
+        self.channels
+            .get_mut(&id)
+            .filter(|channel| channel.attached.contains(&pid))
+            .ok_or(KError::ChannelNotFound)
+    }
+
+    /// Fails if `pid` can't attach to another channel.
+    fn check_limit(&self, pid: Pid) -> Result<(), KError> {
+        let attached = self
+            .channels
+            .values()
+            .filter(|channel| channel.attached.contains(&pid))
+            .count();
+        if attached >= MAX_CHANNELS_PER_PROCESS {
+            Err(KError::TooManyChannels)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn create(&mut self, pid: Pid, name: u64, capacity: usize) -> Result<ChannelId, KError> {
+        if capacity == 0 || capacity > MAX_CHANNEL_CAPACITY {
+            return Err(KError::InvalidLength);
+        }
+        if self.channels.values().any(|channel| channel.name == name) {
+            return Err(KError::ChannelExists);
+        }
+        self.check_limit(pid)?;
+
+        let mut messages = VecDeque::new();
+        messages.try_reserve(capacity)?;
+        let mut attached = Vec::new();
+        attached.try_push(pid)?;
+        self.channels.try_reserve(1)?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.channels.insert(
+            id,
+            Channel {
+                name,
+                creator: Some(pid),
+                granted: Vec::new(),
+                capacity,
+                attached,
+                messages,
+            },
+        );
+
+        Ok(id)
+    }
+
+    /// Attaches `pid` to channel `name`.
+    ///
+    /// `parents` has the parent of every process that was spawned by
+    /// another process.
+    pub fn attach(
+        &mut self,
+        pid: Pid,
+        name: u64,
+        parents: &HashMap<Pid, Pid>,
+    ) -> Result<ChannelId, KError> {
+        let limit = self.check_limit(pid);
+        let (id, channel) = self
+            .channels
+            .iter_mut()
+            .find(|(_id, channel)| channel.name == name)
+            .ok_or(KError::ChannelNotFound)?;
+
+        let related = channel.creator.map_or(false, |creator| {
+            parents.get(&pid) == Some(&creator) || parents.get(&creator) == Some(&pid)
+        });
+        if !related && !channel.granted.contains(&pid) && !channel.attached.contains(&pid) {
+            return Err(KError::ChannelAccessDenied);
+        }
+
+        if !channel.attached.contains(&pid) {
+            limit?;
+            channel.attached.try_push(pid)?;
+        }
+        Ok(*id)
+    }
+
+    /// Allows process `other` to attach to channel `id` (`pid` has to be
+    /// attached to it).
+    pub fn grant(&mut self, pid: Pid, id: ChannelId, other: Pid) -> Result<(), KError> {
+        let channel = self.get_mut(pid, id)?;
+        if !channel.granted.contains(&other) {
+            channel.granted.try_push(other)?;
+        }
+        Ok(())
+    }
+
+    /// Detaches `pid` from channel `id`, removes the channel if `pid` was
+    /// the last process attached to it.
+    pub fn detach(&mut self, pid: Pid, id: ChannelId) -> Result<(), KError> {
+        let channel = self.get_mut(pid, id)?;
+        channel.attached.retain(|&p| p != pid);
+        if channel.attached.is_empty() {
+            self.channels.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Detaches `pid` from all channels (i.e., when the process exits).
+    pub fn detach_all(&mut self, pid: Pid) {
+        for channel in self.channels.values_mut() {
+            channel.attached.retain(|&p| p != pid);
+            // The pid can be handed out again
+            channel.granted.retain(|&p| p != pid);
+            if channel.creator == Some(pid) {
+                channel.creator = None;
+            }
+        }
+        self.channels
+            .retain(|_id, channel| !channel.attached.is_empty());
+    }
+
+    pub fn send(&mut self, pid: Pid, id: ChannelId, message: Arc<[u8]>) -> Result<(), KError> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(KError::MessageTooLarge);
+        }
+
+        let channel = self.get_mut(pid, id)?;
+        if channel.messages.len() >= channel.capacity {
+            return Err(KError::ChannelFull);
+        }
+        // Can't fail, we reserved `capacity` when the channel was created
+        channel.messages.push_back(message);
+        Ok(())
+    }
+
+    /// Takes the oldest message out of channel `id` (if it's at most
+    /// `max_len` bytes long).
+    pub fn receive(
+        &mut self,
+        pid: Pid,
+        id: ChannelId,
+        max_len: usize,
+    ) -> Result<Arc<[u8]>, KError> {
+        let channel = self.get_mut(pid, id)?;
+        match channel.messages.front() {
+            Some(message) if message.len() > max_len => Err(KError::MessageTooLarge),
+            Some(_message) => Ok(channel.messages.pop_front().unwrap()),
+            None => Err(KError::ChannelEmpty),
+        }
+    }
+}
+
+fn execute(op: Op) -> Result<NodeResult, KError> {
+    let kcb = super::kcb::get_kcb();
+    kcb.replica
+        .as_ref()
+        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+            replica.execute_mut(op, *token)
+        })
+}
+
+/// Creates channel `name` and attaches `pid` to it.
+pub fn create(pid: Pid, name: u64, capacity: usize) -> Result<ChannelId, KError> {
+    match execute(Op::ChannelCreate(pid, name, capacity)) {
+        Ok(NodeResult::ChannelAttached(id)) => Ok(id),
+        Err(e) => Err(e),
+        Ok(_) => unreachable!("Got unexpected response"),
+    }
+}
+
+/// Attaches `pid` to the existing channel `name`.
+pub fn attach(pid: Pid, name: u64) -> Result<ChannelId, KError> {
+    match execute(Op::ChannelAttach(pid, name)) {
+        Ok(NodeResult::ChannelAttached(id)) => Ok(id),
+        Err(e) => Err(e),
+        Ok(_) => unreachable!("Got unexpected response"),
+    }
+}
+
+/// Lets process `other` attach to channel `id`.
+pub fn grant(pid: Pid, id: ChannelId, other: Pid) -> Result<(), KError> {
+    match execute(Op::ChannelGrant(pid, id, other)) {
+        Ok(NodeResult::ChannelGranted) => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => unreachable!("Got unexpected response"),
+    }
+}
+
+pub fn detach(pid: Pid, id: ChannelId) -> Result<(), KError> {
+    match execute(Op::ChannelDetach(pid, id)) {
+        Ok(NodeResult::ChannelDetached) => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => unreachable!("Got unexpected response"),
+    }
+}
+
+/// Copies the message at `buffer` (in the address space of `pid`) into the
+/// kernel and queues it in channel `id`.
+pub fn send(pid: Pid, id: ChannelId, buffer: u64, len: usize) -> Result<(), KError> {
+    if len > MAX_MESSAGE_SIZE {
+        return Err(KError::MessageTooLarge);
+    }
+
+    let kernslice = KernSlice::new(buffer, len);
+    match execute(Op::ChannelSend(pid, id, kernslice.buffer)) {
+        Ok(NodeResult::MessageSent) => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => unreachable!("Got unexpected response"),
+    }
+}
+
+/// Takes the oldest message out of channel `id` and copies it to `buffer`
+/// (in the address space of `pid`), returns the length of the message.
+pub fn receive(pid: Pid, id: ChannelId, buffer: u64, len: usize) -> Result<usize, KError> {
+    let message = match execute(Op::ChannelReceive(pid, id, len)) {
+        Ok(NodeResult::MessageReceived(message)) => message,
+        Err(e) => return Err(e),
+        Ok(_) => unreachable!("Got unexpected response"),
+    };
+    trace!("pid {} received {} bytes on {}", pid, message.len(), id);
+
+    let mut user_slice = UserSlice::new(buffer, message.len());
+    user_slice.copy_from_slice(&message);
+    Ok(message.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(len: usize) -> Arc<[u8]> {
+        alloc::vec![0xab; len].into()
+    }
+
+    fn no_parents() -> HashMap<Pid, Pid> {
+        HashMap::new()
+    }
+
+    #[test]
+    fn create_attach_detach() {
+        let mut channels = Channels::default();
+        let id = channels.create(1, 0xdead, 4).unwrap();
+        assert_eq!(channels.create(2, 0xdead, 4), Err(KError::ChannelExists));
+        assert_eq!(
+            channels.attach(2, 0xbeef, &no_parents()),
+            Err(KError::ChannelNotFound)
+        );
+        channels.grant(1, id, 2).unwrap();
+        assert_eq!(channels.attach(2, 0xdead, &no_parents()), Ok(id));
+
+        // The channel stays around until the last process detached
+        channels.detach(1, id).unwrap();
+        assert_eq!(channels.detach(1, id), Err(KError::ChannelNotFound));
+        channels.send(2, id, message(1)).unwrap();
+        channels.detach(2, id).unwrap();
+        assert_eq!(
+            channels.attach(1, 0xdead, &no_parents()),
+            Err(KError::ChannelNotFound)
+        );
+    }
+
+    #[test]
+    fn send_receive() {
+        let mut channels = Channels::default();
+        let id = channels.create(1, 1, 2).unwrap();
+        channels.grant(1, id, 2).unwrap();
+        assert_eq!(channels.attach(2, 1, &no_parents()), Ok(id));
+
+        assert_eq!(channels.receive(1, id, 8), Err(KError::ChannelEmpty));
+        channels.send(2, id, message(3)).unwrap();
+        channels.send(2, id, message(5)).unwrap();
+        assert_eq!(channels.send(2, id, message(1)), Err(KError::ChannelFull));
+        assert_eq!(
+            channels.send(2, id, message(MAX_MESSAGE_SIZE + 1)),
+            Err(KError::MessageTooLarge)
+        );
+        // Only attached processes can use the channel
+        assert_eq!(channels.receive(3, id, 8), Err(KError::ChannelNotFound));
+
+        assert_eq!(channels.receive(1, id, 8).unwrap().len(), 3);
+        assert_eq!(channels.receive(1, id, 4), Err(KError::MessageTooLarge));
+        assert_eq!(channels.receive(1, id, 5).unwrap().len(), 5);
+        assert_eq!(channels.receive(1, id, 8), Err(KError::ChannelEmpty));
+    }
+
+    #[test]
+    fn detach_all() {
+        let mut channels = Channels::default();
+        let a = channels.create(1, 1, 1).unwrap();
+        let b = channels.create(2, 2, 1).unwrap();
+        channels.grant(2, b, 1).unwrap();
+        assert_eq!(channels.attach(1, 2, &no_parents()), Ok(b));
+
+        channels.detach_all(1);
+        assert_eq!(
+            channels.attach(3, 1, &no_parents()),
+            Err(KError::ChannelNotFound)
+        );
+        assert_eq!(
+            channels.send(1, b, message(1)),
+            Err(KError::ChannelNotFound)
+        );
+        channels.send(2, b, message(1)).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn channel_limit() {
+        let mut channels = Channels::default();
+        for name in 0..MAX_CHANNELS_PER_PROCESS as u64 {
+            channels.create(1, name, 1).unwrap();
+        }
+        let other = channels.create(2, u64::MAX, 1).unwrap();
+        channels.grant(2, other, 1).unwrap();
+        assert_eq!(
+            channels.create(1, u64::MAX - 1, 1),
+            Err(KError::TooManyChannels)
+        );
+        assert_eq!(
+            channels.attach(1, u64::MAX, &no_parents()),
+            Err(KError::TooManyChannels)
+        );
+        // Attaching again to a channel doesn't count
+        assert_eq!(channels.attach(1, 0, &no_parents()), Ok(0));
+
+        // Detaching makes room for another channel
+        channels.detach(1, 0).unwrap();
+        assert_eq!(channels.attach(1, u64::MAX, &no_parents()), Ok(other));
+    }
+
+    #[test]
+    fn access_control() {
+        let mut channels = Channels::default();
+        let id = channels.create(1, 1, 1).unwrap();
+        let mut parents = HashMap::new();
+        parents.insert(2, 1);
+        parents.insert(1, 3);
+        parents.insert(4, 2);
+
+        // The children and the parent of the creator can attach
+        assert_eq!(channels.attach(2, 1, &parents), Ok(id));
+        assert_eq!(channels.attach(3, 1, &parents), Ok(id));
+        // Others need to be granted access
+        assert_eq!(
+            channels.attach(4, 1, &parents),
+            Err(KError::ChannelAccessDenied)
+        );
+        assert_eq!(channels.grant(5, id, 4), Err(KError::ChannelNotFound));
+        channels.grant(2, id, 4).unwrap();
+        assert_eq!(channels.attach(4, 1, &parents), Ok(id));
+
+        // Relationships end with the creator
+        channels.detach_all(1);
+        channels.detach(3, id).unwrap();
+        assert_eq!(
+            channels.attach(3, 1, &parents),
+            Err(KError::ChannelAccessDenied)
+        );
+    }
+}
@@ -5,11 +5,12 @@
 
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::cell::{RefCell, RefMut};
 use core::fmt::Debug;
 use core::slice::from_raw_parts;
 
-use arrayvec::ArrayVec;
+use fallible_collections::FallibleVec;
 use log::error;
 use logos::Logos;
 use node_replication::{Replica, ReplicaToken};
@@ -20,19 +21,23 @@ use crate::arch::memory::paddr_to_kernel_vaddr;
 use crate::arch::MAX_NUMA_NODES;
 use crate::error::KError;
 
-use crate::arch::process::PROCESS_TABLE;
 use crate::memory::emem::EmergencyAllocator;
 use crate::memory::mcache::TCache;
 use crate::memory::mcache::TCacheSp;
-use crate::memory::{AllocatorStatistics, GlobalMemory, GrowBackend, PAddr, PhysicalPageProvider};
+use crate::memory::{
+    AllocatorStatistics, GlobalMemory, GrowBackend, PAddr, PhysicalPageProvider, ReapBackend,
+};
 use crate::nr::KernelNode;
-use crate::nrproc::NrProcess;
+use crate::nrproc::{NrProcess, ProcessTable};
 use crate::process::{Pid, Process, MAX_PROCESSES};
 
 pub use crate::arch::kcb::{get_kcb, try_get_kcb};
 use driverkit::pci::PciDevice;
 
-pub trait MemManager: PhysicalPageProvider + AllocatorStatistics + GrowBackend {}
+pub trait MemManager:
+    PhysicalPageProvider + AllocatorStatistics + GrowBackend + ReapBackend
+{
+}
 
 /// Definition to parse the kernel command-line arguments.
 #[derive(Logos, Debug, PartialEq, Clone, Copy)]
@@ -45,6 +50,10 @@ enum CmdToken {
     #[token("bsp-only")]
     BspOnly,
 
+    /// Keep the files in persistent memory
+    #[token("pmemfs")]
+    PmemFs,
+
     /// Run kernel test
     #[token("test")]
     Test,
@@ -65,6 +74,10 @@ enum CmdToken {
     #[token("appcmd")]
     AppArgs,
 
+    /// How many processes can exist at the same time.
+    #[token("maxprocs")]
+    MaxProcesses,
+
     #[regex("[a-zA-Z0-9\\._-]*")]
     Ident,
 
@@ -95,6 +108,9 @@ pub struct BootloaderArguments {
     pub test: Option<&'static str>,
     pub bsp_only: bool,
     pub kgdb: bool,
+    pub max_processes: usize,
+    /// Use the first persistent memory region for the file-system.
+    pub pmemfs: bool,
 }
 
 impl Default for BootloaderArguments {
@@ -107,6 +123,8 @@ impl Default for BootloaderArguments {
             bsp_only: false,
             test: None,
             kgdb: false,
+            max_processes: MAX_PROCESSES,
+            pmemfs: false,
         }
     }
 }
@@ -126,6 +144,8 @@ impl BootloaderArguments {
             bsp_only: false,
             test: None,
             kgdb: false,
+            max_processes: MAX_PROCESSES,
+            pmemfs: false,
         }
     }
 
@@ -155,11 +175,15 @@ impl BootloaderArguments {
                 CmdToken::BspOnly => {
                     parsed_args.bsp_only = true;
                 }
+                CmdToken::PmemFs => {
+                    parsed_args.pmemfs = true;
+                }
                 CmdToken::Log
                 | CmdToken::Test
                 | CmdToken::InitBinary
                 | CmdToken::InitArgs
-                | CmdToken::AppArgs => {
+                | CmdToken::AppArgs
+                | CmdToken::MaxProcesses => {
                     prev = token;
                 }
                 CmdToken::Ident => match prev {
@@ -183,6 +207,15 @@ impl BootloaderArguments {
                         parsed_args.test = Some(slice);
                         prev = CmdToken::Error;
                     }
+                    CmdToken::MaxProcesses => {
+                        match slice.parse::<usize>() {
+                            Ok(max_processes) if max_processes > 0 => {
+                                parsed_args.max_processes = max_processes;
+                            }
+                            _ => error!("Invalid maxprocs={} in cmd arguments: {}", slice, args),
+                        }
+                        prev = CmdToken::Error;
+                    }
                     _ => {
                         error!("Invalid cmd arguments: {} (skipped {})", args, slice);
                         continue;
@@ -194,6 +227,7 @@ impl BootloaderArguments {
                         && prev != CmdToken::InitArgs
                         && prev != CmdToken::AppArgs
                         && prev != CmdToken::Test
+                        && prev != CmdToken::MaxProcesses
                     {
                         error!("Malformed args (unexpected equal sign) in {}", args);
                         continue;
@@ -329,8 +363,8 @@ where
     /// Measures cycles spent in TLB shootdown handler for responder.
     pub tlb_time: u64,
 
-    /// Tokens to access process replicas
-    pub process_token: ArrayVec<ReplicaToken, { MAX_PROCESSES }>,
+    /// Tokens to access process replicas (indexed by pid, registered lazily)
+    process_token: Vec<Option<ReplicaToken>>,
 
     /// Reference to a shared memory device.
     pub ivshmem_dev: Option<PciDevice>,
@@ -363,7 +397,7 @@ impl<A: ArchSpecificKcb> Kcb<A> {
             print_buffer: None,
             replica: None,
             tlb_time: 0,
-            process_token: ArrayVec::new_const(),
+            process_token: Vec::new(),
             ivshmem_dev: None,
         }
     }
@@ -376,17 +410,32 @@ impl<A: ArchSpecificKcb> Kcb<A> {
         self.replica = Some((replica, idx_token));
     }
 
-    pub fn register_with_process_replicas(&mut self) {
-        let node = self.arch.node();
-        debug_assert!(PROCESS_TABLE.len() > node, "Invalid Node ID");
-
-        for pid in 0..MAX_PROCESSES {
-            debug_assert!(PROCESS_TABLE[node].len() > pid, "Invalid PID");
+    /// Returns the replica of process `pid` on the local NUMA node and the
+    /// token this core uses to access it.
+    ///
+    /// The core registers with a process replica when it accesses it for the
+    /// first time.
+    #[allow(clippy::type_complexity)]
+    pub fn process_replica(
+        &mut self,
+        pid: Pid,
+    ) -> Result<(Arc<Replica<'static, NrProcess<A::Process>>>, ReplicaToken), KError> {
+        let replica = self.arch.process_table().get(self.arch.node(), pid)?;
+        if let Some(Some(token)) = self.process_token.get(pid) {
+            return Ok((replica, *token));
+        }
 
-            let token = PROCESS_TABLE[node][pid].register();
-            self.process_token
-                .push(token.expect("Need to be able to register"));
+        let token = replica
+            .register()
+            .ok_or(KError::ReplicaRegistrationFailed)?;
+        if self.process_token.len() <= pid {
+            let additional = pid + 1 - self.process_token.len();
+            FallibleVec::try_reserve(&mut self.process_token, additional)?;
+            self.process_token.resize(pid + 1, None);
         }
+        self.process_token[pid] = Some(token);
+
+        Ok((replica, token))
     }
 
     pub fn set_panic_mode(&mut self) {
@@ -541,6 +590,25 @@ impl<A: ArchSpecificKcb> Kcb<A> {
             .map_or(self.emanager(), |pmem| pmem.borrow_mut())
     }
 
+    /// How many base- and large-pages the TCaches of this core gave back to
+    /// the NCaches so far (summed over all arenas).
+    pub fn reaped_pages(&self) -> (usize, usize) {
+        let arenas = core::iter::once(&self.physical_memory)
+            .chain(core::iter::once(&self.pmem_memory))
+            .chain(self.memory_arenas.iter().flatten())
+            .chain(self.pmem_arenas.iter().flatten());
+
+        arenas
+            .filter_map(|arena| arena.pmanager.as_ref())
+            .filter_map(|tcache| tcache.try_borrow().ok())
+            .fold((0, 0), |(base, large), tcache| {
+                (
+                    base + tcache.reaped_base_pages(),
+                    large + tcache.reaped_large_pages(),
+                )
+            })
+    }
+
     pub fn kernel_binary(&self) -> &'static [u8] {
         self.kernel_binary
     }
@@ -551,20 +619,14 @@ impl<A: ArchSpecificKcb> Kcb<A> {
 }
 
 pub trait ArchSpecificKcb {
-    type Process: Process + Sync;
+    type Process: Process + Sync + 'static;
 
     fn node(&self) -> usize;
     fn hwthread_id(&self) -> usize;
     fn install(&mut self);
     fn current_pid(&self) -> Result<Pid, KError>;
 
-    #[allow(clippy::type_complexity)] // fix this once `associated_type_defaults` works
-    fn process_table(
-        &self,
-    ) -> &'static ArrayVec<
-        ArrayVec<Arc<Replica<'static, NrProcess<Self::Process>>>, MAX_PROCESSES>,
-        MAX_NUMA_NODES,
-    >;
+    fn process_table(&self) -> &'static ProcessTable<Self::Process>;
 }
 
 #[cfg(test)]
@@ -686,6 +748,16 @@ mod test {
         assert_eq!(ba.log_filter, "gdbstub=trace,nrk::arch::gdb=trace");
     }
 
+    #[test]
+    fn parse_args_maxprocs() {
+        let ba = BootloaderArguments::from_str("./kernel maxprocs=512 log=debug");
+        assert_eq!(ba.max_processes, 512);
+        assert_eq!(ba.log_filter, "debug");
+
+        let ba = BootloaderArguments::from_str("./kernel maxprocs=0");
+        assert_eq!(ba.max_processes, crate::process::MAX_PROCESSES);
+    }
+
     #[test]
     fn parse_test() {
         let args = "./kernel test=userspace";
@@ -61,6 +61,7 @@ mod cnrfs;
 mod error;
 mod fs;
 mod graphviz;
+mod ipc;
 mod kcb;
 mod memory;
 mod nr;
@@ -72,6 +73,7 @@ mod mpmc;
 mod process;
 mod scheduler;
 mod stack;
+mod vectors;
 
 pub mod panic;
 
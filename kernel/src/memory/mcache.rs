@@ -23,12 +23,12 @@ use static_assertions as sa;
 use super::*;
 
 /// A big cache of base and large pages, fits on a 2 MiB page.
-pub type NCache = MCache<131071, 131070>;
+pub type NCache = MCache<131069, 131070>;
 sa::assert_eq_size!(NCache, [u8; LARGE_PAGE_SIZE]);
 sa::const_assert!(core::mem::align_of::<NCache>() <= super::BASE_PAGE_SIZE);
 
 /// A small cache of 4 KiB and 2 MiB pages, fits on a 4K page.
-pub type TCache = MCache<381, 128>;
+pub type TCache = MCache<379, 128>;
 sa::assert_eq_size!(TCache, [u8; BASE_PAGE_SIZE]);
 sa::const_assert!(core::mem::align_of::<TCache>() <= super::BASE_PAGE_SIZE);
 
@@ -48,6 +48,10 @@ pub struct MCache<const BP: usize, const LP: usize> {
     base_page_addresses: arrayvec::ArrayVec<PAddr, BP>,
     /// A vector of free, cached large-page addresses
     large_page_addresses: arrayvec::ArrayVec<PAddr, LP>,
+    /// How many base-pages were taken away from us with `ReapBackend`.
+    reaped_base_pages: usize,
+    /// How many large-pages were taken away from us with `ReapBackend`.
+    reaped_large_pages: usize,
 }
 
 impl<const BP: usize, const LP: usize> crate::kcb::MemManager for MCache<BP, LP> {}
@@ -58,6 +62,8 @@ impl<const BP: usize, const LP: usize> MCache<BP, LP> {
             node,
             base_page_addresses: arrayvec::ArrayVec::new_const(),
             large_page_addresses: arrayvec::ArrayVec::new_const(),
+            reaped_base_pages: 0,
+            reaped_large_pages: 0,
         }
     }
 
@@ -168,6 +174,8 @@ impl<const BP: usize, const LP: usize> MCache<BP, LP> {
             (*(ncache.as_mut_ptr())).node = node;
             (*(ncache.as_mut_ptr())).base_page_addresses = arrayvec::ArrayVec::new_const();
             (*(ncache.as_mut_ptr())).large_page_addresses = arrayvec::ArrayVec::new_const();
+            (*(ncache.as_mut_ptr())).reaped_base_pages = 0;
+            (*(ncache.as_mut_ptr())).reaped_large_pages = 0;
             ncache.assume_init_mut()
         }
     }
@@ -240,6 +248,16 @@ impl<const BP: usize, const LP: usize> AllocatorStatistics for MCache<BP, LP> {
     fn free_large_pages(&self) -> usize {
         self.large_page_addresses.len()
     }
+
+    /// How many base-pages were reaped from the cache.
+    fn reaped_base_pages(&self) -> usize {
+        self.reaped_base_pages
+    }
+
+    /// How many large-pages were reaped from the cache.
+    fn reaped_large_pages(&self) -> usize {
+        self.reaped_large_pages
+    }
 }
 
 impl<const BP: usize, const LP: usize> PhysicalPageProvider for MCache<BP, LP> {
@@ -322,6 +340,7 @@ impl<const BP: usize, const LP: usize> ReapBackend for MCache<BP, LP> {
         for insert in free_list.iter_mut() {
             if let Some(paddr) = self.base_page_addresses.pop() {
                 *insert = Some(self.paddr_to_base_page(paddr));
+                self.reaped_base_pages += 1;
             } else {
                 // We don't have anything left in our cache
                 break;
@@ -334,6 +353,7 @@ impl<const BP: usize, const LP: usize> ReapBackend for MCache<BP, LP> {
         for insert in free_list.iter_mut() {
             if let Some(paddr) = self.large_page_addresses.pop() {
                 *insert = Some(self.paddr_to_large_page(paddr));
+                self.reaped_large_pages += 1;
             } else {
                 // We don't have anything left in our cache
                 break;
@@ -584,6 +604,7 @@ mod test {
         assert_eq!(free_list[0].unwrap().affinity, 4);
         assert!(free_list[1].is_none());
         assert!(free_list[2].is_none());
+        assert_eq!(tcache.reaped_base_pages(), 2);
 
         let mut free_list = [None, None];
         tcache.reap_large_pages(&mut free_list);
@@ -593,6 +614,7 @@ mod test {
         assert_eq!(free_list[1].unwrap().base.as_usize(), LARGE_PAGE_SIZE);
         assert_eq!(free_list[1].unwrap().size, LARGE_PAGE_SIZE);
         assert_eq!(free_list[1].unwrap().affinity, 4);
+        assert_eq!(tcache.reaped_large_pages(), 2);
     }
 
     /// Test that release and allocate works as expected.
@@ -16,16 +16,19 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::intrinsics::likely;
 use core::mem::transmute;
-use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
 use core::{fmt, ptr};
 
 use arrayvec::ArrayVec;
+use lazy_static::lazy_static;
 use log::{debug, error, trace, warn};
 use slabmalloc::{Allocator, ZoneAllocator};
 use spin::Mutex;
 use x86::bits64::paging;
 
 use crate::arch::MAX_NUMA_NODES;
+use crate::kcb::{MemManager, PhysicalMemoryArena};
 use crate::prelude::*;
 use crate::{kcb, round_up};
 
@@ -41,6 +44,7 @@ use vspace::MapAction;
 pub mod detmem;
 pub mod emem;
 pub mod mcache;
+pub mod policy;
 pub mod vspace;
 #[cfg(test)]
 pub mod vspace_model;
@@ -48,6 +52,59 @@ pub mod vspace_model;
 /// How many initial physical memory regions we support.
 pub const MAX_PHYSICAL_REGIONS: usize = 64;
 
+/// A TCache that holds more free base-pages than this after a release gives
+/// base-pages back to its NCache.
+const TCACHE_BASE_HIGH_WATERMARK: usize = 256;
+
+/// How many free base-pages a TCache keeps when it gives memory back because
+/// it reached `TCACHE_BASE_HIGH_WATERMARK`.
+const TCACHE_BASE_LOW_WATERMARK: usize = 64;
+
+/// A TCache that holds more free large-pages than this after a release gives
+/// large-pages back to its NCache.
+const TCACHE_LARGE_HIGH_WATERMARK: usize = 64;
+
+/// How many free large-pages a TCache keeps when it gives memory back because
+/// it reached `TCACHE_LARGE_HIGH_WATERMARK`.
+const TCACHE_LARGE_LOW_WATERMARK: usize = 8;
+
+/// How long a core that ran out of memory waits for the other cores to give
+/// their free frames back.
+const RECLAIM_TIMEOUT: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    /// Set for every core that was asked to give all free frames in its TCaches
+    /// back to the NCaches (see `KernelAllocator::reclaim_node_memory`).
+    ///
+    /// A core clears its flag once it gave the memory back, which happens
+    /// either on an IPI or lazily during the next timer interrupt.
+    pub(crate) static ref RECLAIM_REQUESTS: Vec<CachePadded<AtomicBool>> = {
+        let num_threads = atopology::MACHINE_TOPOLOGY.num_threads();
+        let mut requests =
+            Vec::try_with_capacity(num_threads).expect("Not enough memory to initialize system");
+        for _i in 0..num_threads {
+            requests.push(CachePadded::new(AtomicBool::new(false)));
+        }
+
+        requests
+    };
+
+    /// The ids of all cores in the system, so `reclaim_node_memory` doesn't
+    /// have to walk the topology while it waits.
+    ///
+    /// Both statics are initialized during boot, a core that ran out of memory
+    /// can't allocate them.
+    pub(crate) static ref RECLAIM_CORES: Vec<atopology::GlobalThreadId> = {
+        let mut cores = Vec::try_with_capacity(atopology::MACHINE_TOPOLOGY.num_threads())
+            .expect("Not enough memory to initialize system");
+        for thread in atopology::MACHINE_TOPOLOGY.threads() {
+            cores.push(thread.id);
+        }
+
+        cores
+    };
+}
+
 /// The global allocator in the kernel.
 //#[cfg(not(any(test, fuzzing)))]
 #[cfg(target_os = "none")]
@@ -278,10 +335,42 @@ impl KernelAllocator {
     }
 
     /// Try to refill our core-local tcache.
+    ///
+    /// If the NCache of our node runs out of memory, we ask all other cores to
+    /// give back the frames cached in their TCaches and try again (cores of
+    /// other nodes can hold frames of our node too, see
+    /// `reclaim_node_memory`).
     pub fn try_refill_tcache(
         needed_base_pages: usize,
         needed_large_pages: usize,
         mem_type: MemType,
+    ) -> Result<(), KError> {
+        match KernelAllocator::refill_tcache_from_ncache(
+            needed_base_pages,
+            needed_large_pages,
+            mem_type,
+        ) {
+            Err(KError::CacheExhausted) => {
+                KernelAllocator::reclaim_node_memory(mem_type)?;
+                KernelAllocator::refill_tcache_from_ncache(
+                    needed_base_pages,
+                    needed_large_pages,
+                    mem_type,
+                )
+                .map_err(|e| match e {
+                    KError::CacheExhausted => KError::OutOfMemory,
+                    e => e,
+                })
+            }
+            r => r,
+        }
+    }
+
+    /// Moves frames from the NCache of our node to our core-local tcache.
+    fn refill_tcache_from_ncache(
+        needed_base_pages: usize,
+        needed_large_pages: usize,
+        mem_type: MemType,
     ) -> Result<(), KError> {
         let kcb = kcb::try_get_kcb().ok_or(KError::KcbUnavailable)?;
         if mem_type == MemType::Mem && kcb.physical_memory.gmanager.is_none() {
@@ -330,6 +419,125 @@ impl KernelAllocator {
         Ok(())
     }
 
+    /// Give a (base or large page) frame back to the core-local tcache, or to
+    /// the ncache of the frame's node in case the tcache is full or the frame
+    /// is from a different node than the tcache.
+    pub fn release_frame(frame: Frame, mem_type: MemType) -> Result<(), KError> {
+        let kcb = kcb::try_get_kcb().ok_or(KError::KcbUnavailable)?;
+        let (gmanager, mut mem_manager, affinity) = match mem_type {
+            MemType::Mem => (
+                kcb.physical_memory.gmanager,
+                kcb.try_mem_manager()?,
+                kcb.physical_memory.affinity,
+            ),
+            MemType::PMem => (
+                kcb.pmem_memory.gmanager,
+                kcb.pmem_manager(),
+                kcb.pmem_memory.affinity,
+            ),
+            _ => unreachable!(),
+        };
+
+        let r = match frame.size() {
+            BASE_PAGE_SIZE | LARGE_PAGE_SIZE if frame.affinity != affinity => {
+                Err(KError::InvalidAffinityId)
+            }
+            BASE_PAGE_SIZE => mem_manager.release_base_page(frame),
+            LARGE_PAGE_SIZE => mem_manager.release_large_page(frame),
+            _ => return Err(KError::InvalidFrame),
+        };
+
+        match (r, gmanager) {
+            (Ok(()), Some(gmanager))
+                if mem_manager.free_base_pages() > TCACHE_BASE_HIGH_WATERMARK
+                    || mem_manager.free_large_pages() > TCACHE_LARGE_HIGH_WATERMARK =>
+            {
+                let mut ncache = gmanager.node_caches[frame.affinity as usize].lock();
+                give_back(
+                    &mut *mem_manager,
+                    &mut **ncache,
+                    TCACHE_BASE_LOW_WATERMARK,
+                    TCACHE_LARGE_LOW_WATERMARK,
+                )
+            }
+            (Ok(()), _) => Ok(()),
+            (Err(_e), Some(gmanager)) => {
+                let mut ncache = gmanager.node_caches[frame.affinity as usize].lock();
+                if frame.size() == BASE_PAGE_SIZE {
+                    ncache.release_base_page(frame)
+                } else {
+                    ncache.release_large_page(frame)
+                }
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    /// Asks all other cores to give the free frames in their TCaches back to
+    /// the NCaches and waits (at most `RECLAIM_TIMEOUT`) until they did.
+    ///
+    /// Cores of other nodes can hold frames of our node too, in the arenas
+    /// they use to allocate with a NUMA policy (see `policy`).
+    fn reclaim_node_memory(mem_type: MemType) -> Result<(), KError> {
+        let kcb = kcb::try_get_kcb().ok_or(KError::KcbUnavailable)?;
+        let node = match mem_type {
+            MemType::Mem => kcb.physical_memory.affinity,
+            MemType::PMem => kcb.pmem_memory.affinity,
+            _ => unreachable!(),
+        };
+        let gtid = kcb.arch.id();
+        let remote_cores = || RECLAIM_CORES.iter().filter(|id| **id as usize != gtid);
+
+        for id in remote_cores() {
+            RECLAIM_REQUESTS[*id as usize].store(true, Ordering::Release);
+            crate::arch::request_reclaim(*id);
+        }
+
+        let start = rawtime::Instant::now();
+        while start.elapsed() < RECLAIM_TIMEOUT {
+            let pending =
+                remote_cores().any(|id| RECLAIM_REQUESTS[*id as usize].load(Ordering::Acquire));
+            if !pending {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+
+        debug!("Not all cores gave memory of node {} back in time", node);
+        Ok(())
+    }
+
+    /// Gives all free frames in our core-local tcaches back to the NCaches
+    /// if another core asked us to (see `reclaim_node_memory`).
+    ///
+    /// This should only be called in places where we don't rely on frames
+    /// we put in the TCache earlier (e.g., interrupt handlers).
+    pub fn reclaim_if_requested() {
+        let kcb = match kcb::try_get_kcb() {
+            Some(kcb) => kcb,
+            None => return,
+        };
+        let request = match RECLAIM_REQUESTS.get(kcb.arch.id()) {
+            Some(request) if request.load(Ordering::Acquire) => request,
+            _ => return,
+        };
+
+        let r = reap_arena(&kcb.physical_memory)
+            .and_then(|_| reap_arena(&kcb.pmem_memory))
+            .and_then(|_| {
+                kcb.memory_arenas
+                    .iter()
+                    .chain(kcb.pmem_arenas.iter())
+                    .flatten()
+                    .try_for_each(reap_arena)
+            });
+        match r {
+            Ok(()) => request.store(false, Ordering::Release),
+            // We'll try again on the next timer interrupt
+            Err(e) => debug!("Unable to give memory back: {:?}", e),
+        }
+    }
+
     /// Refill TCache only if the layout will exhaust the cache's current
     /// stored memory
     ///
@@ -418,6 +626,69 @@ impl KernelAllocator {
     }
 }
 
+/// Moves free frames from `tcache` to `ncache` until `tcache` holds at most
+/// `keep_base` base-pages and `keep_large` large-pages (or `ncache` is full).
+fn give_back(
+    tcache: &mut dyn MemManager,
+    ncache: &mut dyn MemManager,
+    keep_base: usize,
+    keep_large: usize,
+) -> Result<(), KError> {
+    const BATCH_SIZE: usize = 32;
+
+    loop {
+        let count = core::cmp::min(
+            tcache.free_base_pages().saturating_sub(keep_base),
+            ncache.spare_base_page_capacity(),
+        );
+        if count == 0 {
+            break;
+        }
+
+        let mut free_list = [None; BATCH_SIZE];
+        let count = core::cmp::min(count, BATCH_SIZE);
+        tcache.reap_base_pages(&mut free_list[..count]);
+        for frame in free_list.iter().flatten() {
+            ncache.release_base_page(*frame)?;
+        }
+    }
+
+    loop {
+        let count = core::cmp::min(
+            tcache.free_large_pages().saturating_sub(keep_large),
+            ncache.spare_large_page_capacity(),
+        );
+        if count == 0 {
+            break;
+        }
+
+        let mut free_list = [None; BATCH_SIZE];
+        let count = core::cmp::min(count, BATCH_SIZE);
+        tcache.reap_large_pages(&mut free_list[..count]);
+        for frame in free_list.iter().flatten() {
+            ncache.release_large_page(*frame)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gives all free frames of the core-local tcache in `arena` back to the
+/// NCache of the arena's node.
+///
+/// This covers the arenas a core isn't using at the moment too (see
+/// `Kcb::memory_arenas` and `Kcb::pmem_arenas`).
+fn reap_arena(arena: &PhysicalMemoryArena) -> Result<(), KError> {
+    match (arena.gmanager, arena.pmanager.as_ref()) {
+        (Some(gmanager), Some(pmanager)) => {
+            let mut tcache = pmanager.try_borrow_mut()?;
+            let mut ncache = gmanager.node_caches[arena.affinity as usize].lock();
+            give_back(&mut *tcache, &mut **ncache, 0, 0)
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Implementation of GlobalAlloc for the kernel.
 ///
 /// The algorithm in alloc/dealloc should take care of allocating kernel objects of
@@ -858,6 +1129,16 @@ pub trait AllocatorStatistics {
     fn free_large_pages(&self) -> usize {
         0
     }
+
+    /// How many base-pages the allocator gave back through `ReapBackend`.
+    fn reaped_base_pages(&self) -> usize {
+        0
+    }
+
+    /// How many large-pages the allocator gave back through `ReapBackend`.
+    fn reaped_large_pages(&self) -> usize {
+        0
+    }
 }
 
 pub trait PhysicalAllocator {
@@ -1231,6 +1512,73 @@ impl<'a> PageTableProvider<'a> for NRKPageTableProvider {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use mcache::TCache;
+
+    /// A TCache gives its frames back until it reaches the low watermark.
+    #[test]
+    fn give_back_to_ncache() {
+        let mut tcache = TCache::new(0);
+        let mut ncache = TCache::new(0);
+        for i in 0..100 {
+            tcache
+                .release_base_page(Frame::new(
+                    PAddr::from(i * BASE_PAGE_SIZE),
+                    BASE_PAGE_SIZE,
+                    0,
+                ))
+                .expect("release");
+        }
+        for i in 1..11 {
+            tcache
+                .release_large_page(Frame::new(
+                    PAddr::from(i * LARGE_PAGE_SIZE),
+                    LARGE_PAGE_SIZE,
+                    0,
+                ))
+                .expect("release");
+        }
+
+        give_back(&mut tcache, &mut ncache, 40, 2).expect("give back");
+        assert_eq!(tcache.free_base_pages(), 40);
+        assert_eq!(tcache.free_large_pages(), 2);
+        assert_eq!(tcache.reaped_base_pages(), 60);
+        assert_eq!(tcache.reaped_large_pages(), 8);
+        assert_eq!(ncache.free_base_pages(), 60);
+        assert_eq!(ncache.free_large_pages(), 8);
+
+        give_back(&mut tcache, &mut ncache, 0, 0).expect("give back");
+        assert_eq!(tcache.free(), 0);
+        assert_eq!(ncache.free(), 100 * BASE_PAGE_SIZE + 10 * LARGE_PAGE_SIZE);
+    }
+
+    /// We don't reap more than what fits in the NCache.
+    #[test]
+    fn give_back_ncache_full() {
+        let mut tcache = TCache::new(0);
+        let mut ncache = TCache::new(0);
+        for i in 0..300 {
+            ncache
+                .release_base_page(Frame::new(
+                    PAddr::from(i * BASE_PAGE_SIZE),
+                    BASE_PAGE_SIZE,
+                    0,
+                ))
+                .expect("release");
+        }
+        for i in 300..379 + 20 {
+            tcache
+                .release_base_page(Frame::new(
+                    PAddr::from(i * BASE_PAGE_SIZE),
+                    BASE_PAGE_SIZE,
+                    0,
+                ))
+                .expect("release");
+        }
+
+        give_back(&mut tcache, &mut ncache, 0, 0).expect("give back");
+        assert_eq!(ncache.spare_base_page_capacity(), 0);
+        assert_eq!(tcache.free_base_pages(), 20);
+    }
 
     #[test]
     fn frame_iter() {
@@ -0,0 +1,285 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Allocates the memory of a process according to its `AllocationPolicy`.
+//!
+//! A core only refills its TCache from the NCache of the node its current
+//! arena belongs to. To get frames from another node, we switch the arena
+//! (see `Kcb::set_mem_affinity`), allocate and switch back once we're done.
+//! We don't refill the TCache of such a (remote) arena, the frames come
+//! straight from the NCache of the node, so no free frames stay behind in an
+//! arena the core doesn't use afterwards. The frames keep their node in
+//! `Frame::affinity`, so `release_frame` can give them back to the right
+//! NCache later.
+
+use arrayvec::ArrayVec;
+use fallible_collections::FallibleVec;
+use kpi::{AllocationPolicy, MemType};
+use log::warn;
+
+use crate::arch::MAX_NUMA_NODES;
+use crate::error::KError;
+use crate::kcb::{self, ArchSpecificKcb};
+use crate::prelude::*;
+
+use super::{Frame, KernelAllocator, PhysicalPageProvider};
+
+/// How many NUMA nodes we can allocate from.
+fn num_nodes() -> usize {
+    core::cmp::min(
+        core::cmp::max(atopology::MACHINE_TOPOLOGY.num_nodes(), 1),
+        MAX_NUMA_NODES,
+    )
+}
+
+/// Checks that `policy` only refers to NUMA nodes that exist.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn validate(policy: AllocationPolicy) -> Result<(), KError> {
+    match policy {
+        AllocationPolicy::Preferred(node) | AllocationPolicy::Bind(node) if node >= num_nodes() => {
+            Err(KError::InvalidAffinityId)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns the nodes (in the order we try them) to allocate the `nth` frame
+/// of a mapping from.
+fn candidate_nodes(
+    policy: AllocationPolicy,
+    local: atopology::NodeId,
+    num_nodes: usize,
+    nth: usize,
+) -> ArrayVec<atopology::NodeId, MAX_NUMA_NODES> {
+    let first = match policy {
+        AllocationPolicy::Local => local,
+        AllocationPolicy::Preferred(node) | AllocationPolicy::Bind(node) => node,
+        AllocationPolicy::Interleave => nth % num_nodes,
+    };
+
+    let mut nodes = ArrayVec::new();
+    nodes.push(first);
+    if let AllocationPolicy::Preferred(_) | AllocationPolicy::Interleave = policy {
+        nodes.extend((0..num_nodes).filter(|node| *node != first));
+    }
+    nodes
+}
+
+/// Makes the core allocate `mem_type` memory from `node`.
+fn set_affinity(mem_type: MemType, node: atopology::NodeId) -> Result<(), KError> {
+    let kcb = kcb::get_kcb();
+    match mem_type {
+        MemType::Mem => kcb.set_mem_affinity(node),
+        MemType::PMem => kcb.set_pmem_affinity(node),
+        _ => unreachable!(),
+    }
+}
+
+/// Takes a frame from the TCache of the core's current arena.
+fn allocate_from_tcache(mem_type: MemType, large: bool) -> Result<Frame, KError> {
+    let kcb = kcb::get_kcb();
+    let mut tcache = match mem_type {
+        MemType::Mem => kcb.try_mem_manager()?,
+        MemType::PMem => kcb.pmem_manager(),
+        _ => unreachable!(),
+    };
+
+    if large {
+        tcache.allocate_large_page()
+    } else {
+        tcache.allocate_base_page()
+    }
+}
+
+/// Takes a frame from the NCache of the core's current arena, asks the other
+/// cores to give their free frames back if it's empty.
+fn allocate_from_ncache(mem_type: MemType, large: bool) -> Result<Frame, KError> {
+    let kcb = kcb::get_kcb();
+    let arena = match mem_type {
+        MemType::Mem => &kcb.physical_memory,
+        MemType::PMem => &kcb.pmem_memory,
+        _ => unreachable!(),
+    };
+    let gmanager = arena.gmanager.ok_or(KError::GlobalMemoryNotSet)?;
+    let node = arena.affinity as usize;
+
+    let allocate = || {
+        let mut ncache = gmanager.node_caches[node].lock();
+        if large {
+            ncache.allocate_large_page()
+        } else {
+            ncache.allocate_base_page()
+        }
+    };
+    match allocate() {
+        Err(KError::CacheExhausted) => {
+            KernelAllocator::reclaim_node_memory(mem_type)?;
+            allocate().map_err(|e| match e {
+                KError::CacheExhausted => KError::OutOfMemory,
+                e => e,
+            })
+        }
+        r => r,
+    }
+}
+
+/// Allocates a frame from `node`.
+///
+/// If `node` is the `home` node of the core, the TCache is refilled with (up
+/// to) `remaining` frames of that size if necessary.
+fn allocate_on_node(
+    mem_type: MemType,
+    node: atopology::NodeId,
+    home: atopology::NodeId,
+    large: bool,
+    remaining: usize,
+) -> Result<Frame, KError> {
+    set_affinity(mem_type, node)?;
+    if let Ok(frame) = allocate_from_tcache(mem_type, large) {
+        return Ok(frame);
+    }
+    if node != home {
+        return allocate_from_ncache(mem_type, large);
+    }
+
+    let (base_pages, large_pages) = if large {
+        (0, remaining)
+    } else {
+        (remaining, 0)
+    };
+    let refilled = KernelAllocator::try_refill_tcache(base_pages, large_pages, mem_type);
+    // A partial refill may still have given us a frame
+    allocate_from_tcache(mem_type, large).map_err(|e| refilled.err().unwrap_or(e))
+}
+
+/// Allocates `large_pages` large-pages followed by `base_pages` base-pages
+/// from the NUMA node(s) that `policy` picks.
+///
+/// With `AllocationPolicy::Interleave` the n-th frame of the mapping comes
+/// from node `n % num_nodes`. The core allocates from the same node as
+/// before again once this returns.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn allocate_frames(
+    policy: AllocationPolicy,
+    base_pages: usize,
+    large_pages: usize,
+    mem_type: MemType,
+) -> Result<Vec<Frame>, KError> {
+    let kcb = kcb::get_kcb();
+    let local = kcb.arch.node();
+    let previous = match mem_type {
+        MemType::Mem => kcb.physical_memory.affinity,
+        MemType::PMem => kcb.pmem_memory.affinity,
+        _ => unreachable!(),
+    };
+    let num_nodes = num_nodes();
+    let mut frames = Vec::try_with_capacity(base_pages + large_pages)?;
+
+    // Nodes that ran out of memory (for base and large pages), we don't try
+    // them again for this mapping
+    let mut exhausted = [[false; MAX_NUMA_NODES]; 2];
+    let allocated = (0..large_pages + base_pages).try_for_each(|nth| {
+        let large = nth < large_pages;
+        let remaining = if large {
+            large_pages - nth
+        } else {
+            large_pages + base_pages - nth
+        };
+
+        let mut error = KError::OutOfMemory;
+        for node in candidate_nodes(policy, local, num_nodes, nth) {
+            if exhausted[large as usize][node] {
+                continue;
+            }
+            match allocate_on_node(mem_type, node, previous, large, remaining) {
+                Ok(frame) => {
+                    frames
+                        .try_push(frame)
+                        .expect("Can't fail see `try_with_capacity`");
+                    return Ok(());
+                }
+                Err(e) => {
+                    exhausted[large as usize][node] = true;
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    });
+    let restored = set_affinity(mem_type, previous);
+
+    match allocated.and(restored) {
+        Ok(()) => Ok(frames),
+        Err(e) => {
+            release_frames(frames, mem_type);
+            Err(e)
+        }
+    }
+}
+
+/// Gives back `frames` that were allocated with `allocate_frames`.
+///
+/// Releases all of them even if some fail (those are logged and lost), so
+/// callers can report the error that made them give back the frames.
+pub fn release_frames(frames: Vec<Frame>, mem_type: MemType) {
+    for frame in frames {
+        if let Err(e) = KernelAllocator::release_frame(frame, mem_type) {
+            warn!("Can't release {:?}: {}", frame, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn local_and_bind() {
+        assert_eq!(
+            candidate_nodes(AllocationPolicy::Local, 1, 4, 3).as_slice(),
+            &[1]
+        );
+        assert_eq!(
+            candidate_nodes(AllocationPolicy::Bind(2), 1, 4, 3).as_slice(),
+            &[2]
+        );
+    }
+
+    #[test]
+    fn preferred_falls_back() {
+        assert_eq!(
+            candidate_nodes(AllocationPolicy::Preferred(2), 0, 4, 0).as_slice(),
+            &[2, 0, 1, 3]
+        );
+        assert_eq!(
+            candidate_nodes(AllocationPolicy::Preferred(0), 0, 1, 5).as_slice(),
+            &[0]
+        );
+    }
+
+    #[test]
+    fn interleave() {
+        for nth in 0..8 {
+            let nodes = candidate_nodes(AllocationPolicy::Interleave, 0, 3, nth);
+            assert_eq!(nodes[0], nth % 3);
+            assert_eq!(nodes.len(), 3);
+        }
+    }
+
+    #[test]
+    fn policy_flags() {
+        for policy in &[
+            AllocationPolicy::Local,
+            AllocationPolicy::Preferred(3),
+            AllocationPolicy::Interleave,
+            AllocationPolicy::Bind(11),
+        ] {
+            assert_eq!(
+                AllocationPolicy::from_flags((*policy).into()),
+                Some(*policy)
+            );
+        }
+        assert_eq!(AllocationPolicy::from_flags(0), None);
+        assert_eq!(AllocationPolicy::from_flags(0x17), None);
+    }
+}
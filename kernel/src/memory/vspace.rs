@@ -3,14 +3,15 @@
 
 //! A trait defining architecture independent address spaces.
 
-use core::cmp::PartialEq;
+use alloc::vec::Vec;
+use core::cmp::{Ordering, PartialEq};
 use core::fmt;
 
 use crate::error::KError;
 use bit_field::BitField;
 use x86::current::paging::{PDFlags, PDPTFlags, PTFlags};
 
-use super::{Frame, PAddr, VAddr};
+use super::{Frame, PAddr, VAddr, BASE_PAGE_SIZE};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct TlbFlushHandle {
@@ -52,6 +53,11 @@ impl Default for CoreBitMap {
 }
 
 impl CoreBitMap {
+    /// Iterates over the cores that are set in the bitmap.
+    pub fn iter(&self) -> CoreBitMapIter {
+        CoreBitMapIter(*self)
+    }
+
     pub fn set_bit(&mut self, bit: usize, value: bool) {
         if bit <= 127 {
             self.low.set_bit(bit, value);
@@ -167,6 +173,13 @@ pub trait AddressSpace {
     /// and access rights or an error in case no mapping is found.
     fn resolve(&self, vaddr: VAddr) -> Result<(PAddr, MapAction), KError>;
 
+    /// Is any page in the region `[base, base + size)` mapped?
+    fn overlaps(&self, base: VAddr, size: usize) -> bool {
+        (base.as_usize()..base.as_usize() + size)
+            .step_by(BASE_PAGE_SIZE)
+            .any(|vaddr| self.resolve(VAddr::from(vaddr)).is_ok())
+    }
+
     /// Removes the frame from the address space that contains `vaddr`.
     ///
     /// # Returns
@@ -174,8 +187,69 @@ pub trait AddressSpace {
     /// invoked to flush the TLB.
     fn unmap(&mut self, vaddr: VAddr) -> Result<TlbFlushHandle, KError>;
 
-    // Returns an iterator of all currently mapped memory regions.
-    //fn mappings()
+    /// Returns all pages that are mapped in the user part of the address
+    /// space (ordered by their virtual address).
+    fn mappings(&self) -> Result<Vec<MappedPage>, KError> {
+        Err(KError::NotSupported)
+    }
+}
+
+/// A page that is mapped in an address space (an entry of the page-table
+/// that points to a frame).
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MappedPage {
+    pub vaddr: VAddr,
+    pub paddr: PAddr,
+    /// Size of the page (one of the page sizes the MMU supports).
+    pub size: usize,
+    pub rights: MapAction,
+}
+
+/// Compares the pages mapped in two address spaces (both ordered by virtual
+/// address as returned by `AddressSpace::mappings`).
+///
+/// Calls `report` with the pages of `expected` and `found` for every virtual
+/// address where the two differ (`None` if only one side maps a page there).
+/// A page that is split up differently on one side is reported at every
+/// address where a page starts.
+pub fn diff_mappings<F: FnMut(VAddr, Option<&MappedPage>, Option<&MappedPage>)>(
+    expected: &[MappedPage],
+    found: &[MappedPage],
+    mut report: F,
+) {
+    let mut expected = expected.iter().peekable();
+    let mut found = found.iter().peekable();
+
+    loop {
+        match (expected.peek(), found.peek()) {
+            (Some(e), Some(f)) => match e.vaddr.cmp(&f.vaddr) {
+                Ordering::Equal => {
+                    if e != f {
+                        report(e.vaddr, Some(e), Some(f));
+                    }
+                    expected.next();
+                    found.next();
+                }
+                Ordering::Less => {
+                    report(e.vaddr, Some(e), None);
+                    expected.next();
+                }
+                Ordering::Greater => {
+                    report(f.vaddr, None, Some(f));
+                    found.next();
+                }
+            },
+            (Some(e), None) => {
+                report(e.vaddr, Some(e), None);
+                expected.next();
+            }
+            (None, Some(f)) => {
+                report(f.vaddr, None, Some(f));
+                found.next();
+            }
+            (None, None) => break,
+        }
+    }
 }
 
 /// Mapping rights to give to address translation.
@@ -408,3 +482,82 @@ impl fmt::Display for MapAction {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+    fn page(vaddr: u64, paddr: u64, size: usize, rights: MapAction) -> MappedPage {
+        MappedPage {
+            vaddr: VAddr::from(vaddr),
+            paddr: PAddr::from(paddr),
+            size,
+            rights,
+        }
+    }
+
+    fn diff(expected: &[MappedPage], found: &[MappedPage]) -> Vec<(u64, bool, bool)> {
+        let mut diffs = Vec::new();
+        diff_mappings(expected, found, |vaddr, e, f| {
+            diffs.push((vaddr.as_u64(), e.is_some(), f.is_some()))
+        });
+        diffs
+    }
+
+    #[test]
+    fn diff_identical() {
+        let pages = [
+            page(0x1000, 0x5000, BASE_PAGE_SIZE, MapAction::ReadUser),
+            page(
+                0x20_0000,
+                0x40_0000,
+                LARGE_PAGE_SIZE,
+                MapAction::ReadWriteUser,
+            ),
+        ];
+        assert!(diff(&pages, &pages).is_empty());
+        assert!(diff(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn diff_divergent() {
+        let expected = [
+            page(0x1000, 0x5000, BASE_PAGE_SIZE, MapAction::ReadUser),
+            page(0x2000, 0x6000, BASE_PAGE_SIZE, MapAction::ReadUser),
+            page(
+                0x20_0000,
+                0x40_0000,
+                LARGE_PAGE_SIZE,
+                MapAction::ReadWriteUser,
+            ),
+        ];
+        let found = [
+            // Different rights
+            page(0x1000, 0x5000, BASE_PAGE_SIZE, MapAction::ReadWriteUser),
+            // 0x2000 is missing, 0x20_0000 is split in base pages
+            page(
+                0x20_0000,
+                0x40_0000,
+                BASE_PAGE_SIZE,
+                MapAction::ReadWriteUser,
+            ),
+            page(
+                0x20_1000,
+                0x40_1000,
+                BASE_PAGE_SIZE,
+                MapAction::ReadWriteUser,
+            ),
+        ];
+
+        assert_eq!(
+            diff(&expected, &found),
+            [
+                (0x1000, true, true),
+                (0x2000, true, false),
+                (0x20_0000, true, true),
+                (0x20_1000, false, true)
+            ]
+        );
+    }
+}
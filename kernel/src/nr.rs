@@ -2,43 +2,102 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use crate::prelude::*;
+use alloc::sync::Arc;
 use core::fmt::Debug;
 
+use fallible_collections::{try_vec, FallibleVec, FallibleVecGlobal};
 use hashbrown::HashMap;
 use log::{error, trace};
 use node_replication::Dispatch;
 
 use crate::arch::MAX_CORES;
 use crate::error::KError;
+use crate::ipc::Channels;
 use crate::memory::VAddr;
-use crate::process::{Pid, MAX_PROCESSES};
+use crate::process::Pid;
+use crate::vectors::{Binding, Vectors};
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum ReadOps {
-    CurrentProcess(atopology::GlobalThreadId),
+    /// All processes that should run on a core
+    CoreAssignments(atopology::GlobalThreadId),
+    /// The process that allocated an interrupt vector on a core
+    VectorOwner(atopology::GlobalThreadId, u64),
+    /// Whether one of the two processes is the parent of the other
+    Related(Pid, Pid),
+    /// The device entries that are routed to vectors of a process
+    VectorBindings(Pid),
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Op {
-    /// Allocate a new process (Pid)
-    AllocatePid,
-    /// Destroy a process
-    FreePid(Pid),
-    /// Assign a core to a process
+    /// Allocate a new process (Pid), optionally as the child of another one
+    AllocatePid(Option<Pid>),
+    /// Destroy a process (and release the cores assigned to it), keeps the
+    /// exit code around until someone waits for the process
+    FreePid(Pid, u64),
+    /// Retrieve the exit code of a child process (parent, child) and make its
+    /// Pid available again
+    ReapPid(Pid, Pid),
+    /// Make a Pid available again that was allocated for a process that
+    /// could not be created
+    ReleasePid(Pid),
+    /// Stop scheduling a process on any core (used when a process exits)
+    SchedReleaseCores(Pid),
+    /// Give a single core of a process back to the scheduler
+    SchedReleaseCore(Pid, atopology::GlobalThreadId),
+    /// Assign a core to a process (if no core is given, any free core that
+    /// matches the affinity is picked); a core can be shared by multiple
+    /// processes
     SchedAllocateCore(
         Pid,
         Option<atopology::NodeId>,
         Option<atopology::GlobalThreadId>,
         VAddr,
     ),
+    /// Create a message channel (pid, name, capacity)
+    ChannelCreate(Pid, u64, usize),
+    /// Attach a process to an existing channel (by name)
+    ChannelAttach(Pid, u64),
+    /// Let a process (the last argument) attach to a channel
+    ChannelGrant(Pid, kpi::ipc::ChannelId, Pid),
+    ChannelDetach(Pid, kpi::ipc::ChannelId),
+    ChannelSend(Pid, kpi::ipc::ChannelId, Arc<[u8]>),
+    /// Take the oldest message out of a channel (if it fits in the given
+    /// number of bytes)
+    ChannelReceive(Pid, kpi::ipc::ChannelId, usize),
+    /// Allocate an interrupt vector on a core the process runs on
+    VectorAllocate(Pid, atopology::GlobalThreadId),
+    /// Free an interrupt vector the process allocated
+    VectorFree(Pid, atopology::GlobalThreadId, u64),
+    /// Route a device entry to a vector of the process (and make the process
+    /// the owner of the device if nobody else owns it)
+    VectorBind(Pid, Binding),
 }
 
 #[derive(Debug, Clone)]
 pub enum NodeResult {
     PidAllocated(Pid),
-    PidReturned,
-    CoreInfo(CoreInfo),
+    /// Pid was freed, contains the number of processes that are still alive.
+    PidReturned(usize),
+    /// Pid was reaped, contains the exit code of the process.
+    PidReaped(u64),
+    PidReleased,
+    CoreAssignments(Vec<CoreInfo>),
     CoreAllocated(atopology::GlobalThreadId),
+    CoresReleased,
+    CoreReleased(atopology::GlobalThreadId),
+    ChannelAttached(kpi::ipc::ChannelId),
+    ChannelDetached,
+    ChannelGranted,
+    MessageSent,
+    MessageReceived(Arc<[u8]>),
+    VectorAllocated(u64),
+    VectorOwner(Option<Pid>),
+    Related(bool),
+    VectorFreed,
+    VectorBound,
+    VectorBindings(Vec<Binding>),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -49,19 +108,52 @@ pub struct CoreInfo {
 
 pub struct KernelNode {
     process_map: HashMap<Pid, ()>,
-    scheduler_map: HashMap<atopology::GlobalThreadId, CoreInfo>,
+    /// Processes that are assigned to a core (there is no entry for idle
+    /// cores).
+    scheduler_map: HashMap<atopology::GlobalThreadId, Vec<CoreInfo>>,
+    /// Exit codes of processes that terminated but weren't waited on yet.
+    exited: HashMap<Pid, u64>,
+    /// The parent of every process that was spawned by another process (only
+    /// the parent can wait for it).
+    parents: HashMap<Pid, Pid>,
+    /// Pids that were handed out before and can be reused.
+    free_pids: Vec<Pid>,
+    /// The next pid that was never handed out before.
+    next_pid: Pid,
+    /// Upper bound for pids (i.e., the maximum number of processes).
+    max_processes: usize,
+    /// Message channels between processes.
+    channels: Channels,
+    /// Interrupt vectors processes allocated for their devices.
+    vectors: Vectors,
 }
 
-impl Default for KernelNode {
-    fn default() -> KernelNode {
+impl KernelNode {
+    pub fn new(max_processes: usize) -> KernelNode {
         KernelNode {
-            process_map: HashMap::new(),   // with_capacity(MAX_PROCESSES),
+            process_map: HashMap::new(),
             scheduler_map: HashMap::new(), // with_capacity(MAX_CORES),
+            exited: HashMap::new(),
+            parents: HashMap::new(),
+            free_pids: Vec::new(),
+            next_pid: 0,
+            max_processes,
+            channels: Channels::default(),
+            vectors: Vectors::default(),
         }
     }
-}
 
-impl KernelNode {
+    /// Removes `pid` from all cores it is assigned to (along with the
+    /// interrupt vectors it allocated there and its devices).
+    fn unassign_cores(&mut self, pid: Pid) {
+        for assigned in self.scheduler_map.values_mut() {
+            assigned.retain(|cinfo| cinfo.pid != pid);
+        }
+        self.scheduler_map
+            .retain(|_gtid, assigned| !assigned.is_empty());
+        self.vectors.free_all(pid);
+    }
+
     pub fn synchronize() -> Result<(), KError> {
         let kcb = super::kcb::get_kcb();
         kcb.replica
@@ -72,6 +164,133 @@ impl KernelNode {
             })
     }
 
+    /// Releases `pid` along with all cores that are assigned to the process.
+    ///
+    /// The pid can't be reused until `exit_code` is retrieved with
+    /// [`KernelNode::reap_pid`]. Returns how many processes are still alive.
+    pub fn free_pid(pid: Pid, exit_code: u64) -> Result<usize, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::FreePid(pid, exit_code), *token);
+
+                match response {
+                    Ok(NodeResult::PidReturned(alive)) => Ok(alive),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Allocates a new pid (for a child of `parent`, if given).
+    pub fn allocate_pid(parent: Option<Pid>) -> Result<Pid, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::AllocatePid(parent), *token);
+
+                match response {
+                    Ok(NodeResult::PidAllocated(pid)) => Ok(pid),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Makes `pid` available again if the process couldn't be created after
+    /// [`KernelNode::allocate_pid`] (unlike `free_pid`, no exit code is kept
+    /// for the parent).
+    pub fn release_pid(pid: Pid) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::ReleasePid(pid), *token);
+
+                match response {
+                    Ok(NodeResult::PidReleased) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Returns the exit code of `pid` (a child of `parent`) once the process
+    /// has terminated.
+    ///
+    /// Fails with `KError::ProcessNotExited` if `pid` is still running and
+    /// with `KError::ProcessNotChild` if `parent` didn't spawn it.
+    pub fn reap_pid(parent: Pid, pid: Pid) -> Result<u64, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::ReapPid(parent, pid), *token);
+
+                match response {
+                    Ok(NodeResult::PidReaped(exit_code)) => Ok(exit_code),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Removes all core assignments of `pid` from the scheduler map.
+    ///
+    /// After this returns, `schedule()` will no longer pick up `pid` on any
+    /// core (but cores that currently run it have to be stopped separately).
+    pub fn release_cores(pid: Pid) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::SchedReleaseCores(pid), *token);
+
+                match response {
+                    Ok(NodeResult::CoresReleased) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Removes the assignment of core `gtid` to `pid` from the scheduler map.
+    ///
+    /// The executor that currently runs on `gtid` has to be stopped
+    /// separately.
+    pub fn release_core(pid: Pid, gtid: atopology::GlobalThreadId) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute_mut(Op::SchedReleaseCore(pid, gtid), *token);
+
+                match response {
+                    Ok(NodeResult::CoreReleased(_gtid)) => Ok(()),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
+    /// Returns true if `a` is the parent or a child of `b`.
+    pub fn is_related(a: Pid, b: Pid) -> Result<bool, KError> {
+        let kcb = super::kcb::get_kcb();
+        kcb.replica
+            .as_ref()
+            .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+                let response = replica.execute(ReadOps::Related(a, b), *token);
+
+                match response {
+                    Ok(NodeResult::Related(related)) => Ok(related),
+                    Err(e) => Err(e),
+                    Ok(_) => unreachable!("Got unexpected response"),
+                }
+            })
+    }
+
     pub fn allocate_core_to_process(
         pid: Pid,
         entry_point: VAddr,
@@ -101,58 +320,213 @@ impl Dispatch for KernelNode {
 
     fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
         match op {
-            ReadOps::CurrentProcess(gtid) => {
-                let core_info = self
+            ReadOps::CoreAssignments(gtid) => {
+                let assigned = self
                     .scheduler_map
                     .get(&gtid)
                     .ok_or(KError::NoExecutorForCore)?;
-                Ok(NodeResult::CoreInfo(*core_info))
+                let mut core_infos = Vec::try_with_capacity(assigned.len())?;
+                core_infos.extend_from_slice(assigned);
+                Ok(NodeResult::CoreAssignments(core_infos))
+            }
+            ReadOps::VectorOwner(gtid, vector) => {
+                Ok(NodeResult::VectorOwner(self.vectors.owner(gtid, vector)))
+            }
+            ReadOps::Related(a, b) => Ok(NodeResult::Related(
+                self.parents.get(&a) == Some(&b) || self.parents.get(&b) == Some(&a),
+            )),
+            ReadOps::VectorBindings(pid) => {
+                Ok(NodeResult::VectorBindings(self.vectors.bindings(pid)?))
             }
         }
     }
 
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
         match op {
-            Op::AllocatePid => {
-                // TODO(performance): O(n) scan probably not what we really
-                // want, fine for now, MAX_PROCESSES is tiny
-                for i in 0..MAX_PROCESSES {
-                    if !self.process_map.contains_key(&i) {
-                        self.process_map.try_reserve(1)?;
-                        let r = self.process_map.insert(i, ());
-                        assert!(r.is_none(), "!contains_key");
-                        return Ok(NodeResult::PidAllocated(i));
+            Op::AllocatePid(parent) => {
+                self.process_map.try_reserve(1)?;
+                self.parents.try_reserve(1)?;
+                // Recycle pids first, so we only grow the process table when
+                // we have to
+                let pid = match self.free_pids.pop() {
+                    Some(pid) => pid,
+                    None if self.next_pid < self.max_processes => {
+                        self.next_pid += 1;
+                        self.next_pid - 1
                     }
+                    None => return Err(KError::OutOfPids),
+                };
+
+                let r = self.process_map.insert(pid, ());
+                assert!(r.is_none(), "Pid is handed out twice");
+                if let Some(parent) = parent {
+                    self.parents.insert(pid, parent);
                 }
-                Err(KError::OutOfPids)
+                Ok(NodeResult::PidAllocated(pid))
             }
-            // TODO: better impl, what about scheduler_map?
-            Op::FreePid(pid) => match self.process_map.remove(&pid) {
-                Some(_) => Ok(NodeResult::PidReturned),
-                None => {
+            Op::FreePid(pid, exit_code) => {
+                if !self.process_map.contains_key(&pid) {
                     error!("Process not found");
-                    Err(KError::NoProcessFoundForPid)
+                    return Err(KError::NoProcessFoundForPid);
                 }
-            },
+                let children = self.parents.values().filter(|&&p| p == pid).count();
+                FallibleVec::try_reserve(&mut self.free_pids, children + 1)?;
+                self.exited.try_reserve(1)?;
+
+                self.process_map.remove(&pid);
+                self.unassign_cores(pid);
+                self.channels.detach_all(pid);
+
+                // Nobody can wait for the children anymore: the ones that
+                // exited are reaped now, the others once they exit
+                let exited = &mut self.exited;
+                let free_pids = &mut self.free_pids;
+                self.parents.retain(|&child, &mut parent| {
+                    if parent == pid && exited.remove(&child).is_some() {
+                        free_pids.push(child);
+                    }
+                    parent != pid
+                });
+
+                if self.parents.contains_key(&pid) {
+                    self.exited.insert(pid, exit_code);
+                } else {
+                    self.free_pids.push(pid);
+                }
+                Ok(NodeResult::PidReturned(self.process_map.len()))
+            }
+            Op::ReapPid(parent, pid) => {
+                if self.parents.get(&pid) != Some(&parent) {
+                    let exists =
+                        self.process_map.contains_key(&pid) || self.exited.contains_key(&pid);
+                    return match exists {
+                        true => Err(KError::ProcessNotChild),
+                        false => Err(KError::NoProcessFoundForPid),
+                    };
+                }
+
+                match self.exited.get(&pid) {
+                    Some(&exit_code) => {
+                        FallibleVec::try_reserve(&mut self.free_pids, 1)?;
+                        self.exited.remove(&pid);
+                        self.parents.remove(&pid);
+                        self.free_pids.push(pid);
+                        Ok(NodeResult::PidReaped(exit_code))
+                    }
+                    None => Err(KError::ProcessNotExited),
+                }
+            }
+            Op::ReleasePid(pid) => {
+                if !self.process_map.contains_key(&pid) {
+                    return Err(KError::NoProcessFoundForPid);
+                }
+                FallibleVec::try_reserve(&mut self.free_pids, 1)?;
+
+                self.process_map.remove(&pid);
+                self.parents.remove(&pid);
+                self.unassign_cores(pid);
+                self.channels.detach_all(pid);
+                self.free_pids.push(pid);
+                Ok(NodeResult::PidReleased)
+            }
+            Op::SchedReleaseCores(pid) => {
+                self.unassign_cores(pid);
+                Ok(NodeResult::CoresReleased)
+            }
+            Op::SchedReleaseCore(pid, gtid) => {
+                let assigned = self
+                    .scheduler_map
+                    .get_mut(&gtid)
+                    .ok_or(KError::CoreNotAllocated)?;
+                let idx = assigned
+                    .iter()
+                    .position(|cinfo| cinfo.pid == pid)
+                    .ok_or(KError::CoreNotAllocated)?;
+                assigned.remove(idx);
+                if assigned.is_empty() {
+                    self.scheduler_map.remove(&gtid);
+                }
+                self.vectors.free_core(pid, gtid);
+                Ok(NodeResult::CoreReleased(gtid))
+            }
             Op::SchedAllocateCore(pid, _affinity, Some(gtid), entry_point) => {
                 assert!((gtid as usize) < MAX_CORES, "Invalid gtid");
+                trace!("Op::SchedAllocateCore pid={}, gtid={}", pid, gtid);
 
-                match self.scheduler_map.get(&gtid) {
-                    Some(_cinfo) => Err(KError::CoreAlreadyAllocated),
-                    None => {
-                        trace!("Op::SchedAllocateCore pid={}, gtid={}", pid, gtid);
+                self.scheduler_map.try_reserve(1)?;
+                let assigned = self.scheduler_map.entry(gtid).or_insert_with(Vec::new);
+                if assigned.iter().any(|cinfo| cinfo.pid == pid) {
+                    return Err(KError::CoreAlreadyAllocated);
+                }
+                // Other processes may already run on this core, in that case
+                // the core's run-queue multiplexes them
+                assigned.try_push(CoreInfo { pid, entry_point })?;
 
-                        self.scheduler_map.try_reserve(1)?;
-                        let r = self
-                            .scheduler_map
-                            .insert(gtid, CoreInfo { pid, entry_point });
-                        assert!(r.is_none(), "get() -> None");
+                Ok(NodeResult::CoreAllocated(gtid))
+            }
+            Op::SchedAllocateCore(pid, affinity, None, entry_point) => {
+                // Every replica sees the same topology, so this picks the
+                // same core everywhere
+                let gtid = atopology::MACHINE_TOPOLOGY
+                    .threads()
+                    .filter(|t| t.id < MAX_CORES)
+                    .filter(|t| affinity.map_or(true, |node| t.node_id.unwrap_or(0) == node))
+                    .map(|t| t.id)
+                    .find(|gtid| self.scheduler_map.get(gtid).map_or(true, |a| a.is_empty()))
+                    .ok_or(KError::NoFreeCore)?;
+                trace!("Op::SchedAllocateCore pid={}, picked gtid={}", pid, gtid);
 
-                        Ok(NodeResult::CoreAllocated(gtid))
-                    }
+                self.scheduler_map.try_reserve(1)?;
+                self.scheduler_map
+                    .insert(gtid, try_vec![CoreInfo { pid, entry_point }]?);
+                Ok(NodeResult::CoreAllocated(gtid))
+            }
+            Op::ChannelCreate(pid, name, capacity) => {
+                let id = self.channels.create(pid, name, capacity)?;
+                Ok(NodeResult::ChannelAttached(id))
+            }
+            Op::ChannelAttach(pid, name) => {
+                let id = self.channels.attach(pid, name, &self.parents)?;
+                Ok(NodeResult::ChannelAttached(id))
+            }
+            Op::ChannelGrant(pid, id, other) => {
+                self.channels.grant(pid, id, other)?;
+                Ok(NodeResult::ChannelGranted)
+            }
+            Op::ChannelDetach(pid, id) => {
+                self.channels.detach(pid, id)?;
+                Ok(NodeResult::ChannelDetached)
+            }
+            Op::ChannelSend(pid, id, message) => {
+                self.channels.send(pid, id, message)?;
+                Ok(NodeResult::MessageSent)
+            }
+            Op::ChannelReceive(pid, id, max_len) => {
+                let message = self.channels.receive(pid, id, max_len)?;
+                Ok(NodeResult::MessageReceived(message))
+            }
+            Op::VectorAllocate(pid, gtid) => {
+                // Interrupts are delivered to the process running on the
+                // core, so it has to be one of ours
+                let runs_on_core = self
+                    .scheduler_map
+                    .get(&gtid)
+                    .map_or(false, |assigned| assigned.iter().any(|c| c.pid == pid));
+                if !runs_on_core {
+                    return Err(KError::CoreNotAllocated);
                 }
+
+                let vector = self.vectors.allocate(pid, gtid)?;
+                Ok(NodeResult::VectorAllocated(vector))
+            }
+            Op::VectorFree(pid, gtid, vector) => {
+                self.vectors.free(pid, gtid, vector)?;
+                Ok(NodeResult::VectorFreed)
+            }
+            Op::VectorBind(pid, binding) => {
+                self.vectors.bind(pid, binding)?;
+                Ok(NodeResult::VectorBound)
             }
-            Op::SchedAllocateCore(_pid, _affinity, _gtid, _entry_point) => unimplemented!(),
         }
     }
 }
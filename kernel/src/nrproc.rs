@@ -3,21 +3,29 @@
 
 use crate::prelude::*;
 
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::alloc::Allocator;
+use core::ops::Range;
 
-use fallible_collections::vec::FallibleVec;
+use arrayvec::ArrayVec;
+use fallible_collections::vec::{FallibleVec, FallibleVecGlobal};
 use kpi::process::{FrameId, ProcessInfo};
-use kpi::MemType;
-use node_replication::Dispatch;
-
-use crate::arch::process::PROCESS_TABLE;
-use crate::arch::Module;
+use kpi::system::{PageMapping, PageTableDivergence};
+use kpi::{AllocationPolicy, MemType};
+use log::error;
+use node_replication::{Dispatch, Log, Replica, ReplicaToken};
+use spin::{Mutex, RwLock};
+
+use crate::arch::memory::LARGE_PAGE_SIZE;
+use crate::arch::MAX_NUMA_NODES;
 use crate::error::KError;
 use crate::memory::detmem::DA;
-use crate::memory::vspace::{AddressSpace, MapAction, TlbFlushHandle};
-use crate::memory::{Frame, PAddr, VAddr};
-use crate::process::{Eid, Executor, Pid, Process, MAX_PROCESSES};
+use crate::memory::vspace::{diff_mappings, AddressSpace, MapAction, MappedPage, TlbFlushHandle};
+use crate::memory::{
+    paddr_to_kernel_vaddr, Frame, PAddr, PhysicalPageProvider, VAddr, BASE_PAGE_SIZE,
+};
+use crate::process::{Binary, Eid, Executor, Pid, Process};
 
 use crate::kcb::{ArchSpecificKcb, Kcb};
 
@@ -26,17 +34,35 @@ use crate::kcb::{ArchSpecificKcb, Kcb};
 pub enum ReadOps {
     ProcessInfo,
     MemResolve(VAddr),
+    ActiveCores,
+    /// All pages that are mapped in the process.
+    Mappings,
+    /// Events that were sent to the process and wait for delivery.
+    PendingEvents,
+    /// Events the process subscribed to.
+    SubscribedEvents,
+    /// The NUMA policy for memory of the process.
+    AllocationPolicy,
+    /// The frame a copy-on-write page is mapped to and its final rights.
+    MemCopyOnWriteSource(VAddr),
+    /// The reserved region a page belongs to (or its mapping, if the page is
+    /// backed already).
+    MemReservation(VAddr),
 }
 
 /// Mutable operations on the NrProcess.
 #[derive(PartialEq, Clone, Debug)]
 pub enum Op {
     ProcRaiseIrq,
-    Load(Pid, &'static Module, Vec<Frame>),
+    Load(Pid, Binary, Vec<Frame>),
 
     /// Assign a core to a process.
     AssignExecutor(atopology::NodeId, atopology::GlobalThreadId),
 
+    /// Take the executor away from a core (it can be assigned again later).
+    ReleaseExecutor(atopology::GlobalThreadId),
+
+    /// Tear down the process (returns the memory that was given to it).
     Destroy,
 
     /// Assign a physical frame to a process (returns a FrameId).
@@ -44,37 +70,221 @@ pub enum Op {
 
     DispatcherAllocation(Frame),
 
-    MemMapFrame(VAddr, Frame, MapAction),
+    MemMapFrame(VAddr, Frame, MapAction, MemType),
     MemMapDevice(Frame, MapAction),
+    /// Map memory that isn't owned by the process (e.g., pages of a file).
+    MemMapShared(VAddr, Frame, MapAction),
+    /// Map memory that isn't owned by the process read-only, the first
+    /// write replaces it with a private copy (mapped with the given rights).
+    MemMapCopyOnWrite(VAddr, Frame, MapAction),
+    /// Replace a copy-on-write page with a private copy (in the frame).
+    MemCopyOnWrite(VAddr, Frame),
     MemMapFrameId(VAddr, FrameId, MapAction),
+    /// Reserve a region that is backed with memory on first access.
+    MemReserve(VAddr, usize, MapAction),
+    /// Remove the reservation of a region (doesn't unmap anything).
+    MemUnreserve(VAddr),
+    /// Back a page of a reserved region with a frame.
+    MemMapLazy(VAddr, Frame),
     MemAdjust,
     MemUnmap(VAddr),
+
+    /// Replace the events the process subscribed to (a bitmask).
+    SubscribeEvents(u64),
+    /// Send an event to the process.
+    PostEvent(u64),
+    /// Remove the lowest pending event (it's about to be delivered).
+    TakeEvent,
+
+    /// Replace the NUMA policy for memory of the process.
+    SetAllocationPolicy(AllocationPolicy),
 }
 
 /// Possible return values from the NrProcess.
 #[derive(Debug, Clone)]
 pub enum NodeResult<E: Executor> {
     Loaded,
-    Destroyed,
+    Destroyed(Vec<(Frame, MemType)>),
     ProcessInfo(ProcessInfo),
+    ActiveCores(Vec<atopology::GlobalThreadId>),
+    Mappings(Vec<MappedPage>),
     Executor(Box<E>),
+    ExecutorReleased,
     VectorAllocated(u64),
     ExecutorsCreated(usize),
     Mapped,
     MappedFrameId(PAddr, usize),
     Adjusted,
-    Unmapped(TlbFlushHandle),
+    Unmapped(TlbFlushHandle, Option<MemType>),
     Resolved(PAddr, MapAction),
+    Reserved(VAddr, usize),
     FrameId(usize),
+    /// A bitmask of events (subscribed or pending ones).
+    Events(u64),
+    /// Whether the event was queued for delivery (`false` if the process
+    /// didn't subscribe to it).
+    EventPosted(bool),
+    EventTaken(Option<u64>),
+    AllocationPolicy(AllocationPolicy),
+}
+
+/// Converts a page into the format we report to user-space.
+fn page_mapping(page: &MappedPage) -> PageMapping {
+    PageMapping {
+        paddr: page.paddr.as_u64(),
+        size: page.size,
+        rights: page.rights.to_string(),
+    }
 }
 
 /// Advances the replica of all the processes on the current NUMA node.
 pub fn advance_all() {
     let kcb = super::kcb::get_kcb();
-    let node = kcb.arch.node();
 
-    for pid in 0..MAX_PROCESSES {
-        let _r = PROCESS_TABLE[node][pid].sync(kcb.process_token[pid]);
+    for pid in kcb.arch.process_table().pids() {
+        if let Ok((replica, token)) = kcb.process_replica(pid) {
+            let _r = replica.sync(token);
+        }
+    }
+}
+
+/// The replicated state of all processes.
+///
+/// Holds a replica of every process for every NUMA node. The replicas of a
+/// process are created on-demand when its pid is handed out for the first
+/// time, and are reused whenever the pid gets recycled.
+pub struct ProcessTable<P: Process> {
+    /// Process replicas for every NUMA node (indexed by pid).
+    replicas: ArrayVec<RwLock<Vec<Option<Arc<Replica<'static, NrProcess<P>>>>>>, MAX_NUMA_NODES>,
+    /// The token that cores from other NUMA nodes share to read a replica
+    /// (indexed by node and pid, registered lazily).
+    foreign_tokens: ArrayVec<Mutex<Vec<Option<ReplicaToken>>>, MAX_NUMA_NODES>,
+    /// Constructs the (empty) process struct that is stored in a replica.
+    new_process: fn(Pid, DA) -> Result<P, KError>,
+}
+
+impl<P: Process + Sync + 'static> ProcessTable<P> {
+    pub fn new(new_process: fn(Pid, DA) -> Result<P, KError>) -> Self {
+        // Want at least one replica...
+        let numa_nodes = core::cmp::max(1, atopology::MACHINE_TOPOLOGY.num_nodes());
+
+        let mut replicas = ArrayVec::new();
+        let mut foreign_tokens = ArrayVec::new();
+        for _n in 0..numa_nodes {
+            debug_assert!(!replicas.is_full());
+            replicas.push(RwLock::new(Vec::new()));
+            foreign_tokens.push(Mutex::new(Vec::new()));
+        }
+
+        ProcessTable {
+            replicas,
+            foreign_tokens,
+            new_process,
+        }
+    }
+
+    /// How many replicas every process has (one per NUMA node).
+    pub fn num_replicas(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// All pids that may have replicas in the table.
+    pub fn pids(&self) -> Range<Pid> {
+        0..self.replicas[0].read().len()
+    }
+
+    /// Returns the replica of process `pid` on NUMA node `node`.
+    pub fn get(
+        &self,
+        node: usize,
+        pid: Pid,
+    ) -> Result<Arc<Replica<'static, NrProcess<P>>>, KError> {
+        self.replicas
+            .get(node)
+            .ok_or(KError::InvalidAffinityId)?
+            .read()
+            .get(pid)
+            .and_then(|replica| replica.clone())
+            .ok_or(KError::NoProcessFoundForPid)
+    }
+
+    /// Calls `f` with the replica of process `pid` on NUMA node `node`, for
+    /// cores that are not on `node`.
+    ///
+    /// These cores share a single token per replica and take turns using it,
+    /// so they can't use up the registration slots the replica needs for the
+    /// cores of its own node.
+    pub fn read_foreign<R>(
+        &self,
+        node: usize,
+        pid: Pid,
+        f: impl FnOnce(&Replica<'static, NrProcess<P>>, ReplicaToken) -> R,
+    ) -> Result<R, KError> {
+        let replica = self.get(node, pid)?;
+        let mut tokens = self
+            .foreign_tokens
+            .get(node)
+            .ok_or(KError::InvalidAffinityId)?
+            .lock();
+
+        let token = match tokens.get(pid) {
+            Some(Some(token)) => *token,
+            _ => {
+                if tokens.len() <= pid {
+                    let additional = pid + 1 - tokens.len();
+                    FallibleVec::try_reserve(&mut *tokens, additional)?;
+                    tokens.resize(pid + 1, None);
+                }
+                let token = replica
+                    .register()
+                    .ok_or(KError::ReplicaRegistrationFailed)?;
+                tokens[pid] = Some(token);
+                token
+            }
+        };
+
+        Ok(f(&replica, token))
+    }
+
+    /// Creates the replicas of process `pid` on all NUMA nodes (unless they
+    /// exist already).
+    pub fn create(&self, pid: Pid) -> Result<(), KError> {
+        if self.get(0, pid).is_ok() {
+            return Ok(());
+        }
+
+        let log = Arc::try_new(Log::<<NrProcess<P> as Dispatch>::WriteOperation>::new(
+            LARGE_PAGE_SIZE,
+        ))?;
+        let da = DA::new()?;
+
+        // Every replica is allocated from the memory of its NUMA node
+        let kcb = super::kcb::get_kcb();
+        let local_node = kcb.arch.node();
+        let mut replicas: ArrayVec<_, MAX_NUMA_NODES> = ArrayVec::new();
+        let created = (0..self.replicas.len()).try_for_each(|node| {
+            kcb.set_mem_affinity(node as atopology::NodeId)?;
+            let process = Box::try_new((self.new_process)(pid, da.clone())?)?;
+            replicas.push(Replica::<NrProcess<P>>::with_data(
+                &log,
+                NrProcess::new(process, da.clone()),
+            ));
+            Ok::<(), KError>(())
+        });
+        kcb.set_mem_affinity(local_node as atopology::NodeId)?;
+        created?;
+
+        for (node, replica) in replicas.into_iter().enumerate() {
+            let mut table = self.replicas[node].write();
+            if table.len() <= pid {
+                let additional = pid + 1 - table.len();
+                FallibleVec::try_reserve(&mut *table, additional)?;
+                table.resize(pid + 1, None);
+            }
+            table[pid] = Some(replica);
+        }
+
+        Ok(())
     }
 }
 
@@ -84,6 +294,24 @@ pub struct NrProcess<P: Process, M: Allocator + Clone = alloc::alloc::Global> {
     active_cores: Vec<(atopology::GlobalThreadId, Eid), M>,
     /// The process struct itself.
     process: Box<P>,
+    /// Memory that was mapped into the process with `Op::MemMapFrame` (the
+    /// frames are shared among all replicas).
+    memory: Vec<(Frame, MemType)>,
+    /// Regions that get backed with memory on the first access to a page
+    /// (base, size, rights).
+    reserved: Vec<(VAddr, usize, MapAction)>,
+    /// Pages that are mapped read-only until the first write makes a
+    /// private copy of them (base, rights of the copy).
+    copy_on_write: Vec<(VAddr, MapAction)>,
+    /// Set while a program is loaded, events can only be sent to the process
+    /// in that time.
+    loaded: bool,
+    /// Events the process handles itself (bit `n` is set for event `n`).
+    subscribed_events: u64,
+    /// Events that were sent to the process but weren't delivered yet.
+    pending_events: u64,
+    /// From which NUMA node(s) memory mapped by the process is allocated.
+    allocation_policy: AllocationPolicy,
 }
 
 impl<P: Process> NrProcess<P> {
@@ -91,25 +319,23 @@ impl<P: Process> NrProcess<P> {
         NrProcess {
             active_cores: Vec::new(),
             process,
+            memory: Vec::new(),
+            reserved: Vec::new(),
+            copy_on_write: Vec::new(),
+            loaded: false,
+            subscribed_events: 0,
+            pending_events: 0,
+            allocation_policy: AllocationPolicy::default(),
         }
     }
 }
 
 impl<P: Process> NrProcess<P> {
-    pub fn load(
-        pid: Pid,
-        module: &'static Module,
-        writeable_sections: Vec<Frame>,
-    ) -> Result<(), KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
-
+    pub fn load(pid: Pid, binary: Binary, writeable_sections: Vec<Frame>) -> Result<(), KError> {
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
 
-        let response = PROCESS_TABLE[node][pid].execute_mut(
-            Op::Load(pid, module, writeable_sections),
-            kcb.process_token[pid],
-        );
+        let response = replica.execute_mut(Op::Load(pid, binary, writeable_sections), token);
         match response {
             Ok(NodeResult::Loaded) => Ok(()),
             Err(e) => Err(e),
@@ -118,14 +344,12 @@ impl<P: Process> NrProcess<P> {
     }
 
     pub fn resolve(pid: Pid, base: VAddr) -> Result<(u64, u64), KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
         debug_assert!(base.as_u64() < kpi::KERNEL_BASE, "Invalid base");
 
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
 
-        let response =
-            PROCESS_TABLE[node][pid].execute(ReadOps::MemResolve(base), kcb.process_token[pid]);
+        let response = replica.execute(ReadOps::MemResolve(base), token);
         match response {
             Ok(NodeResult::Resolved(paddr, _rights)) => Ok((paddr.as_u64(), 0x0)),
             Err(e) => Err(e),
@@ -133,12 +357,106 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
-    pub fn synchronize(pid: Pid) {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+    /// Tears down the process `pid` on all replicas.
+    ///
+    /// Returns the frames that belonged to the process, they have to be given
+    /// back to the memory allocator by the caller.
+    pub fn destroy(pid: Pid) -> Result<Vec<(Frame, MemType)>, KError> {
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute_mut(Op::Destroy, token);
+        match response {
+            Ok(NodeResult::Destroyed(frames)) => Ok(frames),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the cores that have been running executors of process `pid`.
+    pub fn active_cores(pid: Pid) -> Result<Vec<atopology::GlobalThreadId>, KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute(ReadOps::ActiveCores, token);
+        match response {
+            Ok(NodeResult::ActiveCores(cores)) => Ok(cores),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the pages mapped in the replica of process `pid` on NUMA
+    /// node `node`.
+    pub fn mappings(node: usize, pid: Pid) -> Result<Vec<MappedPage>, KError> {
+        let kcb = super::kcb::get_kcb();
+        let response = if node == kcb.arch.node() {
+            let (replica, token) = kcb.process_replica(pid)?;
+            replica.execute(ReadOps::Mappings, token)
+        } else {
+            kcb.arch
+                .process_table()
+                .read_foreign(node, pid, |replica, token| {
+                    replica.execute(ReadOps::Mappings, token)
+                })?
+        };
+
+        match response {
+            Ok(NodeResult::Mappings(pages)) => Ok(pages),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Compares the page-tables of process `pid` on every replica with the
+    /// ones on the first NUMA node.
+    ///
+    /// Returns (up to `max_reported` of) the addresses where the replicas
+    /// differ and how many there are in total. The replicas are read one
+    /// after the other, so if the process changes its address space
+    /// meanwhile that shows up as a difference too.
+    pub fn verify_replicas(
+        pid: Pid,
+        max_reported: usize,
+    ) -> Result<(Vec<PageTableDivergence>, usize), KError> {
+        let kcb = super::kcb::get_kcb();
+        let expected = NrProcess::<P>::mappings(0, pid)?;
+
+        let mut divergences = Vec::new();
+        let mut total = 0;
+        for node in 1..kcb.arch.process_table().num_replicas() {
+            let found = NrProcess::<P>::mappings(node, pid)?;
+            let mut result = Ok(());
+            diff_mappings(&expected, &found, |vaddr, expected, found| {
+                total += 1;
+                if result.is_ok() && divergences.len() < max_reported {
+                    result = divergences.try_push(PageTableDivergence {
+                        node,
+                        vaddr: vaddr.as_u64(),
+                        expected: expected.map(page_mapping),
+                        found: found.map(page_mapping),
+                    });
+                }
+            });
+            result?;
+        }
 
-        PROCESS_TABLE[node][pid].sync(kcb.process_token[pid]);
+        if total > 0 {
+            error!(
+                "Page-tables of process {} differ at {} addresses on {} replicas",
+                pid,
+                total,
+                kcb.arch.process_table().num_replicas()
+            );
+        }
+        Ok((divergences, total))
+    }
+
+    pub fn synchronize(pid: Pid) {
+        let kcb = super::kcb::get_kcb();
+        if let Ok((replica, token)) = kcb.process_replica(pid) {
+            replica.sync(token);
+        }
     }
 
     pub fn map_device_frame(
@@ -146,13 +464,10 @@ impl<P: Process> NrProcess<P> {
         frame: Frame,
         action: MapAction,
     ) -> Result<(u64, u64), KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
-
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
 
-        let response = PROCESS_TABLE[node][pid]
-            .execute_mut(Op::MemMapDevice(frame, action), kcb.process_token[pid]);
+        let response = replica.execute_mut(Op::MemMapDevice(frame, action), token);
         match response {
             Ok(NodeResult::Mapped) => Ok((frame.base.as_u64(), frame.size() as u64)),
             Err(e) => Err(e),
@@ -160,16 +475,18 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
-    pub fn unmap(pid: Pid, base: VAddr) -> Result<TlbFlushHandle, KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
-
+    /// Unmaps the frame mapped at `base` in process `pid`.
+    ///
+    /// If the process owned the frame its memory type is returned too, the
+    /// caller gives the frame (`handle.frame`) back to the memory allocator
+    /// once the TLB shootdown is done.
+    pub fn unmap(pid: Pid, base: VAddr) -> Result<(TlbFlushHandle, Option<MemType>), KError> {
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
 
-        let response =
-            PROCESS_TABLE[node][pid].execute_mut(Op::MemUnmap(base), kcb.process_token[pid]);
+        let response = replica.execute_mut(Op::MemUnmap(base), token);
         match response {
-            Ok(NodeResult::Unmapped(handle)) => Ok(handle),
+            Ok(NodeResult::Unmapped(handle, owned)) => Ok((handle, owned)),
             Err(e) => Err(e),
             _ => unreachable!("Got unexpected response"),
         }
@@ -181,15 +498,10 @@ impl<P: Process> NrProcess<P> {
         base: VAddr,
         action: MapAction,
     ) -> Result<(PAddr, usize), KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
-
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
 
-        let response = PROCESS_TABLE[node][pid].execute_mut(
-            Op::MemMapFrameId(base, frame_id, action),
-            kcb.process_token[pid],
-        );
+        let response = replica.execute_mut(Op::MemMapFrameId(base, frame_id, action), token);
         match response {
             Ok(NodeResult::MappedFrameId(paddr, size)) => Ok((paddr, size)),
             Err(e) => Err(e),
@@ -202,17 +514,16 @@ impl<P: Process> NrProcess<P> {
         base: VAddr,
         frames: Vec<Frame>,
         action: MapAction,
+        mem_type: MemType,
     ) -> Result<(u64, u64), KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
-
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
 
         let mut virtual_offset = 0;
         for frame in frames {
-            let response = PROCESS_TABLE[node][pid].execute_mut(
-                Op::MemMapFrame(base + virtual_offset, frame, action),
-                kcb.process_token[pid],
+            let response = replica.execute_mut(
+                Op::MemMapFrame(base + virtual_offset, frame, action, mem_type),
+                token,
             );
             match response {
                 Ok(NodeResult::Mapped) => {}
@@ -231,14 +542,190 @@ impl<P: Process> NrProcess<P> {
         Ok((base.as_u64(), virtual_offset as u64))
     }
 
-    pub fn pinfo(pid: Pid) -> Result<ProcessInfo, KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+    /// Reserves `[base, base + size)`, the region is backed with zeroed
+    /// memory page by page when it is accessed (see `map_lazy_page`).
+    ///
+    /// Fails with `AlreadyMapped` if the region overlaps another reservation
+    /// or pages that are mapped already.
+    pub fn reserve(
+        pid: Pid,
+        base: VAddr,
+        size: usize,
+        action: MapAction,
+    ) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
 
+        let response = replica.execute_mut(Op::MemReserve(base, size, action), token);
+        match response {
+            Ok(NodeResult::Reserved(base, size)) => Ok((base.as_u64(), size as u64)),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Removes the reservation of the region that starts at `base`, returns
+    /// the region. The pages that were backed so far stay mapped.
+    pub fn unreserve(pid: Pid, base: VAddr) -> Result<(VAddr, usize), KError> {
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute_mut(Op::MemUnreserve(base), token);
+        match response {
+            Ok(NodeResult::Reserved(base, size)) => Ok((base, size)),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Backs the page that contains `vaddr` with a zeroed frame, in case
+    /// `vaddr` is part of a reserved region.
+    ///
+    /// Fails with `NotMapped` if `vaddr` isn't reserved.
+    pub fn map_lazy_page(pid: Pid, vaddr: VAddr) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        // Don't bother with a frame unless the page still has to be backed
+        let base = vaddr.align_down_to_base_page();
+        match replica.execute(ReadOps::MemReservation(base), token) {
+            Ok(NodeResult::Reserved(_base, _size)) => {}
+            Ok(NodeResult::Resolved(_paddr, _rights)) => return Ok(()),
+            Err(e) => return Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+
+        crate::memory::KernelAllocator::try_refill_tcache(1, 0, MemType::Mem)?;
+        let mut frame = kcb.mem_manager().allocate_base_page()?;
+        unsafe { frame.zero() };
+
+        let response = replica.execute_mut(Op::MemMapLazy(base, frame), token);
+        match response {
+            Ok(NodeResult::Mapped) => Ok(()),
+            // Lost the race against another core, the page is backed already
+            Ok(NodeResult::Resolved(_paddr, _rights)) => kcb.mem_manager().release_base_page(frame),
+            Err(e) => {
+                kcb.mem_manager().release_base_page(frame)?;
+                Err(e)
+            }
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Maps `frames` at `base` without handing them over to the process, the
+    /// first write to a page replaces it with a private copy (see
+    /// `copy_on_write`).
+    ///
+    /// `mapped` counts the frames that got mapped, so the caller can undo
+    /// them if this fails.
+    pub fn map_copy_on_write_frames(
+        pid: Pid,
+        base: VAddr,
+        frames: Vec<Frame>,
+        action: MapAction,
+        mapped: &mut usize,
+    ) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let mut virtual_offset = 0;
+        for frame in frames {
+            let response = replica.execute_mut(
+                Op::MemMapCopyOnWrite(base + virtual_offset, frame, action),
+                token,
+            );
+            match response {
+                Ok(NodeResult::Mapped) => *mapped += 1,
+                Err(e) => return Err(e),
+                _ => unreachable!("Got unexpected response"),
+            }
+
+            virtual_offset += frame.size();
+        }
+
+        Ok((base.as_u64(), virtual_offset as u64))
+    }
+
+    /// Replaces the copy-on-write page that contains `vaddr` with a private
+    /// copy.
+    ///
+    /// Fails with `NotMapped` if `vaddr` isn't in a copy-on-write page. The
+    /// caller has to flush the old page from the TLBs with the returned
+    /// handle (`None` if another core made the copy already).
+    pub fn copy_on_write(pid: Pid, vaddr: VAddr) -> Result<Option<TlbFlushHandle>, KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let base = vaddr.align_down_to_base_page();
+        let source = match replica.execute(ReadOps::MemCopyOnWriteSource(base), token) {
+            Ok(NodeResult::Resolved(paddr, _action)) => paddr,
+            Err(e) => return Err(e),
+            _ => unreachable!("Got unexpected response"),
+        };
+
+        crate::memory::KernelAllocator::try_refill_tcache(1, 0, MemType::Mem)?;
+        let frame = kcb.mem_manager().allocate_base_page()?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                paddr_to_kernel_vaddr(source).as_mut_ptr::<u8>(),
+                frame.kernel_vaddr().as_mut_ptr::<u8>(),
+                BASE_PAGE_SIZE,
+            );
+        }
 
-        let response =
-            PROCESS_TABLE[node][pid].execute(ReadOps::ProcessInfo, kcb.process_token[pid]);
+        let response = replica.execute_mut(Op::MemCopyOnWrite(base, frame), token);
+        match response {
+            Ok(NodeResult::Unmapped(handle, _owned)) => Ok(Some(handle)),
+            // Lost the race against another core, the page is a copy already
+            Err(KError::NotMapped) => {
+                kcb.mem_manager().release_base_page(frame)?;
+                Ok(None)
+            }
+            Err(e) => {
+                kcb.mem_manager().release_base_page(frame)?;
+                Err(e)
+            }
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Maps `frames` at `base` without handing them over to the process.
+    ///
+    /// `mapped` counts the frames that got mapped, so the caller can undo
+    /// them if this fails.
+    pub fn map_shared_frames(
+        pid: Pid,
+        base: VAddr,
+        frames: Vec<Frame>,
+        action: MapAction,
+        mapped: &mut usize,
+    ) -> Result<(u64, u64), KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let mut virtual_offset = 0;
+        for frame in frames {
+            let response = replica.execute_mut(
+                Op::MemMapShared(base + virtual_offset, frame, action),
+                token,
+            );
+            match response {
+                Ok(NodeResult::Mapped) => *mapped += 1,
+                Err(e) => return Err(e),
+                _ => unreachable!("Got unexpected response"),
+            }
+
+            virtual_offset += frame.size();
+        }
+
+        Ok((base.as_u64(), virtual_offset as u64))
+    }
+
+    pub fn pinfo(pid: Pid) -> Result<ProcessInfo, KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute(ReadOps::ProcessInfo, token);
         match response {
             Ok(NodeResult::ProcessInfo(pinfo)) => Ok(pinfo),
             Err(e) => Err(e),
@@ -246,18 +733,16 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
-    pub fn allocate_executor<A>(kcb: &Kcb<A>, pid: Pid) -> Result<Box<P::E>, KError>
+    pub fn allocate_executor<A>(kcb: &mut Kcb<A>, pid: Pid) -> Result<Box<P::E>, KError>
     where
         A: ArchSpecificKcb<Process = P>,
         P: Process + core::marker::Sync + 'static,
     {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
-
         let gtid = kcb.arch.hwthread_id();
         let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
 
-        let response = kcb.arch.process_table()[node][pid]
-            .execute_mut(Op::AssignExecutor(gtid, node), kcb.process_token[pid]);
+        let response = replica.execute_mut(Op::AssignExecutor(gtid, node), token);
         match response {
             Ok(NodeResult::Executor(executor)) => Ok(executor),
             Err(e) => Err(e),
@@ -265,14 +750,24 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
-    pub fn allocate_frame_to_process(pid: Pid, frame: Frame) -> Result<FrameId, KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+    /// Returns the executor that runs on core `gtid` to the process.
+    pub fn release_executor(pid: Pid, gtid: atopology::GlobalThreadId) -> Result<(), KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute_mut(Op::ReleaseExecutor(gtid), token);
+        match response {
+            Ok(NodeResult::ExecutorReleased) => Ok(()),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
 
+    pub fn allocate_frame_to_process(pid: Pid, frame: Frame) -> Result<FrameId, KError> {
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
 
-        let response = PROCESS_TABLE[node][pid]
-            .execute_mut(Op::AllocateFrameToProcess(frame), kcb.process_token[pid]);
+        let response = replica.execute_mut(Op::AllocateFrameToProcess(frame), token);
         match response {
             Ok(NodeResult::FrameId(fid)) => Ok(fid),
             Err(e) => Err(e),
@@ -280,14 +775,109 @@ impl<P: Process> NrProcess<P> {
         }
     }
 
-    pub fn allocate_dispatchers(pid: Pid, frame: Frame) -> Result<usize, KError> {
-        debug_assert!(pid < MAX_PROCESSES, "Invalid PID");
+    /// Subscribes process `pid` to the events in `mask`, returns the previous
+    /// subscription. `EVENT_KILL` can't be subscribed to.
+    pub fn subscribe_events(pid: Pid, mask: u64) -> Result<u64, KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute_mut(Op::SubscribeEvents(mask), token);
+        match response {
+            Ok(NodeResult::Events(previous)) => Ok(previous),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
 
+    /// Returns the events process `pid` subscribed to (as a bitmask).
+    pub fn subscribed_events(pid: Pid) -> Result<u64, KError> {
         let kcb = super::kcb::get_kcb();
-        let node = kcb.arch.node();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute(ReadOps::SubscribedEvents, token);
+        match response {
+            Ok(NodeResult::Events(subscribed)) => Ok(subscribed),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Sends `event` to process `pid`.
+    ///
+    /// Returns `false` if `pid` didn't subscribe to `event`, it's up to the
+    /// caller to decide what happens with the process in that case.
+    pub fn post_event(pid: Pid, event: u64) -> Result<bool, KError> {
+        debug_assert!(event <= kpi::process::MAX_EVENT, "Invalid event");
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute_mut(Op::PostEvent(event), token);
+        match response {
+            Ok(NodeResult::EventPosted(queued)) => Ok(queued),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Returns the lowest event that is pending for process `pid` and
+    /// removes it from the pending events.
+    ///
+    /// Only goes through the log if the local replica has something pending,
+    /// so this is cheap enough to call on every timer interrupt.
+    pub fn take_event(pid: Pid) -> Result<Option<u64>, KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        match replica.execute(ReadOps::PendingEvents, token) {
+            Ok(NodeResult::Events(0)) => return Ok(None),
+            Ok(NodeResult::Events(_pending)) => {}
+            Err(e) => return Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+
+        let response = replica.execute_mut(Op::TakeEvent, token);
+        match response {
+            Ok(NodeResult::EventTaken(event)) => Ok(event),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    /// Sets the NUMA policy for memory of process `pid`, returns the previous
+    /// policy.
+    pub fn set_allocation_policy(
+        pid: Pid,
+        policy: AllocationPolicy,
+    ) -> Result<AllocationPolicy, KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute_mut(Op::SetAllocationPolicy(policy), token);
+        match response {
+            Ok(NodeResult::AllocationPolicy(previous)) => Ok(previous),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
 
-        let response = PROCESS_TABLE[node][pid]
-            .execute_mut(Op::DispatcherAllocation(frame), kcb.process_token[pid]);
+    /// Returns the NUMA policy for memory of process `pid`.
+    pub fn allocation_policy(pid: Pid) -> Result<AllocationPolicy, KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute(ReadOps::AllocationPolicy, token);
+        match response {
+            Ok(NodeResult::AllocationPolicy(policy)) => Ok(policy),
+            Err(e) => Err(e),
+            _ => unreachable!("Got unexpected response"),
+        }
+    }
+
+    pub fn allocate_dispatchers(pid: Pid, frame: Frame) -> Result<usize, KError> {
+        let kcb = super::kcb::get_kcb();
+        let (replica, token) = kcb.process_replica(pid)?;
+
+        let response = replica.execute_mut(Op::DispatcherAllocation(frame), token);
 
         match response {
             Ok(NodeResult::ExecutorsCreated(how_many)) => Ok(how_many),
@@ -314,17 +904,96 @@ where
                 let (paddr, rights) = self.process.vspace().resolve(base)?;
                 Ok(NodeResult::Resolved(paddr, rights))
             }
+            ReadOps::ActiveCores => {
+                let mut cores = Vec::try_with_capacity(self.active_cores.len())?;
+                cores.extend(self.active_cores.iter().map(|(gtid, _eid)| *gtid));
+                Ok(NodeResult::ActiveCores(cores))
+            }
+            ReadOps::Mappings => Ok(NodeResult::Mappings(self.process.vspace().mappings()?)),
+            ReadOps::PendingEvents => Ok(NodeResult::Events(
+                self.pending_events & self.subscribed_events,
+            )),
+            ReadOps::SubscribedEvents => Ok(NodeResult::Events(self.subscribed_events)),
+            ReadOps::AllocationPolicy => Ok(NodeResult::AllocationPolicy(self.allocation_policy)),
+            ReadOps::MemReservation(base) => {
+                let (b, s, _action) = *self
+                    .reserved
+                    .iter()
+                    .find(|(b, s, _action)| *b <= base && base < *b + *s)
+                    .ok_or(KError::NotMapped)?;
+                match self.process.vspace().resolve(base) {
+                    Ok((paddr, rights)) => Ok(NodeResult::Resolved(paddr, rights)),
+                    Err(_) => Ok(NodeResult::Reserved(b, s)),
+                }
+            }
+            ReadOps::MemCopyOnWriteSource(base) => {
+                let (_base, action) = self
+                    .copy_on_write
+                    .iter()
+                    .find(|(b, _action)| *b == base)
+                    .ok_or(KError::NotMapped)?;
+                let (paddr, _rights) = self.process.vspace().resolve(base)?;
+                Ok(NodeResult::Resolved(paddr, *action))
+            }
         }
     }
 
     fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
         match op {
-            Op::Destroy => unimplemented!("Destrroy"),
+            Op::Destroy => {
+                let frames = self.process.destroy()?;
+                for frame in frames {
+                    self.memory.try_push((frame, MemType::Mem))?;
+                }
+                self.active_cores.clear();
+                self.reserved.clear();
+                self.copy_on_write.clear();
+                self.loaded = false;
+                self.subscribed_events = 0;
+                self.pending_events = 0;
+                self.allocation_policy = AllocationPolicy::default();
+                Ok(NodeResult::Destroyed(core::mem::take(&mut self.memory)))
+            }
+            Op::SubscribeEvents(mask) => {
+                let previous = self.subscribed_events;
+                self.subscribed_events = mask & !(1 << kpi::process::EVENT_KILL);
+                self.pending_events &= self.subscribed_events;
+                Ok(NodeResult::Events(previous))
+            }
+            Op::PostEvent(event) => {
+                if !self.loaded {
+                    return Err(KError::NoProcessFoundForPid);
+                }
+                let bit = 1 << event;
+                if self.subscribed_events & bit != 0 {
+                    self.pending_events |= bit;
+                    Ok(NodeResult::EventPosted(true))
+                } else {
+                    Ok(NodeResult::EventPosted(false))
+                }
+            }
+            Op::TakeEvent => {
+                let pending = self.pending_events & self.subscribed_events;
+                if pending == 0 {
+                    Ok(NodeResult::EventTaken(None))
+                } else {
+                    let event = pending.trailing_zeros() as u64;
+                    self.pending_events &= !(1 << event);
+                    Ok(NodeResult::EventTaken(Some(event)))
+                }
+            }
+            Op::SetAllocationPolicy(policy) => {
+                let previous = self.allocation_policy;
+                self.allocation_policy = policy;
+                Ok(NodeResult::AllocationPolicy(previous))
+            }
             Op::ProcRaiseIrq => unimplemented!("ProcRaiseIrq"),
             Op::MemAdjust => unimplemented!("MemAdjust"),
 
-            Op::Load(pid, module, writeable_sections) => {
-                self.process.load(pid, module, writeable_sections)?;
+            Op::Load(pid, binary, writeable_sections) => {
+                self.process
+                    .load(pid, binary.module(), writeable_sections)?;
+                self.loaded = true;
                 Ok(NodeResult::Loaded)
             }
 
@@ -333,9 +1002,11 @@ where
                 Ok(NodeResult::ExecutorsCreated(how_many))
             }
 
-            Op::MemMapFrame(base, frame, action) => {
+            Op::MemMapFrame(base, frame, action, mem_type) => {
                 crate::memory::KernelAllocator::try_refill_tcache(7, 0, MemType::Mem)?;
+                FallibleVec::try_reserve(&mut self.memory, 1)?;
                 self.process.vspace_mut().map_frame(base, frame, action)?;
+                self.memory.try_push((frame, mem_type))?;
                 Ok(NodeResult::Mapped)
             }
 
@@ -346,6 +1017,51 @@ where
                 Ok(NodeResult::Mapped)
             }
 
+            // Like MapFrame, but the process doesn't own (and never gives back) the frame
+            Op::MemMapShared(base, frame, action) => {
+                crate::memory::KernelAllocator::try_refill_tcache(7, 0, MemType::Mem)?;
+                self.process.vspace_mut().map_frame(base, frame, action)?;
+                Ok(NodeResult::Mapped)
+            }
+
+            // Like MapShared, but read-only until the first write
+            Op::MemMapCopyOnWrite(base, frame, action) => {
+                let read_only = if action.is_executable() {
+                    MapAction::ReadExecuteUser
+                } else {
+                    MapAction::ReadUser
+                };
+                crate::memory::KernelAllocator::try_refill_tcache(7, 0, MemType::Mem)?;
+                FallibleVec::try_reserve(&mut self.copy_on_write, 1)?;
+                self.process
+                    .vspace_mut()
+                    .map_frame(base, frame, read_only)?;
+                self.copy_on_write.try_push((base, action))?;
+                Ok(NodeResult::Mapped)
+            }
+
+            Op::MemCopyOnWrite(base, frame) => {
+                let idx = self
+                    .copy_on_write
+                    .iter()
+                    .position(|(b, _action)| *b == base)
+                    .ok_or(KError::NotMapped)?;
+                let (_base, action) = self.copy_on_write[idx];
+
+                crate::memory::KernelAllocator::try_refill_tcache(7, 0, MemType::Mem)?;
+                FallibleVec::try_reserve(&mut self.memory, 1)?;
+                let mut shootdown_handle = self.process.vspace_mut().unmap(base)?;
+                self.process.vspace_mut().map_frame(base, frame, action)?;
+                self.memory.try_push((frame, MemType::Mem))?;
+                self.copy_on_write.swap_remove(idx);
+
+                for (gtid, _eid) in self.active_cores.iter() {
+                    shootdown_handle.add_core(*gtid);
+                }
+                // The old frame isn't ours, nothing to release
+                Ok(NodeResult::Unmapped(shootdown_handle, None))
+            }
+
             Op::MemMapFrameId(base, frame_id, action) => {
                 let frame = self.process.get_frame(frame_id)?;
                 crate::memory::KernelAllocator::try_refill_tcache(7, 0, MemType::Mem)?;
@@ -354,15 +1070,70 @@ where
                 Ok(NodeResult::MappedFrameId(frame.base, frame.size))
             }
 
+            Op::MemReserve(base, size, action) => {
+                let end = base + size;
+                if self
+                    .reserved
+                    .iter()
+                    .any(|(b, s, _action)| base < *b + *s && *b < end)
+                    || self.process.vspace().overlaps(base, size)
+                {
+                    return Err(KError::AlreadyMapped { base });
+                }
+                self.reserved.try_push((base, size, action))?;
+                Ok(NodeResult::Reserved(base, size))
+            }
+
+            Op::MemUnreserve(base) => {
+                let idx = self
+                    .reserved
+                    .iter()
+                    .position(|(b, _size, _action)| *b == base)
+                    .ok_or(KError::NotMapped)?;
+                let (base, size, _action) = self.reserved.swap_remove(idx);
+                Ok(NodeResult::Reserved(base, size))
+            }
+
+            Op::MemMapLazy(base, frame) => {
+                let (_b, _s, action) = *self
+                    .reserved
+                    .iter()
+                    .find(|(b, s, _action)| *b <= base && base < *b + *s)
+                    .ok_or(KError::NotMapped)?;
+                // Some other core might have touched the page first.
+                if let Ok((paddr, rights)) = self.process.vspace().resolve(base) {
+                    return Ok(NodeResult::Resolved(paddr, rights));
+                }
+
+                crate::memory::KernelAllocator::try_refill_tcache(7, 0, MemType::Mem)?;
+                FallibleVec::try_reserve(&mut self.memory, 1)?;
+                self.process.vspace_mut().map_frame(base, frame, action)?;
+                self.memory.try_push((frame, MemType::Mem))?;
+                Ok(NodeResult::Mapped)
+            }
+
             Op::MemUnmap(vaddr) => {
                 let mut shootdown_handle = self.process.vspace_mut().unmap(vaddr)?;
+                self.copy_on_write
+                    .retain(|(base, _action)| *base != shootdown_handle.vaddr);
+                // The frame is handed back to the caller with the handle (the
+                // one we recorded at map time also knows its NUMA affinity)
+                let owned = self
+                    .memory
+                    .iter()
+                    .position(|(frame, _mem_type)| frame.base == shootdown_handle.frame.base)
+                    .map(|idx| {
+                        let (frame, mem_type) = self.memory.swap_remove(idx);
+                        shootdown_handle.frame = frame;
+                        mem_type
+                    });
                 // Figure out which cores are running our current process
                 // (this is where we send IPIs later)
                 for (gtid, _eid) in self.active_cores.iter() {
                     shootdown_handle.add_core(*gtid);
                 }
 
-                Ok(NodeResult::Unmapped(shootdown_handle))
+                Ok(NodeResult::Unmapped(shootdown_handle, owned))
             }
 
             Op::AssignExecutor(gtid, region) => {
@@ -372,6 +1143,18 @@ where
                 Ok(NodeResult::Executor(executor))
             }
 
+            Op::ReleaseExecutor(gtid) => {
+                let idx = self
+                    .active_cores
+                    .iter()
+                    .position(|(core, _eid)| *core == gtid)
+                    .ok_or(KError::NoExecutorForCore)?;
+                let (_gtid, eid) = self.active_cores[idx];
+                self.process.release_executor(eid)?;
+                self.active_cores.swap_remove(idx);
+                Ok(NodeResult::ExecutorReleased)
+            }
+
             Op::AllocateFrameToProcess(frame) => {
                 let fid = self.process.add_frame(frame)?;
                 Ok(NodeResult::FrameId(fid))
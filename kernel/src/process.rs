@@ -12,11 +12,11 @@ use core::fmt::Debug;
 use arrayvec::ArrayVec;
 use cstr_core::CStr;
 use fallible_collections::vec::FallibleVecGlobal;
-use fallible_collections::vec::TryCollect;
+use fallible_collections::FallibleVec;
 use fallible_collections::TryReserveError;
 use kpi::process::{FrameId, ELF_OFFSET};
 use kpi::MemType;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 
 use crate::arch::memory::{paddr_to_kernel_vaddr, LARGE_PAGE_SIZE};
 use crate::arch::process::UserPtr;
@@ -24,12 +24,15 @@ use crate::arch::{Module, MAX_CORES, MAX_NUMA_NODES};
 use crate::error::KError;
 use crate::fallible_string::TryString;
 use crate::fs::Fd;
+use crate::kcb::ArchSpecificKcb;
 use crate::memory::vspace::AddressSpace;
 use crate::memory::{Frame, KernelAllocator, PhysicalPageProvider, VAddr};
 use crate::prelude::overlaps;
 use crate::{cnrfs, kcb, nr, nrproc, round_up};
 
-/// How many (concurrent) processes the systems supports.
+/// How many (concurrent) processes the systems supports by default.
+///
+/// Can be changed with `maxprocs=<n>` on the kernel command line.
 pub const MAX_PROCESSES: usize = 12;
 
 /// How many registered "named" frames a process can have.
@@ -108,6 +111,10 @@ pub trait Process {
 
     fn get_executor(&mut self, for_region: atopology::NodeId) -> Result<Box<Self::E>, KError>;
 
+    /// Puts the executor `eid` (handed out by `get_executor`) back into the
+    /// cache so another core can use it.
+    fn release_executor(&mut self, eid: Eid) -> Result<(), KError>;
+
     fn allocate_fd(&mut self) -> Option<(u64, &mut Fd)>;
 
     fn deallocate_fd(&mut self, fd: usize) -> Result<usize, KError>;
@@ -119,6 +126,14 @@ pub trait Process {
     fn add_frame(&mut self, frame: Frame) -> Result<FrameId, KError>;
     fn get_frame(&mut self, frame_id: FrameId) -> Result<Frame, KError>;
     fn deallocate_frame(&mut self, fid: FrameId) -> Result<Frame, KError>;
+
+    /// Tears down the process and resets `self` so it can be reused for a new
+    /// process.
+    ///
+    /// Memory that exists for every replica (e.g., page-tables, read-only ELF
+    /// sections) is freed directly. Frames that are shared among all replicas
+    /// are returned and must be freed once by the caller.
+    fn destroy(&mut self) -> Result<Vec<Frame>, KError>;
 }
 
 /// ResumeHandle is the HW specific logic that switches the CPU
@@ -156,11 +171,28 @@ struct DataSecAllocator {
 impl DataSecAllocator {
     /// We can call finish on it to return the ordered list of frames that were
     /// used for the writeable section.
-    fn finish(self) -> Result<Vec<Frame>, TryReserveError> {
-        self.frames
-            .into_iter()
-            .map(|(_offset, base)| base)
-            .try_collect()
+    ///
+    /// The frames stay with the allocator if this fails.
+    fn finish(&mut self) -> Result<Vec<Frame>, TryReserveError> {
+        let mut frames = Vec::try_with_capacity(self.frames.len())?;
+        frames.extend(self.frames.drain(..).map(|(_offset, base)| base));
+        Ok(frames)
+    }
+
+    /// Gives the frames allocated so far back (i.e., loading the binary
+    /// failed).
+    fn release(&mut self) {
+        for (_offset, frame) in self.frames.drain(..) {
+            release_frame(frame);
+        }
+    }
+}
+
+/// Gives `frame` back to the allocator, for error paths that already have an
+/// error to report.
+fn release_frame(frame: Frame) {
+    if let Err(e) = KernelAllocator::release_frame(frame, MemType::Mem) {
+        warn!("Unable to release {:?}: {}", frame, e);
     }
 }
 
@@ -193,14 +225,16 @@ impl elfloader::ElfLoader for DataSecAllocator {
                 );
                 let large_pages = size_page / LARGE_PAGE_SIZE;
                 KernelAllocator::try_refill_tcache(0, large_pages, MemType::Mem)
-                    .expect("Refill didn't work");
+                    .map_err(|_e| elfloader::ElfLoaderErr::OutOfMemory)?;
+                FallibleVec::try_reserve(&mut self.frames, large_pages)
+                    .map_err(|_e| elfloader::ElfLoaderErr::OutOfMemory)?;
 
                 let kcb = crate::kcb::get_kcb();
                 let mut pmanager = kcb.mem_manager();
                 for i in 0..large_pages {
                     let frame = pmanager
                         .allocate_large_page()
-                        .expect("We refilled so allocation should work.");
+                        .map_err(|_e| elfloader::ElfLoaderErr::OutOfMemory)?;
 
                     trace!(
                         "add to self.frames  (elf_va={:#x}, pa={:#x})",
@@ -338,25 +372,121 @@ impl elfloader::ElfLoader for DataSecAllocator {
 /// Parse & relocate ELF
 /// Create an initial VSpace
 pub fn make_process<P: Process>(binary: &'static str) -> Result<Pid, KError> {
-    KernelAllocator::try_refill_tcache(7, 1, MemType::Mem)?;
     let kcb = kcb::get_kcb();
 
     // Lookup binary of the process
-    let mut mod_file = None;
-    for module in &kcb.arch.kernel_args().modules {
-        if module.name() == binary {
-            mod_file = Some(module);
-        }
-    }
-
-    let mod_file = mod_file.ok_or(KError::BinaryNotFound { binary })?;
+    let mod_file = find_module(binary).ok_or(KError::BinaryNotFound { binary })?;
     info!(
         "binary={} cmdline={} module={:?}",
         binary, kcb.cmdline.init_args, mod_file
     );
 
+    make_process_from_module::<P>(Binary::Boot(mod_file), None)
+}
+
+/// The ELF file a process is created from.
+#[derive(PartialEq, Clone)]
+pub enum Binary {
+    /// A module passed to the kernel by the bootloader.
+    Boot(&'static Module),
+    /// A file read from the file-system, the `Module` describes the buffer.
+    ///
+    /// The buffer is freed once neither the process nor the NR log refers to
+    /// it anymore.
+    File(Module, Arc<Vec<u8>>),
+}
+
+impl Debug for Binary {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Leave out the contents of the file
+        write!(f, "Binary {{ {:?} }}", self.module())
+    }
+}
+
+impl Binary {
+    pub fn module(&self) -> &Module {
+        match self {
+            Binary::Boot(module) => module,
+            Binary::File(module, _buffer) => module,
+        }
+    }
+}
+
+/// Find a binary with the given `name` in the modules passed by the bootloader.
+pub fn find_module(name: &str) -> Option<&'static Module> {
+    let kcb = kcb::get_kcb();
+    kcb.arch
+        .kernel_args()
+        .modules
+        .iter()
+        .rev()
+        .find(|module| module.name() == name)
+}
+
+/// Reads the ELF file at `pathname` from the file-system.
+///
+/// The file is opened on behalf of process `pid`, `pathname` is a
+/// (null-terminated) string in its address space.
+pub fn load_module_from_fs(pid: Pid, pathname: u64) -> Result<Binary, KError> {
+    let name = userptr_to_str(pathname)?;
+
+    let mut info: kpi::io::FileInfo = Default::default();
+    cnrfs::MlnrKernelNode::file_info(pid, pathname, &mut info as *mut _ as u64)?;
+    if info.ftype != kpi::io::FileType::File.into() || info.fsize == 0 {
+        return Err(KError::InvalidFile);
+    }
+
+    let size = info.fsize as usize;
+    let mut binary: Vec<u8> = Vec::try_with_capacity(size)?;
+    let (fd, _) = cnrfs::MlnrKernelNode::map_fd(
+        pid,
+        pathname,
+        kpi::io::FileFlags::O_RDONLY.into(),
+        kpi::io::FileModes::S_IRUSR.into(),
+    )?;
+    let read = cnrfs::MlnrKernelNode::file_io(
+        kpi::FileOperation::ReadAt,
+        pid,
+        fd,
+        binary.as_mut_ptr() as u64,
+        size as u64,
+        0,
+    );
+    cnrfs::MlnrKernelNode::unmap_fd(pid, fd)?;
+    let (read, _) = read?;
+    if read as usize != size {
+        return Err(KError::UnableToLoad);
+    }
+    unsafe { binary.set_len(size) };
+
+    // Moving the Vec into the Arc doesn't move the buffer
+    let binary = Arc::try_new(binary)?;
+    let vaddr = VAddr::from(binary.as_ptr() as u64);
+    let module = Module::new(
+        &name,
+        vaddr,
+        crate::memory::kernel_vaddr_to_paddr(vaddr),
+        size,
+    );
+
+    Ok(Binary::File(module, binary))
+}
+
+/// Create a process from the ELF binary in `binary`.
+///
+/// Allocates a new Pid (a child of `parent`, if given), and loads the binary
+/// on every replica. If this fails, the Pid, the FS state and the memory of
+/// the process are given back.
+pub fn make_process_from_module<P: Process>(
+    binary: Binary,
+    parent: Option<Pid>,
+) -> Result<Pid, KError> {
+    KernelAllocator::try_refill_tcache(7, 1, MemType::Mem)?;
+    let kcb = kcb::get_kcb();
+
     let elf_module = unsafe {
-        elfloader::ElfBinary::new(mod_file.as_slice()).map_err(|_e| KError::UnableToParseElf)?
+        elfloader::ElfBinary::new(binary.module().as_slice())
+            .map_err(|_e| KError::UnableToParseElf)?
     };
 
     // We don't have an offset for non-pie applications (i.e., rump apps)
@@ -370,30 +500,69 @@ pub fn make_process<P: Process>(binary: &'static str) -> Result<Pid, KError> {
         offset,
         frames: Vec::try_with_capacity(MAX_WRITEABLE_SECTIONS_PER_PROCESS)?,
     };
-    elf_module
+    let data_frames = elf_module
         .load(&mut data_sec_loader)
-        .map_err(|_e| KError::UnableToLoad)?;
-    let data_frames: Vec<Frame> = data_sec_loader.finish()?;
-    debug_assert!(
-        data_frames.len() <= MAX_WRITEABLE_SECTIONS_PER_PROCESS,
-        "TODO(error-handlin): Maybe reject ELF files with more?"
-    );
+        .map_err(|_e| KError::UnableToLoad)
+        .and_then(|()| Ok(data_sec_loader.finish()?));
+    let data_frames: Vec<Frame> = match data_frames {
+        Ok(frames) if frames.len() <= MAX_WRITEABLE_SECTIONS_PER_PROCESS => frames,
+        Ok(frames) => {
+            frames.into_iter().for_each(release_frame);
+            return Err(KError::UnableToLoad);
+        }
+        Err(e) => {
+            data_sec_loader.release();
+            return Err(e);
+        }
+    };
 
     // Allocate a new process
-    kcb.replica
-        .as_ref()
-        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
-            let response = replica.execute_mut(nr::Op::AllocatePid, *token)?;
-            if let nr::NodeResult::PidAllocated(pid) = response {
-                cnrfs::MlnrKernelNode::add_process(pid)
-                    .expect("TODO(error-handling): revert state");
-                crate::nrproc::NrProcess::<P>::load(pid, mod_file, data_frames)
-                    .expect("TODO(error-handling): revert state properly");
-                Ok(pid)
-            } else {
-                Err(KError::ProcessLoadingFailed)
+    let pid = match nr::KernelNode::allocate_pid(parent) {
+        Ok(pid) => pid,
+        Err(e) => {
+            data_frames.into_iter().for_each(release_frame);
+            return Err(e);
+        }
+    };
+
+    let created = kcb
+        .arch
+        .process_table()
+        .create(pid)
+        .and_then(|()| cnrfs::MlnrKernelNode::add_process(pid));
+    if let Err(e) = created {
+        data_frames.into_iter().for_each(release_frame);
+        revert_pid(pid);
+        return Err(e);
+    }
+
+    // The process owns the data frames from now on, `destroy` gives them back
+    if let Err(e) = crate::nrproc::NrProcess::<P>::load(pid, binary, data_frames) {
+        match crate::nrproc::NrProcess::<P>::destroy(pid) {
+            Ok(frames) => {
+                for (frame, mem_type) in frames {
+                    if let Err(e) = KernelAllocator::release_frame(frame, mem_type) {
+                        warn!("Unable to release {:?}: {}", frame, e);
+                    }
+                }
             }
-        })
+            Err(e) => warn!("Unable to destroy process {}: {}", pid, e),
+        }
+        if let Err(e) = cnrfs::MlnrKernelNode::remove_process(pid) {
+            warn!("Unable to remove process {} from the FS: {}", pid, e);
+        }
+        revert_pid(pid);
+        return Err(e);
+    }
+
+    Ok(pid)
+}
+
+/// Hands out `pid` again after spawning the process failed.
+fn revert_pid(pid: Pid) {
+    if let Err(e) = nr::KernelNode::release_pid(pid) {
+        warn!("Unable to release pid {}: {}", pid, e);
+    }
 }
 
 /// Create dispatchers for a given Pid to run on all cores.
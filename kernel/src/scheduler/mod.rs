@@ -5,6 +5,8 @@
 
 use core::intrinsics::unlikely;
 
+use log::warn;
+
 use crate::error::KError;
 use crate::kcb::{self, ArchSpecificKcb};
 use crate::nr;
@@ -13,7 +15,51 @@ use crate::process::{Executor, ResumeHandle};
 
 use crate::arch::timer;
 
-/// Runs the process allocated to the given core.
+mod runqueue;
+
+pub use runqueue::RunQueue;
+
+/// Makes sure the run-queue of the core has an executor for every process
+/// that is assigned to the core.
+///
+/// Executors of processes that no longer run on the core are removed when
+/// the core is released (or the process exits).
+pub fn update_run_queue() -> Result<(), KError> {
+    let kcb = kcb::get_kcb();
+    let response = {
+        let (replica, token) = kcb.replica.as_ref().ok_or(KError::ReplicaNotSet)?;
+        replica.execute(nr::ReadOps::CoreAssignments(kcb.arch.hwthread_id()), *token)
+    };
+
+    let assigned = match response {
+        Ok(nr::NodeResult::CoreAssignments(assigned)) => assigned,
+        Err(KError::NoExecutorForCore) => return Ok(()),
+        Err(e) => return Err(e),
+        Ok(_) => unreachable!("Got unexpected response"),
+    };
+
+    for ci in assigned {
+        let is_running = kcb
+            .arch
+            .current_executor()
+            .map_or(false, |e| e.pid() == ci.pid);
+        if is_running || kcb.arch.run_queue.contains(ci.pid) {
+            continue;
+        }
+
+        kcb.arch.run_queue.try_reserve(1)?;
+        let executor = NrProcess::allocate_executor(kcb, ci.pid)?;
+        unsafe {
+            (*executor.vcpu_kernel()).resume_with_upcall = ci.entry_point;
+        }
+        kcb.arch.run_queue.push(executor, false)?;
+    }
+
+    Ok(())
+}
+
+/// Runs the current executor of the core, or the next one from the run-queue
+/// if the core doesn't have one (idles until there is something to run).
 pub fn schedule() -> ! {
     let kcb = kcb::get_kcb();
 
@@ -34,63 +80,55 @@ pub fn schedule() -> ! {
     #[cfg(not(target_os = "none"))]
     let is_replica_main_thread = false;
 
-    // No process assigned to core? Figure out if there is one now:
+    // No executor on the core? Figure out if there is one now:
+    let mut was_preempted = false;
     if unlikely(kcb.arch.current_executor().is_err()) {
-        if let Some((replica, token)) = kcb.replica.as_ref() {
-            loop {
-                let response =
-                    replica.execute(nr::ReadOps::CurrentProcess(kcb.arch.hwthread_id()), *token);
-
-                match response {
-                    Ok(nr::NodeResult::CoreInfo(ci)) => {
-                        let executor =
-                            NrProcess::allocate_executor(kcb, ci.pid).expect("This should work");
-                        unsafe {
-                            (*executor.vcpu_kernel()).resume_with_upcall = ci.entry_point;
-                        }
-
-                        // info!("Start execution of {} on gtid {}", executor.eid, gtid);
-                        let no = kcb::get_kcb().arch.swap_current_executor(executor);
-                        assert!(no.is_none(), "Handle the case where we replace a process.");
-                        if is_replica_main_thread {
-                            // Make sure we periodically try and advance the replica on main-thread
-                            // even if we're running something (e.g., if everything polls in
-                            // user-space we can livelock)
-                            timer::set(timer::DEFAULT_TIMER_DEADLINE);
-                        }
-                        break;
-                    }
-                    Err(KError::NoExecutorForCore) => {
-                        if is_replica_main_thread {
-                            // There is no process but we're the "main" thread,
-                            // aggressively try and advance the replica
-                            let start = rawtime::Instant::now();
-                            crate::nrproc::advance_all();
-                            crate::arch::advance_fs_replica();
-
-                            if start.elapsed().as_millis() < 1 {
-                                // Wait for a bit in case we don't end up doing
-                                // any work, otherwise this causes too much
-                                // contention and tput drops around ~300k
-                                for _i in 0..25_000 {
-                                    core::hint::spin_loop();
-                                }
-                            }
-                            continue;
-                        } else {
-                            // There is no process, set a timer and go to sleep
-                            timer::set(timer::DEFAULT_TIMER_DEADLINE);
-                        }
-                        crate::arch::halt();
-                    }
-                    other => {
-                        unreachable!(
-                            "Unexpected return from ReadOps::CurrentExecutor {:?}.",
-                            other
-                        );
+        loop {
+            if let Err(e) = update_run_queue() {
+                warn!("Unable to update the run-queue: {:?}", e);
+            }
+
+            if let Some((executor, preempted)) = kcb.arch.run_queue.pop() {
+                // info!("Start execution of {} on gtid {}", executor.eid, gtid);
+                let no = kcb::get_kcb().arch.swap_current_executor(executor);
+                assert!(no.is_none(), "Core already had an executor.");
+                was_preempted = preempted;
+
+                if !kcb.arch.run_queue.is_empty() {
+                    // Others are waiting for the core, make sure we switch to
+                    // them when the time-slice is up
+                    timer::set(timer::DEFAULT_TIME_SLICE);
+                } else {
+                    // Make sure we periodically try and advance the replica
+                    // (and notice new processes for this core) even if we're
+                    // running something (e.g., if everything polls in
+                    // user-space we can livelock)
+                    timer::set(timer::DEFAULT_TIMER_DEADLINE);
+                }
+                break;
+            }
+
+            if is_replica_main_thread {
+                // There is no process but we're the "main" thread,
+                // aggressively try and advance the replica
+                let start = rawtime::Instant::now();
+                crate::nrproc::advance_all();
+                crate::arch::advance_fs_replica();
+
+                if start.elapsed().as_millis() < 1 {
+                    // Wait for a bit in case we don't end up doing
+                    // any work, otherwise this causes too much
+                    // contention and tput drops around ~300k
+                    for _i in 0..25_000 {
+                        core::hint::spin_loop();
                     }
-                };
+                }
+                continue;
+            } else {
+                // There is no process, set a timer and go to sleep
+                timer::set(timer::DEFAULT_TIMER_DEADLINE);
             }
+            crate::arch::halt();
         }
     }
     debug_assert!(
@@ -100,7 +138,13 @@ pub fn schedule() -> ! {
 
     // If we come here, we have a new process, dispatch it:
     unsafe {
-        let rh = kcb::get_kcb().arch.current_executor().map(|p| p.start());
+        let rh = kcb::get_kcb().arch.current_executor().map(|p| {
+            if was_preempted {
+                p.resume()
+            } else {
+                p.start()
+            }
+        });
         rh.unwrap().resume()
     }
 }
@@ -0,0 +1,131 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A per-core queue of executors that wait for their turn to run.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use crate::error::KError;
+use crate::process::{Executor, Pid};
+
+/// An executor that waits in a `RunQueue`.
+struct Runnable<E> {
+    executor: Box<E>,
+    /// The executor ran before and has to be resumed from its save-area
+    /// (instead of being started).
+    preempted: bool,
+}
+
+/// Executors that are assigned to a core but are currently not running.
+///
+/// Executors are picked round-robin: a preempted executor goes to the back
+/// of the queue.
+pub struct RunQueue<E> {
+    /// Created on the first use (`VecDeque::new` allocates, so we can't call
+    /// it in `new`).
+    ready: Option<VecDeque<Runnable<E>>>,
+}
+
+impl<E: Executor> RunQueue<E> {
+    pub const fn new() -> Self {
+        RunQueue { ready: None }
+    }
+
+    fn ready(&mut self) -> &mut VecDeque<Runnable<E>> {
+        self.ready.get_or_insert_with(VecDeque::new)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ready.as_ref().map_or(true, |ready| ready.is_empty())
+    }
+
+    /// Makes sure the next `push` can't fail.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), KError> {
+        self.ready().try_reserve(additional)?;
+        Ok(())
+    }
+
+    /// Adds `executor` to the back of the queue.
+    pub fn push(&mut self, executor: Box<E>, preempted: bool) -> Result<(), KError> {
+        let ready = self.ready();
+        ready.try_reserve(1)?;
+        ready.push_back(Runnable {
+            executor,
+            preempted,
+        });
+        Ok(())
+    }
+
+    /// Takes the executor that waited the longest, along with whether it
+    /// was preempted before.
+    pub fn pop(&mut self) -> Option<(Box<E>, bool)> {
+        let r = self.ready.as_mut()?.pop_front()?;
+        Some((r.executor, r.preempted))
+    }
+
+    /// Is an executor of `pid` waiting in the queue?
+    pub fn contains(&self, pid: Pid) -> bool {
+        self.ready.iter().flatten().any(|r| r.executor.pid() == pid)
+    }
+
+    /// Removes (and drops) all executors of `pid` from the queue.
+    pub fn remove(&mut self, pid: Pid) {
+        if let Some(ready) = self.ready.as_mut() {
+            ready.retain(|r| r.executor.pid() != pid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arch::process::UnixThread;
+
+    fn executor(pid: Pid, eid: usize) -> Box<UnixThread> {
+        Box::new(UnixThread { eid, pid })
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut rq: RunQueue<UnixThread> = RunQueue::new();
+        assert!(rq.is_empty());
+        assert!(rq.pop().is_none());
+
+        rq.push(executor(1, 0), false).expect("Can't push");
+        rq.push(executor(2, 1), true).expect("Can't push");
+        assert!(!rq.is_empty());
+
+        // Executors come out in the order they went in, with their flag
+        let (e, preempted) = rq.pop().expect("Queue has two executors");
+        assert_eq!((e.pid, e.eid, preempted), (1, 0, false));
+        rq.push(e, true).expect("Can't push");
+
+        let (e, preempted) = rq.pop().expect("Queue has two executors");
+        assert_eq!((e.pid, e.eid, preempted), (2, 1, true));
+        let (e, preempted) = rq.pop().expect("Queue has one executor");
+        assert_eq!((e.pid, e.eid, preempted), (1, 0, true));
+        assert!(rq.pop().is_none());
+        assert!(rq.is_empty());
+    }
+
+    #[test]
+    fn remove_by_pid() {
+        let mut rq: RunQueue<UnixThread> = RunQueue::new();
+        rq.remove(1);
+        assert!(!rq.contains(1));
+
+        rq.try_reserve(3).expect("Can't reserve");
+        rq.push(executor(1, 0), false).expect("Can't push");
+        rq.push(executor(2, 1), false).expect("Can't push");
+        rq.push(executor(1, 2), true).expect("Can't push");
+        assert!(rq.contains(1));
+        assert!(rq.contains(2));
+
+        rq.remove(1);
+        assert!(!rq.contains(1));
+        let (e, preempted) = rq.pop().expect("Executor of pid 2 is left");
+        assert_eq!((e.pid, e.eid, preempted), (2, 1, false));
+        assert!(rq.is_empty());
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Interrupt vectors that processes allocate for the MSI / MSI-X interrupts
+//! of their devices.
+//!
+//! Every core has its own set of vectors, so a process can spread the
+//! interrupts of a device over the cores it runs on. The allocations are
+//! part of the replicated state in [`KernelNode`](crate::nr::KernelNode).
+//!
+//! The first process that routes an interrupt of a PCI device owns the
+//! device until it exits, nobody else can route the interrupts of the device.
+//!
+//! The routes themselves are kept here too: the device still raises a vector
+//! after the vector is freed, so the kernel has to unbind the device entries
+//! (see `pci::unbind_vector`) before it frees a vector, releases a core or
+//! the process exits.
+
+use alloc::vec::Vec;
+
+use fallible_collections::FallibleVec;
+use hashbrown::HashMap;
+use kpi::io::{PciAddress, FIRST_DEVICE_VECTOR, LAST_DEVICE_VECTOR};
+
+use crate::error::KError;
+use crate::nr::{NodeResult, Op, ReadOps};
+use crate::process::Pid;
+
+/// An interrupt (MSI / MSI-X entry) of a device that is routed to a vector.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Binding {
+    pub device: PciAddress,
+    pub entry: u64,
+    pub gtid: atopology::GlobalThreadId,
+    pub vector: u64,
+}
+
+/// Vectors allocated on all cores.
+#[derive(Default)]
+pub struct Vectors {
+    /// Owner of every allocated (core, vector) pair.
+    owners: HashMap<(atopology::GlobalThreadId, u64), Pid>,
+    /// Owner of every device a process routes interrupts of.
+    devices: HashMap<PciAddress, Pid>,
+    /// The (core, vector) pair every routed device entry raises.
+    routes: HashMap<(PciAddress, u64), (atopology::GlobalThreadId, u64)>,
+}
+
+impl Vectors {
+    /// Allocates the lowest free vector on core `gtid` for `pid`.
+    pub fn allocate(&mut self, pid: Pid, gtid: atopology::GlobalThreadId) -> Result<u64, KError> {
+        let vector = (FIRST_DEVICE_VECTOR..=LAST_DEVICE_VECTOR)
+            .find(|vector| !self.owners.contains_key(&(gtid, *vector)))
+            .ok_or(KError::OutOfVectors)?;
+
+        self.owners.try_reserve(1)?;
+        self.owners.insert((gtid, vector), pid);
+        Ok(vector)
+    }
+
+    pub fn owner(&self, gtid: atopology::GlobalThreadId, vector: u64) -> Option<Pid> {
+        self.owners.get(&(gtid, vector)).copied()
+    }
+
+    /// Frees `vector` on core `gtid`, fails unless `pid` allocated it.
+    pub fn free(
+        &mut self,
+        pid: Pid,
+        gtid: atopology::GlobalThreadId,
+        vector: u64,
+    ) -> Result<(), KError> {
+        if self.owner(gtid, vector) != Some(pid) {
+            return Err(KError::VectorNotAllocated);
+        }
+        self.owners.remove(&(gtid, vector));
+        self.routes
+            .retain(|_entry, &mut target| target != (gtid, vector));
+        Ok(())
+    }
+
+    /// Makes `pid` the owner of `device`, fails if another process owns it.
+    pub fn claim_device(&mut self, pid: Pid, device: PciAddress) -> Result<(), KError> {
+        match self.devices.get(&device) {
+            Some(&owner) if owner != pid => Err(KError::DeviceNotOwned),
+            Some(_) => Ok(()),
+            None => {
+                self.devices.try_reserve(1)?;
+                self.devices.insert(device, pid);
+                Ok(())
+            }
+        }
+    }
+
+    /// Routes interrupt `entry` of `device` to `vector` on core `gtid`.
+    ///
+    /// Fails unless `pid` allocated the vector and nobody else owns the
+    /// device. A previous route of the entry is replaced.
+    pub fn bind(&mut self, pid: Pid, binding: Binding) -> Result<(), KError> {
+        if self.owner(binding.gtid, binding.vector) != Some(pid) {
+            return Err(KError::VectorNotAllocated);
+        }
+        self.claim_device(pid, binding.device)?;
+
+        self.routes.try_reserve(1)?;
+        self.routes.insert(
+            (binding.device, binding.entry),
+            (binding.gtid, binding.vector),
+        );
+        Ok(())
+    }
+
+    /// All device entries that are routed to vectors of `pid`.
+    pub fn bindings(&self, pid: Pid) -> Result<Vec<Binding>, KError> {
+        let mut bindings = Vec::new();
+        for (&(device, entry), &(gtid, vector)) in self.routes.iter() {
+            if self.owner(gtid, vector) == Some(pid) {
+                bindings.try_push(Binding {
+                    device,
+                    entry,
+                    gtid,
+                    vector,
+                })?;
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// Frees the vectors `pid` allocated on core `gtid` (i.e., when the
+    /// process gives the core back).
+    pub fn free_core(&mut self, pid: Pid, gtid: atopology::GlobalThreadId) {
+        self.owners
+            .retain(|&(core, _vector), &mut owner| owner != pid || core != gtid);
+        self.drop_stale_routes();
+    }
+
+    /// Frees all vectors and devices of `pid`.
+    pub fn free_all(&mut self, pid: Pid) {
+        self.owners.retain(|_core_vector, &mut owner| owner != pid);
+        self.devices.retain(|_device, &mut owner| owner != pid);
+        self.drop_stale_routes();
+    }
+
+    /// Forgets the routes to vectors that are no longer allocated.
+    fn drop_stale_routes(&mut self) {
+        let owners = &self.owners;
+        self.routes
+            .retain(|_entry, target| owners.contains_key(target));
+    }
+}
+
+/// Allocates a vector on core `gtid` for `pid`.
+///
+/// Fails with `KError::CoreNotAllocated` unless `pid` runs on `gtid`.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn allocate(pid: Pid, gtid: atopology::GlobalThreadId) -> Result<u64, KError> {
+    let kcb = super::kcb::get_kcb();
+    kcb.replica
+        .as_ref()
+        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+            let response = replica.execute_mut(Op::VectorAllocate(pid, gtid), *token);
+            match response {
+                Ok(NodeResult::VectorAllocated(vector)) => Ok(vector),
+                Err(e) => Err(e),
+                Ok(_) => unreachable!("Got unexpected response"),
+            }
+        })
+}
+
+/// Frees `vector` on core `gtid` (which `pid` allocated).
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn free(pid: Pid, gtid: atopology::GlobalThreadId, vector: u64) -> Result<(), KError> {
+    let kcb = super::kcb::get_kcb();
+    kcb.replica
+        .as_ref()
+        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+            let response = replica.execute_mut(Op::VectorFree(pid, gtid, vector), *token);
+            match response {
+                Ok(NodeResult::VectorFreed) => Ok(()),
+                Err(e) => Err(e),
+                Ok(_) => unreachable!("Got unexpected response"),
+            }
+        })
+}
+
+/// Records that interrupt `binding.entry` of `binding.device` raises
+/// `binding.vector` on core `binding.gtid` (and makes `pid` the owner of the
+/// device).
+///
+/// Fails with `KError::DeviceNotOwned` if another process owns the device.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn bind(pid: Pid, binding: Binding) -> Result<(), KError> {
+    let kcb = super::kcb::get_kcb();
+    kcb.replica
+        .as_ref()
+        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+            let response = replica.execute_mut(Op::VectorBind(pid, binding), *token);
+            match response {
+                Ok(NodeResult::VectorBound) => Ok(()),
+                Err(e) => Err(e),
+                Ok(_) => unreachable!("Got unexpected response"),
+            }
+        })
+}
+
+/// Returns all device entries that are routed to vectors of `pid`.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn bindings(pid: Pid) -> Result<Vec<Binding>, KError> {
+    let kcb = super::kcb::get_kcb();
+    kcb.replica
+        .as_ref()
+        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+            let response = replica.execute(ReadOps::VectorBindings(pid), *token);
+            match response {
+                Ok(NodeResult::VectorBindings(bindings)) => Ok(bindings),
+                Err(e) => Err(e),
+                Ok(_) => unreachable!("Got unexpected response"),
+            }
+        })
+}
+
+/// Returns the process that allocated `vector` on core `gtid`.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+pub fn owner(gtid: atopology::GlobalThreadId, vector: u64) -> Result<Option<Pid>, KError> {
+    let kcb = super::kcb::get_kcb();
+    kcb.replica
+        .as_ref()
+        .map_or(Err(KError::ReplicaNotSet), |(replica, token)| {
+            let response = replica.execute(ReadOps::VectorOwner(gtid, vector), *token);
+            match response {
+                Ok(NodeResult::VectorOwner(owner)) => Ok(owner),
+                Err(e) => Err(e),
+                Ok(_) => unreachable!("Got unexpected response"),
+            }
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allocate_per_core() {
+        let mut vectors = Vectors::default();
+        let a = vectors.allocate(1, 0).unwrap();
+        let b = vectors.allocate(1, 0).unwrap();
+        assert_ne!(a, b);
+        // Every core has its own vectors
+        assert_eq!(vectors.allocate(2, 1), Ok(a));
+
+        assert_eq!(vectors.owner(0, a), Some(1));
+        assert_eq!(vectors.owner(1, a), Some(2));
+        assert_eq!(vectors.owner(1, b), None);
+    }
+
+    #[test]
+    fn out_of_vectors() {
+        let mut vectors = Vectors::default();
+        for _ in FIRST_DEVICE_VECTOR..=LAST_DEVICE_VECTOR {
+            vectors.allocate(1, 0).unwrap();
+        }
+        assert_eq!(vectors.allocate(1, 0), Err(KError::OutOfVectors));
+
+        vectors.free_core(1, 1);
+        assert_eq!(vectors.allocate(1, 0), Err(KError::OutOfVectors));
+        vectors.free_core(1, 0);
+        assert_eq!(vectors.allocate(1, 0), Ok(FIRST_DEVICE_VECTOR));
+    }
+
+    #[test]
+    fn free_all() {
+        let mut vectors = Vectors::default();
+        let a = vectors.allocate(1, 0).unwrap();
+        let b = vectors.allocate(2, 0).unwrap();
+        let c = vectors.allocate(1, 1).unwrap();
+
+        vectors.free_all(1);
+        assert_eq!(vectors.owner(0, a), None);
+        assert_eq!(vectors.owner(0, b), Some(2));
+        assert_eq!(vectors.owner(1, c), None);
+        assert_eq!(vectors.allocate(3, 0), Ok(a));
+    }
+
+    #[test]
+    fn free_vector() {
+        let mut vectors = Vectors::default();
+        let a = vectors.allocate(1, 0).unwrap();
+        assert_eq!(vectors.free(2, 0, a), Err(KError::VectorNotAllocated));
+        assert_eq!(vectors.free(1, 1, a), Err(KError::VectorNotAllocated));
+        assert_eq!(vectors.free(1, 0, a), Ok(()));
+        assert_eq!(vectors.owner(0, a), None);
+        assert_eq!(vectors.free(1, 0, a), Err(KError::VectorNotAllocated));
+    }
+
+    #[test]
+    fn device_owner() {
+        let mut vectors = Vectors::default();
+        let nic = PciAddress {
+            bus: 0,
+            dev: 3,
+            fun: 0,
+        };
+        assert_eq!(vectors.claim_device(1, nic), Ok(()));
+        assert_eq!(vectors.claim_device(1, nic), Ok(()));
+        assert_eq!(vectors.claim_device(2, nic), Err(KError::DeviceNotOwned));
+
+        // The device is free again once the owner exits
+        vectors.free_all(1);
+        assert_eq!(vectors.claim_device(2, nic), Ok(()));
+    }
+
+    #[test]
+    fn bindings() {
+        let mut vectors = Vectors::default();
+        let nic = PciAddress {
+            bus: 0,
+            dev: 3,
+            fun: 0,
+        };
+        let a = vectors.allocate(1, 0).unwrap();
+        let b = vectors.allocate(1, 1).unwrap();
+        let route = |entry, gtid, vector| Binding {
+            device: nic,
+            entry,
+            gtid,
+            vector,
+        };
+
+        assert_eq!(
+            vectors.bind(2, route(0, 0, a)),
+            Err(KError::VectorNotAllocated)
+        );
+        assert_eq!(vectors.bind(1, route(0, 0, a)), Ok(()));
+        assert_eq!(vectors.bind(1, route(1, 1, b)), Ok(()));
+        assert_eq!(vectors.claim_device(2, nic), Err(KError::DeviceNotOwned));
+        assert_eq!(vectors.bindings(2), Ok(Vec::new()));
+        assert_eq!(vectors.bindings(1).unwrap().len(), 2);
+
+        // Binding an entry again replaces the old route
+        assert_eq!(vectors.bind(1, route(0, 1, b)), Ok(()));
+        let mut bindings = vectors.bindings(1).unwrap();
+        bindings.sort_by_key(|b| b.entry);
+        assert_eq!(bindings, alloc::vec![route(0, 1, b), route(1, 1, b)]);
+
+        vectors.free_core(1, 1);
+        assert_eq!(vectors.bindings(1), Ok(Vec::new()));
+
+        assert_eq!(vectors.bind(1, route(0, 0, a)), Ok(()));
+        assert_eq!(vectors.free(1, 0, a), Ok(()));
+        assert_eq!(vectors.bindings(1), Ok(Vec::new()));
+    }
+}
@@ -448,6 +448,8 @@ struct RunnerArgs<'a> {
     ivshmem: usize,
     /// Shared memory file path.
     shmem_path: String,
+    /// Directory with the files that back the persistent memory.
+    pmem_path: String,
 }
 
 #[allow(unused)]
@@ -472,6 +474,7 @@ impl<'a> RunnerArgs<'a> {
             kgdb: false,
             ivshmem: 0,
             shmem_path: String::new(),
+            pmem_path: String::new(),
         };
 
         if cfg!(feature = "prealloc") {
@@ -501,6 +504,7 @@ impl<'a> RunnerArgs<'a> {
             kgdb: false,
             ivshmem: 0,
             shmem_path: String::new(),
+            pmem_path: String::new(),
         };
 
         if cfg!(feature = "prealloc") {
@@ -626,6 +630,13 @@ impl<'a> RunnerArgs<'a> {
         self
     }
 
+    /// Keep the contents of the persistent memory in files in `path` (so
+    /// they survive the instance).
+    fn pmem_path(mut self, path: &str) -> RunnerArgs<'a> {
+        self.pmem_path = String::from(path);
+        self
+    }
+
     /// Converts the RunnerArgs to a run.py command line invocation.
     fn as_cmd(&'a self) -> Vec<String> {
         // Figure out log-level
@@ -667,6 +678,11 @@ impl<'a> RunnerArgs<'a> {
                     cmd.push(format!("{}", self.pmem));
                 }
 
+                if !self.pmem_path.is_empty() {
+                    cmd.push(String::from("--qemu-pmem-path"));
+                    cmd.push(format!("{}", self.pmem_path));
+                }
+
                 if self.ivshmem > 0 {
                     cmd.push(String::from("--qemu-ivshmem"));
                     cmd.push(format!("{}", self.ivshmem));
@@ -1576,6 +1592,8 @@ fn s04_userspace_rumprt_fs() {
         let mut p = spawn_nrk(&cmdline)?;
         p.exp_string("bytes_written: 12")?;
         p.exp_string("bytes_read: 12")?;
+        p.exp_string("rumpuser_bio: 32 requests completed")?;
+        p.exp_string("nrk_readdir: 12 entries")?;
         output = p.exp_eof()?;
         p.process.exit()
     };
@@ -2005,6 +2023,50 @@ fn s06_shootdown_simple() {
     }
 }
 
+/// Tests that shootdowns don't get lost if the work queue of a core is full.
+#[cfg(not(feature = "baremetal"))]
+#[test]
+fn s06_shootdown_queue_full() {
+    let build = BuildArgs::default().build();
+    let cmdline = RunnerArgs::new_with_build("shootdown-queue-full", &build)
+        .cores(4)
+        .memory(2048)
+        .timeout(20_000);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        p.exp_string("Enqueued 192 shootdowns")?;
+        p.exp_string("All shootdowns acknowledged")?;
+        output = p.exp_eof()?;
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a core that runs out of memory gets the frames the other cores
+/// cached back before it fails.
+#[test]
+fn s06_reclaim_memory() {
+    let build = BuildArgs::default().build();
+    let cmdline = RunnerArgs::new_with_build("reclaim-memory", &build)
+        .cores(4)
+        .memory(1024)
+        .timeout(60_000);
+    let mut output = String::new();
+
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        p.exp_regex(r"Reclaimed [0-9]+ frames from other cores")?;
+        p.exp_string("Out of memory after reclaiming")?;
+        output = p.exp_eof()?;
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
 #[test]
 fn s06_vmops_latency_benchmark() {
     let machine = Machine::determine();
@@ -2637,3 +2699,202 @@ fn s06_pmem_alloc() {
 
     check_for_successful_exit(&cmdline, qemu_run(), output);
 }
+
+/// Tests that processes can map memory with the different NUMA allocation
+/// policies.
+#[test]
+fn s06_numa_policies() {
+    let build = BuildArgs::default()
+        .module("init")
+        .user_feature("test-numa-policy")
+        .release()
+        .build();
+    let cmdline = RunnerArgs::new_with_build("userspace", &build)
+        .nodes(2)
+        .cores(2)
+        .memory(2048)
+        .timeout(20_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("numa_policy_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that files in the persistent file-system survive the VM: the first
+/// instance creates them and is killed, the second one has to find them.
+#[test]
+fn s06_pmemfs_recovery() {
+    let build = BuildArgs::default()
+        .module("init")
+        .user_feature("test-pmemfs")
+        .release()
+        .build();
+
+    let pmem_path = "pmemfs-test";
+    let _ignore = std::fs::remove_dir_all(pmem_path);
+    std::fs::create_dir_all(pmem_path).expect("Can't create directory for the NVDIMM files");
+
+    let cmdline = RunnerArgs::new_with_build("userspace", &build)
+        .nodes(2)
+        .cores(2)
+        .pmem(512)
+        .pmem_path(pmem_path)
+        .cmd("pmemfs")
+        .timeout(20_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("pmemfs_test: created files")?.as_str();
+        p.process.kill(SIGTERM)
+    };
+    wait_for_sigterm(&cmdline, qemu_run(), output);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("pmemfs_test: recovered files")?.as_str();
+        output += p.exp_string("pmemfs_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+
+    let _ignore = std::fs::remove_dir_all(pmem_path);
+}
+
+/// Tests that a process can spawn a child and wait for its exit code (and
+/// that the child can't wait for its parent).
+#[test]
+fn s06_spawn_wait() {
+    let build = BuildArgs::default()
+        .module("init")
+        .user_feature("test-spawn")
+        .release()
+        .build();
+    let cmdline = RunnerArgs::new_with_build("userspace", &build)
+        .cores(2)
+        .timeout(20_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("spawn_test: child exits with 42")?.as_str();
+        output += p.exp_string("spawn_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that two processes take turns on a single core.
+#[test]
+fn s06_sched_share_core() {
+    let build = BuildArgs::default()
+        .module("init")
+        .user_feature("test-sched-share")
+        .release()
+        .build();
+    let cmdline = RunnerArgs::new_with_build("userspace", &build)
+        .cores(1)
+        .timeout(20_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("sched_share_test: child runs on core 0")?
+            .as_str();
+        output += p.exp_string("sched_share_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that a process and its child can exchange messages over a channel.
+#[test]
+fn s06_ipc_channels() {
+    let build = BuildArgs::default()
+        .module("init")
+        .user_feature("test-ipc")
+        .release()
+        .build();
+    let cmdline = RunnerArgs::new_with_build("userspace", &build)
+        .cores(2)
+        .timeout(20_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("ipc_test: child got ping")?.as_str();
+        output += p.exp_string("ipc_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that events are delivered to a subscribed handler and terminate a
+/// child without one.
+#[test]
+fn s06_process_events() {
+    let build = BuildArgs::default()
+        .module("init")
+        .user_feature("test-events")
+        .release()
+        .build();
+    let cmdline = RunnerArgs::new_with_build("userspace", &build)
+        .cores(2)
+        .timeout(20_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p.exp_string("events_test: event delivered")?.as_str();
+        output += p.exp_string("events_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
+
+/// Tests that faults are delivered to a handler and terminate a process
+/// without one (with exit code 139).
+#[test]
+fn s06_process_faults() {
+    let build = BuildArgs::default()
+        .module("init")
+        .user_feature("test-fault")
+        .release()
+        .build();
+    let cmdline = RunnerArgs::new_with_build("userspace", &build)
+        .cores(2)
+        .timeout(20_000);
+
+    let mut output = String::new();
+    let mut qemu_run = || -> Result<WaitStatus> {
+        let mut p = spawn_nrk(&cmdline)?;
+        output += p
+            .exp_string("fault_test: handler skipped the fault")?
+            .as_str();
+        output += p
+            .exp_string("fault_test: child faults without a handler")?
+            .as_str();
+        output += p.exp_string("fault_test OK")?.as_str();
+        output += p.exp_eof()?.as_str();
+        p.process.exit()
+    };
+
+    check_for_successful_exit(&cmdline, qemu_run(), output);
+}
@@ -29,6 +29,66 @@ impl From<FileType> for u64 {
     }
 }
 
+/// Maximum length of a name in a `DirEntry`, longer names are truncated.
+pub const MAX_FILENAME_LEN: usize = 256;
+
+/// A directory entry, the `ReadDir` system call fills a user buffer with an
+/// array of these.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(C)]
+pub struct DirEntry {
+    /// Mnode number of the entry.
+    pub mnode: u64,
+    /// The type of the entry (see `FileType`).
+    pub ftype: u64,
+    /// Length of the name (in bytes).
+    pub name_len: u64,
+    /// Name of the entry, not NUL terminated.
+    pub name: [u8; MAX_FILENAME_LEN],
+}
+
+impl Default for DirEntry {
+    fn default() -> DirEntry {
+        DirEntry {
+            mnode: 0,
+            ftype: 0,
+            name_len: 0,
+            name: [0; MAX_FILENAME_LEN],
+        }
+    }
+}
+
+impl DirEntry {
+    /// Create a new directory entry.
+    pub fn new(mnode: u64, ftype: FileType, name: &str) -> DirEntry {
+        let name_len = core::cmp::min(name.len(), MAX_FILENAME_LEN);
+        let mut entry = DirEntry {
+            mnode,
+            ftype: ftype.into(),
+            name_len: name_len as u64,
+            ..Default::default()
+        };
+        entry.name[..name_len].copy_from_slice(&name.as_bytes()[..name_len]);
+        entry
+    }
+
+    /// The name of the entry.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
+    /// The in-memory representation of the entry, as it is copied into the
+    /// user buffer.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const DirEntry as *const u8,
+                core::mem::size_of::<DirEntry>(),
+            )
+        }
+    }
+}
+
 bitflags! {
     /// File flags to open the file
     pub struct FileFlags:u64 {
@@ -87,6 +147,49 @@ impl FileFlags {
     }
 }
 
+bitflags! {
+    /// Flags to map a file into the address space.
+    pub struct MapFlags: u64 {
+        const MAP_SHARED = 0x1; /* share the pages with the file */
+        const MAP_PRIVATE = 0x2; /* private copy of the file contents */
+        const PROT_WRITE = 0x4; /* mapping is writable */
+        const PROT_EXEC = 0x8; /* mapping is executable */
+    }
+}
+
+/// Convert u64 to MapFlags.
+impl From<u64> for MapFlags {
+    fn from(flag: u64) -> MapFlags {
+        MapFlags::from_bits_truncate(flag)
+    }
+}
+
+/// Convert MapFlags to u64.
+impl From<MapFlags> for u64 {
+    fn from(flag: MapFlags) -> u64 {
+        flag.bits()
+    }
+}
+
+/// Implementation for MapFlags to check the mapping mode and rights.
+impl MapFlags {
+    pub fn is_shared(&self) -> bool {
+        self.contains(MapFlags::MAP_SHARED)
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.contains(MapFlags::MAP_PRIVATE)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.contains(MapFlags::PROT_WRITE)
+    }
+
+    pub fn is_exec(&self) -> bool {
+        self.contains(MapFlags::PROT_EXEC)
+    }
+}
+
 bitflags! {
     /// FileModes to store the file in the memory. A file can be stored in
     /// readable, writable or executable mode.
@@ -126,3 +229,36 @@ impl FileModes {
         (*self & FileModes::S_IXUSR) == FileModes::S_IXUSR
     }
 }
+
+/// First interrupt vector that is handed out for MSI / MSI-X interrupts (every
+/// core has its own set of vectors).
+pub const FIRST_DEVICE_VECTOR: u64 = 0x40;
+
+/// Last interrupt vector that is handed out for MSI / MSI-X interrupts.
+pub const LAST_DEVICE_VECTOR: u64 = 0xef;
+
+/// Bus, device and function number of a PCI device.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub dev: u8,
+    pub fun: u8,
+}
+
+/// Convert u64 (as passed in system calls) to PciAddress.
+impl From<u64> for PciAddress {
+    fn from(address: u64) -> PciAddress {
+        PciAddress {
+            bus: (address >> 8) as u8,
+            dev: ((address >> 3) & 0x1f) as u8,
+            fun: (address & 0x7) as u8,
+        }
+    }
+}
+
+/// Convert PciAddress to u64.
+impl From<PciAddress> for u64 {
+    fn from(address: PciAddress) -> u64 {
+        (address.bus as u64) << 8 | (address.dev as u64 & 0x1f) << 3 | (address.fun as u64 & 0x7)
+    }
+}
@@ -0,0 +1,17 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Definitions for message channels between processes.
+
+/// Identifies a channel a process attached to (returned by `Create` and
+/// `Attach`).
+pub type ChannelId = u64;
+
+/// Largest message (in bytes) that can be sent over a channel.
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Maximum number of messages that can be queued in a channel.
+pub const MAX_CHANNEL_CAPACITY: usize = 1024;
+
+/// Maximum number of channels a process can be attached to at the same time.
+pub const MAX_CHANNELS_PER_PROCESS: usize = 16;
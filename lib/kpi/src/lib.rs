@@ -15,6 +15,7 @@
 extern crate alloc;
 
 pub mod io;
+pub mod ipc;
 pub mod process;
 pub mod system;
 pub mod upcall;
@@ -59,6 +60,8 @@ pub enum SystemCallError {
     PermissionError = 9,
     /// Bad offset
     OffsetError = 10,
+    /// The operation can't complete yet, try again later.
+    WouldBlock = 11,
     /// Placeholder for an invalid, unknown error code.
     Unknown,
 }
@@ -77,6 +80,7 @@ impl From<u64> for SystemCallError {
             8 => SystemCallError::BadFlags,
             9 => SystemCallError::PermissionError,
             10 => SystemCallError::OffsetError,
+            11 => SystemCallError::WouldBlock,
             _ => SystemCallError::Unknown,
         }
     }
@@ -95,7 +99,7 @@ pub enum ProcessOperation {
     GetVCpuArea = 3,
     /// Allocate a device interrupt vector.
     AllocateVector = 4,
-    /// Subscribe to a trap and/or interrupt events.
+    /// Subscribe to events (signals) that are sent to the process.
     SubscribeEvent = 5,
     /// Query info about the current process.
     GetProcessInfo = 6,
@@ -103,6 +107,20 @@ pub enum ProcessOperation {
     RequestCore = 7,
     /// Allocate a physical memory page as a mem object to the process.
     AllocatePhysical = 8,
+    /// Launch a new process from a binary.
+    Spawn = 9,
+    /// Retrieve the exit code of a terminated process.
+    Wait = 10,
+    /// Give a core of the process back to the kernel.
+    ReleaseCore = 11,
+    /// Send an event (signal) to a process.
+    SendEvent = 12,
+    /// Allocate an interrupt vector on a core for MSI / MSI-X interrupts.
+    AllocateMsiVector = 13,
+    /// Route an MSI / MSI-X interrupt of a PCI device to a vector on a core.
+    BindMsiVector = 14,
+    /// Free an interrupt vector that was allocated with `AllocateMsiVector`.
+    FreeMsiVector = 15,
     Unknown,
 }
 
@@ -118,6 +136,13 @@ impl From<u64> for ProcessOperation {
             6 => ProcessOperation::GetProcessInfo,
             7 => ProcessOperation::RequestCore,
             8 => ProcessOperation::AllocatePhysical,
+            9 => ProcessOperation::Spawn,
+            10 => ProcessOperation::Wait,
+            11 => ProcessOperation::ReleaseCore,
+            12 => ProcessOperation::SendEvent,
+            13 => ProcessOperation::AllocateMsiVector,
+            14 => ProcessOperation::BindMsiVector,
+            15 => ProcessOperation::FreeMsiVector,
             _ => ProcessOperation::Unknown,
         }
     }
@@ -135,6 +160,13 @@ impl From<&str> for ProcessOperation {
             "GetProcessInfo" => ProcessOperation::GetProcessInfo,
             "RequestCore" => ProcessOperation::RequestCore,
             "AllocatePhysical" => ProcessOperation::AllocatePhysical,
+            "Spawn" => ProcessOperation::Spawn,
+            "Wait" => ProcessOperation::Wait,
+            "ReleaseCore" => ProcessOperation::ReleaseCore,
+            "SendEvent" => ProcessOperation::SendEvent,
+            "AllocateMsiVector" => ProcessOperation::AllocateMsiVector,
+            "BindMsiVector" => ProcessOperation::BindMsiVector,
+            "FreeMsiVector" => ProcessOperation::FreeMsiVector,
             _ => ProcessOperation::Unknown,
         }
     }
@@ -151,11 +183,63 @@ pub enum MemType {
     Invalid,
 }
 
+/// Decides from which NUMA node(s) the memory of a process is allocated.
+///
+/// A process picks its policy by passing it (see `u64::from`) as the flags
+/// argument of `VSpaceOperation::MapMem`, it applies to that and all later
+/// `MapMem` calls of the process.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum AllocationPolicy {
+    /// Only allocate from the node of the core that maps the memory.
+    Local,
+    /// Allocate from the given node, use other nodes if it runs out of memory.
+    Preferred(system::NodeId),
+    /// Spread the pages of a mapping round-robin across all nodes.
+    Interleave,
+    /// Only allocate from the given node.
+    Bind(system::NodeId),
+}
+
+impl Default for AllocationPolicy {
+    fn default() -> AllocationPolicy {
+        AllocationPolicy::Local
+    }
+}
+
+impl AllocationPolicy {
+    /// Decodes the policy from the flags argument of `VSpaceOperation::MapMem`.
+    ///
+    /// Returns `None` if the flags don't contain a (valid) policy.
+    pub fn from_flags(flags: u64) -> Option<AllocationPolicy> {
+        let node = (flags >> 8) as system::NodeId;
+        match flags & 0xff {
+            1 => Some(AllocationPolicy::Local),
+            2 => Some(AllocationPolicy::Preferred(node)),
+            3 => Some(AllocationPolicy::Interleave),
+            4 => Some(AllocationPolicy::Bind(node)),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes the policy as flags for `VSpaceOperation::MapMem`.
+impl From<AllocationPolicy> for u64 {
+    fn from(policy: AllocationPolicy) -> u64 {
+        match policy {
+            AllocationPolicy::Local => 1,
+            AllocationPolicy::Preferred(node) => 2 | ((node as u64) << 8),
+            AllocationPolicy::Interleave => 3,
+            AllocationPolicy::Bind(node) => 4 | ((node as u64) << 8),
+        }
+    }
+}
+
 /// Flags for the map system call
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 #[repr(u64)]
 pub enum VSpaceOperation {
-    /// Map some anonymous memory
+    /// Map some anonymous memory (the flags argument can contain an
+    /// `AllocationPolicy`)
     MapMem = 1,
     /// Unmap a mapped region
     UnmapMem = 2,
@@ -169,6 +253,12 @@ pub enum VSpaceOperation {
     MapPMem = 6,
     /// Unmap a PMem mapped region
     UnmapPMem = 7,
+    /// Map (part of) a file
+    MapFile = 8,
+    /// Unmap a file mapped region
+    UnmapFile = 9,
+    /// Reserve a region that is backed with DRAM on first access
+    MapMemLazy = 10,
     Unknown,
 }
 
@@ -183,6 +273,9 @@ impl From<u64> for VSpaceOperation {
             5 => VSpaceOperation::Identify,
             6 => VSpaceOperation::MapPMem,
             7 => VSpaceOperation::UnmapPMem,
+            8 => VSpaceOperation::MapFile,
+            9 => VSpaceOperation::UnmapFile,
+            10 => VSpaceOperation::MapMemLazy,
             _ => VSpaceOperation::Unknown,
         }
     }
@@ -199,6 +292,9 @@ impl From<&str> for VSpaceOperation {
             "Identify" => VSpaceOperation::Identify,
             "MapPMem" => VSpaceOperation::MapPMem,
             "UnmapPMem" => VSpaceOperation::UnmapPMem,
+            "MapFile" => VSpaceOperation::MapFile,
+            "UnmapFile" => VSpaceOperation::UnmapFile,
+            "MapMemLazy" => VSpaceOperation::MapMemLazy,
             _ => VSpaceOperation::Unknown,
         }
     }
@@ -232,6 +328,8 @@ pub enum FileOperation {
     FileRename = 11,
     /// Create a directory.
     MkDir = 12,
+    /// Read the entries of a directory.
+    ReadDir = 13,
     Unknown,
 }
 
@@ -251,6 +349,7 @@ impl From<u64> for FileOperation {
             10 => FileOperation::WriteDirect,
             11 => FileOperation::FileRename,
             12 => FileOperation::MkDir,
+            13 => FileOperation::ReadDir,
             _ => FileOperation::Unknown,
         }
     }
@@ -272,6 +371,7 @@ impl From<&str> for FileOperation {
             "WriteDirect" => FileOperation::WriteDirect,
             "Rename" => FileOperation::FileRename,
             "MkDir" => FileOperation::MkDir,
+            "ReadDir" => FileOperation::ReadDir,
             _ => FileOperation::Unknown,
         }
     }
@@ -283,10 +383,15 @@ impl From<&str> for FileOperation {
 pub enum SystemOperation {
     /// Query information about available hardware threads in the system
     GetHardwareThreads = 1,
-    /// Print system/per-core info.
+    /// Print system/per-core info (returns the pages reaped from the core's
+    /// TCaches).
     Stats = 2,
     /// Get the core id for the current thread.
     GetCoreID = 3,
+    /// Compare the page-tables of a process on all replicas.
+    VerifyPageTables = 4,
+    /// Get the NUMA node a physical address belongs to.
+    GetMemoryNode = 5,
     Unknown,
 }
 
@@ -297,6 +402,8 @@ impl From<u64> for SystemOperation {
             1 => SystemOperation::GetHardwareThreads,
             2 => SystemOperation::Stats,
             3 => SystemOperation::GetCoreID,
+            4 => SystemOperation::VerifyPageTables,
+            5 => SystemOperation::GetMemoryNode,
             _ => SystemOperation::Unknown,
         }
     }
@@ -309,11 +416,62 @@ impl From<&str> for SystemOperation {
             "GetHardwareThreads" => SystemOperation::GetHardwareThreads,
             "Stats" => SystemOperation::Stats,
             "GetCoreID" => SystemOperation::GetCoreID,
+            "VerifyPageTables" => SystemOperation::VerifyPageTables,
+            "GetMemoryNode" => SystemOperation::GetMemoryNode,
             _ => SystemOperation::Unknown,
         }
     }
 }
 
+/// Operations on message channels between processes.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[repr(u64)]
+pub enum IpcOperation {
+    /// Create a new channel with a name and attach to it.
+    Create = 1,
+    /// Attach to an existing channel (by name).
+    Attach = 2,
+    /// Detach from a channel (it's removed once no process is attached).
+    Detach = 3,
+    /// Queue a message in a channel.
+    Send = 4,
+    /// Take the oldest message out of a channel.
+    Receive = 5,
+    /// Allow another process to attach to a channel.
+    Grant = 6,
+    Unknown,
+}
+
+impl From<u64> for IpcOperation {
+    /// Construct a IpcOperation enum based on a 64-bit value.
+    fn from(op: u64) -> IpcOperation {
+        match op {
+            1 => IpcOperation::Create,
+            2 => IpcOperation::Attach,
+            3 => IpcOperation::Detach,
+            4 => IpcOperation::Send,
+            5 => IpcOperation::Receive,
+            6 => IpcOperation::Grant,
+            _ => IpcOperation::Unknown,
+        }
+    }
+}
+
+impl From<&str> for IpcOperation {
+    /// Construct a IpcOperation enum based on a str.
+    fn from(op: &str) -> IpcOperation {
+        match op {
+            "Create" => IpcOperation::Create,
+            "Attach" => IpcOperation::Attach,
+            "Detach" => IpcOperation::Detach,
+            "Send" => IpcOperation::Send,
+            "Receive" => IpcOperation::Receive,
+            "Grant" => IpcOperation::Grant,
+            _ => IpcOperation::Unknown,
+        }
+    }
+}
+
 /// SystemCall is the type of call we are invoking.
 ///
 /// It is passed to the kernel in the %rdi register.
@@ -324,6 +482,7 @@ pub enum SystemCall {
     Process = 2,
     VSpace = 3,
     FileIO = 4,
+    Ipc = 5,
     Unknown,
 }
 
@@ -335,6 +494,7 @@ impl SystemCall {
             2 => SystemCall::Process,
             3 => SystemCall::VSpace,
             4 => SystemCall::FileIO,
+            5 => SystemCall::Ipc,
             _ => SystemCall::Unknown,
         }
     }
@@ -348,6 +508,7 @@ impl From<&str> for SystemCall {
             "Process" => SystemCall::Process,
             "VSpace" => SystemCall::VSpace,
             "FileIO" => SystemCall::FileIO,
+            "Ipc" => SystemCall::Ipc,
             _ => SystemCall::Unknown,
         }
     }
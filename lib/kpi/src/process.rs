@@ -9,6 +9,38 @@ use x86::bits64::paging::PML4_SLOT_SIZE;
 /// Max number of cores supported by the process allocator.
 pub const MAX_CORES: usize = 96;
 
+/// Passed as core to `RequestCore` to let the kernel pick any free core.
+pub const ANY_CORE: u64 = u64::MAX;
+
+/// Passed as affinity to `RequestCore` if the core can be on any NUMA node.
+pub const ANY_NODE: u64 = u64::MAX;
+
+/// Highest event number that can be sent to a process (events are numbered
+/// like the NetBSD signals, a subscription is a bitmask of event numbers).
+pub const MAX_EVENT: u64 = 63;
+
+/// Interrupt the process (terminates it unless it subscribed to the event).
+pub const EVENT_INT: u64 = 2;
+
+/// Kill the process (can't be subscribed to).
+pub const EVENT_KILL: u64 = 9;
+
+/// The process accessed memory it isn't allowed to (a page-fault or general
+/// protection fault the kernel couldn't resolve). Delivered right away with a
+/// `kpi::upcall::PAGE_FAULT` or `kpi::upcall::GENERAL_PROTECTION_FAULT`
+/// upcall, terminates the process unless it subscribed to the event. The
+/// handler resumes the process at `VirtualCpu::enabled_state`, so it has to
+/// move `rip` past (or away from) the faulting instruction.
+pub const EVENT_SEGV: u64 = 11;
+
+/// Ask the process to terminate (terminates it unless it subscribed to the
+/// event).
+pub const EVENT_TERM: u64 = 15;
+
+/// Passed as pid to `SendEvent` (or `VerifyPageTables`) to refer to the
+/// calling process.
+pub const CURRENT_PROCESS: u64 = u64::MAX;
+
 /// Offset in address-space for ELF binary relocation.
 pub const ELF_OFFSET: usize = 0x20_0000_0000;
 
@@ -42,6 +74,11 @@ impl CoreToken {
     pub(crate) fn from(ret: u64) -> Self {
         CoreToken(ret.try_into().unwrap())
     }
+
+    /// The global thread id of the core.
+    pub fn gtid(&self) -> usize {
+        self.0
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Copy, Clone, Eq, PartialEq)]
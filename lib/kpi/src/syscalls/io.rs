@@ -33,6 +33,77 @@ impl Irq {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// Allocates an interrupt vector on `core` for MSI / MSI-X interrupts.
+    ///
+    /// The process has to run on `core`, the vector is freed when the
+    /// process gives the core back.
+    pub fn allocate_vector(core: usize) -> Result<u64, SystemCallError> {
+        let (r, vector) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::AllocateMsiVector as u64,
+                core as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(vector)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Frees a vector that was allocated with `allocate_vector`.
+    pub fn free_vector(core: usize, vector: u64) -> Result<(), SystemCallError> {
+        let (r, _) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::FreeMsiVector as u64,
+                core as u64,
+                vector,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Routes MSI-X table entry `entry` of `device` to `vector` on `core`.
+    ///
+    /// For devices without MSI-X this configures their MSI interrupt (which
+    /// is entry 0). The vector has to be allocated with `allocate_vector`.
+    /// The first process that routes an interrupt of a device owns the
+    /// device, other processes can't route its interrupts anymore.
+    pub fn bind_msi(
+        device: PciAddress,
+        entry: usize,
+        core: usize,
+        vector: u64,
+    ) -> Result<(), SystemCallError> {
+        let (r, _) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::BindMsiVector as u64,
+                u64::from(device),
+                entry as u64,
+                core as u64,
+                vector,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
 }
 
 /// System calls related to file-systems.
@@ -252,4 +323,39 @@ impl Fs {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// Read the entries of the directory opened as `fd` into `buffer` (an
+    /// array of `DirEntry` that is `len` bytes long).
+    ///
+    /// Reading starts at the entry given by `cookie` (0 for the first entry).
+    /// Returns the number of entries read and the cookie to continue from,
+    /// zero entries means the end of the directory was reached.
+    pub fn readdir(
+        fd: u64,
+        buffer: u64,
+        len: u64,
+        cookie: u64,
+    ) -> Result<(u64, u64), SystemCallError> {
+        if len < core::mem::size_of::<DirEntry>() as u64 {
+            return Err(SystemCallError::BadAddress);
+        }
+
+        let (r, entries, next_cookie) = unsafe {
+            syscall!(
+                SystemCall::FileIO as u64,
+                FileOperation::ReadDir as u64,
+                fd,
+                buffer,
+                len,
+                cookie,
+                3
+            )
+        };
+
+        if r == 0 {
+            Ok((entries, next_cookie))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
 }
@@ -0,0 +1,128 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! System calls to exchange messages with other processes.
+
+use crate::ipc::ChannelId;
+use crate::{syscall, *};
+
+/// Message channels between processes.
+pub struct Ipc;
+
+impl Ipc {
+    /// Creates a channel called `name` that holds up to `capacity` messages
+    /// and attaches the process to it.
+    pub fn create(name: u64, capacity: usize) -> Result<ChannelId, SystemCallError> {
+        let (r, id) = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::Create as u64,
+                name,
+                capacity as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(id)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Attaches the process to the existing channel called `name`.
+    ///
+    /// Only the parent and the children of the process that created the
+    /// channel, or processes that were granted access with
+    /// [`Ipc::grant`], can attach to it.
+    pub fn attach(name: u64) -> Result<ChannelId, SystemCallError> {
+        let (r, id) =
+            unsafe { syscall!(SystemCall::Ipc as u64, IpcOperation::Attach as u64, name, 2) };
+
+        if r == 0 {
+            Ok(id)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Lets process `pid` attach to channel `id`.
+    pub fn grant(id: ChannelId, pid: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::Grant as u64,
+                id,
+                pid,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Detaches the process from channel `id`.
+    ///
+    /// The channel (and the messages still in it) is removed once the last
+    /// process detached.
+    pub fn detach(id: ChannelId) -> Result<(), SystemCallError> {
+        let r = unsafe { syscall!(SystemCall::Ipc as u64, IpcOperation::Detach as u64, id, 1) };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Queues `message` in channel `id`.
+    ///
+    /// Fails with `WouldBlock` if the channel is full.
+    pub fn send(id: ChannelId, message: &[u8]) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::Send as u64,
+                id,
+                message.as_ptr() as u64,
+                message.len() as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Takes the oldest message out of channel `id` and copies it to
+    /// `buffer`, returns the length of the message.
+    ///
+    /// Fails with `WouldBlock` if the channel is empty. `buffer` has to be
+    /// large enough for the message (messages are at most
+    /// `ipc::MAX_MESSAGE_SIZE` bytes).
+    pub fn try_receive(id: ChannelId, buffer: &mut [u8]) -> Result<usize, SystemCallError> {
+        let (r, len) = unsafe {
+            syscall!(
+                SystemCall::Ipc as u64,
+                IpcOperation::Receive as u64,
+                id,
+                buffer.as_mut_ptr() as u64,
+                buffer.len() as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(len as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+}
@@ -79,6 +79,16 @@ macro_rules! syscall {
         )
     };
 
+    ($arg0:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, 3) => {
+        crate::syscalls::macros::syscall_5_3(
+            $arg0 as u64,
+            $arg1 as u64,
+            $arg2 as u64,
+            $arg3 as u64,
+            $arg4 as u64,
+        )
+    };
+
     ($arg0:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, $arg5:expr, 2) => {
         crate::syscalls::macros::syscall_6_2(
             $arg0 as u64,
@@ -89,6 +99,17 @@ macro_rules! syscall {
             $arg5 as u64,
         )
     };
+
+    ($arg0:expr, $arg1:expr, $arg2:expr, $arg3:expr, $arg4:expr, $arg5:expr, 3) => {
+        crate::syscalls::macros::syscall_6_3(
+            $arg0 as u64,
+            $arg1 as u64,
+            $arg2 as u64,
+            $arg3 as u64,
+            $arg4 as u64,
+            $arg5 as u64,
+        )
+    };
 }
 
 #[inline(always)]
@@ -221,6 +242,24 @@ pub(crate) unsafe fn syscall_5_2(
     (ret, ret2)
 }
 
+#[inline(always)]
+pub(crate) unsafe fn syscall_5_3(
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> (u64, u64, u64) {
+    let ret: u64;
+    let ret2: u64;
+    let ret3: u64;
+    llvm_asm!("syscall" : "={rax}" (ret) "={rdi}" (ret2) "={rsi}" (ret3)
+                   : "{rdi}" (arg1), "{rsi}" (arg2), "{rdx}" (arg3), "{r10}" (arg4), "{r8}" (arg5)
+                   : "rcx", "r11", "memory"
+                   : "volatile");
+    (ret, ret2, ret3)
+}
+
 #[inline(always)]
 pub(crate) unsafe fn syscall6_1(
     arg0: u64,
@@ -258,3 +297,23 @@ pub(crate) unsafe fn syscall_6_2(
                    : "volatile");
     (ret, ret2)
 }
+
+#[inline(always)]
+pub(crate) unsafe fn syscall_6_3(
+    arg0: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> (u64, u64, u64) {
+    let ret: u64;
+    let ret2: u64;
+    let ret3: u64;
+    llvm_asm!("syscall" : "={rax}" (ret) "={rdi}" (ret2) "={rsi}" (ret3)
+                   : "{rdi}" (arg0), "{rsi}" (arg1), "{rdx}" (arg2), "{r10}" (arg3),
+                     "{r8}" (arg4), "{r9}" (arg5)
+                   : "rcx", "r11", "memory"
+                   : "volatile");
+    (ret, ret2, ret3)
+}
@@ -5,6 +5,7 @@
 
 use core::convert::TryInto;
 
+use crate::io::MapFlags;
 use crate::process::FrameId;
 use crate::*;
 
@@ -24,6 +25,32 @@ impl VSpace {
         VSpace::vspace(VSpaceOperation::MapMem, base, bound)
     }
 
+    /// Back a region of memory with DRAM from the NUMA node(s) chosen by
+    /// `policy`.
+    ///
+    /// The policy is also used for all later calls to `map` of the process.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn map_with_policy(
+        base: u64,
+        bound: u64,
+        policy: AllocationPolicy,
+    ) -> Result<(VAddr, PAddr), SystemCallError> {
+        VSpace::vspace_with_flags(VSpaceOperation::MapMem, base, bound, policy.into())
+    }
+
+    /// Reserve a region of memory, every page is backed with DRAM on the
+    /// first access to it.
+    ///
+    /// The returned `PAddr` is meaningless as no memory is mapped yet.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn map_lazy(base: u64, bound: u64) -> Result<(VAddr, PAddr), SystemCallError> {
+        VSpace::vspace(VSpaceOperation::MapMemLazy, base, bound)
+    }
+
     /// Unmap region of virtual memory.
     ///
     /// # Safety
@@ -80,6 +107,61 @@ impl VSpace {
         }
     }
 
+    /// Maps `len` bytes of the file `fd`, starting at `offset`, at `base`.
+    ///
+    /// With `MapFlags::MAP_SHARED` the region is backed by the pages of the
+    /// file itself and sees every subsequent write to the file. A writable
+    /// shared mapping needs a writable `fd`; its stores are written back to
+    /// the file on `unmap_file` and when the process exits. With
+    /// `MapFlags::MAP_PRIVATE` the file pages are mapped copy-on-write, so
+    /// stores stay private to the process.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn map_file(
+        base: u64,
+        len: u64,
+        fd: u64,
+        offset: u64,
+        flags: MapFlags,
+    ) -> Result<VAddr, SystemCallError> {
+        let (err, _len) = syscall!(
+            SystemCall::VSpace as u64,
+            VSpaceOperation::MapFile as u64,
+            base,
+            len,
+            (u64::from(flags) << 32) | fd,
+            offset,
+            2
+        );
+
+        if err == 0 {
+            Ok(VAddr::from(base))
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
+    /// Unmaps a region that was mapped with `map_file`.
+    ///
+    /// # Safety
+    /// Manipulates address space of process.
+    pub unsafe fn unmap_file(base: u64, len: u64) -> Result<(), SystemCallError> {
+        let (err, _len) = syscall!(
+            SystemCall::VSpace as u64,
+            VSpaceOperation::UnmapFile as u64,
+            base,
+            len,
+            2
+        );
+
+        if err == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(err))
+        }
+    }
+
     pub fn identify(base: u64) -> Result<(VAddr, PAddr), SystemCallError> {
         unsafe { VSpace::vspace(VSpaceOperation::Identify, base, 0) }
     }
@@ -90,7 +172,18 @@ impl VSpace {
         base: u64,
         bound: u64,
     ) -> Result<(VAddr, PAddr), SystemCallError> {
-        let (err, paddr, size) = syscall!(SystemCall::VSpace as u64, op as u64, base, bound, 3);
+        VSpace::vspace_with_flags(op, base, bound, 0)
+    }
+
+    /// Manipulate the virtual address space (with operation specific `flags`).
+    unsafe fn vspace_with_flags(
+        op: VSpaceOperation,
+        base: u64,
+        bound: u64,
+        flags: u64,
+    ) -> Result<(VAddr, PAddr), SystemCallError> {
+        let (err, paddr, size) =
+            syscall!(SystemCall::VSpace as u64, op as u64, base, bound, flags, 3);
 
         log::trace!(
             "OP={:?} {:#x} -- {:#x} --> {:#x} -- {:#x}",
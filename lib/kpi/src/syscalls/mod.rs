@@ -6,12 +6,14 @@
 //! Code in this module is not linked into the kernel.
 
 mod io;
+mod ipc;
 mod macros;
 mod memory;
 mod process;
 mod system;
 
 pub use io::{Fs, Irq};
+pub use ipc::Ipc;
 pub use memory::{PhysicalMemory, VSpace};
 pub use process::Process;
 pub use system::System;
@@ -5,7 +5,7 @@
 
 use crate::*;
 
-use crate::process::{CoreToken, ProcessInfo};
+use crate::process::{CoreToken, ProcessInfo, ANY_CORE, ANY_NODE};
 use crate::syscall;
 use crate::x86_64::VirtualCpu;
 
@@ -34,6 +34,54 @@ impl Process {
         }
     }
 
+    /// Request to run on any free core starting at `entry_point`.
+    ///
+    /// If `affinity` is set, the kernel only picks a core from that NUMA
+    /// node. Returns a token for the core that was picked.
+    pub fn request_any_core(
+        affinity: Option<usize>,
+        entry_point: VAddr,
+    ) -> Result<CoreToken, SystemCallError> {
+        let affinity = affinity.map_or(ANY_NODE, |node| node as u64);
+        let (r, gtid) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::RequestCore as u64,
+                ANY_CORE,
+                entry_point.as_u64(),
+                affinity,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(CoreToken::from(gtid))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Give `core_id` back to the kernel.
+    ///
+    /// The core stops running the process right away. If `core_id` is the
+    /// current core, this call doesn't return.
+    pub fn release_core(core_id: usize) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::ReleaseCore as u64,
+                core_id as u64,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Print `buffer` on the console.
     pub fn print(buffer: &str) -> Result<(), SystemCallError> {
         let r = unsafe {
@@ -101,6 +149,115 @@ impl Process {
         }
     }
 
+    /// Launch a new process on core `core_id`.
+    ///
+    /// `binary` is a pointer to a (null-terminated) name of a module passed
+    /// to the kernel at boot, or a path to an ELF file in the file-system.
+    /// Returns the pid of the new process.
+    pub fn spawn(binary: u64, core_id: usize) -> Result<u64, SystemCallError> {
+        let (r, pid) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::Spawn as u64,
+                binary,
+                core_id as u64,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(pid)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Returns the exit code of the process `pid` (a child of the caller),
+    /// fails with `WouldBlock` while it is still running.
+    pub fn try_wait(pid: u64) -> Result<u64, SystemCallError> {
+        let (r, code) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::Wait as u64,
+                pid,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(code)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Waits until the process `pid` (a child of the caller) exits and
+    /// returns its exit code.
+    ///
+    /// Polls the kernel, with exponentially more time between attempts.
+    pub fn wait(pid: u64) -> Result<u64, SystemCallError> {
+        const MAX_SPINS: usize = 1 << 16;
+
+        let mut spins = 1;
+        loop {
+            match Process::try_wait(pid) {
+                Err(SystemCallError::WouldBlock) => {
+                    for _i in 0..spins {
+                        core::hint::spin_loop();
+                    }
+                    spins = core::cmp::min(spins * 2, MAX_SPINS);
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Subscribe to the events in `mask` (bit `n` is set for event `n`).
+    ///
+    /// Subscribed events are delivered to the process as upcalls (with
+    /// `kpi::upcall::EVENT`), this replaces any earlier subscription.
+    /// Returns the mask of the previous subscription.
+    pub fn subscribe_event(mask: u64) -> Result<u64, SystemCallError> {
+        let (r, previous) = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::SubscribeEvent as u64,
+                mask,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(previous)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Send `event` to the process `pid`.
+    ///
+    /// If `pid` didn't subscribe to `event` the event is dropped, unless it
+    /// is `EVENT_INT` or `EVENT_TERM` in which case `pid` is terminated.
+    /// `EVENT_KILL` always terminates `pid`. A process can only send events
+    /// to itself, its parent and its children.
+    pub fn send_event(pid: u64, event: u64) -> Result<(), SystemCallError> {
+        let r = unsafe {
+            syscall!(
+                SystemCall::Process as u64,
+                ProcessOperation::SendEvent as u64,
+                pid,
+                event,
+                1
+            )
+        };
+
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
     /// Exit the process (pass an error `code` to exit).
     pub fn exit(code: u64) -> ! {
         unsafe {
@@ -8,7 +8,7 @@ use alloc::vec::Vec;
 
 use crate::{syscall, *};
 
-use crate::system::{CoreId, CpuThread};
+use crate::system::{CoreId, CpuThread, PageTableDivergence};
 
 pub struct System;
 
@@ -38,11 +38,48 @@ impl System {
     }
 
     /// Prints some stats for the core.
-    pub fn stats() -> Result<(), SystemCallError> {
-        let r = unsafe { syscall!(SystemCall::System as u64, SystemOperation::Stats as u64, 1) };
+    ///
+    /// Returns how many base- and large-pages the core's TCaches gave back
+    /// to the NCaches so far.
+    pub fn stats() -> Result<(u64, u64), SystemCallError> {
+        let (r, reaped_base_pages, reaped_large_pages) =
+            unsafe { syscall!(SystemCall::System as u64, SystemOperation::Stats as u64, 3) };
 
         if r == 0 {
-            Ok(())
+            Ok((reaped_base_pages, reaped_large_pages))
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
+
+    /// Compares the page-tables of process `pid` on all replicas (use
+    /// `process::CURRENT_PROCESS` for the calling process). Only the
+    /// caller, its parent and its children can be checked.
+    ///
+    /// Returns where the replicas differ (at most
+    /// `system::MAX_REPORTED_DIVERGENCES` entries) and the total number of
+    /// differences.
+    pub fn verify_page_tables(
+        pid: u64,
+    ) -> Result<(Vec<PageTableDivergence>, usize), SystemCallError> {
+        let mut buf = alloc::vec![0; 5*4096];
+        let (r, len, total) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::VerifyPageTables as u64,
+                pid,
+                buf.as_mut_ptr() as u64,
+                buf.len() as u64,
+                3
+            )
+        };
+
+        if r == 0 {
+            let len = len as usize;
+            debug_assert!(len <= buf.len());
+            buf.resize(len, 0);
+            let deserialized: Vec<PageTableDivergence> = serde_cbor::from_slice(&buf).unwrap();
+            Ok((deserialized, total as usize))
         } else {
             Err(SystemCallError::from(r))
         }
@@ -64,4 +101,22 @@ impl System {
             Err(SystemCallError::from(r))
         }
     }
+
+    /// Get the NUMA node the physical address `paddr` belongs to.
+    pub fn memory_node(paddr: u64) -> Result<usize, SystemCallError> {
+        let (r, node) = unsafe {
+            syscall!(
+                SystemCall::System as u64,
+                SystemOperation::GetMemoryNode as u64,
+                paddr,
+                2
+            )
+        };
+
+        if r == 0 {
+            Ok(node as usize)
+        } else {
+            Err(SystemCallError::from(r))
+        }
+    }
 }
@@ -3,6 +3,8 @@
 
 //! Data structures to exchange system-wide information between kernel and user-space.
 
+use alloc::string::String;
+
 use serde::{Deserialize, Serialize};
 
 /// A system global ID for a CPU hardware thread.
@@ -33,3 +35,30 @@ pub struct CpuThread {
     /// ID of the thread (relative to the core (usually either 0 or 1)).
     pub thread_id: ThreadId,
 }
+
+/// How many differences `VerifyPageTables` reports at most.
+pub const MAX_REPORTED_DIVERGENCES: usize = 128;
+
+/// A page in the page-table of a process.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct PageMapping {
+    /// Physical address of the page.
+    pub paddr: u64,
+    /// Size of the page (4 KiB, 2 MiB or 1 GiB).
+    pub size: usize,
+    /// Access rights (e.g., `uRW-` for read-write in user-space).
+    pub rights: String,
+}
+
+/// An address where the page-table of a process differs between replicas.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub struct PageTableDivergence {
+    /// The NUMA node of the replica that differs from the one on node 0.
+    pub node: NodeId,
+    /// Virtual address of the page.
+    pub vaddr: u64,
+    /// The page mapped at `vaddr` on node 0 (if any).
+    pub expected: Option<PageMapping>,
+    /// The page mapped at `vaddr` on `node` (if any).
+    pub found: Option<PageMapping>,
+}
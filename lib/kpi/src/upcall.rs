@@ -4,3 +4,16 @@
 //! Upcall command passed as the 2nd argument to the upcall.
 
 pub const NEW_CORE: u64 = 0x99;
+
+/// An event (signal) was sent to the process, the argument is the event
+/// number (see `kpi::process::EVENT_*`).
+pub const EVENT: u64 = 0x9a;
+
+/// A general protection fault happened in the process, the argument is the
+/// error code of the exception (see `kpi::process::EVENT_SEGV`).
+pub const GENERAL_PROTECTION_FAULT: u64 = 0xd;
+
+/// An unresolved page-fault happened in the process, the argument is the
+/// error code of the exception and `VirtualCpu::fault_address` holds the
+/// address that was accessed (see `kpi::process::EVENT_SEGV`).
+pub const PAGE_FAULT: u64 = 0xe;
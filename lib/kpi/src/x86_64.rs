@@ -48,8 +48,17 @@ pub struct VirtualCpu {
     pub is_disabled: bool,
     /// An upcall needs to be executed.
     pub has_pending_upcall: bool,
+    /// Address that was accessed for the last `PAGE_FAULT` upcall.
+    ///
+    /// # ABI
+    /// Added after the other fields so their offsets (used by the assembly
+    /// code) stay the same, but it grows the struct by 8 bytes: binaries
+    /// built against an older `kpi` must be rebuilt.
+    pub fault_address: VAddr,
 }
 
+static_assertions::const_assert_eq!(memoffset::offset_of!(VirtualCpu, enabled_state), 0);
+
 impl VirtualCpu {
     /// Is the vCPU currently disabled or executing in a critical section?
     pub fn upcalls_disabled(&self, rip: VAddr) -> bool {
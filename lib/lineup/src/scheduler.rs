@@ -5,30 +5,155 @@
 //!
 //! Has the following properties:
 //! * Cooperative scheduling (threads can yield voluntarily)
-//! * Round robin scheduling (per-core)
+//! * Round robin scheduling (per-core, within a scheduling class)
+//! * Scheduling classes: earliest-deadline-first threads run before
+//!   `High`, `Normal` and `Low` priority threads
 //! * Per core run and wait lists
-//! * Thread affinity can be defined upon thread creation (currently no migration)
+//! * Thread affinity can be defined upon thread creation, threads can migrate later
+//! * Optional work stealing: idle cores take runnable threads from other cores
+//! * Interrupt threads are pinned to the core they were spawned on
 //! * Waitlist is sorted according to thread wake-up times.
 
 use alloc::collections::VecDeque;
 use alloc::vec::Vec;
+use core::ops::Add;
 use core::ptr;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-use arr_macro::arr;
 use fringe::generator::Generator;
 use log::{error, trace};
 use rawtime::Instant;
 
 use crate::stack::LineupStack;
-use crate::threads::{Runnable, Thread, ThreadId, YieldRequest, YieldResume};
+use crate::threads::{Runnable, SchedClass, Thread, ThreadId, YieldRequest, YieldResume};
 use crate::tls2::{self, SchedulerControlBlock, ThreadControlBlock};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
 
+/// Where a thread is queued in a `RunQueue`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RunQueueSlot {
+    /// In the deadline list, with the given deadline.
+    Deadline(Instant),
+    /// In the FIFO of the given priority level.
+    Level(usize),
+}
+
+/// Per-core list of runnable threads.
+///
+/// Deadline threads are kept sorted like the waitlist (earliest deadline
+/// last), every other class has its own FIFO.
+struct RunQueue {
+    /// Deadline threads sorted by descending deadline.
+    deadline: Vec<(Instant, ThreadId)>,
+    /// FIFOs for `High`, `Normal` and `Low` threads.
+    levels: [VecDeque<ThreadId>; 3],
+}
+
+impl RunQueue {
+    fn with_capacity(capacity: usize) -> Self {
+        RunQueue {
+            deadline: Vec::with_capacity(capacity),
+            levels: [
+                VecDeque::with_capacity(capacity),
+                VecDeque::with_capacity(capacity),
+                VecDeque::with_capacity(capacity),
+            ],
+        }
+    }
+
+    /// Figures out where a thread of `class` that becomes runnable now goes.
+    fn slot(class: SchedClass) -> RunQueueSlot {
+        match class {
+            SchedClass::Deadline(within) => RunQueueSlot::Deadline(Instant::now().add(within)),
+            SchedClass::High => RunQueueSlot::Level(0),
+            SchedClass::Normal => RunQueueSlot::Level(1),
+            SchedClass::Low => RunQueueSlot::Level(2),
+        }
+    }
+
+    /// Appends `tid` to the run queue.
+    fn push(&mut self, tid: ThreadId, class: SchedClass) {
+        self.insert(tid, RunQueue::slot(class));
+    }
+
+    fn insert(&mut self, tid: ThreadId, slot: RunQueueSlot) {
+        match slot {
+            RunQueueSlot::Deadline(until) => {
+                let to_insert = (until, tid);
+                let pos = self
+                    .deadline
+                    .binary_search_by(|probe| probe.cmp(&to_insert).reverse())
+                    .unwrap_or_else(|pos| pos);
+                self.deadline.insert(pos, to_insert);
+            }
+            RunQueueSlot::Level(level) => self.levels[level].push_back(tid),
+        }
+    }
+
+    /// Returns the next thread to run.
+    fn pop(&mut self) -> Option<ThreadId> {
+        self.deadline
+            .pop()
+            .map(|(_until, tid)| tid)
+            .or_else(|| self.levels.iter_mut().find_map(|level| level.pop_front()))
+    }
+
+    /// Returns the next thread to run without removing it.
+    fn peek(&self) -> Option<ThreadId> {
+        self.deadline
+            .last()
+            .map(|&(_until, tid)| tid)
+            .or_else(|| self.levels.iter().find_map(|level| level.front().copied()))
+    }
+
+    /// Removes all entries of `tid`, returns where the first one was.
+    fn remove(&mut self, tid: ThreadId) -> Option<RunQueueSlot> {
+        let mut slot = None;
+        self.deadline.retain(|&(until, dtid)| {
+            if dtid == tid {
+                slot.get_or_insert(RunQueueSlot::Deadline(until));
+            }
+            dtid != tid
+        });
+        for (idx, level) in self.levels.iter_mut().enumerate() {
+            level.retain(|&ltid| {
+                if ltid == tid {
+                    slot.get_or_insert(RunQueueSlot::Level(idx));
+                }
+                ltid != tid
+            });
+        }
+        slot
+    }
+
+    /// Removes the thread that would run last and satisfies `pred`.
+    fn take_last<F: Fn(&ThreadId) -> bool>(&mut self, pred: F) -> Option<ThreadId> {
+        for level in self.levels.iter_mut().rev() {
+            if let Some(pos) = level.iter().rposition(&pred) {
+                return level.remove(pos);
+            }
+        }
+        self.deadline
+            .iter()
+            .position(|(_until, tid)| pred(tid))
+            .map(|pos| self.deadline.remove(pos).1)
+    }
+
+    fn len(&self) -> usize {
+        self.deadline.len() + self.levels.iter().map(|level| level.len()).sum::<usize>()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 /// Scheduler per-core state.
 ///
 /// # Lock order
+/// `SmpScheduler::threads` before any `SchedulerCoreState`, `runnable`
+/// before `SmpScheduler::generators`.
 /// `waiting` before `runnable`.
 /// In case we need to lock across multiple `SchedulerCoreState`
 /// lower `core_id` should be locked first.
@@ -36,7 +161,7 @@ struct SchedulerCoreState {
     /// Per-core list of runnable threads.
     ///
     /// Protected by a mutex since anyone could put threads here.
-    runnable: spin::Mutex<VecDeque<ThreadId>>,
+    runnable: spin::Mutex<RunQueue>,
 
     /// Per-core list of `waiting` threads.
     ///
@@ -45,10 +170,10 @@ struct SchedulerCoreState {
 }
 
 impl SchedulerCoreState {
-    fn new() -> Self {
+    fn with_capacity(capacity: usize) -> Self {
         SchedulerCoreState {
-            runnable: spin::Mutex::new(VecDeque::with_capacity(SmpScheduler::MAX_THREADS)),
-            waiting: spin::Mutex::new(Vec::with_capacity(SmpScheduler::MAX_THREADS)),
+            runnable: spin::Mutex::new(RunQueue::with_capacity(capacity)),
+            waiting: spin::Mutex::new(Vec::with_capacity(capacity)),
         }
     }
 }
@@ -66,11 +191,16 @@ pub struct SmpScheduler<'a> {
     ///
     /// This is slightly different from SchedulerControlBlock
     /// It's per core but only accessed within SmpScheduler
-    per_core: [SchedulerCoreState; 96], // MAX_THREADS
+    /// (indexed by `CoreId`, the length is the number of cores).
+    per_core: Vec<SchedulerCoreState>,
+    /// Upper bound for the number of threads in the scheduler.
+    max_threads: usize,
     /// Contains a global counter of thread IDs
     tid_counter: AtomicUsize,
     /// Maps interrupt vectors to ThreadId
     irqvec_to_tid: spin::Mutex<hashbrown::HashMap<IrqVector, ThreadId>>,
+    /// Idle cores take runnable threads from other cores.
+    work_stealing: AtomicBool,
 }
 
 unsafe impl Send for SmpScheduler<'static> {}
@@ -83,21 +213,60 @@ impl<'a> Default for SmpScheduler<'a> {
 }
 
 impl<'a> SmpScheduler<'a> {
+    /// Default upper bound for the number of threads.
     pub const MAX_THREADS: usize = 2048;
 
+    /// Default number of cores.
+    pub const MAX_CORES: usize = kpi::process::MAX_CORES;
+
+    /// Creates a scheduler for `MAX_CORES` cores and `MAX_THREADS` threads.
     pub fn with_upcalls(upcalls: Upcalls) -> Self {
+        SmpScheduler::with_limits(upcalls, SmpScheduler::MAX_CORES, SmpScheduler::MAX_THREADS)
+    }
+
+    /// Creates a scheduler that dispatches on `cores` cores (`CoreId` 0 to
+    /// `cores - 1`) and holds at most `max_threads` threads.
+    ///
+    /// The per-core lists are sized for an even spread of threads across
+    /// cores, they grow if a core gets more.
+    pub fn with_limits(upcalls: Upcalls, cores: usize, max_threads: usize) -> Self {
+        assert!(cores > 0, "Scheduler needs at least one core");
+        let per_core_capacity = (max_threads + cores - 1) / cores;
+        let mut per_core = Vec::with_capacity(cores);
+        for _core in 0..cores {
+            per_core.push(SchedulerCoreState::with_capacity(per_core_capacity));
+        }
+
         Self {
-            generators: spin::Mutex::new(hashbrown::HashMap::with_capacity(
-                SmpScheduler::MAX_THREADS,
-            )),
-            threads: spin::Mutex::new(hashbrown::HashMap::with_capacity(SmpScheduler::MAX_THREADS)),
+            generators: spin::Mutex::new(hashbrown::HashMap::with_capacity(max_threads)),
+            threads: spin::Mutex::new(hashbrown::HashMap::with_capacity(max_threads)),
             upcalls,
             tid_counter: AtomicUsize::new(0),
-            per_core: arr![SchedulerCoreState::new(); 96], // MAX_THREADS
+            per_core,
+            max_threads,
             irqvec_to_tid: spin::Mutex::new(hashbrown::HashMap::with_capacity(8)),
+            work_stealing: AtomicBool::new(false),
         }
     }
 
+    /// Number of cores the scheduler dispatches on.
+    pub fn cores(&self) -> usize {
+        self.per_core.len()
+    }
+
+    /// Upper bound for the number of threads in the scheduler.
+    pub fn max_threads(&self) -> usize {
+        self.max_threads
+    }
+
+    /// Enables (or disables) work stealing.
+    ///
+    /// With work stealing a core that runs out of runnable threads takes
+    /// one from another core (the stolen thread migrates to the core).
+    pub fn set_work_stealing(&self, enabled: bool) {
+        self.work_stealing.store(enabled, Ordering::Relaxed);
+    }
+
     /// Returns true as long as we have 'active', unfinished thread.
     ///
     /// A thread that is currently blocked/waiting still counts as active.
@@ -117,11 +286,21 @@ impl<'a> SmpScheduler<'a> {
         arg: *mut u8,
         affinity: CoreId,
         interrupt_vector: Option<IrqVector>,
+        class: SchedClass,
         tls: *mut ThreadControlBlock<'static>,
     ) -> Option<ThreadId>
     where
         F: 'static + FnOnce(*mut u8) + Send,
     {
+        if affinity >= self.per_core.len() {
+            error!(
+                "Can't spawn thread on core {} (scheduler has {} cores)",
+                affinity,
+                self.per_core.len()
+            );
+            return None;
+        }
+
         let t = self.tid_counter.fetch_add(1, Ordering::Relaxed);
         let tid = ThreadId(t);
         let (handle, generator) = unsafe {
@@ -133,7 +312,9 @@ impl<'a> SmpScheduler<'a> {
                 arg,
                 self.upcalls,
                 interrupt_vector,
+                class,
                 tls,
+                self.max_threads,
             )
         };
 
@@ -159,7 +340,7 @@ impl<'a> SmpScheduler<'a> {
     {
         let stack = LineupStack::from_size(stack_size);
         let tls = unsafe { tls2::ThreadControlBlock::new_tls_area() };
-        self.spawn_with_args(stack, f, arg, affinity, irq_vec, tls)
+        self.spawn_with_args(stack, f, arg, affinity, irq_vec, Default::default(), tls)
     }
 
     fn add_thread(
@@ -174,7 +355,7 @@ impl<'a> SmpScheduler<'a> {
             tid
         );
 
-        if self.threads.lock().len() <= SmpScheduler::MAX_THREADS {
+        if self.threads.lock().len() < self.max_threads {
             self.generators.lock().insert(tid, generator);
             self.threads.lock().insert(tid, handle);
             Some(tid)
@@ -185,9 +366,16 @@ impl<'a> SmpScheduler<'a> {
     }
 
     /// Marks a thread as sunnable by inserting it into
-    /// `runnable`.
+    /// `runnable` (according to its scheduling class).
+    ///
+    /// Acquires the `threads` lock, don't call it with any locks held.
     fn mark_runnable(&self, tid: ThreadId, affinity: CoreId) {
-        self.per_core[affinity].runnable.lock().push_back(tid);
+        let class = self
+            .threads
+            .lock()
+            .get(&tid)
+            .map_or(SchedClass::default(), |thread| thread.class);
+        self.per_core[affinity].runnable.lock().push(tid, class);
     }
 
     /// Make a thread no longer runnable.
@@ -196,8 +384,7 @@ impl<'a> SmpScheduler<'a> {
     /// This is O(n) but it happens rarely(?); only
     /// call it if tid is different from current thread.
     fn mark_unrunnable(&self, tid: ThreadId, affinity: CoreId) {
-        let mut runnable = self.per_core[affinity].runnable.lock();
-        runnable.retain(|&ltid| ltid != tid);
+        self.per_core[affinity].runnable.lock().remove(tid);
     }
 
     /// Remove a thread from the waitlist.
@@ -217,14 +404,128 @@ impl<'a> SmpScheduler<'a> {
     /// Insert thread in a sorted waitlist
     fn waitlist_insert(&self, tid: ThreadId, affinity: CoreId, until: Instant) {
         let mut waiting = self.per_core[affinity].waiting.lock();
-        let to_insert = (until, tid);
-        match waiting.binary_search_by(|probe| probe.cmp(&to_insert).reverse()) {
-            Err(pos) => waiting.insert(pos, to_insert),
-            Ok(_pos) => panic!("Thread already in waitlist?"),
-        }
+        waitlist_insert_sorted(&mut waiting, tid, until);
         trace!("Waitlist is {:?}", waiting);
     }
 
+    /// Changes the scheduling class of thread `tid`.
+    ///
+    /// A runnable thread is re-queued according to its new class.
+    /// Returns false if the thread doesn't exist.
+    pub fn set_class(&self, tid: ThreadId, class: SchedClass) -> bool {
+        let mut threads = self.threads.lock();
+        let thread = match threads.get_mut(&tid) {
+            Some(thread) => thread,
+            None => return false,
+        };
+        thread.class = class;
+
+        let mut runnable = self.per_core[thread.affinity].runnable.lock();
+        if runnable.remove(tid).is_some() {
+            runnable.push(tid, class);
+        }
+
+        true
+    }
+
+    /// Moves thread `tid` to core `core`.
+    ///
+    /// The thread keeps its place in the run- or waitlist (it is appended to
+    /// the runlist of `core` or keeps its wake-up time). A thread that is
+    /// running continues on `core` once it yields.
+    ///
+    /// Returns false if the thread doesn't exist, `core` is invalid, or the
+    /// thread is an interrupt thread.
+    pub fn migrate(&self, tid: ThreadId, core: CoreId) -> bool {
+        if core >= self.per_core.len() {
+            return false;
+        }
+
+        let mut threads = self.threads.lock();
+        let thread = match threads.get_mut(&tid) {
+            Some(thread) if thread.interrupt_vector.is_none() => thread,
+            _ => return false,
+        };
+        let from = thread.affinity;
+        if from == core {
+            return true;
+        }
+        thread.affinity = core;
+        if !thread.state.is_null() {
+            unsafe { (*thread.state).current_core = core };
+        }
+
+        let (low, high) = (core::cmp::min(from, core), core::cmp::max(from, core));
+        {
+            let mut waiting_low = self.per_core[low].waiting.lock();
+            let mut waiting_high = self.per_core[high].waiting.lock();
+            let (src, dst) = if from == low {
+                (&mut *waiting_low, &mut *waiting_high)
+            } else {
+                (&mut *waiting_high, &mut *waiting_low)
+            };
+            if let Some(pos) = src.iter().position(|&(_instant, wtid)| wtid == tid) {
+                let (until, _tid) = src.remove(pos);
+                waitlist_insert_sorted(dst, tid, until);
+            }
+        }
+        {
+            let mut runnable_low = self.per_core[low].runnable.lock();
+            let mut runnable_high = self.per_core[high].runnable.lock();
+            let (src, dst) = if from == low {
+                (&mut *runnable_low, &mut *runnable_high)
+            } else {
+                (&mut *runnable_high, &mut *runnable_low)
+            };
+            if let Some(slot) = src.remove(tid) {
+                dst.insert(tid, slot);
+            }
+        }
+
+        true
+    }
+
+    /// Takes a runnable thread from another core and migrates it to
+    /// `core_id`.
+    ///
+    /// Victims are probed in order starting at the next core, the thread
+    /// the victim would run last is taken.
+    /// Interrupt threads are never stolen.
+    fn steal(&self, core_id: CoreId) -> Option<ThreadId> {
+        let cores = self.per_core.len();
+        for victim in (1..cores).map(|i| (core_id + i) % cores) {
+            // Avoid the `threads` lock for cores that have nothing to steal
+            if self.per_core[victim].runnable.lock().is_empty() {
+                continue;
+            }
+
+            let mut threads = self.threads.lock();
+            let mut runnable = self.per_core[victim].runnable.lock();
+            // A thread without generator is still being put back by the core
+            // that ran it last.
+            let generators = self.generators.lock();
+            let stolen = runnable.take_last(|tid| {
+                generators.contains_key(tid)
+                    && threads
+                        .get(tid)
+                        .map_or(false, |thread| thread.interrupt_vector.is_none())
+            });
+            drop(generators);
+
+            if let Some(tid) = stolen {
+                let thread = threads.get_mut(&tid).expect("Can't find thread");
+                thread.affinity = core_id;
+                if !thread.state.is_null() {
+                    unsafe { (*thread.state).current_core = core_id };
+                }
+                trace!("Core {} stole {} from core {}", core_id, tid, victim);
+                return Some(tid);
+            }
+        }
+
+        None
+    }
+
     /// Handles a yield request of the thread given by `tid`.
     ///
     /// Updates run and waitlists accordingly.
@@ -240,8 +541,14 @@ impl<'a> SmpScheduler<'a> {
                     .remove(&tid)
                     .expect("Can't remove thread?");
 
-                // Wake up all the waiters
+                // Wake up all the waiters (on the core they're on now, they may
+                // have migrated while waiting)
                 for (sleeping_tid, sleeping_affinity) in thread.joinlist {
+                    let sleeping_affinity = self
+                        .threads
+                        .lock()
+                        .get(&sleeping_tid)
+                        .map_or(sleeping_affinity, |t| t.affinity);
                     log::debug!(
                         "{} will return from join on core {}",
                         sleeping_tid,
@@ -344,6 +651,25 @@ impl<'a> SmpScheduler<'a> {
                     None => YieldResume::Completed,
                 }
             }
+            Some(YieldRequest::Migrate(mtid, core)) => {
+                trace!("YieldRequest::Migrate {:?} to {}", mtid, core);
+                if !self.migrate(mtid, core) {
+                    YieldResume::Rejected
+                } else if mtid == tid {
+                    // Continue on the new core (see `run`)
+                    YieldResume::Migrated
+                } else {
+                    YieldResume::Completed
+                }
+            }
+            Some(YieldRequest::SetClass(ctid, class)) => {
+                trace!("YieldRequest::SetClass {:?} {:?}", ctid, class);
+                if self.set_class(ctid, class) {
+                    YieldResume::Completed
+                } else {
+                    YieldResume::Rejected
+                }
+            }
             Some(YieldRequest::Spawn(function, arg, affinity, irq_vector)) => {
                 trace!("self.spawn {:?} {:p}", function, arg);
                 let tid = self
@@ -365,6 +691,7 @@ impl<'a> SmpScheduler<'a> {
                 arg,
                 affinity,
                 irq_vec,
+                class,
                 tls_private,
             )) => {
                 trace!("self.spawn {:?} {:p}", function, arg);
@@ -377,6 +704,7 @@ impl<'a> SmpScheduler<'a> {
                         arg,
                         affinity,
                         irq_vec,
+                        class,
                         tls_private,
                     )
                     .expect("Can't spawn the thread");
@@ -387,16 +715,22 @@ impl<'a> SmpScheduler<'a> {
 
     /// Finds threads with expired timeouts and re-inserts them from `waiting` into `runnable`
     ///
-    /// Acquires lock on `waiting` and `runnable`.
-    /// TODO(style): Maybe should avoid taking both locks here to avoid deadlock.
+    /// Releases the lock on `waiting` before acquiring `runnable` (and
+    /// `threads` to look up the scheduling class).
     /// TODO(efficiency): Should probably avoid taking `runnable` lock multiple times.
     fn check_wakeups(&self, affinity: CoreId) {
-        let mut waiting = self.per_core[affinity].waiting.lock();
-        while !waiting.is_empty() && waiting.last().unwrap().0 <= Instant::now() {
-            if let Some((_wakeup, tid)) = waiting.pop() {
-                self.mark_runnable(tid, affinity);
+        let mut expired = Vec::new();
+        {
+            let mut waiting = self.per_core[affinity].waiting.lock();
+            while !waiting.is_empty() && waiting.last().unwrap().0 <= Instant::now() {
+                if let Some((_wakeup, tid)) = waiting.pop() {
+                    expired.push(tid);
+                }
             }
         }
+        for tid in expired {
+            self.mark_runnable(tid, affinity);
+        }
     }
 
     /// Check for an incoming interrupt.
@@ -440,7 +774,14 @@ impl<'a> SmpScheduler<'a> {
             self.check_wakeups(core_id);
 
             // The next thread ID we want to run
-            let next_tid = self.per_core[core_id].runnable.lock().pop_front();
+            let next_tid = self.per_core[core_id].runnable.lock().pop();
+            let next_tid = next_tid.or_else(|| {
+                if self.work_stealing.load(Ordering::Relaxed) {
+                    self.steal(core_id)
+                } else {
+                    None
+                }
+            });
             match next_tid {
                 Some(tid) => {
                     let mut generator = self
@@ -475,7 +816,9 @@ impl<'a> SmpScheduler<'a> {
                         trace!("yielded_with = {:?}", yielded_with);
                         resume_action = self.handle_yield_request(tid, yielded_with);
                         trace!("{:?} resume_action = {:?}", tid, resume_action);
-                        if resume_action == YieldResume::Interrupted {
+                        if resume_action == YieldResume::Interrupted
+                            || resume_action == YieldResume::Migrated
+                        {
                             // If we're not done we need to put the generator back:
                             self.generators.lock().insert(tid, generator);
 
@@ -490,6 +833,14 @@ impl<'a> SmpScheduler<'a> {
                                 }
                             }
                             assert!(!thread.state.is_null());
+
+                            // A thread that migrated itself can only become runnable on
+                            // the new core once its generator is back:
+                            if resume_action == YieldResume::Migrated {
+                                let affinity = thread.affinity;
+                                drop(thread_map);
+                                self.mark_runnable(tid, affinity);
+                            }
                             break;
                         }
                         if resume_action == YieldResume::DoNotResume {
@@ -519,6 +870,16 @@ impl<'a> SmpScheduler<'a> {
     }
 }
 
+/// Insert `tid` in a waitlist, the list is sorted by descending wake-up
+/// time (the next thread to wake up is last).
+fn waitlist_insert_sorted(waiting: &mut Vec<(Instant, ThreadId)>, tid: ThreadId, until: Instant) {
+    let to_insert = (until, tid);
+    match waiting.binary_search_by(|probe| probe.cmp(&to_insert).reverse()) {
+        Err(pos) => waiting.insert(pos, to_insert),
+        Ok(_pos) => panic!("Thread already in waitlist?"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use alloc::sync::Arc;
@@ -720,6 +1081,232 @@ mod tests {
         assert_eq!(end_times.pop().unwrap(), ThreadId(0));
     }
 
+    /// Checks that a migrated thread only runs on its new core.
+    #[test]
+    fn migrate_runnable_thread() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Arc::new(Default::default());
+
+        let ran_on: Arc<ArrayQueue<CoreId>> = Arc::new(ArrayQueue::new(1));
+        let ran_on1 = ran_on.clone();
+        let tid = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| {
+                    let _r = ran_on1.push(Environment::core_id());
+                },
+                ptr::null_mut(),
+                0,
+                None,
+            )
+            .unwrap();
+
+        assert!(s.migrate(tid, 1));
+        assert!(s.per_core[0].runnable.lock().is_empty());
+        assert_eq!(s.per_core[1].runnable.lock().peek(), Some(tid));
+
+        s.run(&SchedulerControlBlock::new(0));
+        assert!(ran_on.is_empty(), "Thread ran on its old core");
+        s.run(&SchedulerControlBlock::new(1));
+        assert_eq!(ran_on.pop(), Some(1));
+    }
+
+    /// Checks that a thread can migrate itself.
+    #[test]
+    fn migrate_current_thread() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Arc::new(Default::default());
+
+        let ran_on: Arc<ArrayQueue<CoreId>> = Arc::new(ArrayQueue::new(2));
+        let ran_on1 = ran_on.clone();
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            move |_| {
+                let _r = ran_on1.push(Environment::core_id());
+                assert!(Environment::thread().migrate(Environment::tid(), 1));
+                let _r = ran_on1.push(Environment::core_id());
+            },
+            ptr::null_mut(),
+            0,
+            None,
+        );
+
+        s.run(&SchedulerControlBlock::new(0));
+        assert_eq!(ran_on.len(), 1);
+        s.run(&SchedulerControlBlock::new(1));
+        assert_eq!(ran_on.pop(), Some(0));
+        assert_eq!(ran_on.pop(), Some(1));
+        assert!(!s.has_active_threads());
+    }
+
+    /// Checks that interrupt threads and invalid cores are refused.
+    #[test]
+    fn migrate_rejected() {
+        let s: Arc<SmpScheduler> = Arc::new(Default::default());
+        let irq_tid = s
+            .spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                |_| {},
+                ptr::null_mut(),
+                0,
+                Some(32),
+            )
+            .unwrap();
+        let tid = s
+            .spawn(DEFAULT_STACK_SIZE_BYTES, |_| {}, ptr::null_mut(), 0, None)
+            .unwrap();
+
+        assert!(!s.migrate(irq_tid, 1));
+        assert!(!s.migrate(tid, s.per_core.len()));
+        assert!(!s.migrate(ThreadId(usize::MAX), 1));
+        assert_eq!(s.per_core[0].runnable.lock().len(), 2);
+    }
+
+    /// Checks that an idle core only takes threads from other cores with
+    /// work stealing enabled, and never takes interrupt threads.
+    #[test]
+    fn work_stealing() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Arc::new(Default::default());
+
+        let ran_on: Arc<ArrayQueue<CoreId>> = Arc::new(ArrayQueue::new(2));
+        for _i in 0..2 {
+            let ran_on1 = ran_on.clone();
+            s.spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                move |_| {
+                    let _r = ran_on1.push(Environment::core_id());
+                },
+                ptr::null_mut(),
+                0,
+                None,
+            );
+        }
+        s.spawn(
+            DEFAULT_STACK_SIZE_BYTES,
+            |_| {},
+            ptr::null_mut(),
+            0,
+            Some(32),
+        );
+
+        let scb1 = SchedulerControlBlock::new(1);
+        s.run(&scb1);
+        assert!(ran_on.is_empty(), "Stole work without work stealing");
+
+        s.set_work_stealing(true);
+        s.run(&scb1);
+        assert_eq!(ran_on.pop(), Some(1));
+        assert_eq!(ran_on.pop(), Some(1));
+        // The interrupt thread stays on core 0:
+        assert_eq!(s.per_core[0].runnable.lock().len(), 1);
+    }
+
+    /// Checks that the core and thread limits of a scheduler are enforced.
+    #[test]
+    fn limits() {
+        let s: SmpScheduler = SmpScheduler::with_limits(Default::default(), 2, 2);
+        assert_eq!(s.cores(), 2);
+        assert_eq!(s.max_threads(), 2);
+
+        let spawn = |core| {
+            s.spawn(
+                DEFAULT_STACK_SIZE_BYTES,
+                |_| {},
+                ptr::null_mut(),
+                core,
+                None,
+            )
+        };
+        assert!(spawn(2).is_none(), "Spawned on a core that doesn't exist");
+        assert!(spawn(0).is_some());
+        assert!(spawn(1).is_some());
+        assert!(spawn(0).is_none(), "Exceeded thread limit");
+
+        s.run(&SchedulerControlBlock::new(0));
+        s.run(&SchedulerControlBlock::new(1));
+        assert!(!s.has_active_threads());
+        assert!(spawn(1).is_some());
+    }
+
+    /// Spawns a thread on core 0 that records its id in `order` when it runs.
+    fn spawn_recorder(
+        s: &SmpScheduler<'static>,
+        order: &Arc<ArrayQueue<ThreadId>>,
+        class: SchedClass,
+    ) -> ThreadId {
+        let order = order.clone();
+        s.spawn_with_args(
+            LineupStack::from_size(DEFAULT_STACK_SIZE_BYTES),
+            move |_| {
+                let _r = order.push(Environment::tid());
+            },
+            ptr::null_mut(),
+            0,
+            None,
+            class,
+            unsafe { ThreadControlBlock::new_tls_area() },
+        )
+        .unwrap()
+    }
+
+    /// Checks that threads run in order of their scheduling class and
+    /// round-robin within a class.
+    #[test]
+    fn priority_classes() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Arc::new(Default::default());
+        let order: Arc<ArrayQueue<ThreadId>> = Arc::new(ArrayQueue::new(5));
+
+        let low = spawn_recorder(&s, &order, SchedClass::Low);
+        let normal1 = spawn_recorder(&s, &order, SchedClass::Normal);
+        let high = spawn_recorder(&s, &order, SchedClass::High);
+        let normal2 = spawn_recorder(&s, &order, SchedClass::Normal);
+        let deadline = spawn_recorder(&s, &order, SchedClass::Deadline(Duration::from_secs(1)));
+
+        s.run(&SchedulerControlBlock::new(0));
+        assert_eq!(order.pop(), Some(deadline));
+        assert_eq!(order.pop(), Some(high));
+        assert_eq!(order.pop(), Some(normal1));
+        assert_eq!(order.pop(), Some(normal2));
+        assert_eq!(order.pop(), Some(low));
+    }
+
+    /// Checks that deadline threads run earliest deadline first.
+    #[test]
+    fn deadline_ordering() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Arc::new(Default::default());
+        let order: Arc<ArrayQueue<ThreadId>> = Arc::new(ArrayQueue::new(3));
+
+        let late = spawn_recorder(&s, &order, SchedClass::Deadline(Duration::from_secs(10)));
+        let normal = spawn_recorder(&s, &order, SchedClass::Normal);
+        let early = spawn_recorder(&s, &order, SchedClass::Deadline(Duration::from_millis(1)));
+
+        s.run(&SchedulerControlBlock::new(0));
+        assert_eq!(order.pop(), Some(early));
+        assert_eq!(order.pop(), Some(late));
+        assert_eq!(order.pop(), Some(normal));
+    }
+
+    /// Checks that changing the class of a runnable thread re-queues it.
+    #[test]
+    fn set_class() {
+        let _r = env_logger::try_init();
+        let s: Arc<SmpScheduler> = Arc::new(Default::default());
+        let order: Arc<ArrayQueue<ThreadId>> = Arc::new(ArrayQueue::new(2));
+
+        let first = spawn_recorder(&s, &order, SchedClass::Normal);
+        let second = spawn_recorder(&s, &order, SchedClass::Normal);
+        assert!(s.set_class(second, SchedClass::High));
+        assert!(!s.set_class(ThreadId(usize::MAX), SchedClass::High));
+        assert_eq!(s.per_core[0].runnable.lock().len(), 2);
+
+        s.run(&SchedulerControlBlock::new(0));
+        assert_eq!(order.pop(), Some(second));
+        assert_eq!(order.pop(), Some(first));
+    }
+
     /// Checks that the scheduler can run in parallel.
     ///
     /// Running two long computations on two cores shouldn't take
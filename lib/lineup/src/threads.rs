@@ -6,7 +6,7 @@ use core::hash::{Hash, Hasher};
 use core::{fmt, mem, ptr};
 
 use fringe::generator::{Generator, Yielder};
-use rawtime::Instant;
+use rawtime::{Duration, Instant};
 
 use crate::stack::LineupStack;
 use crate::tls2::{self, ThreadControlBlock};
@@ -33,6 +33,29 @@ impl fmt::Display for ThreadId {
     }
 }
 
+/// Scheduling class of a thread.
+///
+/// A core always runs deadline threads first (earliest deadline first), then
+/// `High`, `Normal` and `Low` threads (round-robin within the same class).
+/// Lower classes only run if nothing else is runnable on the core.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SchedClass {
+    /// The thread should run within the given time after it became runnable.
+    Deadline(Duration),
+    /// Latency sensitive threads.
+    High,
+    /// Default class for threads.
+    Normal,
+    /// Background work.
+    Low,
+}
+
+impl Default for SchedClass {
+    fn default() -> Self {
+        SchedClass::Normal
+    }
+}
+
 pub(crate) struct Thread {
     /// Thread ID
     pub(crate) id: ThreadId,
@@ -40,11 +63,16 @@ pub(crate) struct Thread {
     /// Current core affinity of the thread.
     pub(crate) affinity: CoreId,
 
+    /// Scheduling class of the thread.
+    pub(crate) class: SchedClass,
+
     /// Storage area for resume result (is thread was put in waiting list).
     pub(crate) return_with: Option<YieldResume>,
 
     /// If thread is registered to wake up for the specific interrupt vector.
-    pub(crate) _interrupt_vector: Option<IrqVector>,
+    ///
+    /// Interrupt threads are pinned to their core (they never migrate).
+    pub(crate) interrupt_vector: Option<IrqVector>,
 
     /// Threads currently waiting (join, blocked) on us to exit.
     pub(crate) joinlist: Vec<(ThreadId, CoreId)>,
@@ -84,8 +112,10 @@ impl Thread {
         f: F,
         arg: *mut u8,
         upcalls: Upcalls,
-        _interrupt_vector: Option<IrqVector>,
+        interrupt_vector: Option<IrqVector>,
+        class: SchedClass,
         tcb: *mut ThreadControlBlock<'static>,
+        max_threads: usize,
     ) -> (
         Thread,
         Generator<'a, YieldResume, YieldRequest, LineupStack>,
@@ -101,9 +131,10 @@ impl Thread {
         let thread = Thread {
             id: tid,
             affinity,
+            class,
             return_with: None,
-            _interrupt_vector,
-            joinlist: Vec::with_capacity(crate::scheduler::SmpScheduler::MAX_THREADS),
+            interrupt_vector,
+            joinlist: Vec::with_capacity(max_threads),
             state: tcb,
         };
 
@@ -145,6 +176,10 @@ pub(crate) enum YieldRequest {
     RunnableList(Vec<ThreadId>),
     /// Wait until the thread with given ID is finished.
     JoinOn(ThreadId),
+    /// Move the thread with the given ID to another core.
+    Migrate(ThreadId, CoreId),
+    /// Change the scheduling class of the thread with the given ID.
+    SetClass(ThreadId, SchedClass),
     /// Spawn a new thread that runs the provided function and argument.
     Spawn(
         Option<unsafe extern "C" fn(arg1: *mut u8) -> *mut u8>,
@@ -159,6 +194,7 @@ pub(crate) enum YieldRequest {
         *mut u8,
         CoreId,
         Option<IrqVector>,
+        SchedClass,
         *mut ThreadControlBlock<'static>,
     ),
 }
@@ -175,4 +211,9 @@ pub(crate) enum YieldResume {
     Spawned(ThreadId),
     /// Thread has completed (and has been removed from the scheduler state)
     DoNotResume,
+    /// The request was refused (we immediately resumed without a context switch).
+    Rejected,
+    /// The thread moved itself to another core (never seen by the thread, it
+    /// resumes with `Completed` on the new core).
+    Migrated,
 }
@@ -27,7 +27,7 @@ use crossbeam_queue::ArrayQueue;
 use rawtime::{Duration, Instant};
 
 use crate::stack::LineupStack;
-use crate::threads::{ThreadId, YieldRequest, YieldResume};
+use crate::threads::{SchedClass, ThreadId, YieldRequest, YieldResume};
 use crate::upcalls::Upcalls;
 use crate::{CoreId, IrqVector};
 
@@ -150,9 +150,10 @@ impl<'a> ThreadControlBlock<'a> {
         arg: *mut u8,
         core_id: CoreId,
         irq_vector: Option<IrqVector>,
+        class: SchedClass,
         tcb: *mut ThreadControlBlock<'static>,
     ) -> Option<ThreadId> {
-        let request = YieldRequest::SpawnWithArgs(s, f, arg, core_id, irq_vector, tcb);
+        let request = YieldRequest::SpawnWithArgs(s, f, arg, core_id, irq_vector, class, tcb);
         match self.yielder().suspend(request) {
             YieldResume::Spawned(tid) => Some(tid),
             _ => None,
@@ -228,6 +229,24 @@ impl<'a> ThreadControlBlock<'a> {
         self.yielder().suspend(request);
     }
 
+    /// Moves the thread `tid` to core `core_id`, a thread that migrates
+    /// itself continues on the new core.
+    ///
+    /// Returns false if the thread doesn't exist, the core is invalid or
+    /// the thread is an interrupt thread (those are pinned to their core).
+    pub fn migrate(&self, tid: ThreadId, core_id: CoreId) -> bool {
+        let request = YieldRequest::Migrate(tid, core_id);
+        !matches!(self.yielder().suspend(request), YieldResume::Rejected)
+    }
+
+    /// Changes the scheduling class of thread `tid`.
+    ///
+    /// Returns false if the thread doesn't exist.
+    pub fn set_class(&self, tid: ThreadId, class: SchedClass) -> bool {
+        let request = YieldRequest::SetClass(tid, class);
+        !matches!(self.yielder().suspend(request), YieldResume::Rejected)
+    }
+
     pub(crate) fn suspend(&self, request: YieldRequest) {
         self.yielder().suspend(request);
     }
@@ -16,3 +16,4 @@ pub mod cluster_api;
 pub mod rpc;
 pub mod rpc_api;
 pub mod tcp_client;
+pub mod tcp_server;
@@ -92,9 +92,7 @@ impl From<u8> for RPCType {
     fn from(op: u8) -> RPCType {
         match op {
             1 => RPCType::Registration,
-
-            // TODO: Add RPC requests
-            // 2 => RPCType::Create,
+            2 => RPCType::Create,
             3 => RPCType::Open,
             4 => RPCType::Read,
             5 => RPCType::ReadAt,
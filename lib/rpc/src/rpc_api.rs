@@ -7,10 +7,14 @@ use core::result::Result;
 use crate::cluster_api::NodeId;
 use crate::rpc::{RPCError, RPCHeader, RPCType};
 
+/// RPC handler function, called by the server for every request of the
+/// registered type. Returns the (already serialized) response payload.
+pub type RPCHandler = fn(hdr: &RPCHeader, payload: Vec<u8>) -> Result<Vec<u8>, RPCError>;
+
 /// RPC server operations
 pub trait RPCServerAPI {
     /// register an RPC func with an ID
-    fn register(&self, rpc_id: RPCType) -> Result<(), RPCError>;
+    fn register(&mut self, rpc_id: RPCType, handler: RPCHandler) -> Result<(), RPCError>;
 
     /// receives next RPC call with RPC ID
     fn receive(&self) -> Result<(RPCHeader, Vec<u8>), RPCError>;
@@ -8,6 +8,7 @@ use alloc::{vec, vec::Vec};
 use log::{debug, trace, warn};
 
 use smoltcp::iface::EthernetInterface;
+use smoltcp::phy::Device;
 use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer};
 use smoltcp::time::Instant;
 use smoltcp::wire::IpAddress;
@@ -22,8 +23,11 @@ use crate::rpc_api::RPCClientAPI;
 const RX_BUF_LEN: usize = 4096;
 const TX_BUF_LEN: usize = 4096;
 
-pub struct TCPClient<'a> {
-    iface: EthernetInterface<'a, DevQueuePhy>,
+pub struct TCPClient<'a, D = DevQueuePhy>
+where
+    D: for<'d> Device<'d>,
+{
+    iface: EthernetInterface<'a, D>,
     sockets: SocketSet<'a>,
     server_handle: Option<SocketHandle>,
     server_ip: IpAddress,
@@ -33,12 +37,15 @@ pub struct TCPClient<'a> {
     req_id: u64,
 }
 
-impl TCPClient<'_> {
-    pub fn new<'a>(
+impl<'a, D> TCPClient<'a, D>
+where
+    D: for<'d> Device<'d>,
+{
+    pub fn new(
         server_ip: IpAddress,
         server_port: u16,
-        iface: EthernetInterface<'a, DevQueuePhy>,
-    ) -> TCPClient<'a> {
+        iface: EthernetInterface<'a, D>,
+    ) -> TCPClient<'a, D> {
         TCPClient {
             iface: iface,
             sockets: SocketSet::new(vec![]),
@@ -52,7 +59,10 @@ impl TCPClient<'_> {
     }
 }
 
-impl ClusterClientAPI for TCPClient<'_> {
+impl<D> ClusterClientAPI for TCPClient<'_, D>
+where
+    D: for<'d> Device<'d>,
+{
     /// Register with controller, analogous to LITE join_cluster()
     /// TODO: add timeout?? with error returned if timeout occurs?
     fn join_cluster(&mut self) -> Result<NodeId, ClusterError> {
@@ -95,7 +105,10 @@ impl ClusterClientAPI for TCPClient<'_> {
 }
 
 /// RPC client operations
-impl RPCClientAPI for TCPClient<'_> {
+impl<D> RPCClientAPI for TCPClient<'_, D>
+where
+    D: for<'d> Device<'d>,
+{
     /// calls a remote RPC function with ID
     fn call(&mut self, pid: usize, rpc_id: RPCType, data: Vec<u8>) -> Result<Vec<u8>, RPCError> {
         // Create request header
@@ -222,7 +235,10 @@ impl RPCClientAPI for TCPClient<'_> {
     }
 }
 
-impl TCPClient<'_> {
+impl<D> TCPClient<'_, D>
+where
+    D: for<'d> Device<'d>,
+{
     pub fn fio_write(
         &mut self,
         pid: usize,
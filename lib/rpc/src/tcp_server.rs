@@ -0,0 +1,370 @@
+// Copyright © 2021 University of Colorado. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use abomonation::{decode, encode};
+use alloc::collections::BTreeMap;
+use alloc::{vec, vec::Vec};
+use core::cell::{Cell, RefCell};
+use log::{debug, trace, warn};
+
+use smoltcp::iface::EthernetInterface;
+use smoltcp::phy::Device;
+use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer};
+use smoltcp::time::Instant;
+
+use vmxnet3::smoltcp::DevQueuePhy;
+
+use crate::cluster_api::{ClusterControllerAPI, ClusterError, NodeId};
+use crate::rpc::*;
+use crate::rpc_api::{RPCHandler, RPCServerAPI};
+
+const RX_BUF_LEN: usize = 4096;
+const TX_BUF_LEN: usize = 4096;
+
+/// RPC server that serves any number of clients over TCP.
+///
+/// There is always one socket listening on the server port, once a client
+/// connects to it the socket becomes the connection to this client and a new
+/// listening socket is created. Requests are handled one at a time, in the
+/// order the clients are polled.
+pub struct TCPServer<'a, D = DevQueuePhy>
+where
+    D: for<'d> Device<'d>,
+{
+    iface: RefCell<EthernetInterface<'a, D>>,
+    sockets: RefCell<SocketSet<'a>>,
+    /// Socket that waits for the next client to connect.
+    listen_handle: Cell<Option<SocketHandle>>,
+    /// Sockets of the connected clients.
+    client_handles: RefCell<Vec<SocketHandle>>,
+    /// Socket of the client whose request is currently being processed.
+    current_handle: Cell<Option<SocketHandle>>,
+    server_port: u16,
+    /// Header of the request that is currently being processed, used to
+    /// fill in the request id etc. of the reply.
+    hdr: RefCell<RPCHeader>,
+    handlers: BTreeMap<u8, RPCHandler>,
+    next_client_id: NodeId,
+}
+
+impl<'a, D> TCPServer<'a, D>
+where
+    D: for<'d> Device<'d>,
+{
+    pub fn new(iface: EthernetInterface<'a, D>, port: u16) -> TCPServer<'a, D> {
+        TCPServer {
+            iface: RefCell::new(iface),
+            sockets: RefCell::new(SocketSet::new(vec![])),
+            listen_handle: Cell::new(None),
+            client_handles: RefCell::new(Vec::new()),
+            current_handle: Cell::new(None),
+            server_port: port,
+            hdr: RefCell::new(RPCHeader {
+                client_id: 0,
+                pid: 0,
+                req_id: 0,
+                msg_type: RPCType::Unknown,
+                msg_len: 0,
+            }),
+            handlers: BTreeMap::new(),
+            next_client_id: 0,
+        }
+    }
+
+    /// Creates the server socket and waits (polling) until the first client
+    /// connects, more clients can connect later on.
+    pub fn listen(&mut self) -> Result<(), RPCError> {
+        self.add_listen_socket()?;
+        debug!("Listening at port {}", self.server_port);
+
+        while self.client_handles.borrow().is_empty() {
+            self.accept()?;
+        }
+        Ok(())
+    }
+
+    /// Adds a socket that listens for the next client.
+    fn add_listen_socket(&self) -> Result<(), RPCError> {
+        let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; RX_BUF_LEN]);
+        let tcp_tx_buffer = TcpSocketBuffer::new(vec![0; TX_BUF_LEN]);
+        let mut tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+        tcp_socket
+            .listen(self.server_port)
+            .map_err(|_e| RPCError::TransportError)?;
+        self.listen_handle
+            .set(Some(self.sockets.borrow_mut().add(tcp_socket)));
+        Ok(())
+    }
+
+    /// Polls the interface, takes on a client that connected to the listening
+    /// socket and drops the clients that went away.
+    fn accept(&self) -> Result<(), RPCError> {
+        self.poll();
+
+        let handle = self.listen_handle.get().ok_or(RPCError::TransportError)?;
+        let connected = {
+            let mut sockets = self.sockets.borrow_mut();
+            let socket = sockets.get::<TcpSocket>(handle);
+            // Waiting for send/recv forces the TCP handshake to fully complete
+            socket.is_active() && (socket.may_send() || socket.may_recv())
+        };
+        if connected {
+            debug!("Connected to a client, ready to send/recv data");
+            self.client_handles.borrow_mut().push(handle);
+            self.add_listen_socket()?;
+        }
+
+        let mut sockets = self.sockets.borrow_mut();
+        self.client_handles.borrow_mut().retain(|&handle| {
+            let open = {
+                let mut socket = sockets.get::<TcpSocket>(handle);
+                if socket.is_open() && !socket.may_recv() {
+                    // The client closed the connection, close our end too
+                    socket.close();
+                }
+                socket.is_open()
+            };
+            if !open {
+                debug!("Client disconnected");
+                sockets.remove(handle);
+                if self.current_handle.get() == Some(handle) {
+                    self.current_handle.set(None);
+                }
+            }
+            open
+        });
+        Ok(())
+    }
+
+    fn poll(&self) {
+        match self
+            .iface
+            .borrow_mut()
+            .poll(&mut self.sockets.borrow_mut(), Instant::from_millis(0))
+        {
+            Ok(_) => {}
+            Err(e) => {
+                warn!("poll error: {}", e);
+            }
+        }
+    }
+
+    /// Returns the first client that has data for us.
+    fn next_client(&self) -> Option<SocketHandle> {
+        let mut sockets = self.sockets.borrow_mut();
+        self.client_handles
+            .borrow()
+            .iter()
+            .copied()
+            .find(|&handle| sockets.get::<TcpSocket>(handle).can_recv())
+    }
+
+    /// send data to the client of the current request
+    fn send(&self, data: Vec<u8>) -> Result<(), RPCError> {
+        let handle = self.current_handle.get().ok_or(RPCError::TransportError)?;
+        let mut data_sent = 0;
+        loop {
+            self.poll();
+
+            if data_sent == data.len() {
+                return Ok(());
+            } else {
+                let mut sockets = self.sockets.borrow_mut();
+                let mut socket = sockets.get::<TcpSocket>(handle);
+                if socket.can_send() && socket.send_capacity() > 0 {
+                    let end_index =
+                        data_sent + core::cmp::min(data.len() - data_sent, socket.send_capacity());
+                    if let Ok(bytes_sent) = socket.send_slice(&data[data_sent..end_index]) {
+                        trace!(
+                            "Server sent: [{:?}-{:?}] {:?}/{:?} bytes",
+                            data_sent,
+                            end_index,
+                            data_sent + bytes_sent,
+                            data.len()
+                        );
+                        data_sent += bytes_sent;
+                    } else {
+                        debug!("send_slice failed... trying again?");
+                    }
+                } else if !socket.may_send() {
+                    return Err(RPCError::TransportError);
+                }
+            }
+        }
+    }
+
+    /// receive exactly `expected_data` bytes from the client of the current
+    /// request
+    fn recv(&self, expected_data: usize) -> Result<Vec<u8>, RPCError> {
+        let handle = self.current_handle.get().ok_or(RPCError::TransportError)?;
+        let mut data = vec![0; expected_data];
+        let mut total_data_received = 0;
+
+        loop {
+            self.poll();
+
+            if total_data_received == expected_data {
+                return Ok(data);
+            } else {
+                let mut sockets = self.sockets.borrow_mut();
+                let mut socket = sockets.get::<TcpSocket>(handle);
+                if socket.can_recv() {
+                    if let Ok(bytes_received) =
+                        socket.recv_slice(&mut data[total_data_received..expected_data])
+                    {
+                        total_data_received += bytes_received;
+                        trace!(
+                            "rcv got {:?}/{:?} bytes",
+                            total_data_received,
+                            expected_data
+                        );
+                    } else {
+                        warn!("recv_slice failed... trying again?");
+                    }
+                } else if !socket.may_recv() {
+                    return Err(RPCError::TransportError);
+                }
+            }
+        }
+    }
+
+    /// Serializes a `FIORPCRes` carrying an error, used when a request can't
+    /// be handled.
+    fn error_response(err: RPCError) -> Vec<u8> {
+        let res = FIORPCRes { ret: Err(err) };
+        let mut res_data = Vec::new();
+        unsafe { encode(&res, &mut res_data) }.unwrap();
+        res_data
+    }
+}
+
+impl<D> ClusterControllerAPI for TCPServer<'_, D>
+where
+    D: for<'d> Device<'d>,
+{
+    /// Controller-side implementation for LITE join_cluster()
+    fn add_client(&mut self) -> Result<NodeId, ClusterError> {
+        let client_id = self.next_client_id;
+        self.next_client_id += 1;
+        Ok(client_id)
+    }
+}
+
+/// RPC server operations
+impl<D> RPCServerAPI for TCPServer<'_, D>
+where
+    D: for<'d> Device<'d>,
+{
+    /// register an RPC func with an ID
+    fn register(&mut self, rpc_id: RPCType, handler: RPCHandler) -> Result<(), RPCError> {
+        if rpc_id == RPCType::Registration || rpc_id == RPCType::Unknown {
+            return Err(RPCError::NotSupported);
+        }
+        if self.handlers.contains_key(&(rpc_id as u8)) {
+            return Err(RPCError::AlreadyPresent);
+        }
+        self.handlers.insert(rpc_id as u8, handler);
+        Ok(())
+    }
+
+    /// receives next RPC call with RPC ID (from any client, waits until one
+    /// of them sends a request)
+    fn receive(&self) -> Result<(RPCHeader, Vec<u8>), RPCError> {
+        let handle = loop {
+            self.accept()?;
+            if let Some(handle) = self.next_client() {
+                break handle;
+            }
+        };
+        self.current_handle.set(Some(handle));
+
+        // Receive request header
+        let mut hdr_data = self.recv(core::mem::size_of::<RPCHeader>())?;
+        let (hdr, extra) =
+            unsafe { decode::<RPCHeader>(&mut hdr_data) }.ok_or(RPCError::MalformedRequest)?;
+        if extra.len() > 0 {
+            return Err(RPCError::ExtraData);
+        }
+        let hdr = *hdr;
+
+        // Read the rest of the data
+        let mut payload_data = Vec::new();
+        if hdr.msg_len > 0 {
+            payload_data = self.recv(hdr.msg_len as usize)?;
+        }
+
+        *self.hdr.borrow_mut() = hdr;
+        Ok((hdr, payload_data))
+    }
+
+    /// replies an RPC call with results
+    fn reply(&self, client: NodeId, data: Vec<u8>) -> Result<(), RPCError> {
+        // Create response header, the request id has to match the request
+        let req_hdr = *self.hdr.borrow();
+        let res_hdr = RPCHeader {
+            client_id: client,
+            pid: req_hdr.pid,
+            req_id: req_hdr.req_id,
+            msg_type: req_hdr.msg_type,
+            msg_len: data.len() as u64,
+        };
+
+        // Serialize response header then response body
+        let mut res_data = Vec::new();
+        unsafe { encode(&res_hdr, &mut res_data) }.unwrap();
+        if data.len() > 0 {
+            res_data.extend(data);
+        }
+
+        self.send(res_data)
+    }
+
+    /// Run the RPC server
+    fn run_server(&mut self) -> Result<(), RPCError> {
+        if self.listen_handle.get().is_none() {
+            self.listen()?;
+        }
+
+        loop {
+            let (hdr, payload) = match self.receive() {
+                Ok(request) => request,
+                Err(RPCError::TransportError) => {
+                    // The client went away, keep serving the others
+                    warn!("Lost connection to a client while receiving");
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            trace!("Got RPC request {:?}", hdr);
+
+            let (client_id, res_data) = match hdr.msg_type {
+                RPCType::Registration => {
+                    let client_id = self.add_client().map_err(|_e| RPCError::InternalError)?;
+                    debug!("Registered client {}", client_id);
+                    (client_id, Vec::new())
+                }
+                msg_type => {
+                    let res_data = match self.handlers.get(&(msg_type as u8)) {
+                        Some(handler) => match handler(&hdr, payload) {
+                            Ok(res_data) => res_data,
+                            Err(e) => Self::error_response(e),
+                        },
+                        None => {
+                            warn!("No handler registered for {:?}", msg_type);
+                            Self::error_response(RPCError::NotSupported)
+                        }
+                    };
+                    (hdr.client_id, res_data)
+                }
+            };
+
+            match self.reply(client_id, res_data) {
+                Ok(()) => {}
+                Err(RPCError::TransportError) => {
+                    warn!("Lost connection to client {} while replying", client_id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright © 2021 University of Colorado. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use smoltcp::phy::{self, Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::Result;
+
+type Queues = Arc<Mutex<Vec<VecDeque<Vec<u8>>>>>;
+
+/// A port of an ethernet hub, every frame sent on a port is received by all
+/// other ports of the hub.
+pub struct HubPhy {
+    port: usize,
+    queues: Queues,
+}
+
+impl HubPhy {
+    /// Creates a hub with `ports` ports.
+    pub fn connect(ports: usize) -> Vec<HubPhy> {
+        let queues: Queues = Arc::new(Mutex::new(vec![VecDeque::new(); ports]));
+        (0..ports)
+            .map(|port| HubPhy {
+                port,
+                queues: queues.clone(),
+            })
+            .collect()
+    }
+
+    fn tx_token(&self) -> TxFrame {
+        TxFrame {
+            port: self.port,
+            queues: self.queues.clone(),
+        }
+    }
+}
+
+impl<'a> Device<'a> for HubPhy {
+    type RxToken = RxFrame;
+    type TxToken = TxFrame;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.queues.lock().unwrap()[self.port].pop_front()?;
+        Some((RxFrame(frame), self.tx_token()))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(self.tx_token())
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = 1514;
+        caps
+    }
+}
+
+pub struct RxFrame(Vec<u8>);
+
+impl phy::RxToken for RxFrame {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+pub struct TxFrame {
+    port: usize,
+    queues: Queues,
+}
+
+impl phy::TxToken for TxFrame {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut frame = vec![0; len];
+        let r = f(&mut frame)?;
+
+        let mut queues = self.queues.lock().unwrap();
+        for (port, queue) in queues.iter_mut().enumerate() {
+            if port != self.port {
+                queue.push_back(frame.clone());
+            }
+        }
+        Ok(r)
+    }
+}
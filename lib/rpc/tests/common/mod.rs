@@ -1,8 +1,10 @@
 // Copyright © 2021 University of Colorado. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+pub mod hub;
 pub mod mpsc_client;
 pub mod mpsc_server;
 
+pub use hub::HubPhy;
 pub use mpsc_client::MPSCClient;
 pub use mpsc_server::MPSCServer;
@@ -6,7 +6,7 @@ use std::sync::mpsc::{Receiver, SyncSender};
 
 use rpc::cluster_api::{ClusterControllerAPI, ClusterError, NodeId};
 use rpc::rpc::*;
-use rpc::rpc_api::RPCServerAPI;
+use rpc::rpc_api::{RPCHandler, RPCServerAPI};
 
 pub struct MPSCServer {
     rx: Receiver<Vec<u8>>,
@@ -27,7 +27,7 @@ impl ClusterControllerAPI for MPSCServer {
 
 impl RPCServerAPI for MPSCServer {
     /// register an RPC func with an ID
-    fn register(&self, _rpc_id: RPCType) -> Result<(), RPCError> {
+    fn register(&mut self, _rpc_id: RPCType, _handler: RPCHandler) -> Result<(), RPCError> {
         // TODO
         Err(RPCError::NotSupported)
     }
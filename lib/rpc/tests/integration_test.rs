@@ -30,3 +30,67 @@ fn mpsc_example() {
     let response = client.call(0, RPCType::Unknown, payload).unwrap();
     assert_eq!(response, "HELLO2".as_bytes().to_vec());
 }
+
+#[test]
+fn tcp_multiple_clients() {
+    use std::collections::BTreeMap;
+    use std::thread;
+
+    use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache};
+    use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr};
+
+    use common::HubPhy;
+    use rpc::cluster_api::ClusterClientAPI;
+    use rpc::rpc::{RPCError, RPCHeader, RPCType};
+    use rpc::rpc_api::{RPCClientAPI, RPCServerAPI};
+    use rpc::tcp_client::TCPClient;
+    use rpc::tcp_server::TCPServer;
+
+    const SERVER_PORT: u16 = 6970;
+
+    fn iface(phy: HubPhy, host: u8) -> EthernetInterface<'static, HubPhy> {
+        EthernetInterfaceBuilder::new(phy)
+            .ip_addrs(vec![IpCidr::new(IpAddress::v4(172, 31, 0, host), 24)])
+            .ethernet_addr(EthernetAddress([0x56, 0xb4, 0x44, 0xe9, 0x62, host]))
+            .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+            .finalize()
+    }
+
+    fn echo(_hdr: &RPCHeader, payload: Vec<u8>) -> Result<Vec<u8>, RPCError> {
+        Ok(payload)
+    }
+
+    let mut phys = HubPhy::connect(3);
+    let server_phy = phys.remove(0);
+    thread::spawn(move || {
+        let mut server = TCPServer::new(iface(server_phy, 1), SERVER_PORT);
+        server.register(RPCType::WriteAt, echo).unwrap();
+        server.run_server().unwrap();
+    });
+
+    let server_ip = IpAddress::v4(172, 31, 0, 1);
+    let mut clients: Vec<_> = phys
+        .into_iter()
+        .enumerate()
+        .map(|(i, phy)| TCPClient::new(server_ip, SERVER_PORT, iface(phy, 2 + i as u8)))
+        .collect();
+
+    // Both clients are connected at the same time
+    let ids: Vec<_> = clients
+        .iter_mut()
+        .map(|client| client.join_cluster().unwrap())
+        .collect();
+    assert_eq!(ids, vec![0, 1]);
+
+    for round in 0..2 {
+        for (i, client) in clients.iter_mut().enumerate() {
+            let payload = format!("HELLO{}-{}", i, round).into_bytes();
+            let response = client.call(0, RPCType::WriteAt, payload.clone()).unwrap();
+            assert_eq!(response, payload);
+        }
+    }
+
+    // Requests of types without a handler are answered with an error
+    let response = clients[0].call(0, RPCType::Close, Vec::new()).unwrap();
+    assert!(!response.is_empty());
+}
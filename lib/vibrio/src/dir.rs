@@ -0,0 +1,77 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Enumerate directories of the NRK file-system.
+//!
+//! A [`Dir`] fetches the entries of a directory in batches with the
+//! `ReadDir` system call and hands them out one at a time.
+
+use alloc::boxed::Box;
+
+use crate::io::{DirEntry, FileFlags, FileModes};
+use crate::syscalls::Fs;
+use crate::SystemCallError;
+
+/// How many entries we fetch from the kernel with one `ReadDir`.
+const BATCH_ENTRIES: usize = 8;
+
+/// An open directory, the directory is closed when this is dropped.
+pub struct Dir {
+    fd: u64,
+    /// Cookie for the next `ReadDir` system call.
+    cookie: u64,
+    /// The last batch of entries we got from the kernel.
+    entries: Box<[DirEntry; BATCH_ENTRIES]>,
+    /// How many of `entries` are valid.
+    len: usize,
+    /// The next entry in `entries` to return.
+    pos: usize,
+}
+
+impl Dir {
+    /// Opens the directory `pathname` (a pointer to a NUL terminated path).
+    pub fn open(pathname: u64) -> Result<Dir, SystemCallError> {
+        let fd = Fs::open(
+            pathname,
+            u64::from(FileFlags::O_RDONLY),
+            u64::from(FileModes::S_IRUSR),
+        )?;
+
+        Ok(Dir {
+            fd,
+            cookie: 0,
+            entries: Box::new([DirEntry::default(); BATCH_ENTRIES]),
+            len: 0,
+            pos: 0,
+        })
+    }
+
+    /// Returns the next entry of the directory, or `None` once all entries
+    /// were returned.
+    pub fn read(&mut self) -> Result<Option<&DirEntry>, SystemCallError> {
+        if self.pos == self.len {
+            let (entries, cookie) = Fs::readdir(
+                self.fd,
+                self.entries.as_mut_ptr() as u64,
+                core::mem::size_of_val(&*self.entries) as u64,
+                self.cookie,
+            )?;
+            self.len = entries as usize;
+            self.pos = 0;
+            self.cookie = cookie;
+
+            if self.len == 0 {
+                return Ok(None);
+            }
+        }
+
+        self.pos += 1;
+        Ok(Some(&self.entries[self.pos - 1]))
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        let _r = Fs::close(self.fd);
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright © 2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Message channels to exchange data with other processes.
+//!
+//! The blocking operations must be called from a lineup thread: while the
+//! channel is empty (or full) the thread backs off and lets other threads on
+//! the core run.
+
+use kpi::ipc::ChannelId;
+use kpi::SystemCallError;
+use lineup::tls2::Environment;
+use rawtime::Duration;
+
+use crate::syscalls::Ipc;
+
+/// How often we just yield before we start to sleep between attempts.
+const YIELD_ATTEMPTS: usize = 16;
+
+/// Upper bound for the time we sleep between attempts.
+const MAX_BACKOFF: Duration = Duration::from_millis(1);
+
+/// Waits a bit longer on every attempt, first by yielding to the other
+/// threads then by sleeping.
+struct Backoff {
+    attempt: usize,
+    sleep: Duration,
+}
+
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff {
+            attempt: 0,
+            sleep: Duration::from_micros(10),
+        }
+    }
+
+    fn wait(&mut self) {
+        let thread = Environment::thread();
+        if self.attempt < YIELD_ATTEMPTS {
+            self.attempt += 1;
+            thread.relinquish();
+        } else {
+            thread.sleep(self.sleep);
+            self.sleep = core::cmp::min(self.sleep * 2, MAX_BACKOFF);
+        }
+    }
+}
+
+/// A channel the process is attached to (it detaches when dropped).
+#[derive(Debug)]
+pub struct Channel {
+    id: ChannelId,
+}
+
+impl Channel {
+    /// Creates a channel called `name` that holds up to `capacity` messages.
+    pub fn create(name: u64, capacity: usize) -> Result<Channel, SystemCallError> {
+        let id = Ipc::create(name, capacity)?;
+        Ok(Channel { id })
+    }
+
+    /// Attaches to the existing channel called `name`.
+    pub fn attach(name: u64) -> Result<Channel, SystemCallError> {
+        let id = Ipc::attach(name)?;
+        Ok(Channel { id })
+    }
+
+    pub fn id(&self) -> ChannelId {
+        self.id
+    }
+
+    /// Lets process `pid` attach to the channel (the parent and the children
+    /// of the creator don't need this).
+    pub fn grant(&self, pid: u64) -> Result<(), SystemCallError> {
+        Ipc::grant(self.id, pid)
+    }
+
+    /// Sends `message`, fails with `WouldBlock` if the channel is full.
+    pub fn try_send(&self, message: &[u8]) -> Result<(), SystemCallError> {
+        Ipc::send(self.id, message)
+    }
+
+    /// Sends `message`, waits while the channel is full.
+    pub fn send(&self, message: &[u8]) -> Result<(), SystemCallError> {
+        let mut backoff = Backoff::new();
+        loop {
+            match self.try_send(message) {
+                Err(SystemCallError::WouldBlock) => backoff.wait(),
+                r => return r,
+            }
+        }
+    }
+
+    /// Receives the oldest message into `buffer` and returns its length,
+    /// fails with `WouldBlock` if the channel is empty.
+    pub fn try_receive(&self, buffer: &mut [u8]) -> Result<usize, SystemCallError> {
+        Ipc::try_receive(self.id, buffer)
+    }
+
+    /// Receives the oldest message into `buffer` and returns its length,
+    /// waits until a message arrives.
+    pub fn receive(&self, buffer: &mut [u8]) -> Result<usize, SystemCallError> {
+        let mut backoff = Backoff::new();
+        loop {
+            match self.try_receive(buffer) {
+                Err(SystemCallError::WouldBlock) => backoff.wait(),
+                r => return r,
+            }
+        }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        if let Err(e) = Ipc::detach(self.id) {
+            log::error!("Unable to detach from channel {}: {:?}", self.id, e);
+        }
+    }
+}
@@ -23,6 +23,8 @@ pub use kpi::*;
 extern crate arrayvec;
 extern crate lazy_static;
 
+pub mod dir;
+pub mod ipc;
 pub mod mem;
 pub mod upcalls;
 pub mod vconsole;
@@ -8,10 +8,11 @@
 use core::alloc::{GlobalAlloc, Layout};
 use core::mem::transmute;
 use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use arrayvec::ArrayVec;
 use lazy_static::lazy_static;
-use log::{error, warn};
+use log::warn;
 use spin::Mutex;
 use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, LARGE_PAGE_SIZE};
 
@@ -33,6 +34,40 @@ macro_rules! round_up {
 /// Start of large-page allocation (end of Zone allocator supported sizes)
 const LPRANGE_START: usize = ZoneAllocator::MAX_ALLOC_SIZE + 1;
 
+/// How many small deallocations a `SafeZoneAllocator` does before it tries
+/// to give empty slab pages back to the kernel.
+const RECLAIM_INTERVAL: usize = 4096;
+
+/// How many unmapped address ranges a `Pager` remembers for reuse.
+const MAX_HOLES: usize = 64;
+
+/// Bytes currently mapped by the pagers.
+static MAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Bytes the pagers have given back to the kernel so far.
+static UNMAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Bytes currently mapped through `rumpuser_anonmmap`.
+static ANON_MAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Memory statistics of the user-space allocator.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct MemStats {
+    /// Bytes currently mapped (allocator pages and anonymous mappings).
+    pub mapped_bytes: usize,
+    /// Bytes given back to the kernel so far.
+    pub unmapped_bytes: usize,
+    /// Bytes currently mapped through `rumpuser_anonmmap`.
+    pub anon_mapped_bytes: usize,
+}
+
+/// Returns the memory statistics of the user-space allocator.
+pub fn stats() -> MemStats {
+    MemStats {
+        mapped_bytes: MAPPED_BYTES.load(Ordering::Relaxed),
+        unmapped_bytes: UNMAPPED_BYTES.load(Ordering::Relaxed),
+        anon_mapped_bytes: ANON_MAPPED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
 #[cfg(target_os = "nrk")]
 #[global_allocator]
 static PER_CORE_MEM_PROVIDER: crate::mem::PerCoreAllocator = crate::mem::PerCoreAllocator::new();
@@ -45,11 +80,16 @@ static PER_CORE_MEM_PROVIDER: crate::mem::PerCoreAllocator = crate::mem::PerCore
 pub struct Pager {
     sbrk: u64,
     limit: u64,
+    /// Unmapped address ranges below `sbrk` (base, size) that can be mapped
+    /// again.
+    holes: ArrayVec<(u64, u64), MAX_HOLES>,
 }
 
 impl Pager {
     const BASE_PAGE_SIZE: usize = BASE_PAGE_SIZE;
     const LARGE_PAGE_SIZE: usize = LARGE_PAGE_SIZE;
+    /// Largest region we ask the kernel to map in one system call.
+    const MAP_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
 
     /// Allocates a given `page_size`.
     fn alloc_page(&mut self, page_size: usize) -> Option<*mut u8> {
@@ -63,25 +103,128 @@ impl Pager {
         Some(vaddr.as_mut_ptr())
     }
 
-    /// Allocates a given `page_size`.
+    /// Deallocates a given `page_size`.
     fn dealloc_page(&mut self, ptr: *mut u8, page_size: usize) {
-        warn!("NYI dealloc page {:p} {:#x}", ptr, page_size);
+        if let Err(e) = self.release(ptr as u64, page_size as u64) {
+            warn!("Can't unmap page {:p} {:#x}: {:?}", ptr, page_size, e);
+        }
     }
 
     pub(crate) fn allocate(&mut self, layout: Layout) -> Result<(VAddr, PAddr), SystemCallError> {
         let size = round_up!(layout.size(), 4096) as u64;
-        self.sbrk = round_up!(self.sbrk as usize, core::cmp::max(layout.align(), 4096)) as u64;
+        let align = core::cmp::max(layout.align(), 4096) as u64;
+
+        let base = self.reserve(size, align)?;
+        match Pager::map(base, size) {
+            Ok(r) => {
+                MAPPED_BYTES.fetch_add(size as usize, Ordering::Relaxed);
+                Ok(r)
+            }
+            Err((e, mapped)) => {
+                // The range can only be handed out again once nothing is
+                // mapped in it anymore
+                let unmapped = mapped == 0
+                    || unsafe { crate::syscalls::VSpace::unmap(base, mapped) }
+                        .map_err(|ue| warn!("Can't unmap {:#x} {:#x}: {:?}", base, mapped, ue))
+                        .is_ok();
+                if unmapped {
+                    self.unreserve(base, size);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Picks a free, `align`ed address range of `size` bytes.
+    ///
+    /// Reuses a range we unmapped before if one fits, otherwise the range is
+    /// taken from `sbrk`. The range is always contiguous.
+    fn reserve(&mut self, size: u64, align: u64) -> Result<u64, SystemCallError> {
+        let fits = |&(base, hole_size): &(u64, u64)| {
+            let aligned = round_up!(base, align);
+            aligned + size <= base + hole_size
+        };
+        if let Some(idx) = self.holes.iter().position(fits) {
+            let (base, hole_size) = self.holes.swap_remove(idx);
+            let aligned = round_up!(base, align);
+            // Whatever is left in front of and after the range stays a hole
+            if aligned > base {
+                self.unreserve(base, aligned - base);
+            }
+            if aligned + size < base + hole_size {
+                self.unreserve(aligned + size, base + hole_size - aligned - size);
+            }
+            return Ok(aligned);
+        }
 
+        let base = round_up!(self.sbrk, align);
         // Return out-of-memory error if the vaddr goes beyond the permissible limit.
-        if self.sbrk >= self.limit {
+        if base + size > self.limit {
             return Err(SystemCallError::OutOfMemory);
         }
+        let sbrk = self.sbrk;
+        self.sbrk = base + size;
+        if base > sbrk {
+            // Keep the alignment padding around for smaller allocations
+            self.unreserve(sbrk, base - sbrk);
+        }
+        Ok(base)
+    }
+
+    /// Marks `[base, base+size)` as free again, merges it with adjacent holes
+    /// (and `sbrk`).
+    fn unreserve(&mut self, base: u64, size: u64) {
+        let mut base = base;
+        let mut size = size;
+        while let Some(idx) = self
+            .holes
+            .iter()
+            .position(|&(hbase, hsize)| hbase + hsize == base || base + size == hbase)
+        {
+            let (hbase, hsize) = self.holes.swap_remove(idx);
+            base = core::cmp::min(base, hbase);
+            size += hsize;
+        }
+
+        if base + size == self.sbrk {
+            self.sbrk = base;
+        } else if self.holes.try_push((base, size)).is_err() {
+            // The memory is still returned, we just lose the address range.
+            warn!("Pager has too many holes, losing {:#x} {:#x}", base, size);
+        }
+    }
+
+    /// Backs `[base, base+size)` with memory, returns the address of the
+    /// first mapped frame.
+    ///
+    /// On errors, this also returns how many bytes (from `base`) are mapped
+    /// already.
+    fn map(base: u64, size: u64) -> Result<(VAddr, PAddr), (SystemCallError, u64)> {
+        let mut first = None;
+        for offset in (0..size).step_by(Pager::MAP_CHUNK_SIZE as usize) {
+            let chunk = core::cmp::min(Pager::MAP_CHUNK_SIZE, size - offset);
+            let r = unsafe { crate::syscalls::VSpace::map(base + offset, chunk) }
+                .map_err(|e| (e, offset))?;
+            first.get_or_insert(r);
+        }
+        first.ok_or((SystemCallError::BadAddress, 0))
+    }
 
-        unsafe {
-            let r = crate::syscalls::VSpace::map(self.sbrk, size)?;
-            self.sbrk += size;
-            Ok(r)
+    /// Unmaps `[base, base+size)` (which was allocated from this pager) and
+    /// gives the memory back to the kernel.
+    ///
+    /// The address range can be handed out again by `allocate`.
+    pub(crate) fn release(&mut self, base: u64, size: u64) -> Result<(), SystemCallError> {
+        let size = round_up!(size as usize, 4096) as u64;
+        let start = self.limit - HEAP_PER_CORE_REGION as u64;
+        if base < start || base % 4096 != 0 || base + size > self.sbrk {
+            return Err(SystemCallError::BadAddress);
         }
+        unsafe { crate::syscalls::VSpace::unmap(base, size)? };
+        MAPPED_BYTES.fetch_sub(size as usize, Ordering::Relaxed);
+        UNMAPPED_BYTES.fetch_add(size as usize, Ordering::Relaxed);
+        self.unreserve(base, size);
+        Ok(())
     }
 
     /// Allocates a new ObjectPage from the System.
@@ -91,9 +234,8 @@ impl Pager {
     }
 
     /// Release a ObjectPage back to the System.
-    #[allow(unused)]
     fn release_page(&mut self, p: &'static mut ObjectPage<'static>) {
-        warn!("lost ObjectPage");
+        self.dealloc_page(p as *mut _ as *mut u8, Pager::BASE_PAGE_SIZE);
     }
 
     /// Allocates a new LargeObjectPage from the system.
@@ -105,10 +247,47 @@ impl Pager {
     /// Release a LargeObjectPage back to the System.
     #[allow(unused)]
     fn release_large_page(&mut self, p: &'static mut LargeObjectPage<'static>) {
-        warn!("lost LargeObjectPage");
+        self.dealloc_page(p as *mut _ as *mut u8, Pager::LARGE_PAGE_SIZE);
     }
 }
 
+/// Maps anonymous memory for `layout` from the pager of the current core.
+pub(crate) fn anon_map(layout: Layout) -> Result<*mut u8, SystemCallError> {
+    // The kernel backs big regions with large pages, those need an aligned base
+    let layout = if layout.size() >= Pager::LARGE_PAGE_SIZE {
+        layout
+            .align_to(Pager::LARGE_PAGE_SIZE)
+            .map_err(|_e| SystemCallError::BadAddress)?
+    } else {
+        layout
+    };
+    let (vaddr, _paddr) = PAGER[Environment::core_id()].lock().allocate(layout)?;
+    ANON_MAPPED_BYTES.fetch_add(round_up!(layout.size(), 4096), Ordering::Relaxed);
+    Ok(vaddr.as_mut_ptr())
+}
+
+/// Unmaps anonymous memory that was mapped with `anon_map`.
+pub(crate) fn anon_unmap(ptr: *mut u8, len: usize) -> Result<(), SystemCallError> {
+    pager_for(ptr as u64)?
+        .lock()
+        .release(ptr as u64, len as u64)?;
+    ANON_MAPPED_BYTES.fetch_sub(round_up!(len, 4096), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the pager that handed out the address `addr`.
+///
+/// Memory can be freed on a different core than it was allocated on, but
+/// has to go back to the pager whose heap region it is from.
+fn pager_for(addr: u64) -> Result<&'static Mutex<Pager>, SystemCallError> {
+    let core_id = (addr as usize)
+        .checked_sub(HEAP_START)
+        .map(|offset| offset / HEAP_PER_CORE_REGION)
+        .filter(|core_id| *core_id < MAX_CORES)
+        .ok_or(SystemCallError::BadAddress)?;
+    Ok(&PAGER[core_id])
+}
+
 lazy_static! {
     /// A pager for GlobalAlloc.
     pub static ref PAGER: ArrayVec::<CachePadded<Mutex<Pager>>, MAX_CORES> = {
@@ -116,7 +295,11 @@ lazy_static! {
         for i in 0..MAX_CORES {
             let sbrk = (HEAP_START + (i * HEAP_PER_CORE_REGION)) as u64;
             let limit = (HEAP_START + ((i + 1) * HEAP_PER_CORE_REGION)) as u64;
-            pagers.push(CachePadded::new(Mutex::new(Pager { sbrk, limit })));
+            pagers.push(CachePadded::new(Mutex::new(Pager {
+                sbrk,
+                limit,
+                holes: ArrayVec::new(),
+            })));
         }
         pagers
     };
@@ -127,11 +310,30 @@ lazy_static! {
 /// Note: This is not very scalable since we use a single big lock
 /// around the allocator. There are better ways make the ZoneAllocator
 /// thread-safe directly, but they are not implemented yet.
-pub struct SafeZoneAllocator(CachePadded<Mutex<ZoneAllocator<'static>>>);
+pub struct SafeZoneAllocator(
+    CachePadded<Mutex<ZoneAllocator<'static>>>,
+    /// Small deallocations since the last reclamation.
+    AtomicUsize,
+);
 
 impl SafeZoneAllocator {
     pub const fn new() -> SafeZoneAllocator {
-        SafeZoneAllocator(CachePadded::new(Mutex::new(ZoneAllocator::new())))
+        SafeZoneAllocator(
+            CachePadded::new(Mutex::new(ZoneAllocator::new())),
+            AtomicUsize::new(0),
+        )
+    }
+
+    /// Gives empty slab pages of the allocator back to the kernel.
+    pub fn reclaim(&self) {
+        self.0
+            .lock()
+            .try_reclaim_base_pages(usize::MAX, |page: *mut ObjectPage| unsafe {
+                pager_for(page as u64)
+                    .expect("Slab page not from a pager?")
+                    .lock()
+                    .release_page(&mut *page)
+            });
     }
 }
 
@@ -217,20 +419,20 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
                 try_alloc_largepage().expect("Can't allocate page?") as *mut _ as *mut u8
             }
             big_size => {
-                // int a = (59 + (4 - 1)) / 4;
-                let required_pages =
-                    (big_size + Pager::LARGE_PAGE_SIZE - 1) / Pager::LARGE_PAGE_SIZE;
-                let mut first: *mut u8 = ptr::null_mut();
-                for _page_idx in 0..required_pages {
-                    let ptr = try_alloc_largepage()
-                        .expect("Can't allocate page for big allocation?")
-                        as *mut _ as *mut u8;
-                    if first.is_null() {
-                        first = ptr;
+                // Big allocations need one contiguous region, so they're
+                // served by a single `Pager::allocate`
+                let layout = Layout::from_size_align_unchecked(
+                    round_up!(big_size, Pager::LARGE_PAGE_SIZE),
+                    Pager::LARGE_PAGE_SIZE,
+                );
+                let mut core_id = Environment::core_id();
+                for _i in 0..MAX_CORES {
+                    if let Ok((vaddr, _paddr)) = PAGER[core_id].lock().allocate(layout) {
+                        return vaddr.as_mut_ptr();
                     }
+                    core_id = (core_id + 1) % MAX_CORES;
                 }
-
-                first
+                ptr::null_mut()
             }
         }
     }
@@ -267,13 +469,24 @@ unsafe impl GlobalAlloc for SafeZoneAllocator {
                     // Nothing to do (don't dealloc null pointers).
                 }
 
-                // An proper reclamation strategy could be implemented here
-                // to release empty pages back from the ZoneAllocator to the PAGER
+                // Periodically release empty pages back from the ZoneAllocator
+                // to the PAGER (not on every free to avoid map/unmap churn)
+                if self.1.fetch_add(1, Ordering::Relaxed) + 1 >= RECLAIM_INTERVAL {
+                    self.1.store(0, Ordering::Relaxed);
+                    self.reclaim();
+                }
             }
-            LPRANGE_START..=Pager::LARGE_PAGE_SIZE => PAGER[Environment::core_id()]
+            LPRANGE_START..=Pager::LARGE_PAGE_SIZE => pager_for(ptr as u64)
+                .expect("Large page not from a pager?")
                 .lock()
                 .dealloc_page(ptr, Pager::LARGE_PAGE_SIZE),
-            _ => error!("TODO: Currently can't dealloc of {:?}.", layout),
+            big_size => {
+                let size = round_up!(big_size, Pager::LARGE_PAGE_SIZE) as u64;
+                let pager = pager_for(ptr as u64).expect("Big allocation not from a pager?");
+                if let Err(e) = pager.lock().release(ptr as u64, size) {
+                    warn!("Can't unmap {:p} {:#x}: {:?}", ptr, size, e);
+                }
+            }
         }
     }
 }
@@ -284,9 +497,7 @@ lazy_static! {
     pub static ref PER_CORE_MEM_ALLOCATOR: [SafeZoneAllocator; MAX_CORES] = {
         let mut allocators = ArrayVec::<SafeZoneAllocator, MAX_CORES>::new();
         for _i in 0..MAX_CORES {
-            allocators.push(SafeZoneAllocator(CachePadded::new(Mutex::new(
-                ZoneAllocator::new(),
-            ))));
+            allocators.push(SafeZoneAllocator::new());
         }
         match allocators.into_inner() {
             Ok(allocators) => allocators,
@@ -318,3 +529,76 @@ unsafe impl GlobalAlloc for PerCoreAllocator {
         sza.dealloc(ptr, layout)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pager() -> Pager {
+        Pager {
+            sbrk: HEAP_START as u64,
+            limit: (HEAP_START + HEAP_PER_CORE_REGION) as u64,
+            holes: ArrayVec::new(),
+        }
+    }
+
+    #[test]
+    fn reserve_is_contiguous_and_aligned() {
+        let mut p = pager();
+        let start = p.sbrk;
+        assert_eq!(p.reserve(0x1000, 0x1000), Ok(start));
+        let big = p.reserve(3 * 0x20_0000, 0x20_0000).unwrap();
+        assert_eq!(big % 0x20_0000, 0);
+        assert_eq!(p.sbrk, big + 3 * 0x20_0000);
+        // The alignment padding can be used for small allocations
+        assert_eq!(p.reserve(0x1000, 0x1000), Ok(start + 0x1000));
+        assert_eq!(
+            p.reserve(HEAP_PER_CORE_REGION as u64, 0x1000),
+            Err(SystemCallError::OutOfMemory)
+        );
+    }
+
+    #[test]
+    fn hole_reuse() {
+        let mut p = pager();
+        let a = p.reserve(0x4000, 0x1000).unwrap();
+        let b = p.reserve(0x1000, 0x1000).unwrap();
+        p.unreserve(a, 0x4000);
+        assert_eq!(p.holes.as_slice(), &[(a, 0x4000)]);
+
+        // Fits into the hole, what's left stays a hole
+        assert_eq!(p.reserve(0x1000, 0x1000), Ok(a));
+        assert_eq!(p.holes.as_slice(), &[(a + 0x1000, 0x3000)]);
+        // Doesn't fit, comes from sbrk
+        assert_eq!(p.reserve(0x8000, 0x1000), Ok(b + 0x1000));
+        // Aligned inside the hole
+        assert_eq!(p.reserve(0x2000, 0x2000), Ok(a + 0x2000));
+        assert_eq!(p.holes.as_slice(), &[(a + 0x1000, 0x1000)]);
+    }
+
+    #[test]
+    fn hole_merging() {
+        let mut p = pager();
+        let start = p.sbrk;
+        let a = p.reserve(0x1000, 0x1000).unwrap();
+        let b = p.reserve(0x1000, 0x1000).unwrap();
+        let c = p.reserve(0x1000, 0x1000).unwrap();
+        let _d = p.reserve(0x1000, 0x1000).unwrap();
+
+        p.unreserve(a, 0x1000);
+        p.unreserve(c, 0x1000);
+        assert_eq!(p.holes.len(), 2);
+        // Merges with the hole in front and the one after it
+        p.unreserve(b, 0x1000);
+        assert_eq!(p.holes.as_slice(), &[(a, 0x3000)]);
+        assert_eq!(p.reserve(0x3000, 0x1000), Ok(a));
+        assert!(p.holes.is_empty());
+
+        // Freeing the top of the heap lowers `sbrk` (and takes the holes
+        // below it with it)
+        p.unreserve(a, 0x3000);
+        p.unreserve(start + 0x3000, 0x1000);
+        assert!(p.holes.is_empty());
+        assert_eq!(p.sbrk, start);
+    }
+}
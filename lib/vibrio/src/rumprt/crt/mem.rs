@@ -112,36 +112,15 @@ mod nrk {
 
         let mut pager = crate::mem::PAGER[Environment::core_id()].lock();
 
-        let len = len as usize;
-        let mut remaining = len;
-        let mut ptr = None;
-
-        const CHUNK_SIZE: usize = 64 * 1024 * 1024;
-        for current_offset in (0..len).step_by(CHUNK_SIZE) {
-            let size_to_map = core::cmp::min(CHUNK_SIZE, remaining);
-
-            let layout = core::alloc::Layout::from_size_align_unchecked(
-                core::cmp::min(CHUNK_SIZE, remaining),
-                0x200000,
-            );
-
-            let r = pager.allocate(layout);
-            match r {
-                Ok((va, pa)) => {
-                    remaining -= size_to_map;
-                    if ptr.is_none() {
-                        ptr = Some(va.as_usize() as *mut c_void);
-                    }
-                }
-                Err(e) => {
-                    panic!("{:?}", e);
-                    return MAP_FAILED as *mut c_void;
-                }
+        // The pager maps big regions in chunks, the region stays contiguous
+        let layout = core::alloc::Layout::from_size_align_unchecked(len as usize, 0x200000);
+        match pager.allocate(layout) {
+            Ok((va, _pa)) => va.as_usize() as *mut c_void,
+            Err(e) => {
+                panic!("{:?}", e);
+                return MAP_FAILED as *mut c_void;
             }
         }
-
-        assert!(remaining == 0);
-        ptr.unwrap()
     }
 }
 
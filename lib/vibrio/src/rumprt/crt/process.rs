@@ -46,7 +46,30 @@ pub unsafe extern "C" fn __getrusage50() {
 
 /// The kill function sends the signal given by sig to pid,
 /// a process or a group of processes.
+///
+/// Our own pid (or 0 for our process group) sends the signal to ourselves,
+/// any other positive pid is interpreted as the pid of a NRK process.
 #[no_mangle]
-pub unsafe extern "C" fn kill(pid: pid_t, signal: c_int) {
-    unreachable!("kill pid: {} -> sig:{}", pid, signal);
+pub unsafe extern "C" fn kill(pid: pid_t, signal: c_int) -> c_int {
+    extern "C" {
+        fn getpid() -> pid_t;
+    }
+    trace!("kill pid: {} -> sig: {}", pid, signal);
+
+    let target = if pid == 0 || pid == getpid() {
+        kpi::process::CURRENT_PROCESS
+    } else if pid > 0 {
+        pid as u64
+    } else {
+        crate::rumprt::errno::rumpuser_seterrno(crate::rumprt::errno::ESRCH);
+        return -1;
+    };
+
+    match super::signals::send_signal(target, signal) {
+        0 => 0,
+        error => {
+            crate::rumprt::errno::rumpuser_seterrno(error);
+            -1
+        }
+    }
 }
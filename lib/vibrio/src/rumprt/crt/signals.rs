@@ -3,8 +3,17 @@
 
 //! POSIX signals implementation
 //!
-//! Just very basic stubs for now.
+//! Signals are implemented with events of the kernel: installing a handler
+//! subscribes the process to the event with the signal number, the handler
+//! then runs as part of the upcall that delivers the event (i.e., on
+//! whatever thread happened to run on the core). Masks are not supported.
 
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kpi::process::MAX_EVENT;
+
+use crate::rumprt::errno::{self, rumpuser_seterrno};
 use crate::rumprt::{c_int, c_void};
 
 /// C wrapper for `sigset_t` type.
@@ -36,23 +45,120 @@ pub struct SigAction {
     pub flags: c_int,
 }
 
+const SIG_DFL: u64 = 0;
 const SIG_IGN: u64 = 1;
 
-unsafe fn sigaction(_sig: c_int, _act: *const SigAction, oact: *mut SigAction) -> c_int {
+/// `sa_flags` bit for handlers that take three arguments.
+const SA_SIGINFO: c_int = 0x0040;
+
+const SIGKILL: c_int = 9;
+const SIGSTOP: c_int = 17;
+
+/// Installed handlers (`SIG_DFL`, `SIG_IGN` or a function), indexed by signal.
+static HANDLERS: [AtomicU64; MAX_EVENT as usize + 1] = {
+    const DEFAULT: AtomicU64 = AtomicU64::new(SIG_DFL);
+    [DEFAULT; MAX_EVENT as usize + 1]
+};
+
+/// Signals with a `SA_SIGINFO` handler (bit `n` is set for signal `n`).
+static SIGINFO_HANDLERS: AtomicU64 = AtomicU64::new(0);
+
+/// Event handler that runs the signal handler of `event`.
+fn deliver(event: u64) {
+    let handler = HANDLERS[event as usize].load(Ordering::Acquire);
+    let sig = event as c_int;
+    log::debug!("deliver signal {} to {:#x}", sig, handler);
+
+    // Safety: `sigaction` only stores function pointers besides SIG_DFL and
+    // SIG_IGN, their type depends on SA_SIGINFO
+    unsafe {
+        match handler {
+            SIG_DFL | SIG_IGN => {}
+            _ if SIGINFO_HANDLERS.load(Ordering::Acquire) & (1 << event) != 0 => {
+                let f: unsafe extern "C" fn(c_int, *mut c_void, *mut c_void) =
+                    core::mem::transmute(handler);
+                f(sig, ptr::null_mut(), ptr::null_mut())
+            }
+            _ => {
+                let f: unsafe extern "C" fn(c_int) = core::mem::transmute(handler);
+                f(sig)
+            }
+        }
+    }
+}
+
+unsafe fn sigaction(sig: c_int, act: *const SigAction, oact: *mut SigAction) -> c_int {
+    if sig <= 0 || sig as u64 > MAX_EVENT {
+        rumpuser_seterrno(errno::EINVAL);
+        return -1;
+    }
+    let bit = 1u64 << sig;
+
     if !oact.is_null() {
+        let has_siginfo = SIGINFO_HANDLERS.load(Ordering::Relaxed) & bit != 0;
         let sa: SigAction = SigAction {
             u: SigActionHandler {
-                _bindgen_union_align: SIG_IGN,
+                _bindgen_union_align: HANDLERS[sig as usize].load(Ordering::Relaxed),
             },
             mask: Default::default(),
-            flags: 0,
+            flags: if has_siginfo { SA_SIGINFO } else { 0 },
         };
         *oact = sa;
     }
 
+    if !act.is_null() {
+        if sig == SIGKILL || sig == SIGSTOP {
+            rumpuser_seterrno(errno::EINVAL);
+            return -1;
+        }
+
+        let handler = (*act).u._bindgen_union_align;
+        if (*act).flags & SA_SIGINFO != 0 {
+            SIGINFO_HANDLERS.fetch_or(bit, Ordering::Release);
+        } else {
+            SIGINFO_HANDLERS.fetch_and(!bit, Ordering::Release);
+        }
+        HANDLERS[sig as usize].store(handler, Ordering::Release);
+
+        // Ignored signals need a (no-op) handler too, otherwise the kernel
+        // applies the default action
+        let event_handler = if handler == SIG_DFL {
+            None
+        } else {
+            Some(deliver as fn(u64))
+        };
+        if let Err(e) = crate::upcalls::set_event_handler(sig as u64, event_handler) {
+            log::error!("Can't subscribe to signal {}: {:?}", sig, e);
+            rumpuser_seterrno(errno::EINVAL);
+            return -1;
+        }
+    }
+
     0
 }
 
+/// Sends `sig` to the NRK process `pid` (or the current process for
+/// `kpi::process::CURRENT_PROCESS`).
+///
+/// Returns 0 or an errno value.
+pub(crate) fn send_signal(pid: u64, sig: c_int) -> c_int {
+    if sig < 0 || sig as u64 > MAX_EVENT {
+        return errno::EINVAL;
+    }
+    if sig == 0 {
+        // Only checks if the process exists, we can't do that
+        return 0;
+    }
+
+    match crate::syscalls::Process::send_event(pid, sig as u64) {
+        Ok(()) => 0,
+        Err(e) => {
+            log::error!("Can't send signal {} to {}: {:?}", sig, pid, e);
+            errno::ESRCH
+        }
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn _sys___sigprocmask14(
     _how: c_int,
@@ -4,9 +4,12 @@
 use super::{c_int, c_uint, c_ulong, c_void};
 
 use core::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{fmt, ptr};
 
 use hashbrown::HashMap;
+use kpi::io::PciAddress;
+use kpi::SystemCallError;
 use lineup::tls2::Environment;
 use log::{error, info, trace, warn};
 use spin::Mutex;
@@ -81,10 +84,15 @@ pub unsafe extern "C" fn rumpcomp_pci_confwrite(
     0
 }
 
+/// PCI capability IDs.
+const CAP_ID_MSI: c_uint = 0x05;
+const CAP_ID_MSIX: c_uint = 0x11;
+
 #[derive(Debug, Copy, Clone)]
 struct RumpIRQ {
     tuple: (c_uint, c_uint, c_uint),
-    vector: c_int,
+    vector: u64,
+    core: usize,
     cookie: c_uint,
     handler: Option<unsafe extern "C" fn(arg: *mut c_void) -> c_int>,
     arg: *mut c_void,
@@ -93,12 +101,97 @@ struct RumpIRQ {
 static mut IRQS: [RumpIRQ; 32] = [RumpIRQ {
     tuple: (0, 0, 0),
     vector: 0,
+    core: 0,
     cookie: 0,
     handler: None,
     arg: ptr::null_mut(),
 }; 32];
 
+/// How many entries of `IRQS` are in use.
+static IRQ_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes mapping interrupts (so we don't hand out more than `IRQS` has).
+static IRQ_MAP_LOCK: Mutex<()> = Mutex::new(());
+
+/// Does the device have capability `id` (i.e., support MSI or MSI-X)?
+unsafe fn has_capability(bus: c_uint, dev: c_uint, fun: c_uint, id: c_uint) -> bool {
+    let mut value: c_uint = 0;
+    rumpcomp_pci_confread(bus, dev, fun, 0x04, &mut value);
+    if value & (1 << 20) == 0 {
+        // No capability list
+        return false;
+    }
+
+    rumpcomp_pci_confread(bus, dev, fun, 0x34, &mut value);
+    let mut offset = value & 0xfc;
+    for _ in 0..48 {
+        if offset == 0 {
+            break;
+        }
+        rumpcomp_pci_confread(bus, dev, fun, offset as c_int, &mut value);
+        if value & 0xff == id {
+            return true;
+        }
+        offset = (value >> 8) & 0xfc;
+    }
+
+    false
+}
+
+/// Allocates a vector on `core` and routes interrupt `entry` of `device` to
+/// it.
+fn bind_msi(device: PciAddress, entry: usize, core: usize) -> Result<u64, SystemCallError> {
+    let vector = crate::syscalls::Irq::allocate_vector(core)?;
+    // The kernel writes to the configuration space as well
+    let _l = CONFSPACE_LOCK.lock();
+    if let Err(e) = crate::syscalls::Irq::bind_msi(device, entry, core, vector) {
+        if let Err(e) = crate::syscalls::Irq::free_vector(core, vector) {
+            warn!("Can't free vector {} on core {}: {:?}", vector, core, e);
+        }
+        return Err(e);
+    }
+    Ok(vector)
+}
+
+/// Remembers the interrupt (identified by `cookie`) and starts the thread
+/// that runs its handler on `core`.
+///
+/// The caller holds `IRQ_MAP_LOCK` and made sure there is room in `IRQS`.
+unsafe fn register_irq(tuple: (c_uint, c_uint, c_uint), core: usize, vector: u64, cookie: c_uint) {
+    let slot = IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
+    IRQS[slot] = RumpIRQ {
+        tuple,
+        vector,
+        core,
+        cookie,
+        handler: None,
+        arg: ptr::null_mut(),
+    };
+
+    let cur_thread = lineup::tls2::Environment::thread();
+    let irq_tid = cur_thread
+        .spawn_irq_thread(Some(irq_handler), slot as *mut u8, core, vector)
+        .expect("Can't create IRQ thread?");
+    // Run the handler ahead of regular threads once the IRQ fires
+    cur_thread.set_class(
+        irq_tid,
+        lineup::threads::SchedClass::Deadline(core::time::Duration::from_micros(100)),
+    );
+}
+
+/// Do we have room for another interrupt in `IRQS`?
+fn irq_slot_available() -> bool {
+    if IRQ_COUNT.load(Ordering::Relaxed) >= unsafe { IRQS.len() } {
+        error!("Can't map more than {} IRQs", unsafe { IRQS.len() });
+        false
+    } else {
+        true
+    }
+}
+
 //int rumpcomp_pci_irq_map(unsigned bus, unsigned device, unsigned fun, int intrline, unsigned cookie)
+/// Maps the legacy (INTx) interrupt of a device, these always arrive on
+/// core 0.
 #[no_mangle]
 pub unsafe extern "C" fn rumpcomp_pci_irq_map(
     bus: c_uint,
@@ -115,28 +208,118 @@ pub unsafe extern "C" fn rumpcomp_pci_irq_map(
         vector,
         cookie
     );
-    IRQS[0].tuple = (bus, dev, fun);
-    IRQS[0].vector = vector;
-    IRQS[0].cookie = cookie;
-
-    let cur_thread = lineup::tls2::Environment::thread();
-
-    cur_thread
-        .spawn_irq_thread(
-            Some(irq_handler),
-            core::ptr::null_mut(),
-            0,
-            vector as u64 + 31,
-        )
-        .expect("Can't create IRQ thread?");
+    let _l = IRQ_MAP_LOCK.lock();
+    if !irq_slot_available() {
+        return -1;
+    }
 
     crate::syscalls::Irq::irqalloc(vector as u64, 0).ok();
+    register_irq((bus, dev, fun), 0, vector as u64 + 31, cookie);
 
     0
 }
 
+/// Routes MSI-X table entry `entry` of a device (or its MSI interrupt with
+/// `msix == false`) to one of our cores.
+///
+/// Cores are requested in order at startup, so we run on 0..CORES_ONLINE and
+/// spread the interrupts (e.g., of the queues of a NIC) over them.
+unsafe fn msi_map(
+    bus: c_uint,
+    dev: c_uint,
+    fun: c_uint,
+    entry: c_uint,
+    msix: bool,
+    cookie: c_uint,
+) -> c_int {
+    let device = PciAddress {
+        bus: bus as u8,
+        dev: dev as u8,
+        fun: fun as u8,
+    };
+    // The kernel uses MSI-X if the device supports it, so a device with both
+    // can't use plain MSI
+    let supported = match msix {
+        true => has_capability(bus, dev, fun, CAP_ID_MSIX),
+        false => {
+            has_capability(bus, dev, fun, CAP_ID_MSI) && !has_capability(bus, dev, fun, CAP_ID_MSIX)
+        }
+    };
+    let _l = IRQ_MAP_LOCK.lock();
+    if !supported || !irq_slot_available() {
+        return -1;
+    }
+
+    let cores = core::cmp::max(crate::upcalls::CORES_ONLINE.load(Ordering::Relaxed), 1);
+    let core = IRQ_COUNT.load(Ordering::Relaxed) % cores;
+    match bind_msi(device, entry as usize, core) {
+        Ok(irq_vector) => {
+            info!(
+                "{:?} MSI{} {} on core {} vector {}",
+                device,
+                if msix { "-X" } else { "" },
+                entry,
+                core,
+                irq_vector
+            );
+            register_irq((bus, dev, fun), core, irq_vector, cookie);
+            0
+        }
+        Err(e) => {
+            warn!("Can't use MSI for {:?}: {:?}", device, e);
+            -1
+        }
+    }
+}
+
+//int rumpcomp_pci_msix_map(unsigned bus, unsigned device, unsigned fun, unsigned entry, unsigned cookie)
+/// Maps MSI-X table entry `entry` of a device, called by drivers that
+/// allocate MSI-X interrupts (and know the device layout changes once MSI-X
+/// is enabled). Returns -1 if the driver should use INTx instead.
+#[no_mangle]
+pub unsafe extern "C" fn rumpcomp_pci_msix_map(
+    bus: c_uint,
+    dev: c_uint,
+    fun: c_uint,
+    entry: c_uint,
+    cookie: c_uint,
+) -> c_int {
+    trace!(
+        "rumpcomp_pci_msix_map for ({:#x} {:#x} {:#x}) entry={} {:#x}",
+        bus,
+        dev,
+        fun,
+        entry,
+        cookie
+    );
+    msi_map(bus, dev, fun, entry, true, cookie)
+}
+
+//int rumpcomp_pci_msi_map(unsigned bus, unsigned device, unsigned fun, unsigned cookie)
+/// Maps the MSI interrupt of a device, called by drivers that allocate MSI
+/// interrupts. Returns -1 if the driver should use INTx instead.
+#[no_mangle]
+pub unsafe extern "C" fn rumpcomp_pci_msi_map(
+    bus: c_uint,
+    dev: c_uint,
+    fun: c_uint,
+    cookie: c_uint,
+) -> c_int {
+    trace!(
+        "rumpcomp_pci_msi_map for ({:#x} {:#x} {:#x}) {:#x}",
+        bus,
+        dev,
+        fun,
+        cookie
+    );
+    msi_map(bus, dev, fun, 0, false, cookie)
+}
+
 #[allow(unused)]
-pub unsafe extern "C" fn irq_handler(_arg1: *mut u8) -> *mut u8 {
+pub unsafe extern "C" fn irq_handler(arg1: *mut u8) -> *mut u8 {
+    // The argument is the slot in `IRQS`
+    let slot = arg1 as usize;
+
     let s = lineup::tls2::Environment::scheduler();
     let upcalls = s.rump_upcalls.load(core::sync::atomic::Ordering::Relaxed)
         as *const super::RumpHyperUpcalls;
@@ -153,7 +336,8 @@ pub unsafe extern "C" fn irq_handler(_arg1: *mut u8) -> *mut u8 {
     loop {
         let start = rawtime::Instant::now();
         super::rumpkern_sched(&nlock, None);
-        let r = (IRQS[0].handler.unwrap())(IRQS[0].arg as *mut u64);
+        let irq = IRQS[slot];
+        let r = (irq.handler.unwrap())(irq.arg);
         //assert_eq!(r, 1, "IRQ handler should return 1 (I don't actually know)?");
         super::rumpkern_unsched(&mut nlock, None);
 
@@ -169,11 +353,22 @@ pub unsafe extern "C" fn rumpcomp_pci_irq_establish(
     arg: *mut c_void,
 ) -> *mut c_void {
     trace!("rumpcomp_pci_irq_establish {:#x} {:p}", cookie, arg);
-    IRQS[0].handler = handler;
-    IRQS[0].arg = arg;
-    warn!("register for IRQ {}", IRQS[0].vector as usize + 31);
+    let mapped = core::cmp::min(IRQ_COUNT.load(Ordering::Relaxed), IRQS.len());
+    let irq = match IRQS[..mapped].iter_mut().find(|irq| irq.cookie == cookie) {
+        Some(irq) => irq,
+        None => {
+            error!(
+                "rumpcomp_pci_irq_establish: cookie {:#x} wasn't mapped",
+                cookie
+            );
+            return ptr::null_mut();
+        }
+    };
+    irq.handler = handler;
+    irq.arg = arg;
+    info!("register for IRQ {} on core {}", irq.vector, irq.core);
 
-    &mut IRQS[0] as *mut _ as *mut c_void
+    irq as *mut _ as *mut c_void
 }
 
 #[no_mangle]
@@ -1,7 +1,10 @@
 // Copyright © 2021 VMware, Inc. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
 use core::convert::TryInto;
+use core::sync::atomic::Ordering;
 
 use super::{c_int, c_size_t, c_void, rump_biodone_fn};
 use cstr_core::CStr;
@@ -10,10 +13,112 @@ use kpi::io::*;
 use kpi::FileOperation;
 
 use bitflags::*;
+use lazy_static::lazy_static;
+use lineup::semaphore::Semaphore;
+use lineup::threads::ThreadId;
+use lineup::tls2::Environment;
 use log::*;
 
+use crate::dir::Dir;
 use crate::syscalls::Fs;
 
+const RUMPUSER_BIO_READ: c_int = 0x01;
+const RUMPUSER_BIO_WRITE: c_int = 0x02;
+const RUMPUSER_BIO_SYNC: c_int = 0x04;
+
+/// A block I/O request of the rump kernel (see `rumpuser_bio`).
+struct BioRequest {
+    fd: c_int,
+    op: c_int,
+    data: *mut c_void,
+    dlen: c_size_t,
+    off: i64,
+    biodone: rump_biodone_fn,
+    done_arg: *mut c_void,
+}
+
+unsafe impl Send for BioRequest {}
+
+impl BioRequest {
+    /// Does the I/O, returns the number of bytes transferred and the error
+    /// for `biodone`.
+    fn execute(&self) -> (c_size_t, c_int) {
+        if self.dlen == 0 {
+            return (0, 0);
+        }
+
+        // Writes hit the (in-memory) file system synchronously, so
+        // `RUMPUSER_BIO_SYNC` needs no extra work.
+        let r = if self.op & RUMPUSER_BIO_READ != 0 {
+            Fs::read_at(self.fd as u64, self.data as u64, self.dlen as u64, self.off)
+        } else if self.op & RUMPUSER_BIO_WRITE != 0 {
+            Fs::write_at(self.fd as u64, self.data as u64, self.dlen as u64, self.off)
+        } else {
+            error!("rumpuser_bio: invalid op {:#x}", self.op);
+            return (0, super::errno::EINVAL);
+        };
+
+        match r {
+            Ok(len) => (len as c_size_t, 0),
+            Err(e) => {
+                warn!(
+                    "rumpuser_bio fd={} op={:#x} failed: {:?}",
+                    self.fd, self.op, e
+                );
+                (0, super::errno::EIO)
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// Requests waiting for the bio worker.
+    static ref BIO_QUEUE: spin::Mutex<VecDeque<BioRequest>> = spin::Mutex::new(VecDeque::new());
+    /// Counts the requests in `BIO_QUEUE`, the worker waits on it.
+    static ref BIO_PENDING: Semaphore = Semaphore::new(0);
+    /// The thread that processes `BIO_QUEUE` (started on the first request).
+    static ref BIO_WORKER: spin::Mutex<Option<ThreadId>> = spin::Mutex::new(None);
+}
+
+/// Processes block I/O requests and completes them with `biodone`.
+///
+/// Waits on `BIO_PENDING` while there is nothing to do. A plain
+/// `block`/`make_runnable` pair would lose wakeups that arrive between
+/// finding the queue empty and blocking.
+unsafe extern "C" fn bio_worker(_arg: *mut u8) -> *mut u8 {
+    // We need an lwp to call into the rump kernel
+    let upcalls = super::HYPERUPCALLS.load(Ordering::Relaxed) as *const super::RumpHyperUpcalls;
+    (*upcalls).hyp_schedule.expect("rump_upcalls set")();
+    (*upcalls).hyp_lwproc_newlwp.expect("rump_upcalls set")(0);
+    (*upcalls).hyp_unschedule.expect("rump_upcalls set")();
+
+    let mut nlock: i32 = 1;
+    loop {
+        // Every `up` in `rumpuser_bio` follows a push
+        BIO_PENDING.down();
+        let request = BIO_QUEUE
+            .lock()
+            .pop_front()
+            .expect("BIO_PENDING counts the queued requests");
+
+        let (transferred, error) = request.execute();
+        trace!(
+            "rumpuser_bio done fd={} op={:#x} off={} len={} error={}",
+            request.fd,
+            request.op,
+            request.off,
+            transferred,
+            error
+        );
+
+        super::rumpkern_sched(&nlock, None);
+        if let Some(biodone) = request.biodone {
+            biodone(request.done_arg, transferred, error);
+        }
+        super::rumpkern_unsched(&mut nlock, None);
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[repr(C)]
 pub struct rumpuser_iovec {
@@ -94,18 +199,86 @@ pub unsafe extern "C" fn rumpuser_getfileinfo(
     }
 }
 
+/// int nrk_opendir(const char *name, void **dirp)
+///
+/// Opens a directory of the NRK file-system (rump has no hypercall for this),
+/// its entries are returned by `nrk_readdir`.
+#[no_mangle]
+pub unsafe extern "C" fn nrk_opendir(name: *const i8, dirp: *mut *mut c_void) -> c_int {
+    match Dir::open(name as u64) {
+        Ok(dir) => {
+            *dirp = Box::into_raw(Box::new(dir)) as *mut c_void;
+            0
+        }
+        Err(_) => super::errno::ENOENT as c_int,
+    }
+}
+
+/// int nrk_readdir(void *dir, struct nrk_dirent *entry)
+///
+/// Copies the next entry of `dir` to `entry`, returns ENOENT once all entries
+/// were read.
+#[no_mangle]
+pub unsafe extern "C" fn nrk_readdir(dir: *mut c_void, entry: *mut DirEntry) -> c_int {
+    let dir = &mut *(dir as *mut Dir);
+    match dir.read() {
+        Ok(Some(e)) => {
+            *entry = *e;
+            0
+        }
+        Ok(None) => super::errno::ENOENT as c_int,
+        Err(_) => super::errno::ENOTDIR as c_int,
+    }
+}
+
+/// int nrk_closedir(void *dir)
+#[no_mangle]
+pub unsafe extern "C" fn nrk_closedir(dir: *mut c_void) -> c_int {
+    drop(Box::from_raw(dir as *mut Dir));
+    0
+}
+
 /// void rumpuser_bio(int fd, int op, void *data, size_t dlen, int64_t off, rump_biodone_fn biodone, void *donearg)
+///
+/// The request is completed asynchronously: a worker thread does the I/O and
+/// calls `biodone(donearg, bytes transferred, error)`.
 #[no_mangle]
 pub unsafe extern "C" fn rumpuser_bio(
-    _fd: c_int,
-    _op: c_int,
-    _data: *const super::c_void,
-    _dlen: c_size_t,
-    _off: i64,
-    _biodone: rump_biodone_fn,
-    _done_arg: *const c_void,
+    fd: c_int,
+    op: c_int,
+    data: *const super::c_void,
+    dlen: c_size_t,
+    off: i64,
+    biodone: rump_biodone_fn,
+    done_arg: *const c_void,
 ) {
-    unimplemented!("rumpuser_bio");
+    trace!(
+        "rumpuser_bio fd={} op={:#x} data={:p} dlen={} off={} sync={}",
+        fd,
+        op,
+        data,
+        dlen,
+        off,
+        op & RUMPUSER_BIO_SYNC != 0
+    );
+
+    BIO_QUEUE.lock().push_back(BioRequest {
+        fd,
+        op,
+        data: data as *mut c_void,
+        dlen,
+        off,
+        biodone,
+        done_arg: done_arg as *mut c_void,
+    });
+
+    BIO_PENDING.up();
+
+    BIO_WORKER.lock().get_or_insert_with(|| {
+        Environment::thread()
+            .spawn(Some(bio_worker), core::ptr::null_mut())
+            .expect("Can't create bio worker thread?")
+    });
 }
 
 /// int rumpuser_iovread(int fd, struct rumpuser_iovec *ruiov, size_t iovlen, int64_t off, size_t *retv)
@@ -306,20 +306,67 @@ pub unsafe extern "C" fn rumpuser_exit(value: i64) {
 }
 
 /// int rumpuser_kill(int64_t pid, int sig)
+///
+/// Sends `sig` to the NRK process `pid` (`RUMPUSER_PID_SELF` is the current
+/// process).
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_kill(pid: i64, sig: isize) -> isize {
-    unreachable!("rumpuser_kill({}, {})", pid, sig);
+pub unsafe extern "C" fn rumpuser_kill(pid: i64, sig: c_int) -> c_int {
+    const RUMPUSER_PID_SELF: i64 = -1;
+    trace!("rumpuser_kill({}, {})", pid, sig);
+
+    let target = match pid {
+        RUMPUSER_PID_SELF => kpi::process::CURRENT_PROCESS,
+        pid if pid >= 0 => pid as u64,
+        _ => return errno::ESRCH,
+    };
+    crate::rumprt::crt::signals::send_signal(target, sig)
 }
 
-// No need to implement:
+/// int rumpuser_anonmmap(void *prefaddr, size_t size, int alignbit, int exec, void **memp)
+///
+/// prefaddr         preferred address (ignored, the memory comes from the heap
+///                  region of the current core)
+/// size             size of the mapping
+/// alignbit         the mapping is aligned to 2^alignbit bytes
+/// exec             mapping has to be executable (not supported)
+/// memp             return value for the address of the mapping
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_anonmmap() {
-    unreachable!("rumpuser_anonmmap");
+pub unsafe extern "C" fn rumpuser_anonmmap(
+    _prefaddr: *mut u8,
+    size: usize,
+    alignbit: c_int,
+    exec: c_int,
+    memp: *mut *mut u8,
+) -> c_int {
+    trace!("rumpuser_anonmmap size={} alignbit={}", size, alignbit);
+    if exec != 0 {
+        error!("rumpuser_anonmmap: executable mappings are not supported");
+        return errno::ENOTSUP;
+    }
+
+    let layout = match 1usize
+        .checked_shl(alignbit as u32)
+        .and_then(|align| Layout::from_size_align(size, align).ok())
+    {
+        Some(layout) => layout,
+        None => return errno::EINVAL,
+    };
+    match crate::mem::anon_map(layout) {
+        Ok(ptr) => {
+            *memp = ptr;
+            0
+        }
+        Err(_e) => errno::ENOMEM,
+    }
 }
 
+/// void rumpuser_unmap(void *addr, size_t len)
 #[no_mangle]
-pub unsafe extern "C" fn rumpuser_unmap() {
-    unreachable!("rumpuser_anonmmap");
+pub unsafe extern "C" fn rumpuser_unmap(addr: *mut u8, len: usize) {
+    trace!("rumpuser_unmap {:p} len={}", addr, len);
+    if let Err(e) = crate::mem::anon_unmap(addr, len) {
+        error!("rumpuser_unmap {:p} {:#x} failed: {:?}", addr, len, e);
+    }
 }
 
 #[no_mangle]
@@ -197,6 +197,7 @@ pub unsafe extern "C" fn rumprun_makelwp(
         newlwp as *mut u8,
         coreid,
         None,
+        Default::default(),
         tls_private,
     );
     debug!("rumprun_makelwp spawned {:?} on core {}", tid, coreid);
@@ -255,13 +256,27 @@ pub unsafe extern "C" fn _lwp_getprivate() -> *mut c_void {
     fsbase
 }
 
+/// _lwp_kill() sends signal `sig` to the LWP `lid` in the current process.
+///
+/// Signals can't be directed at a specific LWP, they are sent to the process
+/// and the handler runs on whatever LWP happens to be running.
 #[no_mangle]
-pub unsafe extern "C" fn _lwp_kill() {
-    unreachable!(
-        "_lwp_kill on {} core {}",
-        Environment::tid(),
-        Environment::scheduler().core_id
-    );
+pub unsafe extern "C" fn _lwp_kill(lid: lwpid_t, sig: c_int) -> c_int {
+    use crate::rumprt::errno::{rumpuser_seterrno, ESRCH};
+    trace!("_lwp_kill lid {} sig {}", lid, sig);
+
+    let error = if get_rumprun_lwp_context(lid).is_null() {
+        ESRCH
+    } else {
+        crate::rumprt::crt::signals::send_signal(kpi::process::CURRENT_PROCESS, sig)
+    };
+
+    if error == 0 {
+        0
+    } else {
+        rumpuser_seterrno(error);
+        -1
+    }
 }
 
 /// _lwp_park() can be used to synchronize access to resources
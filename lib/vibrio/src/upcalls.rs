@@ -14,26 +14,100 @@
 //! [3]: http://www.barrelfish.org/publications/ma-fuchs-tm-mp.pdf
 
 use core::sync::atomic::{AtomicUsize, Ordering};
+use kpi::process::MAX_EVENT;
+use kpi::SystemCallError;
 use lazy_static::lazy_static;
+use lineup::scheduler::SmpScheduler;
 use log::trace;
 
+use crate::syscalls::Process;
+
 pub static CORES_ONLINE: AtomicUsize = AtomicUsize::new(1);
 
+/// Handlers for the events (signals) sent to the process (indexed by event
+/// number, 0 means no handler).
+static EVENT_HANDLERS: [AtomicUsize; MAX_EVENT as usize + 1] = {
+    const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+    [NO_HANDLER; MAX_EVENT as usize + 1]
+};
+
+/// The events we're subscribed to (serializes updates of the subscription).
+static SUBSCRIBED_EVENTS: spin::Mutex<u64> = spin::Mutex::new(0);
+
+/// Installs `handler` for `event` (or removes the current one with `None`).
+///
+/// The handler is invoked on the core that receives the upcall while upcalls
+/// are disabled: it must be short and can't block. Events without a handler
+/// are handled by the kernel (e.g., `EVENT_TERM` terminates the process).
+///
+/// A handler for `EVENT_SEGV` finds the state of the faulting thread in
+/// `enabled_state` of the vCPU control area (see
+/// `Process::vcpu_control_area`). It has to skip the faulting instruction or
+/// redirect the thread by updating `enabled_state.rip`, if it returns without
+/// doing so the process exits with 128 + `EVENT_SEGV`.
+pub fn set_event_handler(event: u64, handler: Option<fn(u64)>) -> Result<(), SystemCallError> {
+    if event == 0 || event > MAX_EVENT || event == kpi::process::EVENT_KILL {
+        return Err(SystemCallError::NotSupported);
+    }
+
+    let mut subscribed = SUBSCRIBED_EVENTS.lock();
+    let mask = match handler {
+        Some(f) => {
+            EVENT_HANDLERS[event as usize].store(f as usize, Ordering::Release);
+            *subscribed | (1 << event)
+        }
+        None => *subscribed & !(1 << event),
+    };
+
+    Process::subscribe_event(mask)?;
+    *subscribed = mask;
+    if handler.is_none() {
+        EVENT_HANDLERS[event as usize].store(0, Ordering::Release);
+    }
+
+    Ok(())
+}
+
+/// Runs the handler for `event` (an upcall with `kpi::upcall::EVENT`, or a
+/// fault for `EVENT_SEGV`).
+fn dispatch_event(event: u64) {
+    let handler = EVENT_HANDLERS
+        .get(event as usize)
+        .map_or(0, |h| h.load(Ordering::Acquire));
+
+    if handler != 0 {
+        // Safety: Only `set_event_handler` stores function pointers here
+        let handler: fn(u64) = unsafe { core::mem::transmute(handler) };
+        handler(event);
+    } else {
+        log::warn!("Got event {} without a handler", event);
+    }
+}
+
+/// Number of cores a scheduler needs to dispatch on this machine.
+///
+/// Cores are identified by their hardware thread id, so this is the highest
+/// id + 1 (falls back to `SmpScheduler::MAX_CORES` if we can't find out).
+pub fn scheduler_cores() -> usize {
+    crate::syscalls::System::threads()
+        .ok()
+        .and_then(|threads| threads.iter().map(|t| t.id + 1).max())
+        .unwrap_or(SmpScheduler::MAX_CORES)
+}
+
 lazy_static! {
-    pub static ref PROCESS_SCHEDULER: lineup::scheduler::SmpScheduler<'static> = {
+    pub static ref PROCESS_SCHEDULER: SmpScheduler<'static> = {
         #[cfg(feature = "rumprt")]
-        {
-            lineup::scheduler::SmpScheduler::with_upcalls(lineup::upcalls::Upcalls {
-                curlwp: crate::rumprt::rumpkern_curlwp,
-                deschedule: crate::rumprt::rumpkern_unsched,
-                schedule: crate::rumprt::rumpkern_sched,
-                context_switch: crate::rumprt::prt::context_switch,
-            })
-        }
+        let upcalls = lineup::upcalls::Upcalls {
+            curlwp: crate::rumprt::rumpkern_curlwp,
+            deschedule: crate::rumprt::rumpkern_unsched,
+            schedule: crate::rumprt::rumpkern_sched,
+            context_switch: crate::rumprt::prt::context_switch,
+        };
         #[cfg(not(feature = "rumprt"))]
-        {
-            lineup::scheduler::SmpScheduler::default()
-        }
+        let upcalls = Default::default();
+
+        SmpScheduler::with_limits(upcalls, scheduler_cores(), SmpScheduler::MAX_THREADS)
     };
 }
 
@@ -78,7 +152,29 @@ pub fn upcall_while_enabled(control: &mut kpi::arch::VirtualCpu, cmd: u64, arg:
         }
     }
 
-    if cmd == 0x2a || cmd == 0x24 {
+    if cmd == kpi::upcall::EVENT {
+        trace!("got event {}", arg);
+        dispatch_event(arg);
+    } else if cmd == kpi::upcall::PAGE_FAULT || cmd == kpi::upcall::GENERAL_PROTECTION_FAULT {
+        // The kernel only sends us faults if we have a handler, the faulting
+        // instruction would just fault again if the handler didn't move on
+        let rip = control.enabled_state.rip;
+        log::debug!(
+            "got fault vec={:#x} err={:#x} at {:#x} accessing {:#x}",
+            cmd,
+            arg,
+            rip,
+            control.fault_address
+        );
+        dispatch_event(kpi::process::EVENT_SEGV);
+        if control.enabled_state.rip == rip {
+            log::error!("Fault at {:#x} wasn't handled, exiting", rip);
+            Process::exit(128 + kpi::process::EVENT_SEGV);
+        }
+    } else if cmd == 0x2a
+        || cmd == 0x24
+        || (kpi::io::FIRST_DEVICE_VECTOR..=kpi::io::LAST_DEVICE_VECTOR).contains(&cmd)
+    {
         // TODO(correctness): this will use `gs` to access the SchedulerControlBlock
         // that assumes that we have already called scheduler.run() and we preserve
         // the SchedulerControlBlock register even if we return from run()
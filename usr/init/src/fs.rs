@@ -44,6 +44,8 @@ enum ModelOperation {
     Write(Mnode, i64, char, u64),
     /// Stores info about created files.
     Created(String, FileModes, Mnode),
+    /// Stores info about created directories.
+    Directory(String, Mnode),
 }
 
 /// A file descriptor representaion.
@@ -168,9 +170,10 @@ struct ModelFIO {
 impl Default for ModelFIO {
     fn default() -> Self {
         let oplog = RefCell::new(Vec::with_capacity(64));
+        // The root directory, paths in the model are relative to it
         oplog
             .borrow_mut()
-            .push(ModelOperation::Created("/".to_string(), 0.into(), 1));
+            .push(ModelOperation::Directory(String::new(), 1));
         ModelFIO {
             oplog,
             mnode_counter: RefCell::new(1),
@@ -180,11 +183,26 @@ impl Default for ModelFIO {
 }
 
 impl ModelFIO {
-    /// Find mnode of a path.
+    /// Removes empty components of a path, like the kernel all paths are
+    /// resolved starting from the root (which becomes "").
+    fn normalize(path: &str) -> String {
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<&str>>()
+            .join("/")
+    }
+
+    /// Returns the path of the directory a (normalized) path is in.
+    fn parent(path: &str) -> &str {
+        path.rfind('/').map_or("", |idx| &path[..idx])
+    }
+
+    /// Find mnode of a path (a file or a directory).
     fn path_to_mnode(&self, path: &String) -> Option<Mnode> {
         for x in self.oplog.borrow().iter().rev() {
             match x {
-                ModelOperation::Created(name, _mode, mnode) => {
+                ModelOperation::Created(name, _mode, mnode)
+                | ModelOperation::Directory(name, mnode) => {
                     if &name == &path {
                         return Some(*mnode);
                     }
@@ -196,6 +214,25 @@ impl ModelFIO {
         None
     }
 
+    /// Check if a given path is a directory.
+    fn dir_exists(&self, path: &str) -> bool {
+        self.oplog.borrow().iter().any(|x| match x {
+            ModelOperation::Directory(name, _mnode) => name == path,
+            _ => false,
+        })
+    }
+
+    /// Check if there is nothing in the directory at `path`.
+    fn dir_is_empty(&self, path: &str) -> bool {
+        !self.oplog.borrow().iter().any(|x| match x {
+            ModelOperation::Created(name, _mode, _mnode)
+            | ModelOperation::Directory(name, _mnode) => {
+                name != path && ModelFIO::parent(name) == path
+            }
+            _ => false,
+        })
+    }
+
     /// Find index of a path in the oplog.
     fn path_to_idx(&self, path: &String) -> Option<usize> {
         for (idx, x) in self.oplog.borrow().iter().enumerate().rev() {
@@ -262,7 +299,8 @@ impl ModelFIO {
                         my_idxs.push(idx);
                     }
                 }
-                ModelOperation::Created(_path, _modes, current_mnode) => {
+                ModelOperation::Created(_path, _modes, current_mnode)
+                | ModelOperation::Directory(_path, current_mnode) => {
                     if remove_created && &look_for == current_mnode {
                         my_idxs.push(idx);
                     }
@@ -304,7 +342,7 @@ impl ModelFIO {
 
     // Create just puts the file in the oplop and increases mnode counter.
     pub fn open(&mut self, pathname: u64, flags: u64, modes: u64) -> Result<u64, SystemCallError> {
-        let path = userptr_to_str(pathname)?;
+        let path = ModelFIO::normalize(&userptr_to_str(pathname)?);
         let flags = FileFlags::from(flags);
         let mut modes = FileModes::from(modes);
 
@@ -313,6 +351,20 @@ impl ModelFIO {
             return Err(SystemCallError::InternalError);
         }
 
+        // Directories can be opened (e.g., to read their entries) but not
+        // truncated
+        if self.dir_exists(&path) {
+            let mnode = self.lookup(&path).unwrap();
+            let (fid, fd) = self.fds.allocate_fd()?;
+            fd.update_fd(mnode, flags);
+            if flags.is_truncate() {
+                trace!("open() - can't truncate a directory");
+                self.fds.deallocate_fd(fid)?;
+                return Err(SystemCallError::InternalError);
+            }
+            return Ok(fid);
+        }
+
         // If file exists, only create new fd
         if let Some(mnode) = self.lookup(&path) {
             if flags.is_create() {
@@ -350,6 +402,10 @@ impl ModelFIO {
                 trace!("open() - called on non-existing file without create flag");
                 return Err(SystemCallError::InternalError);
             }
+            if !self.dir_exists(ModelFIO::parent(&path)) {
+                trace!("open() - parent directory of {:?} doesn't exist", path);
+                return Err(SystemCallError::InternalError);
+            }
 
             *self.mnode_counter.borrow_mut() += 1;
             let mnode = *self.mnode_counter.borrow();
@@ -589,10 +645,21 @@ impl ModelFIO {
     }
 
     /// Delete finds and removes a path from the oplog again.
+    ///
+    /// Directories can only be removed once they are empty.
     pub fn delete(&self, name: u64) -> Result<bool, SystemCallError> {
-        let path = userptr_to_str(name)?;
+        let path = ModelFIO::normalize(&userptr_to_str(name)?);
         // TODO: Check to see if there are any open fds to this mnode.
 
+        if path.is_empty() {
+            trace!("delete() - can't remove the root directory");
+            return Err(SystemCallError::InternalError);
+        }
+        if self.dir_exists(&path) && !self.dir_is_empty(&path) {
+            trace!("delete() - directory {:?} isn't empty", path);
+            return Err(SystemCallError::InternalError);
+        }
+
         if let Some(mnode) = self.lookup(&path) {
             self.remove_entries(mnode, true, true);
             Ok(true)
@@ -606,6 +673,26 @@ impl ModelFIO {
         self.fds.deallocate_fd(fid)?;
         Ok(0)
     }
+
+    /// Creates a directory, the parent directory has to exist.
+    pub fn mkdir(&self, pathname: u64, _modes: u64) -> Result<u64, SystemCallError> {
+        let path = ModelFIO::normalize(&userptr_to_str(pathname)?);
+        if self.lookup(&path).is_some() {
+            trace!("mkdir() - {:?} exists already", path);
+            return Err(SystemCallError::InternalError);
+        }
+        if !self.dir_exists(ModelFIO::parent(&path)) {
+            trace!("mkdir() - parent directory of {:?} doesn't exist", path);
+            return Err(SystemCallError::InternalError);
+        }
+
+        *self.mnode_counter.borrow_mut() += 1;
+        let mnode = *self.mnode_counter.borrow();
+        self.oplog
+            .borrow_mut()
+            .push(ModelOperation::Directory(path, mnode));
+        Ok(0)
+    }
 }
 
 /// Two writes/reads at different offsets should return
@@ -679,6 +766,7 @@ enum TestAction {
     Open(Vec<String>, u64, u64),
     Delete(Vec<String>),
     Close(u64),
+    MkDir(Vec<String>, u64),
 }
 
 /// Generates one `TestAction` entry randomly.
@@ -694,6 +782,7 @@ fn action() -> impl Strategy<Value = TestAction> {
         (path(), flag_gen(0xfff), mode_gen(0xfff)).prop_map(|(a, b, c)| TestAction::Open(a, b, c)),
         path().prop_map(TestAction::Delete),
         fd_gen(0xA).prop_map(TestAction::Close),
+        (path(), mode_gen(0xfff)).prop_map(|(a, b)| TestAction::MkDir(a, b)),
     ]
 }
 
@@ -757,10 +846,12 @@ fn path_names() -> impl Strategy<Value = String> {
     ]
 }
 
-/// Creates a path of depth a given depth (4), represented as a
-/// vector of Strings.
+/// Creates a path of depth 1 to 4, represented as a vector of Strings.
+///
+/// Files and directories can only be created in existing directories, so
+/// shorter paths make sure that the deeper ones can exist too.
 fn path() -> impl Strategy<Value = Vec<String>> {
-    proptest::collection::vec(path_names(), 4)
+    proptest::collection::vec(path_names(), 1..=4)
 }
 
 // Verify that our FS implementation behaves according to the `ModelFileSystem`.
@@ -873,16 +964,26 @@ fn model_equivalence(ops: Vec<TestAction>) {
                     fd_map.remove(&fd);
                 }
             }
+            MkDir(path, mode) => {
+                let mut path_str = path.join("/");
+                path_str.push('\0');
+
+                let rmodel = model.mkdir(path_str.as_ptr() as u64, mode);
+                let rtotest = vibrio::syscalls::Fs::mkdir_simple(path_str.as_ptr() as u64, mode);
+                assert_eq!(rmodel, rtotest);
+            }
         }
     }
 
     // Clean up file system by closing all open file descriptors and deleting all existing files
+    // (newest first, so directories are empty by the time we delete them)
     for rtotest_fd in fd_map.values() {
         assert_eq!(vibrio::syscalls::Fs::close(*rtotest_fd).is_ok(), true);
     }
-    for x in model.oplog.borrow().iter() {
+    for x in model.oplog.borrow().iter().rev() {
         match x {
-            ModelOperation::Created(path, _modes, mnode) => {
+            ModelOperation::Created(path, _modes, mnode)
+            | ModelOperation::Directory(path, mnode) => {
                 // mnode=1 is the root ("/") which we can't/shouldn't delete.
                 let mut my_path = path.clone();
                 my_path.push('\0');
@@ -1342,6 +1443,93 @@ fn test_file_position() {
     vibrio::syscalls::Fs::close(fd).unwrap();
 }
 
+/// Map a file shared and private, write to the file and check which
+/// mapping sees the update.
+fn test_file_map() {
+    let shared_base: u64 = 0x7000_0000;
+    let private_base: u64 = 0x7010_0000;
+    let len: u64 = 0x1000;
+
+    let fd = vibrio::syscalls::Fs::open(
+        "test_file_map.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        FileModes::S_IRWXU.into(),
+    )
+    .unwrap();
+    let wdata = [1u8; 10];
+    assert_eq!(
+        vibrio::syscalls::Fs::write(fd, wdata.as_ptr() as u64, 10),
+        Ok(10)
+    );
+
+    unsafe {
+        vibrio::syscalls::VSpace::map_file(
+            shared_base,
+            len,
+            fd,
+            0,
+            MapFlags::MAP_SHARED | MapFlags::PROT_WRITE,
+        )
+        .expect("Can't map file shared");
+        vibrio::syscalls::VSpace::map_file(
+            private_base,
+            len,
+            fd,
+            0,
+            MapFlags::MAP_PRIVATE | MapFlags::PROT_WRITE,
+        )
+        .expect("Can't map file private");
+    }
+    let shared: &mut [u8] = unsafe { from_raw_parts_mut(shared_base as *mut u8, len as usize) };
+    let private: &mut [u8] = unsafe { from_raw_parts_mut(private_base as *mut u8, len as usize) };
+    assert_eq!(shared[9], 1);
+    assert_eq!(shared[10], 0);
+    assert_eq!(private[9], 1);
+    assert_eq!(private[10], 0);
+
+    // Stores to the private mapping copy the page and stay private.
+    private[0] = 3;
+    let mut rdata = [0u8; 1];
+    assert_eq!(
+        vibrio::syscalls::Fs::read_at(fd, rdata.as_mut_ptr() as u64, 1, 0),
+        Ok(1)
+    );
+    assert_eq!(rdata[0], 1);
+
+    // Writes to the file show up in the shared mapping only.
+    let wdata = [2u8; 10];
+    assert_eq!(
+        vibrio::syscalls::Fs::write_at(fd, wdata.as_ptr() as u64, 10, 0),
+        Ok(10)
+    );
+    assert_eq!(unsafe { core::ptr::read_volatile(&shared[0]) }, 2);
+    assert_eq!(private[0], 3);
+
+    // Stores to the shared mapping end up in the file.
+    unsafe { core::ptr::write_volatile(&mut shared[1], 4) };
+
+    // A mapped file can't be removed.
+    assert_eq!(
+        vibrio::syscalls::Fs::delete("test_file_map.txt\0".as_ptr() as u64),
+        Err(SystemCallError::PermissionError)
+    );
+    unsafe {
+        vibrio::syscalls::VSpace::unmap_file(shared_base, len).expect("Can't unmap file");
+        vibrio::syscalls::VSpace::unmap_file(private_base, len).expect("Can't unmap file");
+    }
+    let mut rdata = [0u8; 2];
+    assert_eq!(
+        vibrio::syscalls::Fs::read_at(fd, rdata.as_mut_ptr() as u64, 2, 0),
+        Ok(2)
+    );
+    assert_eq!(rdata, [2, 4]);
+    vibrio::syscalls::Fs::close(fd).unwrap();
+    assert_eq!(
+        vibrio::syscalls::Fs::delete("test_file_map.txt\0".as_ptr() as u64),
+        Ok(true)
+    );
+}
+
 pub fn run_fio_syscall_tests() {
     test_file_read_permission_error();
     test_file_write_permission_error();
@@ -1362,4 +1550,5 @@ pub fn run_fio_syscall_tests() {
     test_file_rename_nonexistent_file();
     test_file_rename_to_existent_file();
     test_file_position();
+    test_file_map();
 }
@@ -17,6 +17,13 @@ impl Bench for MWRL {
     fn init(&self, cores: Vec<usize>, _open_files: usize) {
         unsafe {
             for core in cores {
+                let dir_name = format!("/{}\0", core);
+                vibrio::syscalls::Fs::mkdir_simple(
+                    dir_name.as_ptr() as u64,
+                    u64::from(FileModes::S_IRWXU),
+                )
+                .expect("MkDir syscall failed");
+
                 let file_name = format!("/{}/file-0.txt\0", core);
                 let fd = vibrio::syscalls::Fs::open(
                     file_name.as_ptr() as u64,
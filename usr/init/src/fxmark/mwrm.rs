@@ -36,7 +36,20 @@ impl Bench for MWRM {
         *self.total_cores.borrow_mut() = core_nums;
         let files_per_core = self.total_files / core_nums;
         unsafe {
+            vibrio::syscalls::Fs::mkdir_simple(
+                "/fxmark\0".as_ptr() as u64,
+                u64::from(FileModes::S_IRWXU),
+            )
+            .expect("MkDir syscall failed");
+
             for core in cores {
+                let dir_name = format!("/{}\0", core);
+                vibrio::syscalls::Fs::mkdir_simple(
+                    dir_name.as_ptr() as u64,
+                    u64::from(FileModes::S_IRWXU),
+                )
+                .expect("MkDir syscall failed");
+
                 for iter in 0..files_per_core {
                     let file_name = format!("/{}/file-{}-{}.txt\0", core, core, iter);
                     let fd = vibrio::syscalls::Fs::open(
@@ -70,9 +70,86 @@ fn map_test() {
         assert_eq!(slice[99], 0xb);
     }
 
+    // Only the touched pages of a lazy region get backed with memory.
+    let base: u64 = 0x2000_0000;
+    let size: u64 = 0x4000_0000;
+    unsafe {
+        vibrio::syscalls::VSpace::map_lazy(base, size).expect("Lazy map syscall failed");
+
+        let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, size as usize);
+        for i in (0..size as usize).step_by(0x100_0000) {
+            assert_eq!(slice[i], 0);
+            slice[i] = 0xb;
+            assert_eq!(slice[i], 0xb);
+        }
+
+        vibrio::syscalls::VSpace::unmap(base, size).expect("Unmap syscall failed");
+    }
+
+    // All replicas should end up with the same page-tables
+    let (divergences, total) =
+        vibrio::syscalls::System::verify_page_tables(vibrio::process::CURRENT_PROCESS)
+            .expect("Can't verify page-tables");
+    assert!(divergences.is_empty(), "Replicas differ: {:?}", divergences);
+    assert_eq!(total, 0);
+
     info!("map_test OK");
 }
 
+/// Maps memory with the different NUMA allocation policies.
+fn numa_policy_test() {
+    use vibrio::syscalls::{System, VSpace};
+    use vibrio::AllocationPolicy;
+
+    let size: u64 = 0x1000 * 64;
+    let policies = [
+        AllocationPolicy::Interleave,
+        AllocationPolicy::Preferred(1),
+        AllocationPolicy::Bind(0),
+        AllocationPolicy::Bind(1),
+        AllocationPolicy::Local,
+    ];
+    for (i, policy) in policies.iter().enumerate() {
+        let base = 0x4000_0000 + i as u64 * size;
+        unsafe {
+            VSpace::map_with_policy(base, size, *policy).expect("Map syscall failed");
+
+            let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, size as usize);
+            for i in slice.iter_mut() {
+                *i = 0xb;
+            }
+            assert_eq!(slice[size as usize - 1], 0xb);
+        }
+
+        if let AllocationPolicy::Bind(node) = policy {
+            for page in (base..base + size).step_by(0x1000) {
+                let (_, paddr) = VSpace::identify(page).expect("Identify syscall failed");
+                let frame_node =
+                    System::memory_node(paddr.as_u64()).expect("GetMemoryNode syscall failed");
+                assert_eq!(
+                    frame_node, *node,
+                    "Frame of {:#x} is on the wrong node",
+                    page
+                );
+            }
+        }
+    }
+
+    // The policy sticks for later mappings of the process
+    unsafe {
+        let base = 0x5000_0000;
+        VSpace::map(base, size).expect("Map syscall failed");
+        let slice: &mut [u8] = from_raw_parts_mut(base as *mut u8, size as usize);
+        slice[0] = 0xb;
+        assert_eq!(slice[0], 0xb);
+
+        let r = VSpace::map_with_policy(0x6000_0000, size, AllocationPolicy::Bind(64));
+        assert!(r.is_err(), "Can't bind to a node that doesn't exist");
+    }
+
+    info!("numa_policy_test OK");
+}
+
 fn alloc_test() {
     use alloc::vec::Vec;
     let mut v: Vec<u16> = Vec::with_capacity(256);
@@ -180,6 +257,143 @@ fn scheduler_test() {
     info!("scheduler_test OK");
 }
 
+/// Issues a batch of block I/O requests with `rumpuser_bio` and waits until
+/// the bio worker completed all of them.
+#[cfg(feature = "rumprt")]
+unsafe fn test_rump_bio() {
+    use core::sync::atomic::AtomicUsize;
+    use cstr_core::CStr;
+    use lineup::tls2::Environment;
+    use rumprt::fs::{rumpuser_bio, rumpuser_close, rumpuser_open};
+    use rumprt::{c_int, c_size_t, c_void};
+
+    const REQUESTS: usize = 16;
+    const BLOCK_SIZE: usize = 512;
+    const RUMPUSER_OPEN_RDWR: c_int = 0x0002;
+    const RUMPUSER_OPEN_CREATE: c_int = 0x0004;
+    const RUMPUSER_BIO_READ: c_int = 0x01;
+    const RUMPUSER_BIO_WRITE: c_int = 0x02;
+
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+    static TRANSFERRED: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn biodone(_arg: *mut c_void, len: c_size_t, error: c_int) {
+        assert_eq!(error, 0, "bio request failed");
+        TRANSFERRED.fetch_add(len, Ordering::Relaxed);
+        COMPLETED.fetch_add(1, Ordering::Release);
+    }
+
+    fn wait_for(completed: usize) {
+        while COMPLETED.load(Ordering::Acquire) < completed {
+            Environment::thread().relinquish();
+        }
+    }
+
+    let path = CStr::from_bytes_with_nul(b"rump-bio.img\0").unwrap();
+    let mut fd: c_int = -1;
+    let r = rumpuser_open(
+        path.as_ptr(),
+        RUMPUSER_OPEN_RDWR | RUMPUSER_OPEN_CREATE,
+        &mut fd,
+    );
+    assert_eq!(r, 0, "rumpuser_open succeeded");
+
+    let mut wbuf = [0u8; REQUESTS * BLOCK_SIZE];
+    for (i, block) in wbuf.chunks_mut(BLOCK_SIZE).enumerate() {
+        block.fill(i as u8 + 1);
+    }
+
+    // Issue all requests back-to-back, the worker has to pick up every one
+    for i in 0..REQUESTS {
+        rumpuser_bio(
+            fd,
+            RUMPUSER_BIO_WRITE,
+            wbuf.as_ptr().add(i * BLOCK_SIZE) as *const c_void,
+            BLOCK_SIZE,
+            (i * BLOCK_SIZE) as i64,
+            Some(biodone),
+            ptr::null(),
+        );
+    }
+    wait_for(REQUESTS);
+
+    let mut rbuf = [0u8; REQUESTS * BLOCK_SIZE];
+    for i in 0..REQUESTS {
+        rumpuser_bio(
+            fd,
+            RUMPUSER_BIO_READ,
+            rbuf.as_mut_ptr().add(i * BLOCK_SIZE) as *const c_void,
+            BLOCK_SIZE,
+            (i * BLOCK_SIZE) as i64,
+            Some(biodone),
+            ptr::null(),
+        );
+    }
+    wait_for(2 * REQUESTS);
+
+    assert_eq!(
+        TRANSFERRED.load(Ordering::Relaxed),
+        2 * REQUESTS * BLOCK_SIZE
+    );
+    assert!(rbuf[..] == wbuf[..], "Read matches write");
+    assert_eq!(rumpuser_close(fd), 0);
+    info!("rumpuser_bio: {} requests completed", 2 * REQUESTS);
+}
+
+/// Creates a directory in the NRK file-system and enumerates it with
+/// `nrk_opendir` and `nrk_readdir`.
+#[cfg(feature = "rumprt")]
+unsafe fn test_rump_readdir() {
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use rumprt::fs::{nrk_closedir, nrk_opendir, nrk_readdir};
+    use rumprt::{c_int, c_void};
+    use vibrio::io::*;
+    use vibrio::syscalls::Fs;
+
+    // More files than `Dir` fetches in one batch, so we have to resume
+    const FILES: usize = 12;
+
+    Fs::mkdir_simple("/rump-dir\0".as_ptr() as u64, u64::from(FileModes::S_IRWXU))
+        .expect("MkDir syscall failed");
+    for i in 0..FILES {
+        let path = format!("/rump-dir/file{}\0", i);
+        let fd = Fs::open(
+            path.as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("Open syscall failed");
+        Fs::close(fd).expect("Close syscall failed");
+    }
+
+    let mut dir: *mut c_void = ptr::null_mut();
+    assert_eq!(
+        nrk_opendir("/rump-dir\0".as_ptr() as *const i8, &mut dir),
+        0
+    );
+
+    let mut names = Vec::with_capacity(FILES);
+    let mut entry = DirEntry::default();
+    let r: c_int = loop {
+        let r = nrk_readdir(dir, &mut entry);
+        if r != 0 {
+            break r;
+        }
+        assert_eq!(entry.ftype, u64::from(FileType::File));
+        names.push(String::from_utf8(entry.name().to_vec()).expect("Entry name is UTF-8"));
+    };
+    assert_eq!(r, rumprt::errno::ENOENT, "End of directory");
+    assert_eq!(nrk_closedir(dir), 0);
+
+    names.sort();
+    let mut expected: Vec<String> = (0..FILES).map(|i| format!("file{}", i)).collect();
+    expected.sort();
+    assert_eq!(names, expected);
+    info!("nrk_readdir: {} entries", names.len());
+}
+
 #[cfg(feature = "rumprt")]
 fn test_rump_tmpfs() {
     use cstr_core::CStr;
@@ -212,7 +426,11 @@ fn test_rump_tmpfs() {
         context_switch: rumprt::prt::context_switch,
     };
 
-    let mut scheduler = lineup::scheduler::SmpScheduler::with_upcalls(up);
+    let mut scheduler = lineup::scheduler::SmpScheduler::with_limits(
+        up,
+        vibrio::upcalls::scheduler_cores(),
+        lineup::scheduler::SmpScheduler::MAX_THREADS,
+    );
     scheduler.spawn(
         32 * 4096,
         |_yielder| unsafe {
@@ -261,6 +479,9 @@ fn test_rump_tmpfs() {
             assert_eq!(read_bytes, 12, "Read successful");
             assert_eq!(rbuf[0], 0xa, "Read matches write");
             info!("bytes_read: {:?}", read_bytes);
+
+            test_rump_bio();
+            test_rump_readdir();
         },
         core::ptr::null_mut(),
         0,
@@ -328,7 +549,11 @@ pub fn test_rump_net() {
         context_switch: rumprt::prt::context_switch,
     };
 
-    let mut scheduler = lineup::scheduler::SmpScheduler::with_upcalls(up);
+    let mut scheduler = lineup::scheduler::SmpScheduler::with_limits(
+        up,
+        vibrio::upcalls::scheduler_cores(),
+        lineup::scheduler::SmpScheduler::MAX_THREADS,
+    );
     scheduler.spawn(
         32 * 4096,
         |_yielder| unsafe {
@@ -604,6 +829,73 @@ fn fs_write_test() {
     info!("fs_write Ok");
 }
 
+/// Checks that files survive a restart of the machine (with the `pmemfs`
+/// kernel argument): the first run creates them and waits to get killed,
+/// the next run has to find them.
+fn pmemfs_test() {
+    use alloc::vec::Vec;
+    use vibrio::io::*;
+    use vibrio::syscalls::Fs;
+
+    let data: Vec<u8> = (0..3 * 4096 + 17).map(|i| (i % 251) as u8).collect();
+
+    if Fs::getinfo("/pmemfs\0".as_ptr() as u64).is_err() {
+        Fs::mkdir_simple("/pmemfs\0".as_ptr() as u64, u64::from(FileModes::S_IRWXU))
+            .expect("MkDir syscall failed");
+        let fd = Fs::open(
+            "/pmemfs/tmp.txt\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("FileOpen syscall failed");
+        let ret = Fs::write_at(fd, data.as_ptr() as u64, data.len() as u64, 0)
+            .expect("FileWrite syscall failed");
+        assert_eq!(ret, data.len() as u64);
+        Fs::close(fd).expect("FileClose syscall failed");
+        Fs::rename(
+            "/pmemfs/tmp.txt\0".as_ptr() as u64,
+            "/pmemfs/data.txt\0".as_ptr() as u64,
+        )
+        .expect("FileRename syscall failed");
+
+        let fd = Fs::open(
+            "/pmemfs/deleted.txt\0".as_ptr() as u64,
+            u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+            u64::from(FileModes::S_IRWXU),
+        )
+        .expect("FileOpen syscall failed");
+        Fs::close(fd).expect("FileClose syscall failed");
+        Fs::delete("/pmemfs/deleted.txt\0".as_ptr() as u64).expect("FileDelete syscall failed");
+
+        info!("pmemfs_test: created files");
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+
+    let fileinfo =
+        Fs::getinfo("/pmemfs/data.txt\0".as_ptr() as u64).expect("Recovered file is missing");
+    assert_eq!(fileinfo.fsize, data.len() as u64);
+    assert!(Fs::getinfo("/pmemfs/tmp.txt\0".as_ptr() as u64).is_err());
+    assert!(Fs::getinfo("/pmemfs/deleted.txt\0".as_ptr() as u64).is_err());
+
+    let fd = Fs::open(
+        "/pmemfs/data.txt\0".as_ptr() as u64,
+        u64::from(FileFlags::O_RDONLY),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    let mut contents: Vec<u8> = alloc::vec![0; data.len()];
+    let ret = Fs::read_at(fd, contents.as_mut_ptr() as u64, contents.len() as u64, 0)
+        .expect("FileRead syscall failed");
+    assert_eq!(ret, data.len() as u64);
+    assert_eq!(contents, data);
+    Fs::close(fd).expect("FileClose syscall failed");
+
+    info!("pmemfs_test: recovered files");
+    info!("pmemfs_test OK");
+}
+
 fn pmem_alloc(ncores: Option<usize>) {
     use alloc::vec::Vec;
     use lineup::threads::ThreadId;
@@ -685,6 +977,241 @@ fn pmem_alloc(ncores: Option<usize>) {
     info!("pmem_alloc OK");
 }
 
+/// Spawns a copy of init and waits for it.
+///
+/// The copy finds the marker file the parent created and checks that it
+/// can't wait for its parent (pid 0).
+fn spawn_test() {
+    use vibrio::io::*;
+    use vibrio::syscalls::{Fs, Process};
+    use vibrio::SystemCallError;
+
+    let marker = "/spawn-test\0".as_ptr() as u64;
+    if Fs::getinfo(marker).is_ok() {
+        let code = match Process::try_wait(0) {
+            Err(SystemCallError::PermissionError) => 42,
+            _ => 1,
+        };
+        info!("spawn_test: child exits with {}", code);
+        Process::exit(code);
+    }
+
+    let fd = Fs::open(
+        marker,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    Fs::close(fd).expect("FileClose syscall failed");
+
+    assert!(Process::spawn("does-not-exist\0".as_ptr() as u64, 1).is_err());
+
+    let child = Process::spawn("init\0".as_ptr() as u64, 1).expect("Spawn syscall failed");
+    info!("spawn_test: spawned {}", child);
+    assert_eq!(Process::wait(child), Ok(42));
+    // The exit code can only be retrieved once
+    assert!(Process::try_wait(child).is_err());
+
+    Fs::delete(marker).expect("FileDelete syscall failed");
+    info!("spawn_test OK");
+}
+
+/// Runs a copy of init on the core of this process.
+///
+/// The parent polls for the exit code of its child without ever blocking,
+/// so the child only gets to run if the core's run-queue preempts the parent.
+fn sched_share_test() {
+    use vibrio::io::*;
+    use vibrio::syscalls::{Fs, Process, System};
+    use vibrio::SystemCallError;
+
+    let marker = "/sched-share-test\0".as_ptr() as u64;
+    if Fs::getinfo(marker).is_ok() {
+        info!(
+            "sched_share_test: child runs on core {}",
+            System::core_id().expect("Can't get core id")
+        );
+        Process::exit(7);
+    }
+
+    let fd = Fs::open(
+        marker,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    Fs::close(fd).expect("FileClose syscall failed");
+
+    let core = System::core_id().expect("Can't get core id");
+    let child = Process::spawn("init\0".as_ptr() as u64, core).expect("Spawn syscall failed");
+    info!("sched_share_test: spawned {} on core {}", child, core);
+
+    let code = loop {
+        match Process::try_wait(child) {
+            Err(SystemCallError::WouldBlock) => core::hint::spin_loop(),
+            r => break r,
+        }
+    };
+    assert_eq!(code, Ok(7));
+
+    Fs::delete(marker).expect("FileDelete syscall failed");
+    info!("sched_share_test OK");
+}
+
+/// Exchanges messages over a channel with a copy of init.
+///
+/// The parent creates the channel, so the copy (its child) finds it and
+/// can attach to it.
+fn ipc_test() {
+    use vibrio::ipc::Channel;
+    use vibrio::syscalls::Process;
+    use vibrio::SystemCallError;
+
+    const NAME: u64 = 0x1bc;
+
+    let s = &vibrio::upcalls::PROCESS_SCHEDULER;
+    s.spawn(
+        32 * 4096,
+        move |_| {
+            let mut buffer = [0u8; 16];
+            match Channel::create(NAME, 4) {
+                Ok(channel) => {
+                    let child =
+                        Process::spawn("init\0".as_ptr() as u64, 1).expect("Spawn syscall failed");
+                    channel.send(b"ping").expect("Can't send ping");
+                    let len = channel.receive(&mut buffer).expect("Can't receive pong");
+                    assert_eq!(&buffer[..len], b"pong");
+                    assert_eq!(Process::wait(child), Ok(0));
+                    info!("ipc_test OK");
+                }
+                Err(SystemCallError::PermissionError) => {
+                    let channel = Channel::attach(NAME).expect("Can't attach to channel");
+                    let len = channel.receive(&mut buffer).expect("Can't receive ping");
+                    assert_eq!(&buffer[..len], b"ping");
+                    info!("ipc_test: child got ping");
+                    channel.send(b"pong").expect("Can't send pong");
+                    drop(channel);
+                    Process::exit(0);
+                }
+                Err(e) => panic!("Can't create channel: {:?}", e),
+            }
+        },
+        ptr::null_mut(),
+        0,
+        None,
+    );
+
+    let scb: SchedulerControlBlock = SchedulerControlBlock::new(0);
+    while s.has_active_threads() {
+        s.run(&scb);
+    }
+}
+
+/// Sends events to ourselves and to a copy of init.
+///
+/// The parent handles its own event with an upcall, the child has no handler
+/// for `EVENT_TERM` and gets terminated by the kernel.
+fn events_test() {
+    use vibrio::io::*;
+    use vibrio::process::{CURRENT_PROCESS, EVENT_TERM};
+    use vibrio::syscalls::{Fs, Process};
+    use vibrio::SystemCallError;
+
+    const EVENT_USR: u64 = 10;
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    let marker = "/events-test\0".as_ptr() as u64;
+    if Fs::getinfo(marker).is_ok() {
+        info!("events_test: child waits for termination");
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+
+    vibrio::upcalls::set_event_handler(
+        EVENT_USR,
+        Some(|event| RECEIVED.store(event == EVENT_USR, Ordering::SeqCst)),
+    )
+    .expect("Can't subscribe to event");
+    Process::send_event(CURRENT_PROCESS, EVENT_USR).expect("Can't send event");
+    // Events are delivered with the next timer interrupt
+    while !RECEIVED.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    vibrio::upcalls::set_event_handler(EVENT_USR, None).expect("Can't unsubscribe");
+    info!("events_test: event delivered");
+
+    let fd = Fs::open(
+        marker,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    Fs::close(fd).expect("FileClose syscall failed");
+
+    let child = Process::spawn("init\0".as_ptr() as u64, 1).expect("Spawn syscall failed");
+    // We are neither the parent nor a child of this process
+    assert_eq!(
+        Process::send_event(child + 1, EVENT_TERM),
+        Err(SystemCallError::PermissionError)
+    );
+    Process::send_event(child, EVENT_TERM).expect("Can't terminate child");
+    assert_eq!(Process::wait(child), Ok(128 + EVENT_TERM));
+
+    Fs::delete(marker).expect("FileDelete syscall failed");
+    info!("events_test OK");
+}
+
+/// Faults on an unmapped address with and without a handler.
+///
+/// The parent skips the faulting load in its handler, the child (a copy of
+/// init) has no handler and gets terminated by the kernel.
+fn fault_test() {
+    use vibrio::io::*;
+    use vibrio::process::EVENT_SEGV;
+    use vibrio::syscalls::{Fs, Process};
+
+    const FAULT_ADDRESS: u64 = 0xdead_0000;
+    static HANDLED: AtomicBool = AtomicBool::new(false);
+
+    let marker = "/fault-test\0".as_ptr() as u64;
+    if Fs::getinfo(marker).is_ok() {
+        info!("fault_test: child faults without a handler");
+        let _value = unsafe { ptr::read_volatile(FAULT_ADDRESS as *const u64) };
+        unreachable!("The fault terminates the process");
+    }
+
+    vibrio::upcalls::set_event_handler(
+        EVENT_SEGV,
+        Some(|_event| {
+            let vcpu = Process::vcpu_control_area().expect("Can't read vcpu control area");
+            assert_eq!(vcpu.fault_address.as_u64(), FAULT_ADDRESS);
+            // Skip the load below (`mov rax, [rdi]` is 3 bytes long)
+            vcpu.enabled_state.rip += 3;
+            HANDLED.store(true, Ordering::SeqCst);
+        }),
+    )
+    .expect("Can't subscribe to EVENT_SEGV");
+    unsafe { asm!("mov rax, [rdi]", in("rdi") FAULT_ADDRESS, out("rax") _) };
+    assert!(HANDLED.load(Ordering::SeqCst));
+    vibrio::upcalls::set_event_handler(EVENT_SEGV, None).expect("Can't unsubscribe");
+    info!("fault_test: handler skipped the fault");
+
+    let fd = Fs::open(
+        marker,
+        u64::from(FileFlags::O_RDWR | FileFlags::O_CREAT),
+        u64::from(FileModes::S_IRWXU),
+    )
+    .expect("FileOpen syscall failed");
+    Fs::close(fd).expect("FileClose syscall failed");
+
+    let child = Process::spawn("init\0".as_ptr() as u64, 1).expect("Spawn syscall failed");
+    assert_eq!(Process::wait(child), Ok(128 + EVENT_SEGV));
+
+    Fs::delete(marker).expect("FileDelete syscall failed");
+    info!("fault_test OK");
+}
+
 pub fn install_vcpu_area() {
     let ctl =
         vibrio::syscalls::Process::vcpu_control_area().expect("Can't read vcpu control area.");
@@ -775,6 +1302,27 @@ pub extern "C" fn _start() -> ! {
     #[cfg(feature = "test-pmem-alloc")]
     pmem_alloc(ncores);
 
+    #[cfg(feature = "test-pmemfs")]
+    pmemfs_test();
+
+    #[cfg(feature = "test-numa-policy")]
+    numa_policy_test();
+
+    #[cfg(feature = "test-spawn")]
+    spawn_test();
+
+    #[cfg(feature = "test-sched-share")]
+    sched_share_test();
+
+    #[cfg(feature = "test-ipc")]
+    ipc_test();
+
+    #[cfg(feature = "test-events")]
+    events_test();
+
+    #[cfg(feature = "test-fault")]
+    fault_test();
+
     vibrio::vconsole::init();
 
     debug!("Done with init tests, if we came here probably everything is good.");